@@ -0,0 +1,199 @@
+//! values expected of the files once a run is done, for --assert
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    fmt::{self, Display},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+
+use crate::{encoding, find_target, read_entries, values_equal, Args, Log, Target};
+
+/// a value expected once the run is done, for --assert
+#[derive(Debug)]
+pub struct Assertion {
+    package: String,
+    target: Target,
+    section: Option<String>,
+    key: String,
+    value: String,
+}
+
+impl Display for Assertion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/{}/{}/{}",
+            self.package,
+            self.target.key(),
+            self.section.as_deref().unwrap_or_default(),
+            self.key
+        )
+    }
+}
+
+/// reads the assertions in a --assert file
+pub fn read(path: &Path) -> anyhow::Result<Vec<Assertion>> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("unable to read assertions {}", path.display()))?;
+    let expected: BTreeMap<String, serde_json::Value> = serde_json::from_str(&text)
+        .with_context(|| format!("unable to read assertions {}", path.display()))?;
+
+    expected
+        .into_iter()
+        .map(|(address, value)| {
+            let parts: Vec<_> = address.split('/').collect();
+            let &[package, file, section, key] = parts.as_slice() else {
+                anyhow::bail!("assertion {address:?} is not package/file/section/key");
+            };
+            let target = Target::find(file)
+                .ok_or_else(|| anyhow::anyhow!("unknown file {file:?} in assertion {address:?}"))?;
+            let value = match value {
+                serde_json::Value::String(value) => value,
+                serde_json::Value::Number(value) => value.to_string(),
+                serde_json::Value::Bool(value) => value.to_string(),
+                _ => anyhow::bail!("assertion {address:?} must be a string, number or boolean"),
+            };
+            Ok(Assertion {
+                package: package.to_owned(),
+                target,
+                section: (!section.is_empty()).then(|| section.to_owned()),
+                key: key.to_owned(),
+                value,
+            })
+        })
+        .collect()
+}
+
+/// checks each assertion against the files as they are now, reporting every one that fails
+///
+/// A package is looked for among those the run checked, then in the packages directory.
+pub fn check(
+    assertions: &[Assertion],
+    checked: &[PathBuf],
+    root: &Path,
+    args: &Args,
+    log: &Log,
+) -> anyhow::Result<()> {
+    let mut failed = 0;
+    for assertion in assertions {
+        let package = checked
+            .iter()
+            .find(|path| path.file_name() == Some(OsStr::new(&assertion.package)))
+            .cloned()
+            .unwrap_or_else(|| root.join(&assertion.package));
+
+        let problem = if !package.is_dir() {
+            String::from("no such package")
+        } else {
+            match find_target(&package, assertion.target, args) {
+                None => format!("no {} file", assertion.target.key()),
+                Some(path) => {
+                    let text = encoding::read_to_string(&path)
+                        .with_context(|| format!("unable to read {}", path.display()))?;
+                    let address = match &assertion.section {
+                        Some(section) => format!("{section}.{}", assertion.key),
+                        None => assertion.key.clone(),
+                    };
+                    match read_entries(&text).into_iter().find(|(a, _)| *a == address) {
+                        None => String::from("no such key"),
+                        Some((_, value))
+                            if values_equal(&value, &assertion.value, args.epsilon) =>
+                        {
+                            continue;
+                        }
+                        Some((_, value)) => format!("expected {}, found {value}", assertion.value),
+                    }
+                }
+            }
+        };
+
+        log.error(format_args!("assertion failed: {assertion}: {problem}"));
+        failed += 1;
+    }
+
+    if failed > 0 {
+        anyhow::bail!("{failed} of {} assertion(s) failed", assertions.len());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsStr, process};
+
+    use clap::Parser;
+
+    use super::*;
+    use crate::run;
+
+    #[test]
+    fn failed_assertions_fail_the_run() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-assert-{}", process::id()));
+        let packages = dir.join("packages");
+        let tbm = packages.join("asobo-aircraft-tbm930/SimObjects/Airplanes/tbm");
+        fs::create_dir_all(&tbm).unwrap();
+        let engines = tbm.join("engines.cfg");
+        let patches = dir.join("patches.json");
+        fs::write(
+            &patches,
+            r#"{ "asobo-aircraft-tbm930": { "engines": { "GENERALENGINEDATA.static_thrust": "200" } } }"#,
+        )
+        .unwrap();
+        let expected = dir.join("expected.json");
+        let log_file = dir.join("patchcfg.log");
+
+        let asserted = |assertions: &str| {
+            fs::write(
+                &engines,
+                "[GENERALENGINEDATA]\nstatic_thrust = 100\nmax_rpm = 2000\n",
+            )
+            .unwrap();
+            fs::write(&expected, assertions).unwrap();
+            let args = Args::parse_from([
+                OsStr::new("patchcfg"),
+                OsStr::new("--force"),
+                OsStr::new("--assert"),
+                expected.as_os_str(),
+                OsStr::new("--log-file"),
+                log_file.as_os_str(),
+                packages.as_os_str(),
+                patches.as_os_str(),
+            ]);
+            let result = run(&args, &Log::new(&args).unwrap()).map(|_| ());
+            let errors: Vec<_> = fs::read_to_string(&log_file)
+                .unwrap()
+                .lines()
+                .filter_map(|line| line.strip_prefix("error: "))
+                .map(ToOwned::to_owned)
+                .collect();
+            (result.map_err(|e| format!("{e:#}")), errors)
+        };
+        let passing = asserted(
+            r#"{ "asobo-aircraft-tbm930/engines/GENERALENGINEDATA/static_thrust": 200.0 }"#,
+        );
+        let failing = asserted(
+            r#"{
+                "asobo-aircraft-tbm930/engines/GENERALENGINEDATA/static_thrust": "200",
+                "asobo-aircraft-tbm930/engines/GENERALENGINEDATA/max_rpm": "2100",
+                "asobo-aircraft-tbm930/engines/GENERALENGINEDATA/min_rpm": "500"
+            }"#,
+        );
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(passing, (Ok(()), Vec::new()));
+        assert_eq!(
+            failing,
+            (
+                Err(String::from("2 of 3 assertion(s) failed")),
+                vec![
+                    String::from("assertion failed: asobo-aircraft-tbm930/engines/GENERALENGINEDATA/max_rpm: expected 2100, found 2000"),
+                    String::from("assertion failed: asobo-aircraft-tbm930/engines/GENERALENGINEDATA/min_rpm: no such key"),
+                ]
+            )
+        );
+    }
+}
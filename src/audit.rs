@@ -0,0 +1,156 @@
+//! surveying an install without patching it, for the audit command
+
+use std::{collections::BTreeMap, io::Write, iter, path::Path};
+
+use crate::{encoding, find_target, read_entries, read_packages, split_address, Args, Target};
+
+/// prints the values of some keys across every package, for the audit command
+pub fn audit(
+    packages: &Path,
+    keys: &[String],
+    json: bool,
+    args: &Args,
+    out: &mut impl Write,
+) -> anyhow::Result<()> {
+    let mut rows: BTreeMap<String, BTreeMap<&str, String>> = BTreeMap::new();
+
+    for package in read_packages(packages, args.packages_depth)? {
+        let name = package.file_name().unwrap_or_default().to_string_lossy();
+        let mut values: BTreeMap<&str, Vec<String>> = BTreeMap::new();
+
+        for target in Target::all() {
+            let Some(text) = find_target(&package, target, args)
+                .and_then(|path| encoding::read_to_string(path).ok())
+            else {
+                continue;
+            };
+
+            for (address, value) in read_entries(&text) {
+                for key in keys {
+                    let matches = match split_address(key) {
+                        (Some(""), key) => address == key,
+                        (Some(_), _) => address == *key,
+                        (None, _) => split_address(&address).1 == key,
+                    };
+                    if matches {
+                        let found = values.entry(key).or_default();
+                        if !found.contains(&value) {
+                            found.push(value.clone());
+                        }
+                    }
+                }
+            }
+        }
+
+        if !values.is_empty() {
+            let values = values
+                .into_iter()
+                .map(|(key, values)| (key, values.join(" / ")))
+                .collect();
+            rows.insert(name.into_owned(), values);
+        }
+    }
+
+    if json {
+        writeln!(out, "{}", serde_json::to_string_pretty(&rows)?)?;
+        return Ok(());
+    }
+
+    // Each column is as wide as its widest cell, with - for a key a package doesn't have.
+
+    let header: Vec<&str> = iter::once("package")
+        .chain(keys.iter().map(String::as_str))
+        .collect();
+    let table: Vec<Vec<&str>> = rows
+        .iter()
+        .map(|(name, values)| {
+            iter::once(name.as_str())
+                .chain(
+                    keys.iter()
+                        .map(|key| values.get(key.as_str()).map_or("-", String::as_str)),
+                )
+                .collect()
+        })
+        .collect();
+    let widths: Vec<usize> = (0..header.len())
+        .map(|column| {
+            iter::once(&header)
+                .chain(&table)
+                .map(|row| row[column].chars().count())
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+
+    for row in iter::once(&header).chain(&table) {
+        let cells: Vec<_> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, &width)| format!("{cell:width$}"))
+            .collect();
+        writeln!(out, "{}", cells.join("  ").trim_end())?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, process};
+
+    use clap::Parser;
+
+    use super::*;
+
+    #[test]
+    fn audit_compares_keys_across_packages() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-audit-{}", process::id()));
+        let engines = |package: &str, text: &str| {
+            let path = dir.join(package).join("SimObjects/Airplanes/plane");
+            fs::create_dir_all(&path).unwrap();
+            fs::write(path.join("engines.cfg"), text).unwrap();
+        };
+        engines(
+            "asobo-aircraft-tbm930",
+            "[GENERALENGINEDATA]\nstatic_thrust = 1500\nmax_rpm = 2000\n",
+        );
+        engines(
+            "asobo-aircraft-c152",
+            "[GENERALENGINEDATA]\nmax_rpm = 2700\n\n[ENGINE.1]\nmax_rpm = 2600\n",
+        );
+        fs::create_dir_all(dir.join("asobo-airport-kjfk")).unwrap();
+        let keys = [
+            String::from("GENERALENGINEDATA.static_thrust"),
+            String::from("max_rpm"),
+        ];
+        let args = Args::parse_from(["patchcfg", "packages", "patches.json"]);
+
+        let audited = |json: bool| {
+            let mut out = Vec::new();
+            audit(&dir, &keys, json, &args, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let table = audited(false);
+        let json = audited(true);
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(
+            table,
+            "\
+package                GENERALENGINEDATA.static_thrust  max_rpm
+asobo-aircraft-c152    -                                2700 / 2600
+asobo-aircraft-tbm930  1500                             2000
+"
+        );
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&json).unwrap(),
+            serde_json::json!({
+                "asobo-aircraft-c152": { "max_rpm": "2700 / 2600" },
+                "asobo-aircraft-tbm930": {
+                    "GENERALENGINEDATA.static_thrust": "1500",
+                    "max_rpm": "2000"
+                }
+            })
+        );
+    }
+}
@@ -0,0 +1,262 @@
+//! clearing out old backups, for the prune-backups command
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+
+use crate::{
+    lock::{retry, Lock},
+    wildcard_match, Args, Log, Target,
+};
+
+/// deletes all but the newest `keep` backups of each file, or those older than `older_than`
+pub fn prune(
+    packages: &Path,
+    keep: Option<usize>,
+    older_than: Option<Duration>,
+    dry_run: bool,
+    args: &Args,
+    log: &Log,
+) -> anyhow::Result<()> {
+    let _lock = if dry_run {
+        None
+    } else {
+        Lock::for_packages(packages, args, log)?
+    };
+    let delta_suffix = format!("{}.json", args.backup_suffix);
+    let stems: Vec<_> = Target::all()
+        .flat_map(Target::filenames)
+        .filter_map(|filename| filename.strip_suffix(".cfg"))
+        .map(|stem| format!("{stem}.").to_lowercase())
+        .collect();
+
+    // Backups, with when they were last written, by the file they're of.
+    let mut backups: BTreeMap<PathBuf, Vec<_>> = BTreeMap::new();
+    for entry in walkdir::WalkDir::new(packages) {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_lowercase();
+        let Some(stem) = stems.iter().find(|stem| name.starts_with(stem.as_str())) else {
+            continue;
+        };
+
+        // However broad the suffix, a file we patch is never anyone's backup, and nor are the
+        // ones we keep beside it: its state for --track-changes, and a sidecar.
+        let rest = &name[stem.len()..];
+        if !entry.file_type().is_file()
+            || ["cfg", "patchcfg.json", "cfg.changes"].contains(&rest)
+            || !wildcard_match(&args.backup_suffix, rest) && !wildcard_match(&delta_suffix, rest)
+        {
+            continue;
+        }
+
+        // A backup of a file that isn't there is more likely something else by the same name.
+        let file_name = entry.file_name().to_string_lossy();
+        let original = entry.path().with_file_name(format!(
+            "{}cfg",
+            file_name.get(..stem.len()).unwrap_or(stem)
+        ));
+        if !original.is_file() {
+            continue;
+        }
+
+        let modified = entry.metadata()?.modified()?;
+        backups
+            .entry(original)
+            .or_default()
+            .push((modified, entry.into_path()));
+    }
+
+    let now = SystemTime::now();
+    for files in backups.values_mut() {
+        files.sort_by(|a, b| b.cmp(a));
+        for (idx, (modified, path)) in files.iter().enumerate() {
+            let surplus = keep.is_some_and(|keep| idx >= keep);
+            let old = older_than.is_some_and(|age| {
+                now.duration_since(*modified)
+                    .is_ok_and(|elapsed| elapsed > age)
+            });
+            if !surplus && !old {
+                continue;
+            }
+
+            if dry_run {
+                println!("would delete {}", path.display());
+            } else {
+                retry(args, || fs::remove_file(path))
+                    .with_context(|| format!("unable to delete {}", path.display()))?;
+                println!("deleted {}", path.display());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, process};
+
+    use clap::Parser;
+
+    use super::*;
+
+    #[test]
+    fn keeps_the_newest_backups() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-prune-{}", process::id()));
+        let tbm = dir.join("asobo-aircraft-tbm930/SimObjects/Airplanes/tbm");
+        fs::create_dir_all(&tbm).unwrap();
+        let now = SystemTime::now();
+        let files = [
+            ("engines.cfg", 0),
+            ("engines.2024-06-03.bak", 1),
+            ("engines.2024-06-02.bak", 2),
+            ("engines.2024-06-01.bak", 3),
+            ("notes.2024-06-01.bak", 3),
+        ];
+        for (name, days) in files {
+            let file = File::create(tbm.join(name)).unwrap();
+            file.set_modified(now - Duration::from_secs(days * 24 * 60 * 60))
+                .unwrap();
+        }
+        let remaining = || {
+            let mut names: Vec<_> = fs::read_dir(&tbm)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+                .collect();
+            names.sort();
+            names
+        };
+
+        let args = Args::parse_from([
+            "patchcfg",
+            "--backup-suffix",
+            "*.bak",
+            "prune-backups",
+            "--keep",
+            "1",
+            ".",
+        ]);
+        let log = Log::new(&args).unwrap();
+        let pruned = prune(&dir, Some(1), None, true, &args, &log)
+            .map(|()| remaining())
+            .and_then(|dry_run| {
+                prune(&dir, Some(1), None, false, &args, &log).map(|()| (dry_run, remaining()))
+            });
+        let _ = fs::remove_dir_all(&dir);
+
+        let (dry_run, pruned) = pruned.unwrap();
+        assert_eq!(dry_run.len(), files.len());
+        assert_eq!(
+            pruned,
+            [
+                "engines.2024-06-03.bak",
+                "engines.cfg",
+                "notes.2024-06-01.bak"
+            ]
+        );
+    }
+
+    #[test]
+    fn keep_zero_deletes_every_backup_but_no_config() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-prune-all-{}", process::id()));
+        let tbm = dir.join("asobo-aircraft-tbm930/SimObjects/Airplanes/tbm");
+        fs::create_dir_all(&tbm).unwrap();
+        let now = SystemTime::now();
+        let files = [
+            ("engines.cfg", 0),
+            ("engines.bak.cfg", 1),
+            ("flight_model.cfg", 0),
+            ("flight_model.bak.cfg.json", 1),
+            ("flight_model.bak.cfg", 5),
+        ];
+        for (name, days) in files {
+            let file = File::create(tbm.join(name)).unwrap();
+            file.set_modified(now - Duration::from_secs(days * 24 * 60 * 60))
+                .unwrap();
+        }
+        let remaining = || {
+            let mut names: Vec<_> = fs::read_dir(&tbm)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+                .collect();
+            names.sort();
+            names
+        };
+
+        let args = Args::parse_from(["patchcfg", "prune-backups", "--keep", "0", "."]);
+        let log = Log::new(&args).unwrap();
+        let two_days = Duration::from_secs(2 * 24 * 60 * 60);
+        let pruned = prune(&dir, None, Some(two_days), false, &args, &log)
+            .map(|()| remaining())
+            .and_then(|old| {
+                prune(&dir, Some(0), None, false, &args, &log).map(|()| (old, remaining()))
+            });
+        let _ = fs::remove_dir_all(&dir);
+
+        let (old, all) = pruned.unwrap();
+        assert_eq!(
+            old,
+            [
+                "engines.bak.cfg",
+                "engines.cfg",
+                "flight_model.bak.cfg.json",
+                "flight_model.cfg"
+            ]
+        );
+        assert_eq!(all, ["engines.cfg", "flight_model.cfg"]);
+    }
+
+    #[test]
+    fn only_backups_of_files_beside_them_are_pruned() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-prune-others-{}", process::id()));
+        let tbm = dir.join("asobo-aircraft-tbm930/SimObjects/Airplanes/tbm");
+        fs::create_dir_all(&tbm).unwrap();
+        let files = [
+            "Engines.cfg",
+            "Engines.bak.cfg",
+            "engines.patchcfg.json",
+            "engines.cfg.changes",
+            "flight_model.bak.cfg",
+            "notes.bak.cfg",
+        ];
+        for name in files {
+            File::create(tbm.join(name)).unwrap();
+        }
+
+        let args = Args::parse_from([
+            "patchcfg",
+            "--backup-suffix",
+            "*",
+            "prune-backups",
+            "--keep",
+            "0",
+            ".",
+        ]);
+        let log = Log::new(&args).unwrap();
+        let pruned = prune(&dir, Some(0), None, false, &args, &log).map(|()| {
+            let mut names: Vec<_> = fs::read_dir(&tbm)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+                .collect();
+            names.sort();
+            names
+        });
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(
+            pruned.unwrap(),
+            [
+                "Engines.cfg",
+                "engines.cfg.changes",
+                "engines.patchcfg.json",
+                "flight_model.bak.cfg",
+                "notes.bak.cfg"
+            ]
+        );
+    }
+}
@@ -0,0 +1,96 @@
+//! colored terminal output
+//!
+//! We only need a handful of colors, so rather than take a dependency, we write the ANSI escapes
+//! ourselves.
+
+use std::io::IsTerminal;
+
+use clap::ValueEnum;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ColorChoice {
+    /// color output written to a terminal
+    Auto,
+    Always,
+    Never,
+}
+
+/// colors text, or doesn't, according to the user's choice
+#[derive(Clone, Copy, Debug)]
+pub struct Paint {
+    enabled: bool,
+}
+
+impl Paint {
+    /// paint for output to `stream`, which is only checked under `auto`
+    pub fn new(choice: ColorChoice, stream: &impl IsTerminal) -> Self {
+        let enabled = match choice {
+            ColorChoice::Auto => stream.is_terminal() && std::env::var_os("NO_COLOR").is_none(),
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        };
+        Paint { enabled }
+    }
+
+    pub fn red(self, text: &str) -> String {
+        self.paint("31", text)
+    }
+
+    pub fn green(self, text: &str) -> String {
+        self.paint("32", text)
+    }
+
+    pub fn yellow(self, text: &str) -> String {
+        self.paint("33", text)
+    }
+
+    pub fn bold(self, text: &str) -> String {
+        self.paint("1", text)
+    }
+
+    fn paint(self, code: &str, text: &str) -> String {
+        if self.enabled {
+            format!("\x1b[{code}m{text}\x1b[0m")
+        } else {
+            text.to_owned()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, process};
+
+    use super::*;
+
+    #[test]
+    fn only_color_when_asked_or_on_a_terminal() {
+        let path = std::env::temp_dir().join(format!("patchcfg-color-{}", process::id()));
+        let file = File::create(&path).unwrap();
+        let painted = |choice: ColorChoice| {
+            let paint = Paint::new(choice, &file);
+            [
+                paint.red("old"),
+                paint.green("new"),
+                paint.yellow("warning"),
+                paint.bold("title"),
+            ]
+        };
+        let never = painted(ColorChoice::Never);
+        let auto = painted(ColorChoice::Auto);
+        let always = painted(ColorChoice::Always);
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(never, ["old", "new", "warning", "title"]);
+        assert_eq!(auto, never);
+        assert_eq!(
+            always,
+            [
+                "\x1b[31mold\x1b[0m",
+                "\x1b[32mnew\x1b[0m",
+                "\x1b[33mwarning\x1b[0m",
+                "\x1b[1mtitle\x1b[0m",
+            ]
+        );
+    }
+}
@@ -0,0 +1,241 @@
+//! comparing config files, key by key
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, Write},
+    path::Path,
+};
+
+use anyhow::Context;
+use hashbrown::HashMap;
+use serde::Deserialize;
+
+use crate::{color::Paint, encoding, read_entries, values_equal, ChangeRecord};
+
+/// prints the differences between two config files, for the diff command
+pub fn files(
+    a: &Path,
+    b: &Path,
+    epsilon: f64,
+    paint: Paint,
+    out: &mut impl Write,
+) -> anyhow::Result<()> {
+    let read = |path: &Path| {
+        encoding::read_to_string(path)
+            .map(|text| read_entries(&text))
+            .with_context(|| format!("unable to read {}", path.display()))
+    };
+    print_differences(
+        (&a.display().to_string(), &read(a)?),
+        (&b.display().to_string(), &read(b)?),
+        epsilon,
+        paint,
+        out,
+    )?;
+    Ok(())
+}
+
+/// prints the keys whose values differ between two sets of entries, each named for the keys
+/// found in only one
+pub fn print_differences(
+    (name_a, entries_a): (&str, &[(String, String)]),
+    (name_b, entries_b): (&str, &[(String, String)]),
+    epsilon: f64,
+    paint: Paint,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let lookup_a: HashMap<_, _> = entries_a.iter().cloned().collect();
+    let lookup_b: HashMap<_, _> = entries_b.iter().cloned().collect();
+
+    for (address, old) in entries_a {
+        match lookup_b.get(address) {
+            Some(new) if !values_equal(old, new, epsilon) => {
+                writeln!(
+                    out,
+                    "{address}:\n\t{}\n\t{}",
+                    paint.red(old),
+                    paint.green(new)
+                )?;
+            }
+            Some(_) => {}
+            None => writeln!(out, "{address}: only in {name_a}\n\t{}", paint.red(old))?,
+        }
+    }
+
+    for (address, new) in entries_b {
+        if !lookup_a.contains_key(address) {
+            writeln!(out, "{address}: only in {name_b}\n\t{}", paint.green(new))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// prints the changes that differ between two run reports, for the compare-runs command
+pub fn runs(a: &Path, b: &Path, paint: Paint, out: &mut impl Write) -> anyhow::Result<()> {
+    #[derive(Deserialize)]
+    struct Report {
+        changes: Option<Vec<ChangeRecord>>,
+    }
+
+    let read = |path: &Path| -> anyhow::Result<BTreeMap<String, ChangeRecord>> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("unable to read {}", path.display()))?;
+        let report: Report = serde_json::from_str(&text)
+            .with_context(|| format!("unable to read report {}", path.display()))?;
+        let changes = report.changes.with_context(|| {
+            format!(
+                "{} has no changes; write it with --summary-json --with-changes",
+                path.display()
+            )
+        })?;
+        Ok(changes
+            .into_iter()
+            .map(|change| (change.address(), change))
+            .collect())
+    };
+    let (changes_a, changes_b) = (read(a)?, read(b)?);
+    let describe = |change: &ChangeRecord| match &change.old {
+        Some(old) => format!("{old} -> {}", change.new),
+        None => format!("added {}", change.new),
+    };
+
+    for (address, change) in &changes_a {
+        match changes_b.get(address) {
+            Some(other) if other.old != change.old || other.new != change.new => {
+                writeln!(
+                    out,
+                    "{address}:\n\t{}\n\t{}",
+                    paint.red(&describe(change)),
+                    paint.green(&describe(other))
+                )?;
+            }
+            Some(_) => {}
+            None => writeln!(
+                out,
+                "{address}: only in {}\n\t{}",
+                a.display(),
+                paint.red(&describe(change))
+            )?,
+        }
+    }
+
+    for (address, change) in &changes_b {
+        if !changes_a.contains_key(address) {
+            writeln!(
+                out,
+                "{address}: only in {}\n\t{}",
+                b.display(),
+                paint.green(&describe(change))
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use super::*;
+    use crate::color::ColorChoice;
+
+    #[test]
+    fn files_differ_by_key() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-compare-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (a, b) = (dir.join("a.cfg"), dir.join("b.cfg"));
+        fs::write(
+            &a,
+            "[GENERALENGINEDATA]\nstatic_thrust = 100\nmax_rpm = 2000.0\nmin_throttle = 0\n",
+        )
+        .unwrap();
+        fs::write(
+            &b,
+            "[GENERALENGINEDATA]\nstatic_thrust = 200 ; tuned\nmax_rpm = 2000\n\n[FUEL]\nsize = 3\n",
+        )
+        .unwrap();
+
+        let mut out = Vec::new();
+        let paint = Paint::new(ColorChoice::Never, &io::stdout());
+        let compared = files(&a, &b, 0.0, paint, &mut out);
+        let missing = files(&a, &dir.join("c.cfg"), 0.0, paint, &mut Vec::new());
+        let _ = fs::remove_dir_all(&dir);
+
+        compared.unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!(
+                "GENERALENGINEDATA.static_thrust:\n\t100\n\t200\n\
+                 GENERALENGINEDATA.min_throttle: only in {}\n\t0\n\
+                 FUEL.size: only in {}\n\t3\n",
+                a.display(),
+                b.display()
+            )
+        );
+        assert!(missing
+            .unwrap_err()
+            .to_string()
+            .starts_with("unable to read"));
+    }
+
+    #[test]
+    fn runs_differ_by_change() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-compare-runs-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (a, b) = (dir.join("a.json"), dir.join("b.json"));
+        let change = |key: &str, old: Option<&str>, new: &str| {
+            serde_json::json!({
+                "package": "asobo-aircraft-tbm930",
+                "file": "engines.cfg",
+                "section": "GENERALENGINEDATA",
+                "key": key,
+                "old": old,
+                "new": new,
+            })
+        };
+        let report = |changes: serde_json::Value| {
+            serde_json::json!({ "run_id": "01J0", "packages_matched": 1, "changes": changes })
+                .to_string()
+        };
+        fs::write(
+            &a,
+            report(serde_json::json!([
+                change("static_thrust", Some("100"), "200"),
+                change("max_rpm", Some("2000"), "2100"),
+            ])),
+        )
+        .unwrap();
+        fs::write(
+            &b,
+            report(serde_json::json!([
+                change("static_thrust", Some("100"), "250"),
+                change("max_rpm", Some("2000"), "2100"),
+                change("min_throttle_limit", None, "-0.2"),
+            ])),
+        )
+        .unwrap();
+        fs::write(dir.join("c.json"), r#"{ "run_id": "01J1" }"#).unwrap();
+
+        let mut out = Vec::new();
+        let paint = Paint::new(ColorChoice::Never, &io::stdout());
+        let compared = runs(&a, &b, paint, &mut out);
+        let without_changes = runs(&a, &dir.join("c.json"), paint, &mut Vec::new());
+        let _ = fs::remove_dir_all(&dir);
+
+        compared.unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!(
+                "asobo-aircraft-tbm930: engines.cfg: GENERALENGINEDATA.static_thrust:\n\t100 -> 200\n\t100 -> 250\n\
+                 asobo-aircraft-tbm930: engines.cfg: GENERALENGINEDATA.min_throttle_limit: only in {}\n\tadded -0.2\n",
+                b.display()
+            )
+        );
+        let without_changes = without_changes.unwrap_err().to_string();
+        assert!(without_changes
+            .ends_with("has no changes; write it with --summary-json --with-changes"));
+    }
+}
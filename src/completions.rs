@@ -0,0 +1,486 @@
+//! shell completion scripts
+//!
+//! These are generated from the same clap definitions that parse our arguments, so they stay in
+//! step with the CLI as options are added. We only need to understand one level of subcommands.
+
+use std::io::{self, Write};
+
+use clap::{Command, ValueEnum};
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+    Powershell,
+}
+
+pub fn generate(shell: Shell, cmd: &Command, out: &mut impl Write) -> io::Result<()> {
+    let mut cmd = cmd.clone();
+    cmd.build();
+
+    let spec = Spec::new(&cmd);
+    let script = match shell {
+        Shell::Bash => bash(&spec),
+        Shell::Zsh => zsh(&spec),
+        Shell::Fish => fish(&spec),
+        Shell::Powershell => powershell(&spec),
+    };
+
+    out.write_all(script.as_bytes())
+}
+
+/// everything the scripts need to know about a command
+struct Spec {
+    name: String,
+    about: String,
+    options: Vec<Opt>,
+
+    /// possible values for positional arguments, if they have any
+    values: Vec<String>,
+    subcommands: Vec<Spec>,
+}
+
+struct Opt {
+    short: Option<char>,
+    long: Option<String>,
+    help: String,
+    takes_value: bool,
+    values: Vec<String>,
+}
+
+impl Opt {
+    fn flags(&self) -> Vec<String> {
+        let short = self.short.map(|short| format!("-{short}"));
+        let long = self.long.as_ref().map(|long| format!("--{long}"));
+        short.into_iter().chain(long).collect()
+    }
+}
+
+impl Spec {
+    fn new(cmd: &Command) -> Self {
+        let options = cmd
+            .get_arguments()
+            .filter(|arg| !arg.is_positional() && !arg.is_hide_set())
+            .map(|arg| Opt {
+                short: arg.get_short(),
+                long: arg.get_long().map(ToOwned::to_owned),
+                help: arg.get_help().unwrap_or_default().to_owned(),
+                takes_value: arg.is_takes_value_set(),
+                values: possible_values(arg),
+            })
+            .collect();
+
+        let values = cmd.get_positionals().flat_map(possible_values).collect();
+
+        let subcommands = cmd
+            .get_subcommands()
+            .filter(|sub| sub.get_name() != "help")
+            .map(Spec::new)
+            .collect();
+
+        Spec {
+            name: cmd.get_name().to_owned(),
+            about: cmd.get_about().unwrap_or_default().to_owned(),
+            options,
+            values,
+            subcommands,
+        }
+    }
+
+    /// words to suggest in place of a positional argument
+    fn words(&self) -> Vec<&str> {
+        self.subcommands
+            .iter()
+            .map(|sub| sub.name.as_str())
+            .chain(self.values.iter().map(String::as_str))
+            .collect()
+    }
+}
+
+fn possible_values(arg: &clap::Arg) -> Vec<String> {
+    // Derived value enums still register their values the old way in clap 3.2, so we have to
+    // check there before asking the value parser.
+
+    #[allow(deprecated)]
+    if let Some(values) = arg.get_possible_values() {
+        return values
+            .iter()
+            .map(|value| value.get_name().to_owned())
+            .collect();
+    }
+
+    arg.get_value_parser()
+        .possible_values()
+        .map(|values| values.map(|value| value.get_name().to_owned()).collect())
+        .unwrap_or_default()
+}
+
+fn bash(spec: &Spec) -> String {
+    let name = &spec.name;
+    let subcommands: Vec<_> = spec
+        .subcommands
+        .iter()
+        .map(|sub| sub.name.as_str())
+        .collect();
+
+    let mut script = format!(
+        "_{name}() {{
+    local cur prev cmd opts words
+    cur=\"${{COMP_WORDS[COMP_CWORD]}}\"
+    prev=\"${{COMP_WORDS[COMP_CWORD-1]}}\"
+    cmd=\"\"
+
+    for word in \"${{COMP_WORDS[@]:1:COMP_CWORD-1}}\"; do
+        case \"$word\" in
+            {}) cmd=\"$word\"; break ;;
+        esac
+    done
+
+    case \"$cmd\" in
+",
+        if subcommands.is_empty() {
+            String::from("\"\"")
+        } else {
+            subcommands.join("|")
+        }
+    );
+
+    for (pattern, spec) in spec
+        .subcommands
+        .iter()
+        .map(|sub| (sub.name.as_str(), sub))
+        .chain([("*", spec)])
+    {
+        script += &format!("        {pattern})\n");
+        script += &bash_command(spec);
+        script += "            ;;\n";
+    }
+
+    script += &format!(
+        "    esac
+
+    if [[ \"$cur\" == -* ]]; then
+        COMPREPLY=($(compgen -W \"$opts\" -- \"$cur\"))
+    else
+        COMPREPLY=($(compgen -W \"$words\" -- \"$cur\"))
+    fi
+}}
+
+complete -F _{name} -o bashdefault -o default {name}
+"
+    );
+
+    script
+}
+
+fn bash_command(spec: &Spec) -> String {
+    let opts: Vec<_> = spec.options.iter().flat_map(Opt::flags).collect();
+    let mut script = format!(
+        "            opts=\"{}\"\n            words=\"{}\"\n",
+        opts.join(" "),
+        spec.words().join(" ")
+    );
+
+    let valued: Vec<_> = spec.options.iter().filter(|opt| opt.takes_value).collect();
+    if !valued.is_empty() {
+        script += "            case \"$prev\" in\n";
+        for opt in valued {
+            let reply = if opt.values.is_empty() {
+                String::from("$(compgen -f -- \"$cur\")")
+            } else {
+                format!("$(compgen -W \"{}\" -- \"$cur\")", opt.values.join(" "))
+            };
+            script += &format!(
+                "                {}) COMPREPLY=({reply}); return 0 ;;\n",
+                opt.flags().join("|")
+            );
+        }
+        script += "            esac\n";
+    }
+
+    script
+}
+
+fn zsh(spec: &Spec) -> String {
+    let name = &spec.name;
+    let commands: Vec<_> = spec
+        .subcommands
+        .iter()
+        .map(|sub| format!("'{}:{}'", sub.name, zsh_escape(&sub.about)))
+        .collect();
+
+    let mut script = format!(
+        "#compdef {name}
+
+_{name}() {{
+    local line state
+    local -a commands
+    commands=({})
+
+    _arguments -C \\
+{}        '1: :->first' \\
+        '*:: :->rest'
+
+    case $state in
+        first)
+            _alternative 'commands:command:(($commands))' 'files:file:_files'
+            ;;
+        rest)
+            case $line[1] in
+",
+        commands.join(" "),
+        zsh_options(&spec.options)
+    );
+
+    for sub in &spec.subcommands {
+        let positional = if sub.values.is_empty() {
+            String::from("'*: :_files'")
+        } else {
+            format!("'1: :({})'", sub.values.join(" "))
+        };
+
+        script += &format!(
+            "                {})\n                    _arguments \\\n{}                        {positional}\n                    ;;\n",
+            sub.name,
+            zsh_options(&sub.options).replace("        '", "                        '"),
+        );
+    }
+
+    script += &format!(
+        "                *)
+                    _files
+                    ;;
+            esac
+            ;;
+    esac
+}}
+
+_{name} \"$@\"
+"
+    );
+
+    script
+}
+
+fn zsh_options(options: &[Opt]) -> String {
+    let mut lines = String::new();
+    for opt in options {
+        let flags = opt.flags();
+        let help = zsh_escape(&opt.help);
+        let value = if !opt.takes_value {
+            String::new()
+        } else if opt.values.is_empty() {
+            String::from(": :_files")
+        } else {
+            format!(": :({})", opt.values.join(" "))
+        };
+
+        let spec = match flags.as_slice() {
+            [flag] => format!("'{flag}[{help}]{value}'"),
+            _ => format!(
+                "'({})'{{{}}}'[{help}]{value}'",
+                flags.join(" "),
+                flags.join(",")
+            ),
+        };
+        lines += &format!("        {spec} \\\n");
+    }
+    lines
+}
+
+fn zsh_escape(text: &str) -> String {
+    text.replace('\'', "'\\''")
+        .replace('[', "\\[")
+        .replace(']', "\\]")
+        .replace(':', "\\:")
+}
+
+fn fish(spec: &Spec) -> String {
+    let name = &spec.name;
+    let subcommands: Vec<_> = spec
+        .subcommands
+        .iter()
+        .map(|sub| sub.name.as_str())
+        .collect();
+    let mut script = String::new();
+
+    let top = if subcommands.is_empty() {
+        String::new()
+    } else {
+        String::from(" -n \"__fish_use_subcommand\"")
+    };
+
+    script += &fish_options(name, &top, &spec.options);
+    for sub in &spec.subcommands {
+        script += &format!(
+            "complete -c {name}{top} -f -a \"{}\" -d '{}'\n",
+            sub.name,
+            fish_escape(&sub.about)
+        );
+    }
+
+    for sub in &spec.subcommands {
+        let condition = format!(" -n \"__fish_seen_subcommand_from {}\"", sub.name);
+        script += &fish_options(name, &condition, &sub.options);
+        if !sub.values.is_empty() {
+            script += &format!(
+                "complete -c {name}{condition} -f -a \"{}\"\n",
+                sub.values.join(" ")
+            );
+        }
+    }
+
+    script
+}
+
+fn fish_options(name: &str, condition: &str, options: &[Opt]) -> String {
+    let mut lines = String::new();
+    for opt in options {
+        let mut line = format!("complete -c {name}{condition}");
+        if let Some(short) = opt.short {
+            line += &format!(" -s {short}");
+        }
+        if let Some(long) = &opt.long {
+            line += &format!(" -l {long}");
+        }
+        line += &format!(" -d '{}'", fish_escape(&opt.help));
+        if opt.takes_value {
+            line += " -r";
+        }
+        if !opt.values.is_empty() {
+            line += &format!(" -f -a \"{}\"", opt.values.join(" "));
+        }
+        lines += &line;
+        lines.push('\n');
+    }
+    lines
+}
+
+fn fish_escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('\'', "\\'")
+}
+
+fn powershell(spec: &Spec) -> String {
+    let name = &spec.name;
+    let subcommands: Vec<_> = spec
+        .subcommands
+        .iter()
+        .map(|sub| format!("'{}'", sub.name))
+        .collect();
+
+    let mut script = format!(
+        "using namespace System.Management.Automation
+using namespace System.Management.Automation.Language
+
+Register-ArgumentCompleter -Native -CommandName '{name}' -ScriptBlock {{
+    param($wordToComplete, $commandAst, $cursorPosition)
+
+    $command = '{name}'
+    foreach ($element in $commandAst.CommandElements | Select-Object -Skip 1) {{
+        if ($element -is [StringConstantExpressionAst] -and @({}) -contains $element.Value) {{
+            $command = \"$command;$($element.Value)\"
+            break
+        }}
+    }}
+
+    $completions = @(switch ($command) {{
+",
+        subcommands.join(", ")
+    );
+
+    script += &powershell_command(name, spec);
+    for sub in &spec.subcommands {
+        script += &powershell_command(&format!("{name};{}", sub.name), sub);
+    }
+
+    script += "    })
+
+    $completions.Where{ $_.CompletionText -like \"$wordToComplete*\" } |
+        Sort-Object -Property ListItemText
+}
+";
+
+    script
+}
+
+fn powershell_command(key: &str, spec: &Spec) -> String {
+    let mut block = format!("        '{key}' {{\n");
+    for opt in &spec.options {
+        let help = powershell_escape(&opt.help);
+        for flag in opt.flags() {
+            block += &format!(
+                "            [CompletionResult]::new('{flag}', '{flag}', [CompletionResultType]::ParameterName, '{help}')\n"
+            );
+        }
+    }
+
+    for sub in &spec.subcommands {
+        block += &format!(
+            "            [CompletionResult]::new('{0}', '{0}', [CompletionResultType]::ParameterValue, '{1}')\n",
+            sub.name,
+            powershell_escape(&sub.about)
+        );
+    }
+
+    for value in &spec.values {
+        block += &format!(
+            "            [CompletionResult]::new('{value}', '{value}', [CompletionResultType]::ParameterValue, '{value}')\n"
+        );
+    }
+
+    block += "            break\n        }\n";
+    block
+}
+
+fn powershell_escape(text: &str) -> String {
+    text.replace('\'', "''")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, process};
+
+    use clap::CommandFactory;
+
+    use super::*;
+
+    #[test]
+    fn generates_for_every_shell() {
+        let cmd = crate::Args::command();
+        let shells = [
+            (Shell::Bash, Some("bash")),
+            (Shell::Zsh, Some("zsh")),
+            (Shell::Fish, Some("fish")),
+            (Shell::Powershell, None),
+        ];
+
+        for (shell, checker) in shells {
+            let mut script = Vec::new();
+            generate(shell, &cmd, &mut script).unwrap();
+            let script = String::from_utf8(script).unwrap();
+            for word in [
+                "patchcfg",
+                "force",
+                "apply-plan",
+                "prune-backups",
+                "summary-json",
+            ] {
+                assert!(script.contains(word), "{shell:?} script lacks {word}");
+            }
+
+            // Where the shell is installed, it has to at least parse the script.
+            let Some(checker) = checker else {
+                continue;
+            };
+            let path = std::env::temp_dir()
+                .join(format!("patchcfg-completions-{}-{checker}", process::id()));
+            fs::write(&path, &script).unwrap();
+            let status = process::Command::new(checker).arg("-n").arg(&path).status();
+            let _ = fs::remove_file(&path);
+            if let Ok(status) = status {
+                assert!(status.success(), "{checker} can't parse its script");
+            }
+        }
+    }
+}
@@ -0,0 +1,210 @@
+//! differential backups, for --differential-backup
+//!
+//! Rather than a copy of the whole file, a differential backup records the lines a patch changed,
+//! as runs of patched lines to take out and original lines to put back. Lines keep their endings,
+//! so restoring gets back exactly the bytes we started with. The record also holds a checksum of
+//! the patched file, so we can tell when something else has changed it since.
+//!
+//! Made the other way round, from the patched file to the original, a delta is also how a plan
+//! (see --plan-out) records the changes it's to make.
+
+use serde::{Deserialize, Serialize};
+
+use crate::sha256;
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Delta {
+    /// the SHA-256 of the patched file
+    sha256: String,
+    hunks: Vec<Hunk>,
+}
+
+/// a run of patched lines, and the original lines they took the place of
+#[derive(Debug, Deserialize, Serialize)]
+struct Hunk {
+    /// the index of the first patched line
+    at: usize,
+
+    /// the number of patched lines to take out
+    removed: usize,
+    original: Vec<String>,
+}
+
+impl Delta {
+    pub fn new(original: &str, patched: &str) -> Self {
+        let a: Vec<_> = original.split_inclusive('\n').collect();
+        let b: Vec<_> = patched.split_inclusive('\n').collect();
+
+        // Most of a patched file is unchanged, and the diff is much quicker without the lines
+        // both files start and end with.
+        let start = a.iter().zip(&b).take_while(|(a, b)| a == b).count();
+        let end = a[start..]
+            .iter()
+            .rev()
+            .zip(b[start..].iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        let a = &a[start..a.len() - end];
+        let b = &b[start..b.len() - end];
+
+        let mut hunks = Vec::new();
+        let mut open = false;
+        let mut at = start;
+        for (x, y) in edits(a, b) {
+            if let (Some(_), Some(_)) = (x, y) {
+                open = false;
+                at += 1;
+                continue;
+            }
+            if !open {
+                hunks.push(Hunk {
+                    at,
+                    removed: 0,
+                    original: Vec::new(),
+                });
+                open = true;
+            }
+
+            let hunk = hunks.last_mut().expect("a hunk is open");
+            match x {
+                Some(x) => hunk.original.push(a[x].to_owned()),
+                None => {
+                    hunk.removed += 1;
+                    at += 1;
+                }
+            }
+        }
+
+        Delta {
+            sha256: sha256::hex_digest(patched.as_bytes()),
+            hunks,
+        }
+    }
+
+    /// reconstructs the original file from the patched one, unless it has changed since
+    pub fn apply(&self, patched: &str) -> Option<String> {
+        if sha256::hex_digest(patched.as_bytes()) != self.sha256 {
+            return None;
+        }
+
+        let lines: Vec<_> = patched.split_inclusive('\n').collect();
+        let mut original = String::with_capacity(patched.len());
+        let mut idx = 0;
+        for hunk in &self.hunks {
+            original.extend(lines.get(idx..hunk.at)?.iter().copied());
+            original.extend(hunk.original.iter().map(String::as_str));
+            idx = hunk.at + hunk.removed;
+        }
+        original.extend(lines.get(idx..)?.iter().copied());
+        Some(original)
+    }
+}
+
+/// a shortest edit script from `a` to `b`, as pairs of line indexes: (Some, Some) for a line kept,
+/// (Some, None) for a line only in `a`, and (None, Some) for one only in `b`
+///
+/// This is Myers' algorithm, which is quick when, as here, the files differ in only a few lines.
+fn edits(a: &[&str], b: &[&str]) -> Vec<(Option<usize>, Option<usize>)> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    let offset = n + m + 1;
+    let mut v = vec![0_isize; 2 * offset as usize + 1];
+    let mut trace = Vec::new();
+
+    'search: for d in 0..=n + m {
+        trace.push(v.clone());
+        for k in (-d..=d).step_by(2) {
+            let i = (offset + k) as usize;
+            let mut x = if k == -d || k != d && v[i - 1] < v[i + 1] {
+                v[i + 1]
+            } else {
+                v[i - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[i] = x;
+            if x >= n && y >= m {
+                break 'search;
+            }
+        }
+    }
+
+    // Work back from the end, through the furthest reaching path at each step.
+
+    let mut edits = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let k = x - y;
+        let prev_k =
+            if k == -d || k != d && v[(offset + k - 1) as usize] < v[(offset + k + 1) as usize] {
+                k + 1
+            } else {
+                k - 1
+            };
+        let prev_x = v[(offset + prev_k) as usize];
+        let prev_y = prev_x - prev_k;
+
+        while x > prev_x && y > prev_y {
+            x -= 1;
+            y -= 1;
+            edits.push((Some(x as usize), Some(y as usize)));
+        }
+        if d > 0 {
+            if x == prev_x {
+                edits.push((None, Some(prev_y as usize)));
+            } else {
+                edits.push((Some(prev_x as usize), None));
+            }
+        }
+        x = prev_x;
+        y = prev_y;
+    }
+
+    edits.reverse();
+    edits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn restores_the_original_bytes() {
+        let original = "[GENERAL]\r\na = 1 ; one\r\nb = 2\r\n\r\n[ENGINE.0]\r\nc = 3\r\nd = 4";
+        let cases = [
+            // a changed line, keeping its ending
+            "[GENERAL]\r\na = 10 ; 1 ; one\r\nb = 2\r\n\r\n[ENGINE.0]\r\nc = 3\r\nd = 4",
+            // lines added, and the last one losing its lack of an ending
+            "[GENERAL]\r\na = 1 ; one\r\nb = 2\r\ne = 5\r\n\r\n[ENGINE.0]\r\nc = 3\r\nd = 4\n",
+            // a section body replaced with fewer lines
+            "[GENERAL]\r\na = 1 ; one\r\nb = 2\r\n\r\n[ENGINE.0]\r\nf = 6\r\n",
+            // nothing changed at all
+            original,
+        ];
+        for patched in cases {
+            let delta = Delta::new(original, patched);
+            assert_eq!(
+                delta.apply(patched).as_deref(),
+                Some(original),
+                "{patched:?}"
+            );
+        }
+        assert!(Delta::new(original, original).hunks.is_empty());
+    }
+
+    #[test]
+    fn refuses_a_file_changed_since() {
+        let original = "[GENERAL]\na = 1\nb = 2\n";
+        let patched = "[GENERAL]\na = 10\nb = 2\n";
+        let delta = Delta::new(original, patched);
+
+        let json = serde_json::to_string(&delta).unwrap();
+        let delta: Delta = serde_json::from_str(&json).unwrap();
+        assert_eq!(delta.apply(patched).as_deref(), Some(original));
+        assert_eq!(delta.apply("[GENERAL]\na = 10\nb = 3\n"), None);
+        assert_eq!(delta.apply(original), None);
+    }
+}
@@ -0,0 +1,127 @@
+//! free disk space, for --require-backup-space
+//!
+//! The standard library can't tell us how much space is free, so we ask the system: `df` on Unix
+//! and PowerShell on Windows.
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::Context;
+
+/// stops the run if any volume hasn't room for the bytes needed in the directories on it, as
+/// `available` says
+///
+/// Packages listed with --packages-from may be spread over several disks.
+pub fn check(
+    needed: impl IntoIterator<Item = (PathBuf, u64)>,
+    available: impl Fn(&Path) -> anyhow::Result<(String, u64)>,
+) -> anyhow::Result<()> {
+    let mut volumes: BTreeMap<String, (u64, u64)> = BTreeMap::new();
+    for (dir, bytes) in needed {
+        let (volume, free) = available(&dir)?;
+        volumes.entry(volume).or_insert((0, free)).0 += bytes;
+    }
+
+    for (volume, (needed, free)) in volumes {
+        if needed > free {
+            anyhow::bail!(
+                "not enough space for backups on {volume}: {needed} bytes needed, {free} free"
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// the volume a directory is on, and the number of bytes free on it
+pub fn available(dir: &Path) -> anyhow::Result<(String, u64)> {
+    if cfg!(windows) {
+        available_windows(dir)
+    } else {
+        available_unix(dir)
+    }
+}
+
+fn available_unix(dir: &Path) -> anyhow::Result<(String, u64)> {
+    let output = Command::new("df")
+        .arg("-Pk")
+        .arg(dir)
+        .output()
+        .context("unable to run df")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "df failed for {}: {}",
+            dir.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    // POSIX output is a header, then: filesystem, 1024-blocks, used, available, capacity, and the
+    // mount point, which may contain spaces.
+
+    let text = String::from_utf8_lossy(&output.stdout);
+    let parse = || {
+        let line = text.lines().nth(1)?;
+        let fields: Vec<_> = line.split_whitespace().collect();
+        let available: u64 = fields.get(3)?.parse().ok()?;
+        let mount = fields.get(5..)?.join(" ");
+        Some((mount, available * 1024))
+    };
+    parse().ok_or_else(|| anyhow::anyhow!("unable to read df output for {}", dir.display()))
+}
+
+fn available_windows(dir: &Path) -> anyhow::Result<(String, u64)> {
+    let dir = dir.canonicalize()?;
+    let script = format!(
+        "$d = (Get-Item -LiteralPath '{}').PSDrive; \"$($d.Name) $($d.Free)\"",
+        dir.display().to_string().replace('\'', "''")
+    );
+    let output = Command::new("powershell")
+        .args(["-NoProfile", "-Command", &script])
+        .output()
+        .context("unable to run powershell")?;
+
+    let text = String::from_utf8_lossy(&output.stdout);
+    text.trim()
+        .split_once(' ')
+        .and_then(|(drive, free)| Some((format!("{drive}:"), free.parse().ok()?)))
+        .ok_or_else(|| anyhow::anyhow!("unable to find free space for {}", dir.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backups_must_fit_on_each_volume() {
+        let available = |dir: &Path| match dir.to_str() {
+            Some("/a" | "/b") => Ok((String::from("/"), 100)),
+            Some("/c") => Ok((String::from("/mnt"), 10)),
+            _ => anyhow::bail!("no such directory {}", dir.display()),
+        };
+        let needed = |sizes: &[(&str, u64)]| {
+            sizes
+                .iter()
+                .map(|&(dir, bytes)| (PathBuf::from(dir), bytes))
+                .collect::<Vec<_>>()
+        };
+
+        check(needed(&[("/a", 60), ("/b", 40), ("/c", 10)]), available).unwrap();
+        assert_eq!(
+            check(needed(&[("/a", 60), ("/b", 41), ("/c", 10)]), available)
+                .unwrap_err()
+                .to_string(),
+            "not enough space for backups on /: 101 bytes needed, 100 free"
+        );
+        assert_eq!(
+            check(needed(&[("/c", 11)]), available)
+                .unwrap_err()
+                .to_string(),
+            "not enough space for backups on /mnt: 11 bytes needed, 10 free"
+        );
+        assert!(check(needed(&[("/d", 1)]), available).is_err());
+    }
+}
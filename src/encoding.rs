@@ -0,0 +1,233 @@
+//! reading and writing config files in UTF-16
+//!
+//! Most config files are UTF-8 (or ASCII), but some localized installs write them in UTF-16,
+//! which always starts with a byte order mark, and some editors put a mark at the start of UTF-8
+//! too. We go by the mark, keep it out of the text, and write a file back the way we found it.
+//! The conversions are the standard library's.
+
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+    str,
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Encoding {
+    #[default]
+    Utf8,
+    Utf8Bom,
+    Utf16Le,
+    Utf16Be,
+}
+
+impl Encoding {
+    /// the byte order mark a file in this encoding starts with
+    fn mark(self) -> &'static [u8] {
+        match self {
+            Encoding::Utf8 => b"",
+            Encoding::Utf8Bom => b"\xef\xbb\xbf",
+            Encoding::Utf16Le => b"\xff\xfe",
+            Encoding::Utf16Be => b"\xfe\xff",
+        }
+    }
+}
+
+/// reads a config file, whatever its encoding
+pub fn read_to_string(path: impl AsRef<Path>) -> io::Result<String> {
+    read(path).map(|(text, _)| text)
+}
+
+/// reads a config file, along with its encoding
+pub fn read(path: impl AsRef<Path>) -> io::Result<(String, Encoding)> {
+    decode(&fs::read(path)?)
+}
+
+pub fn decode(bytes: &[u8]) -> io::Result<(String, Encoding)> {
+    let (encoding, units): (_, fn([u8; 2]) -> u16) = match bytes {
+        [0xff, 0xfe, ..] => (Encoding::Utf16Le, u16::from_le_bytes),
+        [0xfe, 0xff, ..] => (Encoding::Utf16Be, u16::from_be_bytes),
+        _ => {
+            let (encoding, body) = match bytes.strip_prefix(Encoding::Utf8Bom.mark()) {
+                Some(body) => (Encoding::Utf8Bom, body),
+                None => (Encoding::Utf8, bytes),
+            };
+            let text = String::from_utf8(body.to_vec())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            return Ok((text, encoding));
+        }
+    };
+
+    let body = &bytes[2..];
+    if !body.len().is_multiple_of(2) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "UTF-16 file has an odd number of bytes",
+        ));
+    }
+    let units: Vec<_> = body
+        .chunks_exact(2)
+        .map(|pair| units([pair[0], pair[1]]))
+        .collect();
+    let text =
+        String::from_utf16(&units).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok((text, encoding))
+}
+
+/// encodes UTF-8 text for writing, with the encoding's byte order mark, if it has one
+pub fn encode(text: &[u8], encoding: Encoding) -> Vec<u8> {
+    let bytes: fn(u16) -> [u8; 2] = match encoding {
+        Encoding::Utf8 => return text.to_vec(),
+        Encoding::Utf8Bom => return [encoding.mark(), text].concat(),
+        Encoding::Utf16Le => u16::to_le_bytes,
+        Encoding::Utf16Be => u16::to_be_bytes,
+    };
+
+    let text = String::from_utf8_lossy(text);
+    let mut encoded = Vec::with_capacity(2 + text.len() * 2);
+    encoded.extend_from_slice(encoding.mark());
+    for unit in text.encode_utf16() {
+        encoded.extend_from_slice(&bytes(unit));
+    }
+    encoded
+}
+
+/// encodes the UTF-8 written to it on its way to `inner`, so a file can be written a line at a
+/// time in its own encoding
+pub struct Writer<W: Write> {
+    inner: W,
+    encoding: Encoding,
+
+    /// the start of a character split between writes
+    pending: Vec<u8>,
+    started: bool,
+}
+
+impl<W: Write> Writer<W> {
+    pub fn new(inner: W, encoding: Encoding) -> Self {
+        Writer {
+            inner,
+            encoding,
+            pending: Vec::new(),
+            started: false,
+        }
+    }
+
+    /// writes the byte order mark if nothing else has been written, so that even an empty file
+    /// keeps its mark, and hands back the inner writer
+    pub fn finish(mut self) -> io::Result<W> {
+        if !self.pending.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "text ends in the middle of a character",
+            ));
+        }
+        if !self.started && self.encoding != Encoding::Utf8 {
+            self.inner.write_all(&encode(b"", self.encoding))?;
+        }
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.encoding == Encoding::Utf8 {
+            return self.inner.write(buf);
+        }
+
+        self.pending.extend_from_slice(buf);
+        let valid = match str::from_utf8(&self.pending) {
+            Ok(text) => text.len(),
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+        };
+
+        let mut encoded = encode(&self.pending[..valid], self.encoding);
+        if self.started {
+            encoded.drain(..self.encoding.mark().len());
+        }
+        self.inner.write_all(&encoded)?;
+        self.started = true;
+        self.pending.drain(..valid);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = "[FLTSIM.0]\ntitle = \"Überflieger ✈\" ; 𝄞\n";
+
+    #[test]
+    fn decodes_known_bytes() {
+        let le = [0xff, 0xfe, b'a', 0x00, 0xe9, 0x00, 0x3d, 0xd8, 0x00, 0xde];
+        let be = [0xfe, 0xff, 0x00, b'a', 0x00, 0xe9, 0xd8, 0x3d, 0xde, 0x00];
+        let bom = [0xef, 0xbb, 0xbf, b'a', 0xc3, 0xa9, 0xf0, 0x9f, 0x98, 0x80];
+        assert_eq!(
+            decode(&le).unwrap(),
+            (String::from("aé😀"), Encoding::Utf16Le)
+        );
+        assert_eq!(
+            decode(&be).unwrap(),
+            (String::from("aé😀"), Encoding::Utf16Be)
+        );
+        assert_eq!(
+            decode(&bom).unwrap(),
+            (String::from("aé😀"), Encoding::Utf8Bom)
+        );
+        assert_eq!(
+            decode("aé".as_bytes()).unwrap(),
+            (String::from("aé"), Encoding::Utf8)
+        );
+    }
+
+    #[test]
+    fn round_trips_every_encoding() {
+        for encoding in [
+            Encoding::Utf8,
+            Encoding::Utf8Bom,
+            Encoding::Utf16Le,
+            Encoding::Utf16Be,
+        ] {
+            let bytes = encode(TEXT.as_bytes(), encoding);
+            assert!(bytes.starts_with(encoding.mark()));
+            assert_eq!(decode(&bytes).unwrap(), (String::from(TEXT), encoding));
+        }
+    }
+
+    #[test]
+    fn utf8_mark_is_kept_out_of_the_text() {
+        let (text, encoding) = decode(b"\xef\xbb\xbf[GENERAL]\n").unwrap();
+        assert!(text.starts_with('['));
+        assert_eq!(
+            encode(text.as_bytes(), encoding),
+            b"\xef\xbb\xbf[GENERAL]\n"
+        );
+    }
+
+    #[test]
+    fn writer_matches_encode_across_split_characters() {
+        for encoding in [Encoding::Utf8Bom, Encoding::Utf16Le, Encoding::Utf16Be] {
+            let mut writer = Writer::new(Vec::new(), encoding);
+            for byte in TEXT.as_bytes() {
+                writer.write_all(&[*byte]).unwrap();
+            }
+            assert_eq!(writer.finish().unwrap(), encode(TEXT.as_bytes(), encoding));
+            assert_eq!(
+                Writer::new(Vec::new(), encoding).finish().unwrap(),
+                encoding.mark()
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_odd_utf16() {
+        assert!(decode(&[0xff, 0xfe, b'a']).is_err());
+    }
+}
@@ -0,0 +1,257 @@
+//! fetching patch files over HTTP, with a cache for when the server can't be reached
+//!
+//! The standard library has no HTTP client, so we ask curl, which also takes care of TLS,
+//! redirects and retrying. Each URL's last good copy is kept in the cache directory with its
+//! ETag, which we send back so the server can tell us the copy is still current without sending
+//! it again.
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::Context;
+
+use crate::sha256;
+
+/// how much to trust the cache
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// ask the server, falling back to the cache if it can't be reached
+    Revalidate,
+
+    /// use the cache and never ask the server
+    Offline,
+
+    /// ask the server, ignoring the cache
+    Refresh,
+}
+
+pub struct Fetched {
+    pub text: String,
+
+    /// why we used the cached copy, if the server couldn't give us one
+    pub fallback: Option<String>,
+}
+
+pub fn is_url(s: &str) -> bool {
+    s.starts_with("http://") || s.starts_with("https://")
+}
+
+/// the cache directory to use when none is given
+pub fn default_cache_dir() -> Option<PathBuf> {
+    if cfg!(windows) {
+        env::var_os("LOCALAPPDATA").map(|dir| PathBuf::from(dir).join("patchcfg"))
+    } else {
+        env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+            .map(|dir| dir.join("patchcfg"))
+    }
+}
+
+pub fn fetch(url: &str, dir: &Path, mode: Mode) -> anyhow::Result<Fetched> {
+    let key = sha256::hex_digest(url.as_bytes());
+    let body = dir.join(format!("{key}.json"));
+    let etag = dir.join(format!("{key}.etag"));
+    let cached = || {
+        fs::read_to_string(&body).with_context(|| format!("unable to read cached copy of {url}"))
+    };
+
+    if mode == Mode::Offline {
+        if !body.is_file() {
+            anyhow::bail!("no cached copy of {url} to use offline");
+        }
+        return Ok(Fetched {
+            text: cached()?,
+            fallback: None,
+        });
+    }
+
+    fs::create_dir_all(dir)
+        .with_context(|| format!("unable to create cache directory {}", dir.display()))?;
+    let known = match mode {
+        Mode::Revalidate if body.is_file() => fs::read_to_string(&etag).ok(),
+        _ => None,
+    };
+
+    match download(url, dir, &key, known.as_deref()) {
+        Ok(Response::NotModified) => Ok(Fetched {
+            text: cached()?,
+            fallback: None,
+        }),
+        Ok(Response::Fetched { text, etag: tag }) => {
+            fs::write(&body, &text)
+                .with_context(|| format!("unable to write {}", body.display()))?;
+            match tag {
+                Some(tag) => fs::write(&etag, tag)?,
+                None if etag.exists() => fs::remove_file(&etag)?,
+                None => {}
+            }
+            Ok(Fetched {
+                text,
+                fallback: None,
+            })
+        }
+        Err(e) if mode == Mode::Revalidate && body.is_file() => Ok(Fetched {
+            text: cached()?,
+            fallback: Some(format!("{e:#}")),
+        }),
+        Err(e) => Err(e),
+    }
+}
+
+enum Response {
+    NotModified,
+    Fetched { text: String, etag: Option<String> },
+}
+
+fn download(url: &str, dir: &Path, key: &str, etag: Option<&str>) -> anyhow::Result<Response> {
+    let part = dir.join(format!("{key}.part"));
+    let headers = dir.join(format!("{key}.headers"));
+
+    let mut command = Command::new("curl");
+    command
+        .args(["--silent", "--show-error", "--location", "--retry", "3"])
+        .args(["--write-out", "%{http_code}"])
+        .arg("--output")
+        .arg(&part)
+        .arg("--dump-header")
+        .arg(&headers);
+    if let Some(etag) = etag {
+        command.args(["--header", &format!("If-None-Match: {etag}")]);
+    }
+    let output = command.arg(url).output().context("unable to run curl");
+
+    let result = read_response(url, output, &part, &headers);
+    let _ = fs::remove_file(&part);
+    let _ = fs::remove_file(&headers);
+    result
+}
+
+fn read_response(
+    url: &str,
+    output: anyhow::Result<std::process::Output>,
+    part: &Path,
+    headers: &Path,
+) -> anyhow::Result<Response> {
+    let output = output?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "unable to fetch {url}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    match String::from_utf8_lossy(&output.stdout).trim() {
+        "304" => Ok(Response::NotModified),
+        status if status.starts_with('2') => {
+            let text = fs::read_to_string(part).with_context(|| format!("{url} is not UTF-8"))?;
+
+            // After a redirect, the headers file holds each response in turn, and the ETag we
+            // want is the last one's.
+            let headers = fs::read_to_string(headers).unwrap_or_default();
+            let etag = headers
+                .lines()
+                .rev()
+                .take_while(|line| !line.starts_with("HTTP/"))
+                .find_map(|line| {
+                    let (name, value) = line.split_once(':')?;
+                    name.eq_ignore_ascii_case("etag")
+                        .then(|| value.trim().to_owned())
+                });
+            Ok(Response::Fetched { text, etag })
+        }
+        status => anyhow::bail!("unable to fetch {url}: the server returned {status}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        process,
+        sync::mpsc,
+        thread,
+    };
+
+    use super::*;
+
+    /// serves each response in turn, one per connection, sending back the If-None-Match header
+    /// of each request
+    fn serve(responses: Vec<String>) -> (String, mpsc::Receiver<Option<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/patches.json", listener.local_addr().unwrap());
+        let (sender, requests) = mpsc::channel();
+
+        thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut etag = None;
+                for line in BufReader::new(&stream).lines() {
+                    let line = line.unwrap();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("if-none-match") {
+                            etag = Some(value.trim().to_owned());
+                        }
+                    }
+                }
+                stream.write_all(response.as_bytes()).unwrap();
+                sender.send(etag).unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    fn ok(etag: &str, body: &str) -> String {
+        format!(
+            "HTTP/1.1 200 OK\r\nETag: {etag}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        )
+    }
+
+    #[test]
+    fn revalidates_and_refreshes() {
+        if Command::new("curl").arg("--version").output().is_err() {
+            // no curl to test with
+            return;
+        }
+        let dir = env::temp_dir().join(format!("patchcfg-fetch-{}", process::id()));
+        let not_modified = String::from("HTTP/1.1 304 Not Modified\r\nConnection: close\r\n\r\n");
+        let (url, requests) = serve(vec![
+            ok("\"v1\"", "{\"first\": {}}"),
+            not_modified,
+            ok("\"v2\"", "{\"second\": {}}"),
+        ]);
+
+        let first = fetch(&url, &dir, Mode::Revalidate).map(|fetched| fetched.text);
+        let first_etag = requests.recv().unwrap();
+        let hit = fetch(&url, &dir, Mode::Revalidate).map(|fetched| fetched.text);
+        let hit_etag = requests.recv().unwrap();
+        let refreshed = fetch(&url, &dir, Mode::Refresh).map(|fetched| fetched.text);
+        let refresh_etag = requests.recv().unwrap();
+
+        // The server has stopped answering now, so these can only come from the cache.
+        let offline = fetch(&url, &dir, Mode::Offline).map(|fetched| fetched.text);
+        let fallback = fetch(&url, &dir, Mode::Revalidate);
+        let uncached = fetch("http://127.0.0.1:9/other.json", &dir, Mode::Offline);
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(first.unwrap(), "{\"first\": {}}");
+        assert_eq!(first_etag, None);
+        assert_eq!(hit.unwrap(), "{\"first\": {}}");
+        assert_eq!(hit_etag.as_deref(), Some("\"v1\""));
+        assert_eq!(refreshed.unwrap(), "{\"second\": {}}");
+        assert_eq!(refresh_etag, None);
+        assert_eq!(offline.unwrap(), "{\"second\": {}}");
+        let fallback = fallback.unwrap();
+        assert_eq!(fallback.text, "{\"second\": {}}");
+        assert!(fallback.fallback.is_some());
+        assert!(uncached.is_err());
+    }
+}
@@ -0,0 +1,121 @@
+//! line-by-line parsing of config files
+
+/// a single line of a config file
+///
+/// Values and comments are left exactly as they appear in the file, surrounding whitespace and
+/// all, so that we can write them back out unchanged.
+#[derive(Debug)]
+pub enum Line<'a> {
+    Blank,
+    Comment(&'a str),
+    Section {
+        name: &'a str,
+        comment: Option<Comment<'a>>,
+    },
+    Entry {
+        key: &'a str,
+        value: &'a str,
+        comment: Option<Comment<'a>>,
+    },
+
+    /// anything else, which we leave alone
+    Other(&'a str),
+}
+
+/// a trailing comment, along with the delimiter that introduced it
+///
+/// Most files use `;`, but some mix in `//`. We keep track of which, so that we write comments
+/// back the way we found them.
+#[derive(Clone, Copy, Debug)]
+pub struct Comment<'a> {
+    pub delimiter: &'static str,
+    pub text: &'a str,
+}
+
+pub fn parse(line: &str) -> Line<'_> {
+    let trimmed = line.trim();
+
+    if trimmed.is_empty() {
+        return Line::Blank;
+    }
+
+    if trimmed.starts_with(';') || trimmed.starts_with("//") {
+        return Line::Comment(line);
+    }
+
+    // Headers go first, so that an = in one, or in its comment, doesn't make a key of it. A line
+    // that only starts like a header, such as `[SECTION]=something`, is left alone rather than
+    // read as a key named `[SECTION]`.
+    if trimmed.starts_with('[') {
+        return match parse_section(line) {
+            Some((name, comment)) => Line::Section { name, comment },
+            None => Line::Other(line),
+        };
+    }
+
+    match line.split_once('=') {
+        Some((key, tail)) => {
+            let (value, comment) = split_comment(tail);
+            Line::Entry {
+                key: key.trim(),
+                value,
+                comment,
+            }
+        }
+        None => Line::Other(line),
+    }
+}
+
+fn parse_section(line: &str) -> Option<(&str, Option<Comment<'_>>)> {
+    // Hand-edited files sometimes comment their headers, e.g. `[ENGINE.0] ; left engine`.
+    let (header, comment) = split_comment(line);
+    let name = header.trim().strip_prefix('[')?.strip_suffix(']')?;
+    Some((name, comment))
+}
+
+/// splits text at whichever comment delimiter comes first
+pub fn split_comment(text: &str) -> (&str, Option<Comment<'_>>) {
+    let (at, delimiter) = match (text.find(';'), text.find("//")) {
+        (Some(semicolon), Some(slashes)) if slashes < semicolon => (slashes, "//"),
+        (Some(semicolon), _) => (semicolon, ";"),
+        (None, Some(slashes)) => (slashes, "//"),
+        (None, None) => return (text, None),
+    };
+
+    let comment = Comment {
+        delimiter,
+        text: &text[at + delimiter.len()..],
+    };
+    (&text[..at], Some(comment))
+}
+
+impl Line<'_> {
+    /// formats the line in our canonical `key = value ; comment` style
+    ///
+    /// Comment-only lines and lines we don't understand are kept as they are, less any trailing
+    /// whitespace.
+    pub fn canonical(&self) -> String {
+        match *self {
+            Line::Blank => String::new(),
+            Line::Comment(text) | Line::Other(text) => text.trim_end().to_owned(),
+            Line::Section { name, comment } => with_comment(format!("[{name}]"), comment),
+            Line::Entry {
+                key,
+                value,
+                comment,
+            } => with_comment(format!("{key} = {}", value.trim()), comment),
+        }
+    }
+}
+
+fn with_comment(text: String, comment: Option<Comment>) -> String {
+    match comment {
+        Some(Comment {
+            delimiter,
+            text: comment,
+        }) if !comment.trim().is_empty() => {
+            format!("{text} {delimiter} {}", comment.trim())
+        }
+        _ => text,
+    }
+}
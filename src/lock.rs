@@ -0,0 +1,233 @@
+//! locks: the one that keeps two runs from writing to the same packages at once, and the ones the
+//! sim takes on the files it loads, which we wait out
+
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process, thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+
+use crate::{ulid::Ulid, Args, Log};
+
+/// an advisory lock on the packages directory, released when dropped
+///
+/// The lock file holds the process ID, run ID, host name and start time of the run holding it,
+/// so a lock left behind by a run that crashed can be recognized and taken over.
+pub struct Lock {
+    path: PathBuf,
+}
+
+impl Lock {
+    /// how old a lock has to be before we assume its run crashed, whether or not we can check
+    const STALE_AFTER: Duration = Duration::from_secs(60 * 60);
+
+    pub fn acquire(path: PathBuf, run: Ulid, log: &Log) -> anyhow::Result<Self> {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let contents = format!("{} {run} {} {started}\n", process::id(), hostname());
+
+        for _ in 0..2 {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    file.write_all(contents.as_bytes())?;
+                    return Ok(Lock { path });
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    let holder = fs::read_to_string(&path).unwrap_or_default();
+                    if !Lock::is_stale(&path, &holder) {
+                        anyhow::bail!(
+                            "another run holds the lock {} ({}); if it isn't running, delete the lock",
+                            path.display(),
+                            holder.trim()
+                        );
+                    }
+
+                    log.warn(format_args!(
+                        "taking over stale lock {} ({})",
+                        path.display(),
+                        holder.trim()
+                    ));
+                    fs::remove_file(&path)?;
+                }
+                Err(e) => {
+                    return Err(e).with_context(|| format!("unable to lock {}", path.display()))
+                }
+            }
+        }
+
+        anyhow::bail!("unable to lock {}", path.display())
+    }
+
+    /// takes the lock on a packages directory for a command that writes to it, unless --no-lock
+    /// is given
+    pub fn for_packages(packages: &Path, args: &Args, log: &Log) -> anyhow::Result<Option<Self>> {
+        if args.no_lock {
+            return Ok(None);
+        }
+        let path = args
+            .lock_file
+            .clone()
+            .unwrap_or_else(|| packages.join(".patchcfg.lock"));
+        Lock::acquire(path, Ulid::new(), log).map(Some)
+    }
+
+    fn is_stale(path: &Path, holder: &str) -> bool {
+        // A lock older than any run takes is stale whoever holds it, which also covers a process
+        // ID that has since been reused. Locks written before the start time was recorded go by
+        // the file's age.
+
+        let mut fields = holder.split_whitespace();
+        let (pid, _run, host, started) =
+            (fields.next(), fields.next(), fields.next(), fields.next());
+        let started = started
+            .and_then(|secs| secs.parse().ok())
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+            .or_else(|| {
+                fs::metadata(path)
+                    .and_then(|metadata| metadata.modified())
+                    .ok()
+            });
+        if started
+            .and_then(|started| started.elapsed().ok())
+            .is_some_and(|age| age > Lock::STALE_AFTER)
+        {
+            return true;
+        }
+
+        // On Linux we can see whether the process holding the lock is still around, as long as
+        // it's on this machine, and not another sharing the drive.
+
+        let proc = Path::new("/proc");
+        match pid {
+            Some(pid) if proc.is_dir() && host.is_none_or(|host| host == hostname()) => {
+                !proc.join(pid).exists()
+            }
+            _ => false,
+        }
+    }
+}
+
+/// the name of this machine, for telling whose a lock is
+fn hostname() -> String {
+    env::var("COMPUTERNAME")
+        .ok()
+        .or_else(|| fs::read_to_string("/proc/sys/kernel/hostname").ok())
+        .or_else(|| fs::read_to_string("/etc/hostname").ok())
+        .map(|name| name.trim().to_owned())
+        .filter(|name| !name.is_empty() && !name.contains(char::is_whitespace))
+        .unwrap_or_else(|| String::from("unknown"))
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// retries an operation that fails because a file is locked, with exponential backoff
+///
+/// The sim briefly locks config files while it loads them. Anything other than a lock fails
+/// straight away.
+pub fn retry<T>(args: &Args, mut f: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+    let mut delay = Duration::from_millis(args.retry_delay);
+    let mut retries = 0;
+
+    loop {
+        match f() {
+            Err(e) if retries < args.retries && is_locked(&e) => {
+                thread::sleep(delay);
+                delay *= 2;
+                retries += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// whether an error is the sim holding a file open, which is only a thing on Windows; anywhere
+/// else, permission denied means just that, and retrying won't help
+fn is_locked(e: &io::Error) -> bool {
+    // ERROR_SHARING_VIOLATION and ERROR_LOCK_VIOLATION
+    const WINDOWS_LOCK_ERRORS: [i32; 2] = [32, 33];
+
+    cfg!(windows)
+        && (e.kind() == io::ErrorKind::PermissionDenied
+            || e.raw_os_error()
+                .is_some_and(|code| WINDOWS_LOCK_ERRORS.contains(&code)))
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[test]
+    fn stale_locks_are_recognized() {
+        let path = std::env::temp_dir().join(format!("patchcfg-lock-{}", process::id()));
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let holder =
+            |pid: u32, host: &str, started: u64| format!("{pid} {} {host} {started}", Ulid::new());
+        let host = hostname();
+        let running = process::id();
+
+        assert!(!Lock::is_stale(&path, &holder(running, &host, now)));
+        assert!(Lock::is_stale(
+            &path,
+            &holder(running, &host, now - 2 * 60 * 60)
+        ));
+        assert!(!Lock::is_stale(&path, &holder(u32::MAX, "elsewhere", now)));
+        if Path::new("/proc").is_dir() {
+            assert!(Lock::is_stale(&path, &holder(u32::MAX, &host, now)));
+        }
+
+        let args = Args::parse_from(["patchcfg", "packages", "patches.json"]);
+        let log = Log::new(&args).unwrap();
+        let lock = Lock::acquire(path.clone(), Ulid::new(), &log).unwrap();
+        assert!(Lock::acquire(path.clone(), Ulid::new(), &log).is_err());
+        drop(lock);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn retries_only_windows_locks() {
+        let args = Args::parse_from(["patchcfg", "--retry-delay", "1", "packages", "patches.json"]);
+        let attempts = |error: fn() -> io::Error| {
+            let mut attempts = 0;
+            let result = retry(&args, || {
+                attempts += 1;
+                if attempts < 3 {
+                    Err(error())
+                } else {
+                    Ok(())
+                }
+            });
+            (attempts, result.is_ok())
+        };
+
+        // a sharing violation that clears after two tries
+        let locked = attempts(|| io::Error::from_raw_os_error(32));
+        let denied = attempts(|| io::Error::from(io::ErrorKind::PermissionDenied));
+        if cfg!(windows) {
+            assert_eq!(locked, (3, true));
+            assert_eq!(denied, (3, true));
+        } else {
+            assert_eq!(locked, (1, false));
+            assert_eq!(denied, (1, false));
+        }
+        assert_eq!(
+            attempts(|| io::Error::from(io::ErrorKind::NotFound)),
+            (1, false)
+        );
+    }
+}
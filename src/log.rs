@@ -0,0 +1,232 @@
+//! warnings and errors, for the terminal and --log-file
+
+use std::{
+    fmt::{self, Display},
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use clap::ValueEnum;
+
+use crate::{color::Paint, Args};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ErrorFormat {
+    Human,
+    Json,
+}
+
+/// the package an error happened in, as context for --error-format json
+#[derive(Debug)]
+pub struct InPackage(pub PathBuf);
+
+impl Display for InPackage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.display().fmt(f)
+    }
+}
+
+/// warnings and errors, written to stderr and teed to the log file if there is one
+pub struct Log {
+    file: Option<File>,
+    paint: Paint,
+    error_format: ErrorFormat,
+
+    /// the number of warnings so far, for --fail-on-warning
+    warnings: AtomicUsize,
+
+    /// whether to keep all but errors off the terminal, for --summary-only-on-change
+    quiet: bool,
+}
+
+impl Log {
+    pub fn new(args: &Args) -> io::Result<Self> {
+        let file = match &args.log_file {
+            Some(path) => Some(
+                OpenOptions::new()
+                    .create(true)
+                    .write(true)
+                    .append(args.append_log)
+                    .truncate(!args.append_log)
+                    .open(path)?,
+            ),
+            None => None,
+        };
+
+        Ok(Log {
+            file,
+            paint: Paint::new(args.color, &io::stderr()),
+            error_format: args.error_format,
+            warnings: AtomicUsize::new(0),
+            quiet: args.summary_only_on_change,
+        })
+    }
+
+    pub fn warn(&self, message: impl Display) {
+        self.warnings.fetch_add(1, Ordering::Relaxed);
+        self.write("warning", self.paint.yellow("warning"), message);
+    }
+
+    pub fn error(&self, message: impl Display) {
+        self.write("error", self.paint.red("error"), message);
+    }
+
+    pub fn info(&self, message: impl Display) {
+        self.write("info", String::from("info"), message);
+    }
+
+    /// reports an error that ends the run (or with --ci, a package), as --error-format says
+    pub fn report(&self, e: &anyhow::Error) {
+        if self.error_format == ErrorFormat::Human {
+            self.error(format_args!("{e:#}"));
+            return;
+        }
+
+        eprintln!("{}", json_report(e));
+        self.to_file("error", format_args!("{e:#}"));
+    }
+
+    /// the number of warnings so far
+    pub fn warnings(&self) -> usize {
+        self.warnings.load(Ordering::Relaxed)
+    }
+
+    /// writes to the log file only, for details that would clutter the terminal
+    pub fn record(&self, message: impl Display) {
+        self.to_file("info", message);
+    }
+
+    /// writes a message, with its level painted for the terminal but plain for the log file
+    fn write(&self, level: &str, painted: String, message: impl Display) {
+        if !self.quiet || level == "error" {
+            eprintln!("{painted}: {message}");
+        }
+        self.to_file(level, message);
+    }
+
+    fn to_file(&self, level: &str, message: impl Display) {
+        // A log we can't write to shouldn't take the run down with it; stderr still has it.
+
+        if let Some(mut file) = self.file.as_ref() {
+            let _ = writeln!(file, "{level}: {message}");
+        }
+    }
+}
+
+/// an error as a JSON object, with the package it happened in and the context around it
+fn json_report(e: &anyhow::Error) -> serde_json::Value {
+    let package = e.downcast_ref::<InPackage>();
+    let mut context: Vec<_> = e
+        .chain()
+        .skip(usize::from(package.is_some()))
+        .map(ToString::to_string)
+        .collect();
+    let error = context.pop();
+    serde_json::json!({
+        "error": error,
+        "context": context,
+        "package": package.map(ToString::to_string),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsStr, fs, process};
+
+    use clap::Parser;
+
+    use super::*;
+    use crate::run;
+
+    #[test]
+    fn warnings_are_teed_to_the_log_file() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-log-{}", process::id()));
+        let package = dir.join("packages/asobo-aircraft-tbm930/SimObjects/Airplanes/tbm");
+        fs::create_dir_all(&package).unwrap();
+        fs::write(
+            package.join("engines.cfg"),
+            "[GENERALENGINEDATA]\nstatic_thrust = 100\n",
+        )
+        .unwrap();
+        let patches = dir.join("patches.json");
+        let patch = r#"{ "asobo-aircraft-tbm93": { "engines": { "GENERALENGINEDATA.static_thrust": "200" } } }"#;
+        fs::write(&patches, patch).unwrap();
+        let log_file = dir.join("patchcfg.log");
+
+        let packages = dir.join("packages");
+        let logged = |flags: &[&str]| {
+            let mut argv = vec![OsStr::new("patchcfg"), OsStr::new("--log-file")];
+            argv.push(log_file.as_os_str());
+            argv.extend(flags.iter().map(OsStr::new));
+            argv.extend([packages.as_os_str(), patches.as_os_str()]);
+            let args = Args::parse_from(argv);
+            let log = Log::new(&args).unwrap();
+            let _ = run(&args, &log);
+            (log.warnings(), fs::read_to_string(&log_file))
+        };
+        let (warnings, first) = logged(&[]);
+        let (_, appended) = logged(&["--append-log"]);
+        let (_, truncated) = logged(&[]);
+        let _ = fs::remove_dir_all(&dir);
+
+        let first = first.unwrap();
+        let near_miss =
+            "warning: no match for 'asobo-aircraft-tbm93'; did you mean 'asobo-aircraft-tbm930'?";
+        assert!(warnings > 0);
+        assert!(first.lines().any(|line| line == near_miss), "{first}");
+        assert!(first.lines().any(|line| line.starts_with("info: run ")));
+        let count = |log: io::Result<String>| {
+            log.unwrap()
+                .lines()
+                .filter(|line| *line == near_miss)
+                .count()
+        };
+        assert_eq!(count(appended), 2);
+        assert_eq!(count(truncated), 1);
+    }
+
+    #[test]
+    fn errors_are_reported_as_json() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-json-error-{}", process::id()));
+        let packages = dir.join("packages");
+        let package = packages.join("asobo-aircraft-tbm930");
+        let tbm = package.join("SimObjects/Airplanes/tbm");
+        fs::create_dir_all(&tbm).unwrap();
+        fs::write(
+            tbm.join("engines.cfg"),
+            "[GENERALENGINEDATA]\nstatic_thrust = high\n",
+        )
+        .unwrap();
+        let patches = dir.join("patches.json");
+
+        let failed = |patch: &str| {
+            fs::write(&patches, patch).unwrap();
+            let args = Args::parse_from([
+                OsStr::new("patchcfg"),
+                OsStr::new("--error-format=json"),
+                packages.as_os_str(),
+                patches.as_os_str(),
+            ]);
+            json_report(&run(&args, &Log::new(&args).unwrap()).unwrap_err())
+        };
+        let malformed = failed("{\n  \"asobo-aircraft-tbm930\": {\n}");
+        let in_package = failed(
+            r#"{ "asobo-aircraft-tbm930": { "engines": { "GENERALENGINEDATA.static_thrust": "@avg" } } }"#,
+        );
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(
+            malformed,
+            serde_json::json!({
+                "error": "EOF while parsing an object at line 3 column 1",
+                "context": [],
+                "package": null,
+            })
+        );
+        assert_eq!(in_package["package"], package.display().to_string());
+        let error = in_package["error"].as_str().unwrap();
+        assert!(error.contains("non-numeric value high"), "{in_package}");
+    }
+}
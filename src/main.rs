@@ -1,247 +1,8076 @@
 use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    env,
+    ffi::{OsStr, OsString},
+    fmt::{self, Display},
     fs,
     io::{self, Write},
+    iter,
+    ops::Range,
     path::{Path, PathBuf},
     process,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        OnceLock,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
-use clap::Parser;
-use hashbrown::HashMap;
-use serde::Deserialize;
+use anyhow::Context;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum, ValueSource};
+use color::{ColorChoice, Paint};
+use delta::Delta;
+use encoding::Encoding;
+use hashbrown::{HashMap, HashSet};
+use line::{Comment, Line};
+use lock::Lock;
+use log::{ErrorFormat, InPackage, Log};
+use plan::{Plan, PlannedFile};
+use render::{read_marker, render, write_modified_file, Written};
+use report::{change_records, ChangeRecord, Summary};
+use serde::{Deserialize, Serialize};
+use ulid::Ulid;
+
+mod assertions;
+mod audit;
+mod backups;
+mod color;
+mod compare;
+mod completions;
+mod csv;
+mod delta;
+mod disk;
+mod encoding;
+mod fetch;
+mod line;
+mod lock;
+mod log;
+mod plan;
+mod render;
+mod report;
+mod restore;
+mod sha256;
+mod sidecar;
+mod spreadsheet;
+mod tables;
+mod template;
+mod tidy;
+mod toml;
+mod ulid;
+mod zip;
 
 #[derive(Debug, Parser)]
-#[clap(version)]
+#[clap(version, subcommand_negates_reqs = true)]
+#[clap(group(
+    clap::ArgGroup::new("summary")
+        .multiple(true)
+        .args(&["summary-json", "summary-only-on-change", "ci"])
+))]
 struct Args {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
     /// packages directory
     ///
-    /// Packages found in this directory will be considered for patching.
-    packages: String,
+    /// Packages found in this directory will be considered for patching. With --root-marker or
+    /// --packages-from, there's no packages directory to give, and the patch file goes here
+    /// instead. May also come from a --project file.
+    #[clap(required_unless_present = "project")]
+    packages: Option<String>,
 
     /// patches
     ///
-    /// A file containing patches to be applied
-    patches: String,
+    /// A file containing patches to be applied, or an http:// or https:// URL to fetch one from.
+    /// May be left out if patchcfg was built with default patches.
+    #[cfg_attr(
+        not(feature = "defaults"),
+        clap(required_unless_present_any = &["root-marker", "packages-from", "set", "csv", "patch-from-git", "project"])
+    )]
+    patches: Option<String>,
+
+    /// don't apply the default patches built into patchcfg
+    #[clap(long)]
+    no_defaults: bool,
+
+    /// find the packages directory using this marker
+    ///
+    /// When no packages directory is given, walk up from the current directory looking for it:
+    /// a directory with this name (e.g. Community) is the packages directory, while a file with
+    /// this name (e.g. .patchcfg-root) marks the directory containing it.
+    #[clap(long, value_name = "NAME")]
+    root_marker: Option<String>,
+
+    /// read the paths of the packages to consider from this file, one per line
+    ///
+    /// Use - to read them from stdin, e.g. from find. Packages are matched to patches by folder
+    /// name, as usual. No packages directory is needed.
+    #[clap(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = &["root-marker", "package"]
+    )]
+    packages_from: Option<String>,
+
+    /// how deep in the packages directory packages are found
+    ///
+    /// Packages are normally the directories in the packages directory. Where they're grouped in
+    /// folders of their own (by vendor, say), a depth of 2 finds the directories in those.
+    #[clap(
+        long,
+        global = true,
+        value_name = "N",
+        default_value_t = 1,
+        value_parser = parse_depth
+    )]
+    packages_depth: usize,
+
+    /// read the packages directory, patch file and options from a project file
+    ///
+    /// A TOML file of options by their long names, e.g. backup-suffix = "orig", with packages and
+    /// patches for the two arguments. Options given on the command line take the place of the
+    /// project's.
+    #[clap(long, value_name = "FILE")]
+    project: Option<PathBuf>,
+
+    /// read more kinds of target file from this file
+    ///
+    /// A JSON object naming each target by the key its patches go under, with its file name, or a
+    /// list of names under which to look for it, e.g. { "systems": "systems.cfg" }. The built-in
+    /// targets (engines, flight_model and aircraft) may be given other names this way, too.
+    #[clap(long, global = true, value_name = "FILE")]
+    targets: Option<PathBuf>,
+
+    /// patch a single key, e.g. c172:flight_model:FLIGHT_TUNING.lift_scalar=1.1
+    ///
+    /// Takes the form <package>:<file>:<key>=<value>, where the file is engines, flight_model or
+    /// aircraft. May be given more than once, and applied on top of any patch files.
+    #[clap(long, value_name = "PATCH", value_parser = parse_set)]
+    set: Vec<Setting>,
+
+    /// use the cached copy of a patch URL without asking the server
+    #[clap(long, conflicts_with = "refresh")]
+    offline: bool,
+
+    /// fetch a patch URL again, ignoring the cached copy
+    #[clap(long)]
+    refresh: bool,
+
+    /// where to keep copies of patch URLs
+    ///
+    /// Defaults to patchcfg under the user's cache directory.
+    #[clap(long, value_name = "DIR")]
+    cache_dir: Option<PathBuf>,
+
+    /// read patches from a file as it was at a git revision, e.g. v1.2:patches.json
+    ///
+    /// Takes the form <rev>:<path>, as for git show, which reads it. The path is from the root
+    /// of the repository, or from the current directory if it starts with ./ Uncommitted changes
+    /// don't matter. Merged over the patch file, if there is one.
+    #[clap(long, value_name = "REV:PATH")]
+    patch_from_git: Option<String>,
+
+    /// read patches from a CSV file, merged over the patch file
+    ///
+    /// The first row names the columns: package, file, key and value, and optionally section
+    /// and comment. The file is engines, flight_model or aircraft, as for --set. Without a
+    /// section, the key may be qualified itself, or left to match in any section.
+    #[clap(long, value_name = "FILE")]
+    csv: Option<PathBuf>,
+
+    /// load a table of values by package, for patch values like "@table:NAME.COLUMN"
+    ///
+    /// Takes the form <name>=<file>. A .csv file has a package column, and a column for each
+    /// value; otherwise, the file is a JSON object of packages, each an object of values. May be
+    /// given more than once.
+    #[clap(long, value_name = "NAME=FILE", value_parser = parse_table)]
+    table: Vec<(String, PathBuf)>,
+
+    /// merge another patch file over the first
+    ///
+    /// May be given more than once; later files take precedence. A key given different values
+    /// by two files is reported as a conflict.
+    #[clap(long, value_name = "FILE")]
+    merge: Vec<PathBuf>,
+
+    /// treat conflicts between merged patch files as errors
+    #[clap(long)]
+    strict_merge: bool,
+
+    /// apply this profile from the patch file
+    ///
+    /// Patch files may hold several named profiles, each a complete set of patches. Without this
+    /// option, the file's default profile is applied.
+    #[clap(long, value_name = "NAME")]
+    profile: Option<String>,
+
+    /// apply patches to this package only
+    ///
+    /// Skips matching patches to packages and patches the named package directly. Required for
+    /// patch files that hold a single patch rather than patches keyed by package name.
+    #[clap(long, value_name = "NAME")]
+    package: Option<String>,
+
+    /// apply patches to renamed package folders
+    ///
+    /// A JSON file mapping the package names patches are keyed by to the folders they should be
+    /// applied to, e.g. { "old-name": "new-name" }. A name may map to a list of folders, and
+    /// several names may map to the same folder, in which case their patches are combined.
+    #[clap(long, value_name = "FILE")]
+    map: Option<PathBuf>,
+
+    /// print the patch that would be applied to this package, as JSON, and stop
+    ///
+    /// The patch is as it stands once everything has had its say: defaults, the patch file and
+    /// its profile, --csv, --merge and --patch-from-git, --set, --map, --tag and --ignore-case.
+    /// Nothing is read from the package or written to it.
+    #[clap(long, value_name = "NAME")]
+    print_resolved_patch: Option<String>,
+
+    /// the sim version being patched, e.g. 1.36.2
+    ///
+    /// Patch files may declare a min_version and/or max_version. Given the sim version, files
+    /// declaring a range that doesn't include it are refused.
+    #[clap(long, value_name = "VERSION")]
+    sim_version: Option<Version>,
+
+    /// fail when a patched file can't be found
+    ///
+    /// Normally, a package that lacks one of the files its patch applies to is skipped quietly.
+    #[clap(long)]
+    fail_if_missing_target: bool,
+
+    /// look for misnamed files by what's in them
+    ///
+    /// Where a package has no file by the expected name, fall back to a .cfg file with the
+    /// section that sets that kind of file apart: [GENERALENGINEDATA] for engines, [FLIGHT_TUNING]
+    /// for the flight model and [FLTSIM.0] for aircraft.
+    #[clap(long)]
+    match_content: bool,
+
+    /// skip a package altogether if any of the files its patch applies to can't be found
+    ///
+    /// Otherwise, the files that are found are patched and the rest are skipped, which can leave
+    /// a package half patched.
+    #[clap(long)]
+    all_or_nothing: bool,
+
+    /// skip any file a patch would change more than this many keys in
+    ///
+    /// A safety rail against a runaway patch, such as an unqualified key that matches in far more
+    /// sections than meant. Each key changed or added, and each section replaced, counts as one.
+    #[clap(long, value_name = "N")]
+    max_changes: Option<usize>,
+
+    /// stop before writing anything if the run would change more than this many keys in all
+    #[clap(long, value_name = "N")]
+    max_total_changes: Option<usize>,
+
+    /// fail the run if there were any warnings
+    ///
+    /// The run still goes on to the end, so every warning is reported, but exits with an error.
+    #[clap(long)]
+    fail_on_warning: bool,
+
+    /// match patches to packages by aircraft title
+    ///
+    /// Instead of the package's folder name, match patch names against the titles of the
+    /// aircraft in the package's aircraft.cfg. A package with several titles (one per [FLTSIM.n]
+    /// section) gets the patch for the first title that has one.
+    #[clap(long)]
+    match_title: bool,
+
+    /// match patches to packages by this aircraft.cfg field, e.g. atc_model
+    ///
+    /// Like --match-title, but for any field: patch names are matched against the field's values
+    /// in the package's aircraft.cfg, in the order they appear, and the package gets the patch
+    /// for the first that has one. A package whose aircraft.cfg doesn't have the field is
+    /// matched by its folder name instead.
+    #[clap(long, value_name = "FIELD", conflicts_with = "match-title")]
+    package_name_from: Option<String>,
+
+    /// match patches to packages regardless of case
+    ///
+    /// Folder names (or titles, with --match-title) are compared to patch names ignoring case, as
+    /// Windows does. Patches whose names differ only in case can't both be used, so this warns
+    /// about them and uses the first in sort order.
+    #[clap(long)]
+    ignore_case: bool,
+
+    /// list patch keys that were found but already had the patched value
+    ///
+    /// These are otherwise skipped quietly, unlike keys that aren't found at all.
+    #[clap(long)]
+    print_unchanged: bool,
+
+    /// say what was decided for each patch key, and why
+    ///
+    /// For debugging a patch that doesn't seem to do anything: each key is reported as changed,
+    /// already equal, added, not found, and so on, with the line it matched.
+    #[clap(long)]
+    explain: bool,
+
+    /// leave files that don't change out of the output altogether
+    ///
+    /// Warnings, --explain and --print-unchanged say nothing about files a run doesn't change,
+    /// and --hash leaves them out of the summary.
+    #[clap(long)]
+    diff_only_changed_files: bool,
+
+    /// apply only keys with one of these tags
+    ///
+    /// Keys are tagged using the object form of a patch value, e.g.
+    /// { "value": "1.1", "tags": ["realism"] }.
+    #[clap(long, value_name = "NAME")]
+    tag: Vec<String>,
+
+    /// follow symlinks when searching packages for files to patch
+    ///
+    /// Symlinked packages are always found, but symlinked directories inside a package are only
+    /// searched with this flag. Symlink loops are skipped.
+    #[clap(long)]
+    follow_symlinks: bool,
+
+    /// only patch keys in these sections, separated by commas
+    ///
+    /// A safety net for broad patches: keys elsewhere are left alone even where an unqualified
+    /// patch key matches them, and so are keys before the first section. Patterns may use * as a
+    /// wildcard, e.g. ENGINE.*, and are matched case-insensitively.
+    #[clap(long, value_name = "SECTION", value_delimiter = ',')]
+    only_sections: Vec<String>,
+
+    /// directories not to search when looking for files to patch
+    ///
+    /// Patterns may use * as a wildcard and are matched case-insensitively against directory
+    /// names. The defaults skip the texture, model and sound folders of MSFS packages.
+    #[clap(
+        long,
+        value_name = "PATTERN",
+        value_delimiter = ',',
+        default_values = &["texture*", "model*", "sound*"],
+        conflicts_with = "no-prune"
+    )]
+    prune: Vec<String>,
+
+    /// search every directory when looking for files to patch
+    #[clap(long)]
+    no_prune: bool,
+
+    /// keep track of the values applied by each run
+    ///
+    /// When writing changes, record the values applied in a .patchcfg.json file beside each
+    /// patched file. When showing changes, show only those that differ from what the last run
+    /// applied, along with the value it applied.
+    #[clap(long)]
+    track_changes: bool,
+
+    /// print only a summary, as JSON
+    ///
+    /// Instead of reporting each change, print the number of packages matched, files changed, and
+    /// keys changed, skipped (already patched), and missing as a single JSON object.
+    #[clap(long)]
+    summary_json: bool,
+
+    /// print nothing unless something changes, and then only the --summary-json summary
+    ///
+    /// For runs from a scheduler. Information and warnings only go to the --log-file, if any,
+    /// though errors are still printed. The run exits with status 2 when it changes anything (or
+    /// without --force, would), and 0 when there was nothing to do.
+    #[clap(long)]
+    summary_only_on_change: bool,
+
+    /// check packages as part of a CI pipeline
+    ///
+    /// Implies --summary-json, handles packages in order of their paths, reports a package that
+    /// can't be read and carries on with the rest, and fails the run if any file needs patching.
+    /// With --force, changes are written instead and only errors fail the run. The summary and
+    /// carrying on are only defaults: --no-summary-json and --fail-fast turn them off.
+    #[clap(long)]
+    ci: bool,
+
+    /// print the usual output instead of the summary, even with --ci
+    #[clap(long, conflicts_with_all = &["summary-json", "summary-only-on-change"])]
+    no_summary_json: bool,
+
+    /// stop at the first package that can't be read, even with --ci
+    #[clap(long)]
+    fail_fast: bool,
+
+    /// read and diff up to this many packages at once
+    ///
+    /// Packages are still reported and written one at a time, in the same order, so the output
+    /// and summary are the same whatever the number of jobs.
+    #[clap(long, value_name = "N", default_value_t = 1, value_parser = parse_jobs)]
+    jobs: usize,
+
+    /// report time spent in each phase of the run
+    ///
+    /// Prints how long was spent scanning for packages and files, reading, diffing and writing
+    /// to stderr at the end of the run.
+    #[clap(long)]
+    stats: bool,
+
+    /// the lock file that keeps two runs from writing at once
+    ///
+    /// Defaults to .patchcfg.lock in the packages directory. Only runs that write take the lock,
+    /// along with restore, apply-plan and prune-backups.
+    #[clap(long, value_name = "PATH", conflicts_with = "no-lock")]
+    lock_file: Option<PathBuf>,
+
+    /// don't take the lock
+    #[clap(long)]
+    no_lock: bool,
+
+    /// include SHA-256 checksums of each file before and after patching in --summary-json
+    #[clap(long, requires = "summary")]
+    hash: bool,
+
+    /// include each change in --summary-json, with its old and new values, for compare-runs
+    #[clap(long, requires = "summary")]
+    with_changes: bool,
+
+    /// how to print the --summary-json report
+    ///
+    /// With csv or tsv, the report is each change instead of the totals: a header row, then a row
+    /// of package, file, section, key, old value, new value and status (changed, added or
+    /// replaced) for each change.
+    #[clap(
+        long,
+        value_enum,
+        value_name = "FORMAT",
+        default_value_t = ReportFormat::Json,
+        requires = "summary"
+    )]
+    report_format: ReportFormat,
+
+    /// lines of context to show around each change when previewing
+    #[clap(long, value_name = "N", default_value_t = 0)]
+    context: usize,
+
+    /// when to color output
+    #[clap(long, value_enum, value_name = "WHEN", default_value_t = ColorChoice::Auto, global = true)]
+    color: ColorChoice,
+
+    /// how to report the error that ends a run
+    ///
+    /// With json, the error is written to stderr as an object with the error itself, the context
+    /// it happened in (outermost first), and the package it happened in, if any.
+    #[clap(
+        long,
+        value_enum,
+        value_name = "FORMAT",
+        default_value_t = ErrorFormat::Human,
+        global = true
+    )]
+    error_format: ErrorFormat,
+
+    /// tolerance for comparing numeric values
+    ///
+    /// Where both the current value and the patched value are numbers, they're considered equal
+    /// (and left alone) if they differ by no more than this.
+    #[clap(long, value_name = "FLOAT", default_value_t = 1e-6, value_parser = parse_epsilon)]
+    epsilon: f64,
+
+    /// treat lists that differ only in their spacing as equal
+    ///
+    /// A value with commas is a list of what's between them, and any other value with spaces in
+    /// it is a list of its words, so "0:0.0, 1:0.1" and "0:0.0,1:0.1" are the same list. Lists
+    /// are compared element by element, as numbers where they can be, and a line is only
+    /// patched if an element differs.
+    #[clap(long)]
+    ignore_list_spacing: bool,
 
     /// write changes
     #[clap(short, long)]
     force: bool,
-}
 
-/// patches to be applied to an aircraft's config files
-///
-/// Patches take the form key / value, where a given key is to be updated to a given value.
-#[derive(Debug, Deserialize)]
-struct Patch {
-    #[serde(default)]
-    engines: HashMap<String, String>,
-    #[serde(default)]
-    flight_model: HashMap<String, String>,
-}
+    /// show each package's changes and ask before writing them
+    #[clap(long, requires = "force")]
+    confirm: bool,
 
-impl Patch {
-    fn diff(&self, path: impl AsRef<Path>) -> io::Result<Diff> {
-        let mut diff = Diff::default();
+    /// answer yes to every prompt
+    ///
+    /// For scripts that pass --confirm but run unattended. This never writes anything without
+    /// --force.
+    #[clap(short = 'y', long)]
+    assume_yes: bool,
 
-        if !self.engines.is_empty() {
-            if let Some(target) = find_path(path.as_ref(), "engines.cfg") {
-                let text = fs::read_to_string(&target)?;
-                diff.engines = PathChanges {
-                    path: target,
-                    changes: build_diff(&self.engines, &text),
-                };
-            }
-        }
+    /// show changes relative to each file's backup
+    ///
+    /// When previewing changes to a file that has already been patched, diff against its .bak.cfg
+    /// (if any) instead, to show the net effect of the patch relative to stock.
+    #[clap(long, conflicts_with = "force")]
+    against_backup: bool,
 
-        if !self.flight_model.is_empty() {
-            if let Some(target) = find_path(path.as_ref(), "flight_model.cfg") {
-                let text = fs::read_to_string(&target)?;
-                diff.flight_model = PathChanges {
-                    path: target,
-                    changes: build_diff(&self.flight_model, &text),
-                };
-            }
-        }
+    /// show how far each patched file is from a stock copy
+    ///
+    /// Instead of the patch's changes, preview the differences between each file as patched and
+    /// the file at the same path under DIR, a copy of the packages directory as it was shipped.
+    /// This includes keys changed by earlier patches.
+    #[clap(
+        long,
+        value_name = "DIR",
+        conflicts_with_all = &["force", "against-backup"]
+    )]
+    baseline: Option<PathBuf>,
 
-        Ok(diff)
-    }
-}
+    /// back up only these target files
+    ///
+    /// By default, every file that gets rewritten is first backed up with a .bak.cfg extension.
+    /// Naming one or more targets here limits backups to those kinds of file.
+    #[clap(
+        long,
+        value_name = "TARGET",
+        value_delimiter = ',',
+        conflicts_with = "no-backup"
+    )]
+    backup: Vec<String>,
 
-fn find_path(path: impl AsRef<Path>, filename: &str) -> Option<PathBuf> {
-    walkdir::WalkDir::new(path)
-        .contents_first(true)
-        .into_iter()
-        .find_map(|entry| {
-            let entry = entry.ok()?;
-            entry
-                .path()
-                .ends_with(filename)
-                .then_some(entry.into_path())
-        })
-}
+    /// do not back up files before rewriting them
+    #[clap(long)]
+    no_backup: bool,
 
-fn build_diff(patch: &HashMap<String, String>, text: &str) -> HashMap<String, (String, String)> {
-    let mut diff = HashMap::new();
+    /// back up only the lines a patch changes
+    ///
+    /// Instead of keeping the whole file, record the original lines in a JSON file named for the
+    /// backup, e.g. engines.bak.cfg.json. The restore command puts them back, as long as nothing
+    /// else has changed the file since.
+    #[clap(long, conflicts_with = "no-backup")]
+    differential_backup: bool,
 
-    for line in text.lines() {
-        if let Some((key, tail)) = line.split_once('=') {
-            let key = key.trim();
+    /// the extension given to backups in place of .cfg
+    ///
+    /// For example, with --backup-suffix orig, engines.cfg is backed up to engines.orig. The
+    /// restore command looks for backups with the same suffix.
+    #[clap(
+        long,
+        global = true,
+        value_name = "EXT",
+        default_value = "bak.cfg",
+        value_parser = parse_backup_suffix
+    )]
+    backup_suffix: String,
 
-            // We have no use for comments at this stage, but we'll do this again later and
-            // do something with them.
+    /// how to record the original value of a changed key
+    ///
+    /// Changed keys keep their original value in a comment. This template, which must contain
+    /// {original}, controls how that comment reads, e.g. "was: {original}".
+    #[clap(
+        long,
+        value_name = "TEMPLATE",
+        value_parser = parse_preserve_format,
+        conflicts_with = "no-preserve-original"
+    )]
+    preserve_format: Option<String>,
 
-            let (value, _comment) = tail.split_once(';').unwrap_or((tail, ""));
-            if let Some(change) = patch.get(key) {
-                // If the value is equal to the changed value, we... actually don't want to bother
-                // with this.
+    /// record the original value of a changed key only the first time it's patched
+    ///
+    /// The original is marked with patchcfg-orig:, and a line that already has one keeps it
+    /// rather than gaining another on each run.
+    #[clap(long, conflicts_with_all = &["no-preserve-original", "preserve-format"])]
+    preserve_original_once: bool,
 
-                if value.trim() == change {
-                    continue;
-                }
+    /// don't record the original value of a changed key
+    #[clap(long)]
+    no_preserve_original: bool,
 
-                diff.insert(key.to_owned(), (change.to_owned(), value.to_owned()));
-            }
-        }
-    }
+    /// strip trailing whitespace from every line of each file written
+    #[clap(long)]
+    trim_trailing: bool,
 
-    diff
-}
+    /// remove duplicate keys from each section a patch changes, keeping the last
+    ///
+    /// Comments above a removed key are kept with the one that stays. Previews don't show the
+    /// effect of tidying.
+    #[clap(long)]
+    tidy: bool,
 
-#[derive(Debug, Default)]
-struct PathChanges {
-    path: PathBuf,
-    changes: HashMap<String, (String, String)>,
+    /// sort the keys of each section a patch changes, when tidying
+    #[clap(long, requires = "tidy")]
+    sort_keys: bool,
+
+    /// mark patched files with the patch applied, and skip files already marked with it
+    ///
+    /// The marker is a comment on the first line of the file recording a hash of the patch.
+    /// Files marked with a different patch are processed as usual.
+    #[clap(long)]
+    marker: bool,
+
+    /// write the changes to overlay files in this directory instead of patching
+    ///
+    /// Each overlay is a minimal config holding just the changed keys under their section
+    /// headers, at the same path within DIR as the file it overlays within the packages
+    /// directory. The files themselves are left alone.
+    #[clap(long, value_name = "DIR", conflicts_with = "force")]
+    overlay_out: Option<PathBuf>,
+
+    /// record the original lines in sidecar files in this directory instead of in comments
+    ///
+    /// Patched lines hold just their new values, and each patched file gets a .changes file at
+    /// the same path within DIR as the file within the packages directory. Given the same DIR,
+    /// the restore command puts the original lines back.
+    #[clap(long, global = true, value_name = "DIR")]
+    sidecar_changes: Option<PathBuf>,
+
+    /// collect the patched files into a zip archive
+    ///
+    /// Each file is stored at its path within the packages directory, alongside a manifest.json
+    /// listing them. Works with or without --force, so the files can be bundled for sharing
+    /// without patching anything.
+    #[clap(long, value_name = "FILE")]
+    zip_out: Option<PathBuf>,
+
+    /// write a patch that undoes this run's changes to this file
+    ///
+    /// The inverse patch sets each changed key back to its old value and comment, and each
+    /// replaced section back to its old lines. Apply it with --no-preserve-original to get the
+    /// original lines back. Keys added from defaults aren't removed by it.
+    #[clap(long, value_name = "FILE")]
+    inverse_out: Option<PathBuf>,
+
+    /// write a plan of this run's changes to this file, for the apply-plan command
+    ///
+    /// The plan lists each file to be patched, with a checksum of it as it is now and the
+    /// changes to be made to it, so the changes can be reviewed before they're applied. Only a
+    /// preview writes a plan.
+    #[clap(long, value_name = "FILE", conflicts_with_all = &["force", "against-backup"])]
+    plan_out: Option<PathBuf>,
+
+    /// run this shell command after a successful run
+    ///
+    /// The command's environment includes PATCHCFG_RUN_ID, PATCHCFG_PACKAGES_MATCHED,
+    /// PATCHCFG_FILES_CHANGED, PATCHCFG_KEYS_CHANGED, PATCHCFG_DRY_RUN and PATCHCFG_SUCCESS. If
+    /// the command fails, so does the run.
+    #[clap(long, value_name = "CMD")]
+    post_hook: Option<String>,
+
+    /// run the post-hook even if the run fails
+    #[clap(long, requires = "post-hook")]
+    post_hook_always: bool,
+
+    /// how many times to retry writing a file the sim has locked
+    #[clap(long, value_name = "N", default_value_t = 3)]
+    retries: u32,
+
+    /// milliseconds to wait before the first retry, doubling for each retry after
+    #[clap(long, value_name = "MS", default_value_t = 100)]
+    retry_delay: u64,
+
+    /// check each backup after writing it
+    ///
+    /// Re-reads every backup to make sure it holds exactly what the file held before it was
+    /// patched. If one doesn't, the original file is put back and the run stops.
+    #[clap(long, conflicts_with = "no-backup")]
+    verify_backup: bool,
+
+    /// check the values of keys once the run is done, failing it if any are wrong
+    ///
+    /// FILE is a JSON object of the values expected, by package/file/section/key, e.g.
+    /// "asobo-aircraft-tbm930/engines/GENERALENGINEDATA/static_thrust": 229, where the file is a
+    /// target and the section is left empty for a key before the first one. The files are read
+    /// again after they're written, so without --force, the values are checked as they are.
+    #[clap(long, value_name = "FILE")]
+    assert: Option<PathBuf>,
+
+    /// refuse to overwrite a file in the way of a backup that isn't one
+    ///
+    /// Where a backup is to go, there may already be a file by that name. If it isn't an earlier
+    /// backup of the same file (a full backup with the same sections, in the same order, or a
+    /// differential backup patchcfg can read), it's overwritten with a warning, or with this, the
+    /// package is left alone and the run stops.
+    #[clap(long, conflicts_with = "no-backup")]
+    safe_backups: bool,
+
+    /// make sure there's room for the backups before writing anything
+    ///
+    /// Adds up the size of every file that might be backed up, and stops before writing if the
+    /// disk it's on doesn't have that much free.
+    #[clap(long, requires = "force", conflicts_with = "no-backup")]
+    require_backup_space: bool,
+
+    /// also write warnings and errors to this file
+    #[clap(long, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+
+    /// append to the log file instead of truncating it
+    #[clap(long, requires = "log-file")]
+    append_log: bool,
 }
 
-/// diff between a given patch and a given file
-///
-/// If a patch needs to be applied, there will be keyes in these maps. If the maps are empty, the
-/// patch has already been applied or the patch contained nothing.
-#[derive(Debug, Default)]
-struct Diff {
-    engines: PathChanges,
-    flight_model: PathChanges,
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// generate shell completions
+    ///
+    /// Prints a completion script for the given shell to stdout.
+    Completions {
+        #[clap(value_enum)]
+        shell: completions::Shell,
+    },
+
+    /// tidy the formatting of a config file
+    ///
+    /// Rewrites the file in a consistent `key = value ; comment` style, leaving values, comments
+    /// and section headers intact.
+    Normalize { file: PathBuf },
+
+    /// show how patchcfg reads a config file
+    ///
+    /// Prints each line as JSON: whether it's a section header, a key, a comment, blank, or
+    /// something else, with the parts of it patchcfg sees. Keys also get the address patches
+    /// match them by.
+    DumpParsed { file: PathBuf },
+
+    /// list the keys a config file has, as patches address them
+    ///
+    /// Prints each `section.key` once, sorted, with root keys as `.key`. Where a key appears
+    /// more than once in a section, the value shown is the first.
+    ListKeys {
+        file: PathBuf,
+
+        /// show each key's current value
+        #[clap(long)]
+        values: bool,
+
+        /// print JSON: a list of keys, or with --values, an object of keys and values
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// show the differences between two config files
+    ///
+    /// Lists keys whose values differ, and keys found in only one of the files. Keys are matched
+    /// by section, like qualified patch keys.
+    Diff { a: PathBuf, b: PathBuf },
+
+    /// show the differences between the changes two runs made
+    ///
+    /// Reads two --summary-json reports written with --with-changes. Changes are matched by
+    /// package, file, section and key, and those in only one report or with different old or
+    /// new values are listed.
+    CompareRuns { a: PathBuf, b: PathBuf },
+
+    /// put back the original files from their backups
+    ///
+    /// Looks through the packages directory for backups of patched files (see --backup-suffix)
+    /// and moves each back over the file it was taken from.
+    Restore { packages: PathBuf },
+
+    /// apply a plan written with --plan-out
+    ///
+    /// Makes exactly the changes in the plan, as long as every file in it is still as it was
+    /// when the plan was made; if any isn't, nothing is changed. Files are written, backed up
+    /// and locked just as a run writes them (see --backup-suffix, --differential-backup,
+    /// --safe-backups and --lock-file).
+    ApplyPlan { plan: PathBuf },
+
+    /// delete old backups
+    ///
+    /// Looks through the packages directory for backups of patched files (see --backup-suffix,
+    /// which here may contain * to take in timestamped backups, e.g. "*.bak" for
+    /// engines.2024-06-01.bak) and deletes all but the newest few of each file, or those older
+    /// than some age. Files being patched are never deleted.
+    PruneBackups {
+        packages: PathBuf,
+
+        /// the number of backups of each file to keep, newest first
+        #[clap(long, value_name = "N", required_unless_present = "older-than")]
+        keep: Option<usize>,
+
+        /// delete backups older than this, e.g. 30d, 12h, 2w
+        #[clap(long, value_name = "AGE", value_parser = parse_age)]
+        older_than: Option<Duration>,
+
+        /// list the backups that would be deleted, without deleting them
+        #[clap(long)]
+        dry_run: bool,
+    },
+
+    /// report the current values of some keys across every package
+    ///
+    /// Prints a table with a row for each package that has any of the keys, for spotting outliers
+    /// before writing a patch. Keys may be qualified with their section, like patch keys; an
+    /// unqualified key that appears in several sections shows each distinct value.
+    Audit {
+        packages: PathBuf,
+
+        /// the keys to report, separated by commas
+        #[clap(long, required = true, value_delimiter = ',')]
+        keys: Vec<String>,
+
+        /// print JSON instead of a table
+        #[clap(long)]
+        json: bool,
+    },
+
+    /// print an example patch file to start from
+    ///
+    /// The example patches a couple of keys in one package. Given a package with --for, it
+    /// patches every key in the package's files instead, at their current values, ready to edit.
+    Template {
+        /// the package to take keys and values from
+        #[clap(long = "for", value_name = "PACKAGE")]
+        package: Option<PathBuf>,
+    },
 }
 
-impl Diff {
-    fn write_changes(&self) -> io::Result<()> {
-        if !self.engines.changes.is_empty() {
-            write_modified_file(&self.engines)?;
-        }
+fn parse_epsilon(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(epsilon) if epsilon >= 0.0 => Ok(epsilon),
+        Ok(_) => Err(String::from("epsilon must not be negative")),
+        Err(e) => Err(e.to_string()),
+    }
+}
 
-        if !self.flight_model.changes.is_empty() {
-            write_modified_file(&self.flight_model)?;
-        }
+fn parse_jobs(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(0) => Err(String::from("jobs must be at least 1")),
+        Ok(jobs) => Ok(jobs),
+        Err(e) => Err(e.to_string()),
+    }
+}
 
-        Ok(())
+fn parse_depth(s: &str) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(0) => Err(String::from("depth must be at least 1")),
+        Ok(depth) => Ok(depth),
+        Err(e) => Err(e.to_string()),
     }
+}
 
-    fn show_changes(&self) -> io::Result<()> {
-        if !self.engines.changes.is_empty() {
-            println!("Engine changes:");
-            for (key, (new, old)) in &self.engines.changes {
-                println!("{key}:\n\t{old}\n\t{new}");
-            }
+/// a patch given with --set
+#[derive(Clone, Debug)]
+struct Setting {
+    package: String,
 
-            if !self.flight_model.changes.is_empty() {
-                println!();
-            }
-        }
+    /// the target, which is looked up once any --targets have been read
+    file: String,
+    key: String,
+    value: String,
+}
 
-        if !self.flight_model.changes.is_empty() {
-            println!("Flight model changes:");
-            for (key, (new, old)) in &self.flight_model.changes {
-                println!("{key}:\n\t{old}\n\t{new}");
-            }
-        }
+fn parse_age(s: &str) -> Result<Duration, String> {
+    let unit = s.len() - s.trim_end_matches(char::is_alphabetic).len();
+    let (number, unit) = s.split_at(s.len() - unit);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("expected a number and a unit (s, m, h, d or w), got {s:?}"))?;
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(format!("unknown unit {unit:?}; use s, m, h, d or w")),
+    };
+    Ok(Duration::from_secs(number * seconds))
+}
 
-        Ok(())
+fn parse_table(s: &str) -> Result<(String, PathBuf), String> {
+    match s.split_once('=') {
+        Some((name, path)) if !name.is_empty() && !path.is_empty() => {
+            Ok((name.to_owned(), PathBuf::from(path)))
+        }
+        _ => Err(String::from("expected <name>=<file>")),
     }
 }
 
-fn write_modified_file(patch: &PathChanges) -> io::Result<()> {
-    let mut buf = Vec::new();
-    let text = fs::read_to_string(&patch.path)?;
+fn parse_set(s: &str) -> Result<Setting, String> {
+    const FORM: &str = "expected <package>:<file>:<key>=<value>";
 
-    for line in text.lines() {
-        // If we get a key from this split_once, we need to check to see whether this is a key
-        // we want to modify. Otherwise, just write the line to our output buffer without
-        // modifications.
-
-        if let Some((key, tail)) = line.split_once('=') {
-            let key = key.trim();
-
-            if let Some((value, original)) = patch.changes.get(key) {
-                // Because we found a change, we're going to A) write our modified value to output
-                // instead of the original value; B) include the original value as a "comment";
-                // and C) include the original comment (if applicable) in a second comment.
-
-                match tail.split_once(';') {
-                    Some((_, comment)) => {
-                        let f = format!("{key} = {value} ; {original} ; {comment}");
-                        println!("{f}");
-                        writeln!(buf, "{f}")?;
-                    }
+    let (package, rest) = s.split_once(':').ok_or(FORM)?;
+    let (file, rest) = rest.split_once(':').ok_or(FORM)?;
+    let (key, value) = rest.split_once('=').ok_or(FORM)?;
 
-                    None => {
-                        let f = format!("{key} = {value} ; {original}");
-                        println!("{f}");
-                        writeln!(buf, "{f}")?;
-                    }
-                }
-            } else {
-                writeln!(buf, "{line}")?;
-            }
-        } else {
-            writeln!(buf, "{line}")?;
-        }
+    if package.trim().is_empty() {
+        return Err(String::from("package must not be empty"));
+    }
+    if key.trim().is_empty() {
+        return Err(String::from("key must not be empty"));
     }
 
-    let backup = patch.path.with_extension("bak.cfg");
-    fs::rename(&patch.path, &backup)?;
-    fs::write(&patch.path, buf)
+    Ok(Setting {
+        package: package.trim().to_owned(),
+        file: file.trim().to_owned(),
+        key: key.trim().to_owned(),
+        value: value.trim().to_owned(),
+    })
 }
 
-fn main() {
-    if let Err(e) = run(&Args::parse()) {
-        eprintln!("{e}");
-        process::exit(1);
+fn parse_backup_suffix(s: &str) -> Result<String, String> {
+    let suffix = s.strip_prefix('.').unwrap_or(s);
+    if suffix.is_empty() {
+        return Err(String::from("suffix must not be empty"));
+    }
+    if suffix.eq_ignore_ascii_case("cfg") {
+        return Err(String::from(
+            "backups would overwrite the files they back up",
+        ));
+    }
+    if suffix.contains(['/', '\\']) {
+        return Err(String::from("suffix must not contain a path separator"));
     }
+    Ok(suffix.to_owned())
 }
 
-fn run(args: &Args) -> anyhow::Result<()> {
-    let patches = read_patches(args.patches.as_ref())?;
-    let packages = read_packages(args.packages.as_ref(), &patches)?;
+fn parse_preserve_format(s: &str) -> Result<String, String> {
+    if !s.contains("{original}") {
+        return Err(String::from("template must contain {original}"));
+    }
 
-    for (package, patch) in packages {
-        let diff = patch.diff(&package)?;
+    // Anything else in braces is probably a typo for {original}.
 
-        if args.force {
-            diff.write_changes()?;
-        } else {
-            diff.show_changes()?;
-        }
+    let rest = s.replace("{original}", "");
+    if rest.contains(['{', '}']) {
+        return Err(String::from("{original} is the only placeholder"));
     }
 
-    Ok(())
+    Ok(s.to_owned())
 }
 
-fn read_patches(path: &Path) -> anyhow::Result<HashMap<String, Patch>> {
-    let text = fs::read_to_string(path)?;
-    Ok(serde_json::from_str(&text)?)
-}
+impl Args {
+    fn original(&self) -> Original {
+        match &self.preserve_format {
+            _ if self.no_preserve_original || self.sidecar_changes.is_some() => Original::Omit,
+            _ if self.preserve_original_once => Original::Once,
+            Some(template) => Original::Template(template.clone()),
+            None => Original::Verbatim,
+        }
+    }
 
-fn read_packages<'a>(
-    path: &Path,
-    patches: &'a HashMap<String, Patch>,
-) -> io::Result<impl Iterator<Item = (PathBuf, &'a Patch)> + 'a> {
-    let candidates = fs::read_dir(path)?.filter_map(|entry| {
-        let entry = entry.ok()?;
-        let path = entry.path();
-        path.is_dir().then_some(path)
-    });
+    /// the name to look a package's patch up by, which is folded to lowercase with --ignore-case
+    fn patch_name<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        if self.ignore_case {
+            Cow::Owned(name.to_lowercase())
+        } else {
+            Cow::Borrowed(name)
+        }
+    }
 
-    Ok(candidates.filter_map(|path| {
-        let name = path.file_name()?.to_str()?;
-        patches.get(name).map(|patch| (path, patch))
-    }))
+    /// whether to print the --summary-json summary in place of the usual output
+    fn summary_only(&self) -> bool {
+        self.summary_json || self.ci && !self.no_summary_json || self.summary_only_on_change
+    }
+
+    /// whether to record each change in the summary, for --with-changes or a --report-format
+    /// that lists them
+    fn records_changes(&self) -> bool {
+        self.with_changes || self.report_format != ReportFormat::Json
+    }
+
+    fn should_backup(&self, target: Target) -> bool {
+        !self.no_backup
+            && (self.backup.is_empty()
+                || self
+                    .backup
+                    .iter()
+                    .any(|name| Target::find(name) == Some(target)))
+    }
+
+    /// where a file is backed up to
+    fn backup_path(&self, path: &Path) -> PathBuf {
+        path.with_extension(&self.backup_suffix)
+    }
+
+    /// where a file's differential backup goes, for --differential-backup
+    fn delta_path(&self, path: &Path) -> PathBuf {
+        path.with_extension(format!("{}.json", self.backup_suffix))
+    }
+}
+
+/// the kinds of config file a patch can apply to
+///
+/// engines.cfg, flight_model.cfg and aircraft.cfg are built in, and a --targets file can add more.
+/// Each is an index into the list of targets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct Target(usize);
+
+/// what we know about a kind of target file
+#[derive(Debug)]
+struct TargetSpec {
+    /// the key patches for this file are found under in a patch file
+    key: String,
+
+    /// what to call its changes, as in "Engine changes:"
+    label: String,
+
+    /// the names to look for it under, in order of preference
+    filenames: Vec<String>,
+
+    /// sections only this kind of file has, for --match-content
+    sections: Vec<String>,
+}
+
+static TARGETS: OnceLock<Vec<TargetSpec>> = OnceLock::new();
+
+impl Target {
+    fn specs() -> &'static [TargetSpec] {
+        TARGETS.get_or_init(|| load_targets(None).expect("the built-in targets are valid"))
+    }
+
+    fn all() -> impl Iterator<Item = Target> {
+        (0..Self::specs().len()).map(Target)
+    }
+
+    /// finds a target by its key, or by the name of its file
+    fn find(name: &str) -> Option<Target> {
+        // The built-in keys used to be accepted in kebab case, e.g. flight-model.
+        let key = name.replace('-', "_");
+        Target::all().find(|target| {
+            target.key() == key
+                || target
+                    .filenames()
+                    .iter()
+                    .any(|filename| filename.eq_ignore_ascii_case(name))
+        })
+    }
+
+    fn spec(self) -> &'static TargetSpec {
+        &Self::specs()[self.0]
+    }
+
+    fn label(self) -> &'static str {
+        &self.spec().label
+    }
+
+    /// the file's name, or for one with several names, the first
+    fn filename(self) -> &'static str {
+        &self.spec().filenames[0]
+    }
+
+    fn filenames(self) -> &'static [String] {
+        &self.spec().filenames
+    }
+
+    /// the key patches for this file are found under in a patch file
+    fn key(self) -> &'static str {
+        &self.spec().key
+    }
+}
+
+/// the file names of a target in a --targets file
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Filenames {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// reads the targets, which are the built-in ones plus any from a --targets file
+///
+/// This has to happen before any patches are read, since they're keyed by target.
+fn load_targets(path: Option<&Path>) -> anyhow::Result<Vec<TargetSpec>> {
+    let builtin = [
+        ("engines", "Engine", "engines.cfg", "GENERALENGINEDATA"),
+        (
+            "flight_model",
+            "Flight model",
+            "flight_model.cfg",
+            "FLIGHT_TUNING",
+        ),
+        ("aircraft", "Aircraft", "aircraft.cfg", "FLTSIM.0"),
+    ];
+    let mut specs: Vec<_> = builtin
+        .into_iter()
+        .map(|(key, label, filename, section)| TargetSpec {
+            key: key.to_owned(),
+            label: label.to_owned(),
+            filenames: vec![filename.to_owned()],
+            sections: vec![section.to_owned()],
+        })
+        .collect();
+
+    let Some(path) = path else {
+        return Ok(specs);
+    };
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("unable to read targets {}", path.display()))?;
+    let targets: BTreeMap<String, Filenames> = serde_json::from_str(&text)
+        .with_context(|| format!("unable to read targets {}", path.display()))?;
+
+    for (key, filenames) in targets {
+        let filenames = match filenames {
+            Filenames::One(filename) => vec![filename],
+            Filenames::Many(filenames) => filenames,
+        };
+        if filenames.is_empty() {
+            anyhow::bail!("target {key} in {} has no file names", path.display());
+        }
+
+        match specs.iter_mut().find(|spec| spec.key == key) {
+            Some(spec) => spec.filenames = filenames,
+            None => {
+                // e.g. "Systems changes:" for systems
+                let mut label = key.replace('_', " ");
+                if let Some(first) = label.get_mut(..1) {
+                    first.make_ascii_uppercase();
+                }
+                specs.push(TargetSpec {
+                    key,
+                    label,
+                    filenames,
+                    sections: Vec::new(),
+                });
+            }
+        }
+    }
+
+    Ok(specs)
+}
+
+/// finds a target file in a package, under any of its names, or with --match-content, by what's
+/// in it
+fn find_target(package: &Path, target: Target, args: &Args) -> Option<PathBuf> {
+    let found = target
+        .filenames()
+        .iter()
+        .find_map(|filename| find_path(package, filename, args));
+    if found.is_some() || !args.match_content || target.spec().sections.is_empty() {
+        return found;
+    }
+
+    // Only .cfg files that aren't named for a target, or backups, are worth a look.
+    let backup = format!(".{}", args.backup_suffix).to_lowercase();
+    find_file(package, args, |path| {
+        let name = path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_lowercase();
+        name.ends_with(".cfg")
+            && !name.ends_with(&backup)
+            && !Target::all().any(|target| {
+                target
+                    .filenames()
+                    .iter()
+                    .any(|filename| filename.eq_ignore_ascii_case(&name))
+            })
+            && has_section(path, &target.spec().sections)
+    })
+}
+
+/// whether a file has any of these sections
+fn has_section(path: &Path, sections: &[String]) -> bool {
+    let Ok(text) = encoding::read_to_string(path) else {
+        return false;
+    };
+    text.lines().any(|line| match line::parse(line) {
+        Line::Section { name, .. } => sections
+            .iter()
+            .any(|section| section.eq_ignore_ascii_case(name)),
+        _ => false,
+    })
+}
+
+/// patches to be applied to an aircraft's config files
+///
+/// Patches take the form key / value, where a given key is to be updated to a given value. A key
+/// may be qualified with the section it belongs to, e.g. `ENGINE.1.max_rpm`, in which case only
+/// the key within that section is patched. Unqualified keys are patched wherever they appear.
+///
+/// In aircraft.cfg, that means a key can be patched for a single livery, e.g. `FLTSIM.2.title`,
+/// or for every livery at once.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(try_from = "HashMap<String, serde_json::Value>")]
+struct Patch {
+    files: BTreeMap<Target, FilePatch>,
+}
+
+impl TryFrom<HashMap<String, serde_json::Value>> for Patch {
+    type Error = serde_json::Error;
+
+    fn try_from(value: HashMap<String, serde_json::Value>) -> Result<Self, Self::Error> {
+        // Keys that aren't targets are ignored, as they always have been.
+        let mut files = BTreeMap::new();
+        for (key, value) in value {
+            if let Some(target) = Target::all().find(|target| target.key() == key) {
+                files.insert(target, read_file_patch(value)?);
+            }
+        }
+        Ok(Patch { files })
+    }
+}
+
+/// the patch for a single file, by key
+type FilePatch = HashMap<String, PatchValue>;
+
+/// the fields of the object form of a patch value, which a section's keys can't be named
+const VALUE_FIELDS: [&str; 8] = [
+    "value", "comment", "tags", "default", "when", "range", "one_of", "matches",
+];
+
+/// reads a file's patch, in which keys may be grouped by section
+///
+/// `{ "ENGINE.0": { "max_rpm": "2700" } }` is the same as `{ "ENGINE.0.max_rpm": "2700" }`. An
+/// object is taken for a section when none of its keys is a field of the object form of a value.
+/// A section with `"_enabled": false` is left out, keys and all.
+fn read_file_patch<'de, D>(deserializer: D) -> Result<FilePatch, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::de::Error;
+
+    let raw: HashMap<String, serde_json::Value> = Deserialize::deserialize(deserializer)?;
+    let mut patch = FilePatch::new();
+    let mut insert = |key: String, value: serde_json::Value| {
+        let value = PatchValue::try_from(value).map_err(D::Error::custom)?;
+        if patch.insert(key.clone(), value).is_some() {
+            return Err(D::Error::custom(format!("patch key {key} is given twice")));
+        }
+        Ok(())
+    };
+
+    for (key, value) in raw {
+        let serde_json::Value::Object(mut fields) = value else {
+            insert(key, value)?;
+            continue;
+        };
+        if fields
+            .keys()
+            .any(|field| VALUE_FIELDS.contains(&field.as_str()))
+        {
+            insert(key, serde_json::Value::Object(fields))?;
+            continue;
+        }
+
+        match fields.remove("_enabled") {
+            None | Some(serde_json::Value::Bool(true)) => {}
+            Some(serde_json::Value::Bool(false)) => continue,
+            Some(other) => {
+                return Err(D::Error::custom(format!(
+                    "_enabled in section {key} must be true or false, found {other}"
+                )))
+            }
+        }
+        for (name, value) in fields {
+            insert(format!("{key}.{name}"), value)?;
+        }
+    }
+
+    Ok(patch)
+}
+
+/// what a patch does to a key
+///
+/// Usually this is just a new value, written as a string. The object form, e.g.
+/// `{ "value": "1.1", "comment": "tuned" }`, can also replace the key's comment, with or without
+/// changing its value, and can tag the key for use with --tag. Its `when` applies the patch only
+/// to lines whose current value satisfies a condition, e.g. `"> 1.5"`. Its `range`, `one_of`
+/// and `matches` check the value the patch would write, so a bad value stops the run.
+///
+/// A key naming a whole section, e.g. `[FLIGHT_TUNING]`, takes a list of lines instead, which
+/// replace the body of that section.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(try_from = "serde_json::Value")]
+struct PatchValue {
+    value: Option<String>,
+    comment: Option<String>,
+    tags: Vec<String>,
+    block: Option<Vec<String>>,
+
+    /// the value to start from if the key is missing, in which case the key is added
+    default: Option<String>,
+
+    /// the condition a line's current value must meet to be patched
+    when: Option<Condition>,
+
+    /// the range, as a condition, the patched value must be in
+    range: Option<Condition>,
+
+    /// the values the patched value must be one of, if any
+    one_of: Vec<String>,
+
+    /// a pattern, which may contain *, the patched value must match
+    matches: Option<String>,
+}
+
+/// a condition on a number, e.g. `> 1.5` or `1..2`
+#[derive(Clone, Debug, PartialEq)]
+enum Condition {
+    Compare(Comparison, f64),
+
+    /// a range, including both ends
+    Between(f64, f64),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Comparison {
+    Less,
+    LessOrEqual,
+    Equal,
+    NotEqual,
+    GreaterOrEqual,
+    Greater,
+}
+
+impl Condition {
+    fn holds(&self, value: f64) -> bool {
+        match *self {
+            Condition::Compare(comparison, operand) => match comparison {
+                Comparison::Less => value < operand,
+                Comparison::LessOrEqual => value <= operand,
+                Comparison::Equal => value == operand,
+                Comparison::NotEqual => value != operand,
+                Comparison::GreaterOrEqual => value >= operand,
+                Comparison::Greater => value > operand,
+            },
+            Condition::Between(low, high) => low <= value && value <= high,
+        }
+    }
+}
+
+impl FromStr for Condition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let number = |s: &str| {
+            s.trim()
+                .parse::<f64>()
+                .map_err(|_| format!("expected a number in condition {s:?}"))
+        };
+
+        if let Some((low, high)) = s.split_once("..") {
+            let (low, high) = (number(low)?, number(high)?);
+            if low > high {
+                return Err(format!("range {s:?} is empty"));
+            }
+            return Ok(Condition::Between(low, high));
+        }
+
+        // Two-character operators go first, so that >= isn't read as > followed by =1.
+        let operators = [
+            ("<=", Comparison::LessOrEqual),
+            (">=", Comparison::GreaterOrEqual),
+            ("==", Comparison::Equal),
+            ("!=", Comparison::NotEqual),
+            ("<", Comparison::Less),
+            (">", Comparison::Greater),
+        ];
+        let s = s.trim();
+        operators
+            .into_iter()
+            .find_map(|(operator, comparison)| {
+                let operand = s.strip_prefix(operator)?;
+                Some(number(operand).map(|operand| Condition::Compare(comparison, operand)))
+            })
+            .unwrap_or_else(|| {
+                Err(format!(
+                    "unknown condition {s:?} (expected e.g. \"> 1.5\", \"== 1\" or \"1..2\")"
+                ))
+            })
+    }
+}
+
+impl Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Condition::Compare(comparison, operand) => {
+                let operator = match comparison {
+                    Comparison::Less => "<",
+                    Comparison::LessOrEqual => "<=",
+                    Comparison::Equal => "==",
+                    Comparison::NotEqual => "!=",
+                    Comparison::GreaterOrEqual => ">=",
+                    Comparison::Greater => ">",
+                };
+                write!(f, "{operator} {operand}")
+            }
+            Condition::Between(low, high) => write!(f, "{low}..{high}"),
+        }
+    }
+}
+
+impl PatchValue {
+    fn has_tag(&self, tags: &[String]) -> bool {
+        tags.iter().any(|tag| {
+            self.tags
+                .iter()
+                .any(|own| own == tag.trim_start_matches('@'))
+        })
+    }
+
+    /// checks a value the patch would write against its rules
+    fn validate(&self, value: &str) -> Result<(), String> {
+        if let Some(range) = &self.range {
+            match value.parse::<f64>() {
+                Ok(number) if range.holds(number) => {}
+                Ok(_) => return Err(format!("{value} is outside the allowed range {range}")),
+                Err(_) => return Err(format!("{value} isn't a number, so it can't be {range}")),
+            }
+        }
+        if !self.one_of.is_empty() && !self.one_of.iter().any(|allowed| allowed == value) {
+            return Err(format!(
+                "{value} isn't one of the allowed values: {}",
+                self.one_of.join(", ")
+            ));
+        }
+        if let Some(pattern) = &self.matches {
+            if !wildcard_match(pattern, value) {
+                return Err(format!("{value} doesn't match {pattern:?}"));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ValueObject {
+    #[serde(default)]
+    value: Option<String>,
+    #[serde(default)]
+    comment: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    default: Option<String>,
+    #[serde(default)]
+    when: Option<String>,
+    #[serde(default)]
+    range: Option<String>,
+    #[serde(default)]
+    one_of: Vec<String>,
+    #[serde(default)]
+    matches: Option<String>,
+}
+
+impl TryFrom<serde_json::Value> for PatchValue {
+    type Error = String;
+
+    fn try_from(value: serde_json::Value) -> Result<Self, Self::Error> {
+        match value {
+            serde_json::Value::String(value) => Ok(PatchValue {
+                value: Some(value),
+                ..Default::default()
+            }),
+
+            object @ serde_json::Value::Object(_) => {
+                let ValueObject {
+                    value,
+                    comment,
+                    tags,
+                    default,
+                    when,
+                    range,
+                    one_of,
+                    matches,
+                } = serde_json::from_value(object).map_err(|e| e.to_string())?;
+                let when = when.as_deref().map(str::parse).transpose()?;
+                let range = range.as_deref().map(str::parse).transpose()?;
+
+                // Tags may be written with or without a leading @, e.g. "@realism".
+                let tags = tags
+                    .into_iter()
+                    .map(|tag| tag.strip_prefix('@').map(ToOwned::to_owned).unwrap_or(tag))
+                    .collect();
+                Ok(PatchValue {
+                    value,
+                    comment,
+                    tags,
+                    block: None,
+                    default,
+                    when,
+                    range,
+                    one_of,
+                    matches,
+                })
+            }
+
+            serde_json::Value::Array(lines) => {
+                let block = lines
+                    .into_iter()
+                    .map(|line| match line {
+                        serde_json::Value::String(line) => Ok(line),
+                        other => Err(format!("expected a line of text, found {other}")),
+                    })
+                    .collect::<Result<_, _>>()?;
+                Ok(PatchValue {
+                    block: Some(block),
+                    ..Default::default()
+                })
+            }
+
+            other => Err(format!(
+                "expected a string, an object or a list of lines, found {other}"
+            )),
+        }
+    }
+}
+
+impl From<&PatchValue> for serde_json::Value {
+    /// the value as it would be written in a patch file
+    fn from(value: &PatchValue) -> Self {
+        if let Some(block) = &value.block {
+            return serde_json::json!(block);
+        }
+
+        let PatchValue {
+            value,
+            comment,
+            tags,
+            block: _,
+            default,
+            when,
+            range,
+            one_of,
+            matches,
+        } = value;
+        if comment.is_none()
+            && tags.is_empty()
+            && default.is_none()
+            && when.is_none()
+            && range.is_none()
+            && one_of.is_empty()
+            && matches.is_none()
+        {
+            if let Some(value) = value {
+                return value.as_str().into();
+            }
+        }
+
+        let mut object = serde_json::Map::new();
+        let mut field = |name: &str, value: Option<serde_json::Value>| {
+            if let Some(value) = value {
+                object.insert(name.to_owned(), value);
+            }
+        };
+        field("value", value.as_deref().map(Into::into));
+        field("comment", comment.as_deref().map(Into::into));
+        field("tags", (!tags.is_empty()).then(|| serde_json::json!(tags)));
+        field("default", default.as_deref().map(Into::into));
+        field("when", when.as_ref().map(|when| when.to_string().into()));
+        field(
+            "range",
+            range.as_ref().map(|range| range.to_string().into()),
+        );
+        field(
+            "one_of",
+            (!one_of.is_empty()).then(|| serde_json::json!(one_of)),
+        );
+        field("matches", matches.as_deref().map(Into::into));
+        serde_json::Value::Object(object)
+    }
+}
+
+impl Display for PatchValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(block) = &self.block {
+            return write!(f, "({} lines)", block.len());
+        }
+
+        match (&self.value, &self.comment) {
+            (Some(value), None) => f.write_str(value)?,
+            (Some(value), Some(comment)) => write!(f, "{value} ({comment})")?,
+            (None, Some(comment)) => write!(f, "({comment})")?,
+            (None, None) => f.write_str("(nothing)")?,
+        }
+        match &self.when {
+            Some(when) => write!(f, " when {when}"),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Patch {
+    fn file(&self, target: Target) -> Option<&FilePatch> {
+        self.files.get(&target)
+    }
+
+    fn file_mut(&mut self, target: Target) -> &mut FilePatch {
+        self.files.entry(target).or_default()
+    }
+
+    fn files(&self) -> impl Iterator<Item = (Target, &FilePatch)> {
+        self.files.iter().map(|(&target, patch)| (target, patch))
+    }
+
+    fn into_files(self) -> impl Iterator<Item = (Target, FilePatch)> {
+        self.files.into_iter()
+    }
+
+    /// the patch as it would be written in a patch file, with its keys in order
+    fn to_json(&self) -> serde_json::Value {
+        let files: BTreeMap<_, BTreeMap<_, serde_json::Value>> = self
+            .files()
+            .filter(|(_, patch)| !patch.is_empty())
+            .map(|(target, patch)| {
+                let values = patch
+                    .iter()
+                    .map(|(key, value)| (key, value.into()))
+                    .collect();
+                (target.key(), values)
+            })
+            .collect();
+        serde_json::json!(files)
+    }
+
+    fn diff(&self, path: impl AsRef<Path>, args: &Args, stats: &mut Stats) -> anyhow::Result<Diff> {
+        let path = path.as_ref();
+        let package = path.file_name().unwrap_or_default().to_string_lossy();
+        let mut diff = Diff::default();
+
+        for (target, patch) in self.files() {
+            if patch.is_empty() {
+                continue;
+            }
+
+            match timed(&mut stats.scanning, || find_target(path, target, args)) {
+                Some(file) => {
+                    let bytes = timed(&mut stats.reading, || read_baseline(&file, args))?;
+                    let (text, encoding) = encoding::decode(&bytes)
+                        .with_context(|| format!("unable to read {}", file.display()))?;
+
+                    let hash = patch_hash(patch);
+                    if args.marker && read_marker(&text) == Some(hash) {
+                        diff.skipped
+                            .push((target, "file already has this patch (marker)"));
+                        continue;
+                    }
+
+                    *diff.file_mut(target) = timed(&mut stats.diffing, || {
+                        build_diff(patch, file, &text, &package, args)
+                    })?;
+                    let file = diff.file_mut(target);
+                    file.marker = args.marker.then_some(hash);
+                    file.encoding = encoding;
+                    file.sha256 = sha256::hex_digest(&bytes);
+                }
+
+                None if args.fail_if_missing_target => anyhow::bail!(
+                    "{}: no {} found for patch",
+                    path.file_name().unwrap_or_default().to_string_lossy(),
+                    target.filename()
+                ),
+
+                None => diff.skipped.push((target, Diff::NOT_FOUND)),
+            }
+        }
+
+        Ok(diff)
+    }
+}
+
+/// reads the file a target file is diffed against
+///
+/// Normally that's the file itself, but we can use its backup instead to see the whole of a patch
+/// relative to stock.
+fn read_baseline(target: &Path, args: &Args) -> io::Result<Vec<u8>> {
+    let backup = args.backup_path(target);
+    if args.against_backup && backup.is_file() {
+        return fs::read(backup);
+    }
+    fs::read(target)
+}
+
+fn find_path(path: impl AsRef<Path>, filename: &str, args: &Args) -> Option<PathBuf> {
+    find_file(path, args, |path| path.ends_with(filename))
+}
+
+/// finds the first file in a package that `matches`, searching as --prune and
+/// --follow-symlinks say
+fn find_file(
+    path: impl AsRef<Path>,
+    args: &Args,
+    mut matches: impl FnMut(&Path) -> bool,
+) -> Option<PathBuf> {
+    let prune: &[String] = if args.no_prune { &[] } else { &args.prune };
+
+    // When following links, walkdir reports a loop as an error rather than descending into it,
+    // and we skip errors, so loops cost us nothing.
+    //
+    // Don't be tempted to list contents first: it only changes when directories are yielded, not
+    // files, and it stops filter_entry from pruning anything, because by the time walkdir asks
+    // about a directory, it has already been through it.
+    walkdir::WalkDir::new(path)
+        .follow_links(args.follow_symlinks)
+        .into_iter()
+        .filter_entry(|entry| {
+            entry.depth() == 0
+                || !entry.file_type().is_dir()
+                || !prune
+                    .iter()
+                    .any(|pattern| wildcard_match(pattern, &entry.file_name().to_string_lossy()))
+        })
+        .find_map(|entry| {
+            let entry = entry.ok()?;
+            (entry.file_type().is_file() && matches(entry.path())).then_some(entry.into_path())
+        })
+}
+
+fn build_diff(
+    patch: &FilePatch,
+    path: PathBuf,
+    text: &str,
+    package: &str,
+    args: &Args,
+) -> anyhow::Result<PathChanges> {
+    let Args {
+        epsilon,
+        ignore_list_spacing,
+        ref only_sections,
+        ..
+    } = *args;
+    let original = args.original();
+    let mut diff = BTreeMap::new();
+    let mut blocks = BTreeMap::new();
+    let mut unchanged = Vec::new();
+    let mut matched = HashSet::new();
+    let mut added: BTreeMap<usize, Vec<Change>> = BTreeMap::new();
+    let mut explained = Vec::new();
+    let mut ignored = Vec::new();
+
+    // The sections we've seen, with the line number of the last entry in each (or of the header,
+    // if it has none), which is where we add any keys it's missing.
+    let mut sections = HashMap::new();
+    let mut section = None;
+
+    let mut replacing: Option<Replacing> = None;
+    let allowed = |section: Option<&str>| {
+        only_sections.is_empty()
+            || section.is_some_and(|section| {
+                only_sections
+                    .iter()
+                    .any(|pattern| wildcard_match(pattern, section))
+            })
+    };
+    let aggregates = aggregate(patch, text, allowed)
+        .with_context(|| format!("unable to patch {}", path.display()))?;
+
+    // Patch keys like point[2] that patch one element of a list, with their sections (if
+    // qualified) and keys proper.
+    let elements: Vec<_> = patch
+        .iter()
+        .filter_map(|(address, value)| {
+            let (section, key) = split_address(address);
+            let (key, index) = split_element(key)?;
+            Some((section, key, index, address, value))
+        })
+        .collect();
+
+    for (idx, line) in text.lines().enumerate() {
+        let parsed = line::parse(line);
+
+        if let Line::Section { .. } = parsed {
+            if let Some(replacing) = replacing.take() {
+                replacing.close(patch, &mut blocks, &mut unchanged, &mut explained);
+            }
+        } else if let Some(replacing) = &mut replacing {
+            replacing.body.push((idx, line));
+            continue;
+        }
+
+        match parsed {
+            Line::Entry {
+                key,
+                value,
+                comment,
+            } => {
+                // Keys before the first header are in the root scope, which has no name.
+                sections.insert(section.unwrap_or_default(), idx);
+
+                let current = value.trim();
+                let place = match section {
+                    Some(section) => format!("[{section}] line {}", idx + 1),
+                    None => format!("line {}", idx + 1),
+                };
+
+                // As with whole keys, a qualified element patch wins over an unqualified one.
+                let mut patched_elements = BTreeMap::new();
+                for qualified in [false, true] {
+                    for &(name, element_key, index, address, value) in &elements {
+                        if element_key == key
+                            && name.is_some() == qualified
+                            && name.is_none_or(|name| name == section.unwrap_or_default())
+                        {
+                            patched_elements.insert(index, (address, value));
+                        }
+                    }
+                }
+
+                if !allowed(section) {
+                    let whole = lookup(patch, section, key).map(|(address, _)| address);
+                    for address in whole
+                        .into_iter()
+                        .chain(patched_elements.values().map(|&(address, _)| address))
+                    {
+                        matched.insert(address);
+                        explained.push((
+                            address.clone(),
+                            format!("matched {place}, outside --only-sections, skipped"),
+                        ));
+                    }
+                    continue;
+                }
+
+                if let Some(&(element, _)) = patched_elements.values().next() {
+                    if let Some((address, _)) = lookup(patch, section, key) {
+                        anyhow::bail!(
+                            "unable to patch {}: patch keys {address} and {element} both patch {place}",
+                            path.display()
+                        );
+                    }
+
+                    let mut new = current.to_owned();
+                    let ranges = list_elements(current);
+
+                    // Replacing from the end keeps the ranges of earlier elements good.
+                    for (&index, &(address, patch)) in patched_elements.iter().rev() {
+                        matched.insert(address);
+                        let Some(range) = ranges.get(index) else {
+                            anyhow::bail!(
+                                "unable to patch {}: {place} has {} elements, so there's no {address}",
+                                path.display(),
+                                ranges.len()
+                            );
+                        };
+                        let element = &current[range.clone()];
+
+                        let recorded = recorded_original(comment, &original).and_then(|recorded| {
+                            let recorded_ranges = list_elements(recorded);
+                            (recorded_ranges.len() == ranges.len())
+                                .then(|| &recorded[recorded_ranges[index].clone()])
+                        });
+
+                        if let Some(when) = &patch.when {
+                            let Ok(number) = element.parse::<f64>() else {
+                                explained.push((
+                                    address.clone(),
+                                    format!(
+                                        "matched {place}, but {element} isn't a number, skipped"
+                                    ),
+                                ));
+                                ignored.push((address.clone(), element.to_owned()));
+                                continue;
+                            };
+                            if !when.holds(number) {
+                                explained.push((
+                                    address.clone(),
+                                    format!("matched {place}, but {element} isn't {when}, skipped"),
+                                ));
+                                continue;
+                            }
+                        }
+
+                        let Some(value) = &patch.value else {
+                            continue;
+                        };
+                        let value = expand(value, package, section)
+                            .and_then(|value| {
+                                resolve(&value, element, recorded)
+                                    .map(|value| value.trim().to_owned())
+                            })
+                            .with_context(|| {
+                                format!("unable to patch {} ({address})", path.display())
+                            })?;
+                        patch.validate(&value).map_err(|e| {
+                            anyhow::anyhow!("unable to patch {} ({address}): {e}", path.display())
+                        })?;
+                        if values_equal(element, &value, epsilon) {
+                            explained.push((
+                                address.clone(),
+                                format!("matched {place}, already {element}, skipped"),
+                            ));
+                            unchanged.push((address.clone(), element.to_owned()));
+                            continue;
+                        }
+
+                        explained.push((
+                            address.clone(),
+                            format!("matched {place}, element {element} != {value}, will change"),
+                        ));
+                        new.replace_range(range.clone(), &value);
+                    }
+
+                    if new != current {
+                        diff.insert(
+                            idx,
+                            Change {
+                                section: section.map(ToOwned::to_owned),
+                                key: key.to_owned(),
+                                old: value.to_owned(),
+                                new,
+                                comment: None,
+                            },
+                        );
+                    }
+                    continue;
+                }
+
+                let Some((address, patch)) = lookup(patch, section, key) else {
+                    continue;
+                };
+
+                matched.insert(address);
+                if let Some(when) = &patch.when {
+                    let Ok(number) = current.parse::<f64>() else {
+                        explained.push((
+                            address.clone(),
+                            format!("matched {place}, but {current} isn't a number, skipped"),
+                        ));
+                        ignored.push((address.clone(), current.to_owned()));
+                        continue;
+                    };
+                    if !when.holds(number) {
+                        explained.push((
+                            address.clone(),
+                            format!("matched {place}, but {current} isn't {when}, skipped"),
+                        ));
+                        continue;
+                    }
+                }
+
+                // If the value is equal to the changed value, we... actually don't want to bother
+                // with this.
+
+                let new = match (&patch.value, aggregates.get(address)) {
+                    (_, Some(aggregate)) => Cow::Borrowed(aggregate.trim()),
+                    (Some(new), None) => expand(new, package, section)
+                        .and_then(|new| {
+                            resolve(&new, current, recorded_original(comment, &original))
+                                .map(|new| new.trim().to_owned())
+                        })
+                        .map(Cow::Owned)
+                        .with_context(|| {
+                            format!("unable to patch {} ({address})", path.display())
+                        })?,
+                    (None, None) => Cow::Borrowed(current),
+                };
+                if patch.value.is_some() {
+                    patch.validate(&new).map_err(|e| {
+                        anyhow::anyhow!("unable to patch {} ({address}): {e}", path.display())
+                    })?;
+                }
+                let new = if values_equal(current, &new, epsilon)
+                    || ignore_list_spacing && lists_equal(current, &new, epsilon)
+                {
+                    Cow::Borrowed(current)
+                } else {
+                    new
+                };
+
+                // Likewise, we only care about the comment if the patch replaces it with a
+                // different one.
+
+                let comment = patch.comment.as_ref().filter(|&new| {
+                    comment.map_or("", |comment| comment.text.trim()) != new.as_str()
+                });
+
+                if new == current && comment.is_none() {
+                    explained.push((
+                        address.clone(),
+                        format!("matched {place}, already {current}, skipped"),
+                    ));
+                    unchanged.push((address.clone(), current.to_owned()));
+                    continue;
+                }
+
+                let reason = if new == current {
+                    format!("matched {place}, comment differs, will change")
+                } else {
+                    format!("matched {place}, value {current} != {new}, will change")
+                };
+                explained.push((address.clone(), reason));
+
+                diff.insert(
+                    idx,
+                    Change {
+                        section: section.map(ToOwned::to_owned),
+                        key: key.to_owned(),
+                        old: value.to_owned(),
+                        new: new.into_owned(),
+                        comment: comment.cloned(),
+                    },
+                );
+            }
+
+            Line::Section { name, .. } => {
+                sections.insert(name, idx);
+                section = Some(name);
+
+                if let Some((address, _)) = patch.get_key_value(&format!("[{name}]")) {
+                    matched.insert(address);
+                    if !allowed(section) {
+                        explained.push((
+                            address.clone(),
+                            format!("matched line {}, outside --only-sections, skipped", idx + 1),
+                        ));
+                        continue;
+                    }
+                    replacing = Some(Replacing {
+                        header: idx,
+                        address,
+                        body: Vec::new(),
+                    });
+                }
+            }
+
+            _ => {}
+        }
+    }
+
+    if let Some(replacing) = replacing {
+        replacing.close(patch, &mut blocks, &mut unchanged, &mut explained);
+    }
+
+    // A patch naming a section the file doesn't have is almost certainly a mistake (a third
+    // engine on a twin, say), so we refuse it rather than quietly patching nothing.
+
+    for (address, value) in patch {
+        if let Some(name) = block_name(address) {
+            if value.block.is_none() {
+                anyhow::bail!(
+                    "patch key {address} replaces a section, so it needs a list of lines"
+                );
+            }
+            if !sections.contains_key(name) {
+                anyhow::bail!(
+                    "unable to patch {}: section [{name}] not found (patch key {address})",
+                    path.display()
+                );
+            }
+            continue;
+        }
+
+        if value.block.is_some() {
+            anyhow::bail!("patch key {address} has a list of lines, but only sections take those");
+        }
+
+        if split_element(split_address(address).1).is_some() {
+            if value.default.is_some() {
+                anyhow::bail!(
+                    "patch key {address} patches an element of a list, so it can't be added"
+                );
+            }
+            if let Some(aggregate) = value.value.as_deref().and_then(Aggregate::parse) {
+                anyhow::bail!("patch key {address} patches an element of a list, so it can't take {aggregate}");
+            }
+        }
+
+        if let Some(name) = split_address(address).0.filter(|name| !name.is_empty()) {
+            // Both replacing a section and patching keys in it is ambiguous, so we refuse it.
+            if patch.contains_key(&format!("[{name}]")) {
+                anyhow::bail!(
+                    "patch key {address} is in section [{name}], which the patch replaces"
+                );
+            }
+
+            if !sections.contains_key(name) {
+                anyhow::bail!(
+                    "unable to patch {}: section [{name}] not found (patch key {address})",
+                    path.display()
+                );
+            }
+        }
+    }
+
+    // Missing keys with a default are added, at the end of their section.
+
+    let mut missing = Vec::new();
+    for (address, value) in patch
+        .iter()
+        .filter(|(address, _)| !matched.contains(address))
+    {
+        let Some(default) = &value.default else {
+            missing.push(address.clone());
+            continue;
+        };
+
+        let (Some(name), key) = split_address(address) else {
+            anyhow::bail!(
+                "patch key {address} has a default, so it needs a section to be added to"
+            );
+        };
+        if name.is_empty() && !sections.contains_key(name) {
+            anyhow::bail!(
+                "unable to patch {}: no keys before the first section to add {address} after",
+                path.display()
+            );
+        }
+        let section = Some(name).filter(|name| !name.is_empty());
+        if !allowed(section) {
+            explained.push((
+                address.clone(),
+                String::from("not found, and outside --only-sections, skipped"),
+            ));
+            continue;
+        }
+
+        // A conditional key is only added if its default meets the condition.
+        if let Some(when) = &value.when {
+            if !default.parse().is_ok_and(|default| when.holds(default)) {
+                explained.push((
+                    address.clone(),
+                    format!("not found, and its default {default} isn't {when}, skipped"),
+                ));
+                continue;
+            }
+        }
+        let new = match &value.value {
+            Some(new) => expand(new, package, section)
+                .and_then(|new| resolve(&new, default, None).map(|new| new.trim().to_owned()))
+                .with_context(|| format!("unable to patch {} ({address})", path.display()))?,
+            None => default.trim().to_owned(),
+        };
+        value
+            .validate(&new)
+            .map_err(|e| anyhow::anyhow!("unable to patch {} ({address}): {e}", path.display()))?;
+
+        let place = match section {
+            Some(name) => format!("[{name}]"),
+            None => String::from("the root scope"),
+        };
+        explained.push((
+            address.clone(),
+            format!("not found in {place}, will add {new} (from default {default})"),
+        ));
+
+        // The section is there, or we'd have bailed by now.
+        added.entry(sections[name]).or_default().push(Change {
+            section: section.map(ToOwned::to_owned),
+            key: key.to_owned(),
+            old: String::new(),
+            new,
+            comment: value.comment.clone(),
+        });
+    }
+    missing.sort();
+    for address in &missing {
+        explained.push((address.clone(), String::from("not found in file")));
+    }
+    for changes in added.values_mut() {
+        changes.sort_by(|a, b| a.key.cmp(&b.key));
+    }
+
+    Ok(PathChanges {
+        path,
+        text: text.to_owned(),
+        changes: diff,
+        added,
+        blocks,
+        unchanged,
+        missing,
+        ignored,
+        explained,
+        marker: None,
+        encoding: Encoding::Utf8,
+        sha256: String::new(),
+    })
+}
+
+/// the name of the section a patch key replaces, if it names a whole section, e.g. `[ENGINE.0]`
+fn block_name(address: &str) -> Option<&str> {
+    address.strip_prefix('[')?.strip_suffix(']')
+}
+
+/// a section we're replacing, as we read through it
+struct Replacing<'a> {
+    /// the line number of the section's header
+    header: usize,
+
+    /// the patch key replacing it
+    address: &'a String,
+
+    /// the lines of its body so far, by line number
+    body: Vec<(usize, &'a str)>,
+}
+
+impl Replacing<'_> {
+    /// records the replacement of the section body, unless it already holds the patched lines
+    fn close(
+        self,
+        patch: &FilePatch,
+        blocks: &mut BTreeMap<usize, Block>,
+        unchanged: &mut Vec<(String, String)>,
+        explained: &mut Vec<(String, String)>,
+    ) {
+        let Replacing {
+            header,
+            address,
+            mut body,
+        } = self;
+        let new = patch[address].block.clone().unwrap_or_default();
+
+        // Blank lines at the end of a section separate it from the next one, so we leave them be.
+
+        while body.last().is_some_and(|(_, line)| line.trim().is_empty()) {
+            body.pop();
+        }
+
+        let old: Vec<_> = body.iter().map(|&(_, line)| line.to_owned()).collect();
+        if old
+            .iter()
+            .map(|line| line.trim_end())
+            .eq(new.iter().map(|line| line.trim_end()))
+        {
+            unchanged.push((address.clone(), format!("({} lines)", new.len())));
+            explained.push((
+                address.clone(),
+                format!(
+                    "matched line {}, already holds the patched lines, skipped",
+                    header + 1
+                ),
+            ));
+            return;
+        }
+
+        explained.push((
+            address.clone(),
+            format!(
+                "matched line {}, {} lines != {} lines, will replace",
+                header + 1,
+                old.len(),
+                new.len()
+            ),
+        ));
+
+        let end = body.last().map_or(header + 1, |&(idx, _)| idx + 1);
+        blocks.insert(
+            header,
+            Block {
+                name: block_name(address).unwrap_or_default().to_owned(),
+                old,
+                new,
+                end,
+            },
+        );
+    }
+}
+
+/// fills in the placeholders in a patch value
+///
+/// `{package}` is the package's folder name and `{index}` is the index of the section the key
+/// is in, e.g. 1 for `[FLTSIM.1]`. Write `{{` and `}}` for literal braces.
+fn expand<'a>(
+    value: &'a str,
+    package: &str,
+    section: Option<&str>,
+) -> anyhow::Result<Cow<'a, str>> {
+    if let Some(reference) = value.strip_prefix("@table:") {
+        return tables::lookup(reference, package).map(Cow::Owned);
+    }
+    if !value.contains(['{', '}']) {
+        return Ok(Cow::Borrowed(value));
+    }
+
+    let mut expanded = String::new();
+    let mut rest = value;
+    while let Some(at) = rest.find(['{', '}']) {
+        expanded += &rest[..at];
+        rest = &rest[at..];
+
+        if let Some(tail) = rest.strip_prefix("{{") {
+            expanded.push('{');
+            rest = tail;
+            continue;
+        }
+        if let Some(tail) = rest.strip_prefix("}}") {
+            expanded.push('}');
+            rest = tail;
+            continue;
+        }
+
+        let Some((name, tail)) = rest[1..].split_once('}').filter(|_| rest.starts_with('{')) else {
+            anyhow::bail!("unmatched brace in {value} (use {{{{ or }}}} for a literal brace)");
+        };
+        match name {
+            "package" => expanded += package,
+            "index" => {
+                let index = section
+                    .and_then(|section| section.rsplit_once('.'))
+                    .map(|(_, index)| index)
+                    .filter(|index| index.parse::<u32>().is_ok());
+                let Some(index) = index else {
+                    anyhow::bail!(
+                        "{value} uses {{index}}, but section [{}] has no index",
+                        section.unwrap_or_default()
+                    );
+                };
+                expanded += index;
+            }
+            _ => anyhow::bail!(
+                "unknown placeholder {{{name}}} in {value} (expected {{package}} or {{index}})"
+            ),
+        }
+        rest = tail;
+    }
+    expanded += rest;
+
+    Ok(Cow::Owned(expanded))
+}
+
+/// works out the value a patch sets, which may be relative to the current value
+///
+/// A value like `+10%` or `-5%` scales the current value by that percentage. The sign is required,
+/// to tell these apart from values that just happen to end in a percent sign. A line patched
+/// before is scaled from the `original` value it recorded, if that's a number, rather than from
+/// its current value, so that applying the same patch again leaves it as it is. An aggregate
+/// like `@avg` of the current value alone is just that value; see [`aggregate`] for the rest.
+fn resolve<'a>(
+    patch: &'a str,
+    current: &'a str,
+    original: Option<&'a str>,
+) -> anyhow::Result<Cow<'a, str>> {
+    if Aggregate::parse(patch).is_some() {
+        return Ok(Cow::Borrowed(current));
+    }
+    let Some(percent) = parse_percent(patch) else {
+        return Ok(Cow::Borrowed(patch));
+    };
+
+    let current: f64 = original
+        .and_then(|original| original.parse().ok())
+        .map_or_else(|| current.parse(), Ok)
+        .map_err(|_| anyhow::anyhow!("can't apply {patch} to non-numeric value {current}"))?;
+
+    Ok(Cow::Owned(format_number(current * (1.0 + percent / 100.0))))
+}
+
+/// a value computed from every line a patch key matches, e.g. `@max`
+#[derive(Clone, Copy, Debug)]
+enum Aggregate {
+    Min,
+    Max,
+    Avg,
+}
+
+impl Aggregate {
+    fn parse(value: &str) -> Option<Aggregate> {
+        match value {
+            "@min" => Some(Aggregate::Min),
+            "@max" => Some(Aggregate::Max),
+            "@avg" => Some(Aggregate::Avg),
+            _ => None,
+        }
+    }
+
+    fn of(self, values: &[f64]) -> f64 {
+        match self {
+            Aggregate::Min => values.iter().copied().fold(f64::INFINITY, f64::min),
+            Aggregate::Max => values.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+            Aggregate::Avg => values.iter().sum::<f64>() / values.len() as f64,
+        }
+    }
+}
+
+impl Display for Aggregate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Aggregate::Min => "@min",
+            Aggregate::Max => "@max",
+            Aggregate::Avg => "@avg",
+        })
+    }
+}
+
+/// works out the values of patch keys set to an aggregate, over every line each one matches
+///
+/// An unqualified key like `thrust_scalar` matches in every section, so `@avg` evens out the
+/// engines of an asymmetric mod. Lines that don't meet a key's `when` are left out.
+fn aggregate(
+    patch: &FilePatch,
+    text: &str,
+    allowed: impl Fn(Option<&str>) -> bool,
+) -> anyhow::Result<HashMap<String, String>> {
+    let mut values: HashMap<&String, (Aggregate, Vec<f64>)> = HashMap::new();
+    let mut section = None;
+    let mut replaced = false;
+
+    for line in text.lines() {
+        match line::parse(line) {
+            Line::Section { name, .. } => {
+                section = Some(name);
+                replaced = patch.contains_key(&format!("[{name}]")) && allowed(section);
+            }
+            Line::Entry { key, value, .. } if !replaced && allowed(section) => {
+                let Some((address, patch)) = lookup(patch, section, key) else {
+                    continue;
+                };
+                let Some(aggregate) = patch.value.as_deref().and_then(Aggregate::parse) else {
+                    continue;
+                };
+
+                let value = value.trim();
+                let number = match value.parse::<f64>() {
+                    Ok(number) => number,
+                    Err(_) if patch.when.is_some() => continue,
+                    Err(_) => anyhow::bail!(
+                        "can't take {aggregate} of non-numeric value {value} ({address})"
+                    ),
+                };
+                if patch.when.as_ref().is_some_and(|when| !when.holds(number)) {
+                    continue;
+                }
+                values
+                    .entry(address)
+                    .or_insert((aggregate, Vec::new()))
+                    .1
+                    .push(number);
+            }
+            _ => {}
+        }
+    }
+
+    Ok(values
+        .into_iter()
+        .map(|(address, (aggregate, numbers))| {
+            (address.clone(), format_number(aggregate.of(&numbers)))
+        })
+        .collect())
+}
+
+fn parse_percent(value: &str) -> Option<f64> {
+    let number = value.strip_suffix('%')?;
+    if !number.starts_with(['+', '-']) {
+        return None;
+    }
+    number.parse().ok()
+}
+
+/// formats a computed number without the noise of floating point error
+fn format_number(value: f64) -> String {
+    let text = format!("{value:.6}");
+    text.trim_end_matches('0').trim_end_matches('.').to_owned()
+}
+
+/// compares values as numbers, within a tolerance, when both of them are numbers
+fn values_equal(current: &str, target: &str, epsilon: f64) -> bool {
+    match (current.parse::<f64>(), target.parse::<f64>()) {
+        (Ok(current), Ok(target)) => (current - target).abs() <= epsilon,
+        _ => current == target,
+    }
+}
+
+/// whether two values are the same list but for their spacing, for --ignore-list-spacing
+fn lists_equal(current: &str, target: &str, epsilon: f64) -> bool {
+    if current.contains(',') != target.contains(',') {
+        return false;
+    }
+    let a = list_elements(current);
+    let b = list_elements(target);
+    a.len() == b.len()
+        && a.into_iter()
+            .zip(b)
+            .all(|(a, b)| values_equal(&current[a], &target[b], epsilon))
+}
+
+/// finds the patch key and value for a key, preferring a section-qualified match
+///
+/// A key before the first section header is qualified with an empty section name, e.g. `.key`.
+fn lookup<'a>(
+    patch: &'a FilePatch,
+    section: Option<&str>,
+    key: &str,
+) -> Option<(&'a String, &'a PatchValue)> {
+    patch
+        .get_key_value(&format!("{}.{key}", section.unwrap_or_default()))
+        .or_else(|| patch.get_key_value(key))
+}
+
+/// splits a patch key into its section (if any) and the key proper
+///
+/// The root scope, before the first section header, is the section with an empty name.
+fn split_address(address: &str) -> (Option<&str>, &str) {
+    match address.rsplit_once('.') {
+        Some((section, key)) => (Some(section), key),
+        None => (None, address),
+    }
+}
+
+/// splits a patch key for one element of a list, e.g. `point[2]`, into the key and the index
+fn split_element(key: &str) -> Option<(&str, usize)> {
+    let (key, index) = key.strip_suffix(']')?.split_once('[')?;
+    let index = index.parse().ok()?;
+    (!key.is_empty()).then_some((key, index))
+}
+
+/// the places of the elements in a list value
+///
+/// A value with commas is split on them, with the space around each element left in place;
+/// otherwise, the elements are separated by spaces.
+fn list_elements(value: &str) -> Vec<Range<usize>> {
+    if value.contains(',') {
+        let mut elements = Vec::new();
+        let mut start = 0;
+        for piece in value.split(',') {
+            let end = start + piece.len();
+            let leading = piece.len() - piece.trim_start().len();
+            let trailing = piece.len() - piece.trim_end().len();
+            elements.push((start + leading).min(end - trailing)..end - trailing);
+            start = end + 1;
+        }
+        return elements;
+    }
+
+    let mut elements = Vec::new();
+    let mut start = None;
+    for (idx, c) in value.char_indices() {
+        match (c.is_whitespace(), start) {
+            (false, None) => start = Some(idx),
+            (true, Some(from)) => {
+                elements.push(from..idx);
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(from) = start {
+        elements.push(from..value.len());
+    }
+    elements
+}
+
+/// the patched files collected for --zip-out
+#[derive(Debug, Default)]
+struct Bundle {
+    zip: zip::ZipWriter,
+    files: Vec<BundledFile>,
+}
+
+#[derive(Debug, Serialize)]
+struct BundledFile {
+    path: String,
+    package: String,
+    lines_changed: usize,
+}
+
+impl Bundle {
+    fn write(mut self, path: &Path, run_id: &str) -> anyhow::Result<()> {
+        let manifest = serde_json::json!({ "run_id": run_id, "files": self.files });
+        self.zip.add(
+            "manifest.json",
+            (serde_json::to_string_pretty(&manifest)? + "\n").as_bytes(),
+        );
+        fs::write(path, self.zip.finish())?;
+        Ok(())
+    }
+}
+
+/// patches by package, then file, then address, as written by --inverse-out
+type InversePatch = BTreeMap<String, BTreeMap<&'static str, BTreeMap<String, serde_json::Value>>>;
+
+/// a single value to be changed, identified in the diff by its line number
+#[derive(Debug)]
+struct Change {
+    section: Option<String>,
+    key: String,
+    old: String,
+    new: String,
+
+    /// the line's new comment, if the patch replaces it
+    comment: Option<String>,
+}
+
+impl Change {
+    fn address(&self) -> String {
+        match &self.section {
+            Some(section) => format!("{section}.{}", self.key),
+            None => self.key.clone(),
+        }
+    }
+
+    fn value_changed(&self) -> bool {
+        self.old.trim() != self.new
+    }
+
+    /// formats the changed line, given the comment it had before
+    fn line(&self, comment: Option<Comment>, original: &Original) -> String {
+        // Because we found a change, we're going to A) write our modified value to output
+        // instead of the original value; B) include the original value as a "comment"; and C)
+        // include the original comment (if applicable) in a second comment. A patch can replace
+        // the original comment, and if it leaves the value alone, there's no original value to
+        // include.
+
+        let Change { key, old, new, .. } = self;
+        let delimiter = comment.map_or(";", |comment| comment.delimiter);
+        // A line patched before keeps the original it has, wherever the comment comes from.
+        let recorded = comment.and_then(|comment| {
+            let at = comment.text.find(ORIGINAL_MARKER)?;
+            let (recorded, _) = line::split_comment(&comment.text[at..]);
+            Some(recorded.trim())
+        });
+
+        // An empty comment in the patch removes the line's comment.
+        let comment = self
+            .comment
+            .as_deref()
+            .or(comment.map(|comment| comment.text))
+            .filter(|comment| !comment.trim().is_empty());
+
+        // Without trimming, the space after the delimiter would build up from one run to the next.
+        let comment = match original {
+            Original::Once => comment.map(str::trim),
+            _ => comment,
+        };
+
+        let old = match original {
+            _ if !self.value_changed() => None,
+            Original::Verbatim => Some(Cow::Borrowed(old.as_str())),
+            Original::Template(template) => {
+                Some(Cow::Owned(template.replace("{original}", old.trim())))
+            }
+            Original::Once => match recorded {
+                Some(_) if self.comment.is_none() => None,
+                Some(recorded) => Some(Cow::Borrowed(recorded)),
+                None => Some(Cow::Owned(format!("{ORIGINAL_MARKER} {}", old.trim()))),
+            },
+            Original::Omit => None,
+        };
+
+        match (old, comment) {
+            (Some(old), Some(comment)) => {
+                format!("{key} = {new} {delimiter} {old} {delimiter} {comment}")
+            }
+            (Some(old), None) => format!("{key} = {new} {delimiter} {old}"),
+            (None, Some(comment)) => format!("{key} = {new} {delimiter} {comment}"),
+            (None, None) => format!("{key} = {new}"),
+        }
+    }
+}
+
+/// a section body to be replaced wholesale
+#[derive(Debug)]
+struct Block {
+    name: String,
+    old: Vec<String>,
+    new: Vec<String>,
+
+    /// the line number just past the end of the old body
+    end: usize,
+}
+
+/// the original value a patched line recorded in its comment, if it did
+///
+/// With --preserve-original-once, that's whatever follows the marker. Otherwise it's the first
+/// field of the comment, with any --preserve-format template taken off, which a line that wasn't
+/// patched may have something else in, so it's only used where it has to be a number.
+fn recorded_original<'a>(comment: Option<Comment<'a>>, original: &Original) -> Option<&'a str> {
+    let text = comment?.text;
+    let recorded = match (text.find(ORIGINAL_MARKER), original) {
+        (Some(at), _) => line::split_comment(&text[at + ORIGINAL_MARKER.len()..]).0,
+        (None, Original::Verbatim) => line::split_comment(text).0,
+        (None, Original::Template(template)) => {
+            let (prefix, suffix) = template.split_once("{original}")?;
+            line::split_comment(text)
+                .0
+                .trim()
+                .strip_prefix(prefix.trim_start())?
+                .strip_suffix(suffix.trim_end())?
+        }
+        (None, Original::Once | Original::Omit) => return None,
+    };
+    Some(recorded.trim()).filter(|recorded| !recorded.is_empty())
+}
+
+/// marks the original value of a line, for --preserve-original-once
+const ORIGINAL_MARKER: &str = "patchcfg-orig:";
+
+/// how a changed line records the value it had before
+#[derive(Debug)]
+enum Original {
+    /// exactly as it was, e.g. `key = new ; old`
+    Verbatim,
+
+    /// according to a template, e.g. `was: {original}`
+    Template(String),
+
+    /// marked, e.g. `key = new ; patchcfg-orig: old`, unless the line already has a marked original
+    Once,
+
+    /// not at all
+    Omit,
+}
+
+#[derive(Debug, Default)]
+struct PathChanges {
+    path: PathBuf,
+
+    /// the text the changes were worked out from
+    text: String,
+    changes: BTreeMap<usize, Change>,
+
+    /// keys to be added, by the line number they're to follow
+    added: BTreeMap<usize, Vec<Change>>,
+
+    /// sections whose bodies are to be replaced, by the line number of their headers
+    blocks: BTreeMap<usize, Block>,
+
+    /// lines that matched the patch but already had the patched value, by address
+    unchanged: Vec<(String, String)>,
+
+    /// patch keys that matched no line in the file
+    missing: Vec<String>,
+
+    /// lines a conditional patch key matched but couldn't test, not being numbers, by address
+    ignored: Vec<(String, String)>,
+
+    /// what was decided for each patch key, by address, for --explain
+    explained: Vec<(String, String)>,
+
+    /// the hash of the patch, to be recorded in the file for --marker
+    marker: Option<u64>,
+
+    /// the encoding the file was read in, and is to be written back in
+    encoding: Encoding,
+
+    /// the SHA-256 of the file as it was read, before decoding
+    sha256: String,
+}
+
+impl PathChanges {
+    fn is_empty(&self) -> bool {
+        self.changes.is_empty() && self.added.is_empty() && self.blocks.is_empty()
+    }
+
+    /// the number of keys changed or added, and sections replaced
+    fn count(&self) -> usize {
+        self.changes.len() + self.added.values().map(Vec::len).sum::<usize>() + self.blocks.len()
+    }
+}
+
+/// diff between a given patch and a given file
+///
+/// If a patch needs to be applied, there will be keyes in these maps. If the maps are empty, the
+/// patch has already been applied or the patch contained nothing.
+#[derive(Debug, Default)]
+struct Diff {
+    files: BTreeMap<Target, PathChanges>,
+
+    /// files that were patched but not diffed, and why
+    skipped: Vec<(Target, &'static str)>,
+}
+
+impl Diff {
+    /// the reason given for skipping a file that isn't in the package
+    const NOT_FOUND: &'static str = "file not found";
+
+    /// the number of changes the diff will write, leaving out files over --max-changes
+    fn count(&self, args: &Args) -> usize {
+        self.files
+            .values()
+            .map(PathChanges::count)
+            .filter(|&count| args.max_changes.is_none_or(|max| count <= max))
+            .sum()
+    }
+
+    /// skips files the patch would change too much of, for --max-changes
+    fn limit_changes(&mut self, max: usize, package: &Path, log: &Log) {
+        let over: Vec<_> = self
+            .files
+            .iter()
+            .filter(|(_, file)| file.count() > max)
+            .map(|(&target, _)| target)
+            .collect();
+
+        for target in over {
+            let file = self.files.remove(&target).expect("the file is in the diff");
+            log.warn(format_args!(
+                "{}: {}: {} changes is more than --max-changes {max}; skipping file",
+                package.display(),
+                file.path.display(),
+                file.count()
+            ));
+            self.skipped
+                .push((target, "too many changes (--max-changes)"));
+        }
+    }
+
+    /// the files that were patched but couldn't be found
+    fn missing(&self) -> impl Iterator<Item = Target> + '_ {
+        self.skipped
+            .iter()
+            .filter(|(_, reason)| *reason == Diff::NOT_FOUND)
+            .map(|&(target, _)| target)
+    }
+
+    /// the files to report on, which may leave out the ones that don't change
+    fn reported(&self, args: &Args) -> impl Iterator<Item = (Target, &PathChanges)> {
+        let focus = args.diff_only_changed_files;
+        self.files()
+            .into_iter()
+            .filter(move |(_, file)| !focus || !file.is_empty())
+    }
+
+    fn warn_missing(&self, package: &Path, args: &Args, log: &Log) {
+        let package = package.file_name().unwrap_or_default().to_string_lossy();
+        for (_, file) in self.reported(args) {
+            for address in &file.missing {
+                log.warn(format_args!(
+                    "{package}: {}: patch key {address} not found",
+                    file.path.display()
+                ));
+            }
+            for (address, value) in &file.ignored {
+                log.warn(format_args!(
+                    "{package}: {}: patch key {address} has a condition, but {value} isn't a number",
+                    file.path.display()
+                ));
+            }
+        }
+    }
+
+    /// writes the changes to each file as a minimal config of its own, under `dir`
+    ///
+    /// Overlays mirror the layout of the packages directory, e.g.
+    /// `<dir>/<package>/SimObjects/.../engines.cfg`.
+    fn write_overlay(&self, package: &Path, dir: &Path, args: &Args) -> anyhow::Result<()> {
+        for (_, file) in self.files() {
+            if file.is_empty() {
+                continue;
+            }
+
+            let relative = file.path.strip_prefix(package).unwrap_or(&file.path);
+            let path = dir
+                .join(package.file_name().unwrap_or_default())
+                .join(relative);
+
+            // Changes are in file order, so each section's keys are together already.
+
+            let mut overlay = String::new();
+            let mut section = None;
+            let mut changes: Vec<_> = file
+                .changes
+                .iter()
+                .map(|(&idx, change)| (idx, change))
+                .chain(
+                    file.added
+                        .iter()
+                        .flat_map(|(&idx, added)| added.iter().map(move |change| (idx, change))),
+                )
+                .collect();
+            changes.sort_by_key(|&(idx, _)| idx);
+
+            for (_, change) in changes {
+                if change.section != section {
+                    if let Some(name) = &change.section {
+                        if !overlay.is_empty() {
+                            overlay.push('\n');
+                        }
+                        overlay += &format!("[{name}]\n");
+                    }
+                    section = change.section.clone();
+                }
+                overlay += &change.line(None, &Original::Omit);
+                overlay.push('\n');
+            }
+
+            for block in file.blocks.values() {
+                if !overlay.is_empty() {
+                    overlay.push('\n');
+                }
+                overlay += &format!("[{}]\n", block.name);
+                for line in &block.new {
+                    overlay += line;
+                    overlay.push('\n');
+                }
+            }
+
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(&path, encoding::encode(overlay.as_bytes(), file.encoding))
+                .with_context(|| format!("unable to write overlay {}", path.display()))?;
+            if !args.summary_only() {
+                println!("wrote {}", path.display());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn print_unchanged(&self, package: &Path, args: &Args, log: &Log) {
+        let package = package.file_name().unwrap_or_default().to_string_lossy();
+        for (_, file) in self.reported(args) {
+            for (address, value) in &file.unchanged {
+                log.info(format_args!(
+                    "{package}: {}: patch key {address} already {value}",
+                    file.path.display()
+                ));
+            }
+        }
+    }
+
+    fn explain(&self, package: &Path, patch: &Patch, args: &Args, log: &Log) {
+        let package = package.file_name().unwrap_or_default().to_string_lossy();
+        for (_, file) in self.reported(args) {
+            for (address, reason) in &file.explained {
+                log.info(format_args!(
+                    "{package}: {}: {address}: {reason}",
+                    file.path.display()
+                ));
+            }
+        }
+
+        if args.diff_only_changed_files {
+            return;
+        }
+        for &(target, reason) in &self.skipped {
+            let mut addresses: Vec<_> = patch
+                .file(target)
+                .into_iter()
+                .flat_map(|file| file.keys())
+                .collect();
+            addresses.sort();
+            for address in addresses {
+                log.info(format_args!(
+                    "{package}: {}: {address}: {reason}",
+                    target.filename()
+                ));
+            }
+        }
+    }
+
+    /// adds the patched files to a bundle, for --zip-out
+    fn add_to_bundle(&self, package: &Path, bundle: &mut Bundle, args: &Args) -> io::Result<()> {
+        let name = package.file_name().unwrap_or_default().to_string_lossy();
+
+        for (_, file) in self.files() {
+            if file.is_empty() {
+                continue;
+            }
+
+            let relative = file.path.strip_prefix(package).unwrap_or(&file.path);
+            let path = iter::once(name.to_string())
+                .chain(
+                    relative
+                        .components()
+                        .map(|component| component.as_os_str().to_string_lossy().into_owned()),
+                )
+                .collect::<Vec<_>>()
+                .join("/");
+
+            let (contents, lines) = render(file, args)?;
+            bundle
+                .zip
+                .add(&path, &encoding::encode(&contents, file.encoding));
+            bundle.files.push(BundledFile {
+                path,
+                package: name.to_string(),
+                lines_changed: lines.len(),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// adds these changes to a plan, for --plan-out
+    fn add_to_plan(&self, package: &Path, plan: &mut Plan, args: &Args) -> io::Result<()> {
+        for (target, file) in self.reported(args) {
+            if file.is_empty() {
+                continue;
+            }
+
+            // A delta takes its second text to its first, so this one takes the file as it is
+            // now to the patched file.
+            let (patched, _) = render(file, args)?;
+            plan.files.push(PlannedFile {
+                path: file.path.clone(),
+                target: target.key().to_owned(),
+                sha256: file.sha256.clone(),
+                changes: change_records(package, target, file),
+                delta: Delta::new(&String::from_utf8_lossy(&patched), &file.text),
+            });
+        }
+        Ok(())
+    }
+
+    /// adds the patch that undoes these changes to an inverse patch file, for --inverse-out
+    fn add_inverse(&self, package: &Path, inverse: &mut InversePatch) {
+        let package = package.file_name().unwrap_or_default().to_string_lossy();
+
+        for (target, file) in self.files() {
+            if file.changes.is_empty() && file.blocks.is_empty() {
+                continue;
+            }
+
+            let lines: Vec<_> = file.text.lines().collect();
+            let values = inverse
+                .entry(package.to_string())
+                .or_default()
+                .entry(target.key())
+                .or_default();
+
+            for (&idx, change) in &file.changes {
+                // The old comment is put back too, or cleared if there wasn't one, since the
+                // patched line may have gained one.
+                let comment = match line::parse(lines[idx]) {
+                    Line::Entry {
+                        comment: Some(comment),
+                        ..
+                    } => comment.text.trim().to_owned(),
+                    _ => String::new(),
+                };
+                let address = format!(
+                    "{}.{}",
+                    change.section.as_deref().unwrap_or_default(),
+                    change.key
+                );
+                values.insert(
+                    address,
+                    serde_json::json!({ "value": change.old.trim(), "comment": comment }),
+                );
+            }
+
+            for block in file.blocks.values() {
+                values.insert(format!("[{}]", block.name), serde_json::json!(block.old));
+            }
+        }
+    }
+
+    fn write_changes(
+        &self,
+        package: &Path,
+        args: &Args,
+        log: &Log,
+    ) -> anyhow::Result<Vec<Written>> {
+        // Every backup is checked before any file is written, so that refusing one leaves the
+        // whole package as it was.
+
+        for (target, file) in self.files() {
+            if !file.is_empty() && args.should_backup(target) {
+                check_backup(&file.path, &file.text, args, log)?;
+            }
+        }
+
+        let mut written = Vec::new();
+
+        for (target, file) in self.files() {
+            if file.is_empty() {
+                continue;
+            }
+
+            let backup = args.should_backup(target);
+            written.push(write_modified_file(file, backup, args)?);
+            if backup && args.verify_backup {
+                verify_backup(file, args)?;
+            }
+            if args.track_changes {
+                write_state(file)?;
+            }
+            if let Some(dir) = &args.sidecar_changes {
+                sidecar::write(file, &sidecar::path(package, &file.path, dir))?;
+            }
+        }
+
+        Ok(written)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.files().iter().all(|(_, file)| file.is_empty())
+    }
+
+    fn file_mut(&mut self, target: Target) -> &mut PathChanges {
+        self.files.entry(target).or_default()
+    }
+
+    fn files(&self) -> Vec<(Target, &PathChanges)> {
+        self.files
+            .iter()
+            .map(|(&target, file)| (target, file))
+            .collect()
+    }
+
+    fn show_changes(&self, args: &Args, out: &mut impl Write) -> anyhow::Result<()> {
+        let paint = Paint::new(args.color, &io::stdout());
+        let track = args.track_changes;
+        let mut first = true;
+
+        for (target, file) in self.files() {
+            // When tracking changes, we only show what's different from the last run.
+
+            let last_run = if track {
+                read_state(&file.path)?
+            } else {
+                BTreeMap::new()
+            };
+
+            let changes: Vec<_> = file
+                .changes
+                .iter()
+                .filter(|(_, change)| last_run.get(&change.address()) != Some(&change.new))
+                .collect();
+            let lines: Vec<_> = file.text.lines().collect();
+
+            if changes.is_empty() && file.added.is_empty() && file.blocks.is_empty() {
+                continue;
+            }
+
+            if !first {
+                writeln!(out)?;
+            }
+            first = false;
+
+            writeln!(
+                out,
+                "{}",
+                paint.bold(&format!("{} changes:", target.label()))
+            )?;
+            for (&idx, change) in changes {
+                writeln!(out, "{}:", change.address())?;
+                let before = idx.saturating_sub(args.context)..idx;
+                let after = idx + 1..(idx + 1 + args.context).min(lines.len());
+
+                // With context, we show whole lines, like a diff, rather than just the values.
+
+                if args.context > 0 {
+                    let comment = match line::parse(lines[idx]) {
+                        Line::Entry { comment, .. } => comment,
+                        _ => None,
+                    };
+                    let new = change.line(comment, &args.original());
+
+                    for line in before {
+                        writeln!(out, "\t{:>4}  {}", line + 1, lines[line])?;
+                    }
+                    writeln!(out, "\t{:>4}- {}", idx + 1, paint.red(lines[idx]))?;
+                    writeln!(out, "\t{:>4}+ {}", idx + 1, paint.green(&new))?;
+                    for line in after {
+                        writeln!(out, "\t{:>4}  {}", line + 1, lines[line])?;
+                    }
+                } else if change.value_changed() {
+                    writeln!(
+                        out,
+                        "\t{}\n\t{}",
+                        paint.red(&change.old),
+                        paint.green(&change.new)
+                    )?;
+                }
+                if let Some(comment) = &change.comment {
+                    writeln!(out, "\tcomment: {comment}")?;
+                }
+                if let Some(last) = last_run.get(&change.address()) {
+                    writeln!(out, "\t(was {last} last run)")?;
+                }
+            }
+            for change in file.added.values().flatten() {
+                writeln!(
+                    out,
+                    "{} (added):\n\t{}",
+                    change.address(),
+                    paint.green(&change.new)
+                )?;
+                if let Some(comment) = &change.comment {
+                    writeln!(out, "\tcomment: {comment}")?;
+                }
+            }
+            for block in file.blocks.values() {
+                writeln!(out, "[{}] replaced:", block.name)?;
+                for line in &block.old {
+                    writeln!(out, "\t{}", paint.red(&format!("- {line}")))?;
+                }
+                for line in &block.new {
+                    writeln!(out, "\t{}", paint.green(&format!("+ {line}")))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// shows the differences between each file as patched and its stock copy under `dir`
+    fn show_divergence(
+        &self,
+        package: &Path,
+        dir: &Path,
+        args: &Args,
+        log: &Log,
+        out: &mut impl Write,
+    ) -> anyhow::Result<()> {
+        let paint = Paint::new(args.color, &io::stdout());
+        let name = package.file_name().unwrap_or_default();
+
+        for (target, file) in self.files() {
+            let relative = file.path.strip_prefix(package).unwrap_or(&file.path);
+            let baseline = dir.join(name).join(relative);
+            if !baseline.is_file() {
+                log.info(format_args!(
+                    "{}: no baseline for {} at {}",
+                    name.to_string_lossy(),
+                    file.path.display(),
+                    baseline.display()
+                ));
+                continue;
+            }
+
+            let stock = encoding::read_to_string(&baseline)
+                .with_context(|| format!("unable to read {}", baseline.display()))?;
+            let (patched, _) = render(file, args)?;
+            let patched = String::from_utf8_lossy(&patched);
+
+            writeln!(
+                out,
+                "{}",
+                paint.bold(&format!(
+                    "{} relative to {}:",
+                    target.label(),
+                    baseline.display()
+                ))
+            )?;
+            compare::print_differences(
+                (&baseline.display().to_string(), &read_entries(&stock)),
+                ("patched", &read_entries(&patched)),
+                args.epsilon,
+                paint,
+                out,
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// checks that a file's backup holds exactly what the file held before we patched it, putting
+/// the original back if it doesn't
+fn verify_backup(patch: &PathChanges, args: &Args) -> anyhow::Result<()> {
+    let backup = if args.differential_backup {
+        args.delta_path(&patch.path)
+    } else {
+        args.backup_path(&patch.path)
+    };
+    let original = if args.differential_backup {
+        read_delta(&backup).and_then(|delta| {
+            let patched = encoding::read_to_string(&patch.path)?;
+            delta.apply(&patched).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "it does not fit the patched file",
+                )
+            })
+        })
+    } else {
+        encoding::read_to_string(&backup)
+    };
+    let problem = match original {
+        Ok(text) if text == patch.text => return Ok(()),
+        Ok(_) => String::from("it does not match the original"),
+        Err(e) => e.to_string(),
+    };
+
+    fs::write(
+        &patch.path,
+        encoding::encode(patch.text.as_bytes(), patch.encoding),
+    )?;
+    anyhow::bail!(
+        "backup {} failed verification: {problem} (restored {})",
+        backup.display(),
+        patch.path.display()
+    )
+}
+
+/// makes sure a file's backup won't overwrite anything but an earlier backup of it, or warns
+/// that it will, where --safe-backups isn't given
+fn check_backup(path: &Path, text: &str, args: &Args, log: &Log) -> anyhow::Result<()> {
+    let backup = if args.differential_backup {
+        args.delta_path(path)
+    } else {
+        args.backup_path(path)
+    };
+    if !backup.exists() || is_backup_of(&backup, text, args) {
+        return Ok(());
+    }
+
+    if args.safe_backups {
+        anyhow::bail!(
+            "{} is in the way of the backup of {}, and isn't one (--safe-backups)",
+            backup.display(),
+            path.display()
+        );
+    }
+    log.warn(format_args!(
+        "{} doesn't look like a backup of {}; overwriting it",
+        backup.display(),
+        path.display()
+    ));
+    Ok(())
+}
+
+/// whether a file where a backup is to go is an earlier backup of a file with this text
+///
+/// Patches change keys, add them and replace section bodies, but never add or remove sections, so
+/// a full backup has just the sections of the file it was taken from.
+fn is_backup_of(backup: &Path, text: &str, args: &Args) -> bool {
+    if args.differential_backup {
+        return read_delta(backup).is_ok();
+    }
+
+    let sections = |text: &str| -> Vec<String> {
+        text.lines()
+            .filter_map(|line| match line::parse(line) {
+                Line::Section { name, .. } => Some(name.to_owned()),
+                _ => None,
+            })
+            .collect()
+    };
+    encoding::read_to_string(backup).is_ok_and(|backup| sections(&backup) == sections(text))
+}
+
+fn read_delta(path: &Path) -> io::Result<Delta> {
+    let text = fs::read_to_string(path)?;
+    Ok(serde_json::from_str(&text)?)
+}
+
+/// where we keep the values applied to a file by the last run, for --track-changes
+fn state_path(path: &Path) -> PathBuf {
+    path.with_extension("patchcfg.json")
+}
+
+fn read_state(path: &Path) -> anyhow::Result<BTreeMap<String, String>> {
+    match fs::read_to_string(state_path(path)) {
+        Ok(text) => Ok(serde_json::from_str(&text)?),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write_state(patch: &PathChanges) -> anyhow::Result<()> {
+    let mut state = read_state(&patch.path)?;
+    for change in patch.changes.values().chain(patch.added.values().flatten()) {
+        state.insert(change.address(), change.new.clone());
+    }
+    fs::write(
+        state_path(&patch.path),
+        serde_json::to_string_pretty(&state)?,
+    )?;
+    Ok(())
+}
+
+/// hashes a file's patch, so we can tell whether it has already been applied
+///
+/// This is 64-bit FNV-1a over the patch's keys in order, which, unlike the standard library's
+/// hasher, is guaranteed to give the same result from one build to the next.
+fn patch_hash(patch: &FilePatch) -> u64 {
+    let mut entries: Vec<_> = patch.iter().collect();
+    entries.sort_by_key(|&(key, _)| key);
+
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for (key, value) in entries {
+        let when = value.when.as_ref().map(ToString::to_string);
+        let fields = [
+            key.as_str(),
+            value.value.as_deref().unwrap_or_default(),
+            value.comment.as_deref().unwrap_or_default(),
+        ]
+        .into_iter()
+        .chain(value.block.iter().flatten().map(String::as_str))
+        .chain(when.as_deref());
+
+        // The separator keeps {"ab": "c"} and {"a": "bc"} from hashing alike.
+        for byte in fields.flat_map(|field| field.bytes().chain([0])) {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    hash
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum ReportFormat {
+    Json,
+    Csv,
+    Tsv,
+}
+
+fn main() {
+    let args = parse_args();
+    let log = match Log::new(&args) {
+        Ok(log) => log,
+        Err(e) => {
+            eprintln!("unable to open log file: {e}");
+            process::exit(1);
+        }
+    };
+
+    match load_targets(args.targets.as_deref()) {
+        // Nothing has looked at the targets yet, so they can't have been set already.
+        Ok(targets) => TARGETS.set(targets).expect("targets are only set once"),
+        Err(e) => {
+            log.error(format_args!("{e:#}"));
+            process::exit(1);
+        }
+    }
+
+    let result = match &args.command {
+        // A run has already failed on any warnings, before its post-hook.
+        Some(command) => run_command(command, &args, &log)
+            .and_then(|()| check_warnings(&args, &log))
+            .map(|()| false),
+        None => run(&args, &log).map(|summary| summary.files_changed > 0),
+    };
+
+    if let Err(e) = &result {
+        log.report(e);
+    }
+    match exit_code(&result, &args) {
+        0 => {}
+        code => process::exit(code),
+    }
+}
+
+/// the status to exit with, given whether the run changed anything, or failed
+///
+/// With --summary-only-on-change, a change is told from nothing to do by the status alone.
+fn exit_code(result: &anyhow::Result<bool>, args: &Args) -> i32 {
+    match result {
+        Err(_) => 1,
+        Ok(true) if args.summary_only_on_change => 2,
+        Ok(_) => 0,
+    }
+}
+
+/// runs one of the subcommands
+fn run_command(command: &Command, args: &Args, log: &Log) -> anyhow::Result<()> {
+    match command {
+        Command::Completions { shell } => {
+            completions::generate(*shell, &Args::command(), &mut io::stdout()).map_err(Into::into)
+        }
+        Command::Normalize { file } => normalize(file),
+        Command::DumpParsed { file } => dump_parsed(file, &mut io::stdout()),
+        Command::ListKeys { file, values, json } => {
+            list_keys(file, *values, *json, &mut io::stdout())
+        }
+        Command::Restore { packages } => restore::restore(packages, args, log),
+        Command::ApplyPlan { plan } => plan::apply(plan, args, log),
+        Command::PruneBackups {
+            packages,
+            keep,
+            older_than,
+            dry_run,
+        } => backups::prune(packages, *keep, *older_than, *dry_run, args, log),
+        Command::Audit {
+            packages,
+            keys,
+            json,
+        } => audit::audit(packages, keys, *json, args, &mut io::stdout()),
+        Command::Template { package } => {
+            template::print(package.as_deref(), args, &mut io::stdout())
+        }
+        Command::Diff { a, b } => {
+            let paint = Paint::new(args.color, &io::stdout());
+            compare::files(a, b, args.epsilon, paint, &mut io::stdout())
+        }
+        Command::CompareRuns { a, b } => compare::runs(
+            a,
+            b,
+            Paint::new(args.color, &io::stdout()),
+            &mut io::stdout(),
+        ),
+    }
+}
+
+/// parses the command line, along with the project file, if there is one
+fn parse_args() -> Args {
+    args_from(env::args_os().collect())
+}
+
+fn args_from(argv: Vec<OsString>) -> Args {
+    let matches = Args::command().get_matches_from(&argv);
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let Some(path) = &args.project else {
+        return args;
+    };
+    if args.command.is_some() {
+        Args::command()
+            .error(
+                clap::ErrorKind::ArgumentConflict,
+                "--project can't be used with a subcommand",
+            )
+            .exit();
+    }
+
+    match project_args(path, &matches) {
+        Ok((positionals, options)) => {
+            let mut argv = argv.into_iter();
+            let command_line = argv.next().into_iter().chain(positionals).chain(argv);
+            Args::try_parse_from(command_line.chain(options)).unwrap_or_else(|e| e.exit())
+        }
+        Err(e) => {
+            eprintln!("error: {e:#}");
+            process::exit(1);
+        }
+    }
+}
+
+/// the arguments a project file adds to the command line, leaving out any given there already:
+/// the packages directory and patch file, and the options
+fn project_args(
+    path: &Path,
+    matches: &clap::ArgMatches,
+) -> anyhow::Result<(Vec<OsString>, Vec<OsString>)> {
+    let text =
+        fs::read_to_string(path).with_context(|| format!("unable to read {}", path.display()))?;
+    let entries = toml::parse(&text)
+        .map_err(anyhow::Error::msg)
+        .with_context(|| format!("unable to read project {}", path.display()))?;
+
+    let command = Args::command();
+    let given = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+    let mut options = Vec::new();
+    let mut positionals = Vec::new();
+
+    for (key, value) in entries {
+        let name = key.replace('_', "-");
+
+        // Positional arguments given on the command line take the place of both of the project's,
+        // because a lone one may be either.
+        if name == "packages" || name == "patches" {
+            let toml::Value::Text(value) = value else {
+                anyhow::bail!("{key} in {} should be a string", path.display());
+            };
+            if !given("packages") {
+                let at = if name == "packages" {
+                    0
+                } else {
+                    positionals.len()
+                };
+                positionals.insert(at, OsString::from(value));
+            }
+            continue;
+        }
+
+        let arg = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(&name) && name != "project")
+            .with_context(|| format!("unknown option {key} in {}", path.display()))?;
+        if given(arg.get_id()) {
+            continue;
+        }
+
+        let values = match (value, arg.is_takes_value_set()) {
+            (toml::Value::Bool(true), false) => {
+                options.push(OsString::from(format!("--{name}")));
+                continue;
+            }
+            (toml::Value::Bool(false), false) => continue,
+            (toml::Value::Text(value), true) => vec![value],
+            (toml::Value::Array(values), true) => values,
+            (_, false) => anyhow::bail!("{key} in {} should be true or false", path.display()),
+            (toml::Value::Bool(_), true) => {
+                anyhow::bail!("{key} in {} takes a value", path.display())
+            }
+        };
+        for value in values {
+            options.push(OsString::from(format!("--{name}={value}")));
+        }
+    }
+
+    Ok((positionals, options))
+}
+
+fn run(args: &Args, log: &Log) -> anyhow::Result<Summary> {
+    let run = Ulid::new();
+    log.info(format_args!("run {run}"));
+
+    // Failing on warnings fails the run, which the post-hook has to know.
+
+    let result =
+        patch(args, log, run).and_then(|summary| check_warnings(args, log).map(|()| summary));
+    let Some(hook) = &args.post_hook else {
+        return result;
+    };
+
+    match result {
+        Ok(summary) => run_hook(hook, run, Some(&summary)).map(|()| summary),
+        Err(e) => {
+            // The run's own error matters more than the hook's, so that's the one we return.
+            if args.post_hook_always {
+                if let Err(hook_error) = run_hook(hook, run, None) {
+                    log.error(format_args!("{hook_error:#}"));
+                }
+            }
+            Err(e)
+        }
+    }
+}
+
+/// fails if there have been any warnings, for --fail-on-warning
+fn check_warnings(args: &Args, log: &Log) -> anyhow::Result<()> {
+    let warnings = log.warnings();
+    if args.fail_on_warning && warnings > 0 {
+        anyhow::bail!("failing on {warnings} warning(s)");
+    }
+    Ok(())
+}
+
+/// asks a yes or no question on the terminal, taking anything but yes for no
+fn confirm(question: &str) -> io::Result<bool> {
+    eprint!("{question} [y/N] ");
+    io::stderr().flush()?;
+
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// stops the run if a disk doesn't have room for the backups of the files on it
+///
+/// Backing a file up moves it aside, but the patched copy needs as much space again, so we count
+/// the whole size of every file we might patch.
+fn check_backup_space(packages: &[(PathBuf, &Patch)], args: &Args) -> anyhow::Result<()> {
+    disk::check(backup_space(packages, args)?, disk::available)
+}
+
+/// the bytes the backups of each packages directory will take
+fn backup_space(
+    packages: &[(PathBuf, &Patch)],
+    args: &Args,
+) -> anyhow::Result<HashMap<PathBuf, u64>> {
+    let mut needed: HashMap<PathBuf, u64> = HashMap::new();
+    for (package, patch) in packages {
+        for (target, file) in patch.files() {
+            if file.is_empty() || !args.should_backup(target) {
+                continue;
+            }
+            if let Some(path) = find_target(package, target, args) {
+                let dir = match package.parent() {
+                    Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+                    _ => PathBuf::from("."),
+                };
+                *needed.entry(dir).or_default() += fs::metadata(path)?.len();
+            }
+        }
+    }
+    Ok(needed)
+}
+
+/// runs the post-patch hook, with the results of the run in its environment
+///
+/// Without a summary, the run failed, and the hook only gets the run ID and PATCHCFG_SUCCESS=0.
+fn run_hook(hook: &str, run: Ulid, summary: Option<&Summary>) -> anyhow::Result<()> {
+    let mut command = if cfg!(windows) {
+        let mut command = process::Command::new("cmd");
+        command.arg("/C");
+        command
+    } else {
+        let mut command = process::Command::new("sh");
+        command.arg("-c");
+        command
+    };
+    command
+        .arg(hook)
+        .env("PATCHCFG_RUN_ID", run.to_string())
+        .env(
+            "PATCHCFG_SUCCESS",
+            if summary.is_some() { "1" } else { "0" },
+        );
+
+    if let Some(summary) = summary {
+        command
+            .env(
+                "PATCHCFG_PACKAGES_MATCHED",
+                summary.packages_matched.to_string(),
+            )
+            .env("PATCHCFG_FILES_CHANGED", summary.files_changed.to_string())
+            .env("PATCHCFG_KEYS_CHANGED", summary.keys_changed.to_string())
+            .env("PATCHCFG_DRY_RUN", if summary.dry_run { "1" } else { "0" });
+    }
+
+    let status = command
+        .status()
+        .with_context(|| format!("unable to run post-hook {hook:?}"))?;
+    if !status.success() {
+        anyhow::bail!("post-hook {hook:?} failed ({status})");
+    }
+    Ok(())
+}
+
+fn patch(args: &Args, log: &Log, run: Ulid) -> anyhow::Result<Summary> {
+    let start = Instant::now();
+    let mut stats = Stats::default();
+
+    // Clap requires both of these unless we've been given a subcommand, a root marker, or a list
+    // of packages. Otherwise, a lone positional argument is the patch file. A project file may
+    // leave out either, though, which clap can't know.
+
+    let no_packages = || {
+        let project = args
+            .project
+            .as_deref()
+            .unwrap_or(Path::new("a project file"));
+        anyhow::anyhow!(
+            "no packages directory given on the command line or in {}",
+            project.display()
+        )
+    };
+    let (packages, patches) = match (&args.packages, &args.patches, &args.packages_from) {
+        (Some(_), Some(_), Some(_)) => {
+            anyhow::bail!("--packages-from takes the place of the packages directory")
+        }
+        (Some(packages), Some(patches), None) => (PathBuf::from(packages), Some(patches)),
+        (Some(patches), None, Some(_)) => (PathBuf::new(), Some(patches)),
+        (Some(patches), None, None) => match &args.root_marker {
+            Some(marker) => (find_root(marker, &env::current_dir()?)?, Some(patches)),
+            None if DEFAULTS.is_some()
+                || !args.set.is_empty()
+                || args.csv.is_some()
+                || args.patch_from_git.is_some() =>
+            {
+                (PathBuf::from(patches), None)
+            }
+            None => return Err(no_packages()),
+        },
+        _ => return Err(no_packages()),
+    };
+
+    // Only a run that writes can trip over another, so previews don't take the lock.
+
+    let lock_path = match &args.lock_file {
+        Some(path) => Some(path.clone()),
+        None if args.packages_from.is_none() => Some(packages.join(".patchcfg.lock")),
+        None => None,
+    };
+    let _lock = match &lock_path {
+        Some(path) if args.force && !args.no_lock => Some(Lock::acquire(path.clone(), run, log)?),
+        _ => None,
+    };
+
+    let assertions = args.assert.as_deref().map(assertions::read).transpose()?;
+
+    for name in &args.backup {
+        if Target::find(name).is_none() {
+            anyhow::bail!("unknown target {name:?} for --backup");
+        }
+    }
+    tables::load(&args.table)?;
+    let patches = resolve_patches(patches, args, log)?;
+    if patches.is_empty() {
+        log.info("patch file contains no packages");
+    }
+    if let Some(name) = &args.print_resolved_patch {
+        let patch = patches
+            .get(&*args.patch_name(name))
+            .with_context(|| format!("no patch for {name}"))?;
+        println!("{}", serde_json::to_string_pretty(&patch.to_json())?);
+        return Ok(Summary::default());
+    }
+    let root = packages.clone();
+    let mut packages: Vec<_> = match &args.package {
+        Some(name) => {
+            let path = packages.join(name);
+            if !path.is_dir() {
+                anyhow::bail!("no package named {name} in {}", packages.display());
+            }
+            let key = args.patch_name(name);
+            if !patches.is_empty() && !patches.contains_key(&*key) {
+                log.info(format_args!("no patch for {name}"));
+            }
+            patches
+                .get(&*key)
+                .map(|patch| (path, patch))
+                .into_iter()
+                .collect()
+        }
+        None => timed(&mut stats.scanning, || -> anyhow::Result<_> {
+            let candidates = match &args.packages_from {
+                Some(list) => read_package_list(list, io::stdin(), log)?,
+                None => read_packages(&packages, args.packages_depth)?,
+            };
+            let scanned = candidates.len();
+            let names: Vec<String> = candidates
+                .iter()
+                .filter_map(|path| Some(args.patch_name(path.file_name()?.to_str()?).into_owned()))
+                .collect();
+            let matched: Vec<_> = match_packages(candidates, &patches, args).collect();
+            if !args.match_title && args.package_name_from.is_none() {
+                warn_near_misses(&patches, &names, log);
+            }
+            if matched.is_empty() && !patches.is_empty() {
+                log.info(format_args!(
+                    "no packages matched any patch (scanned {scanned} directories)"
+                ));
+            }
+            Ok(matched)
+        })?,
+    };
+    if args.ci {
+        packages.sort_by(|(a, _), (b, _)| a.cmp(b));
+    }
+    let mut summary = Summary {
+        run_id: run.to_string(),
+        dry_run: !args.force,
+        changes: args.records_changes().then(Vec::new),
+        ..Default::default()
+    };
+
+    if args.require_backup_space {
+        check_backup_space(&packages, args)?;
+    }
+
+    let mut inverse = BTreeMap::new();
+    let mut plan = Plan {
+        run_id: run.to_string(),
+        lock: lock_path,
+        files: Vec::new(),
+    };
+    let mut bundle = args.zip_out.as_ref().map(|_| Bundle::default());
+
+    let mut failed = 0;
+    let diffs = diff_in_parallel(&packages, args, &mut stats);
+    if let Some(max) = args.max_total_changes {
+        let total: usize = diffs.iter().flatten().map(|diff| diff.count(args)).sum();
+        if total > max {
+            anyhow::bail!(
+                "the run would make {total} changes, more than --max-total-changes {max}; nothing was written"
+            );
+        }
+    }
+    let checked: Vec<_> = packages.iter().map(|(path, _)| path.clone()).collect();
+    let mut diffs = diffs.into_iter();
+    for (package, patch) in packages {
+        let in_package = |e: anyhow::Error| e.context(InPackage(package.clone()));
+        let diff = match diffs.next() {
+            Some(diff) => diff,
+            None => patch.diff(&package, args, &mut stats),
+        };
+        let mut diff = match diff.map_err(in_package) {
+            Ok(diff) => diff,
+            Err(e) if args.ci && !args.fail_fast => {
+                log.report(&e);
+                failed += 1;
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        if let Some(max) = args.max_changes {
+            diff.limit_changes(max, &package, log);
+        }
+        if args.all_or_nothing {
+            let missing: Vec<_> = diff.missing().map(Target::filename).collect();
+            if !missing.is_empty() {
+                log.warn(format_args!(
+                    "{}: no {} found; skipping package",
+                    package.display(),
+                    missing.join(" or ")
+                ));
+                continue;
+            }
+        }
+        diff.warn_missing(&package, args, log);
+        if args.explain {
+            diff.explain(&package, patch, args, log);
+        }
+        if args.print_unchanged {
+            diff.print_unchanged(&package, args, log);
+        }
+        summary.add(&diff);
+        if args.inverse_out.is_some() {
+            diff.add_inverse(&package, &mut inverse);
+        }
+        if args.plan_out.is_some() {
+            diff.add_to_plan(&package, &mut plan, args)
+                .map_err(|e| in_package(e.into()))?;
+        }
+        if let Some(bundle) = &mut bundle {
+            diff.add_to_bundle(&package, bundle, args)
+                .map_err(|e| in_package(e.into()))?;
+        }
+
+        let mut written = Vec::new();
+        if let Some(dir) = &args.overlay_out {
+            timed(&mut stats.writing, || {
+                diff.write_overlay(&package, dir, args)
+            })
+            .map_err(in_package)?;
+        } else if args.force {
+            let confirmed = !args.confirm || args.assume_yes || diff.is_empty() || {
+                diff.show_changes(args, &mut io::stdout())?;
+                confirm(&format!("write these changes to {}?", package.display()))?
+            };
+            if confirmed {
+                written = timed(&mut stats.writing, || {
+                    diff.write_changes(&package, args, log)
+                })
+                .map_err(in_package)?;
+            }
+
+            for file in &written {
+                if !args.summary_only() {
+                    file.lines.iter().for_each(|line| println!("{line}"));
+                }
+                if let Some(backup) = &file.backup {
+                    log.record(format_args!(
+                        "run {run}: backed up {} to {}",
+                        file.path.display(),
+                        backup.display()
+                    ));
+                }
+            }
+        } else if let (Some(dir), false) = (&args.baseline, args.summary_only()) {
+            diff.show_divergence(&package, dir, args, log, &mut io::stdout())
+                .map_err(in_package)?;
+        } else if !args.summary_only() {
+            diff.show_changes(args, &mut io::stdout())?;
+        }
+
+        if args.hash {
+            summary.add_hashes(&diff, &written, args);
+        }
+        if args.records_changes() {
+            summary.add_changes(&package, &diff, args);
+        }
+    }
+
+    if let (Some(path), Some(bundle)) = (&args.zip_out, bundle) {
+        bundle
+            .write(path, &summary.run_id)
+            .with_context(|| format!("unable to write {}", path.display()))?;
+    }
+
+    if let Some(path) = &args.inverse_out {
+        fs::write(path, serde_json::to_string_pretty(&inverse)? + "\n")
+            .with_context(|| format!("unable to write inverse patch {}", path.display()))?;
+    }
+    if let Some(path) = &args.plan_out {
+        fs::write(path, serde_json::to_string_pretty(&plan)? + "\n")
+            .with_context(|| format!("unable to write plan {}", path.display()))?;
+    }
+
+    if args.summary_only() && (!args.summary_only_on_change || summary.files_changed > 0) {
+        match args.report_format {
+            ReportFormat::Json => println!("{}", serde_json::to_string(&summary)?),
+            ReportFormat::Csv => print!("{}", summary.table(csv::row)),
+            ReportFormat::Tsv => print!("{}", summary.table(csv::tsv_row)),
+        }
+    }
+
+    if args.stats {
+        stats.print(start.elapsed(), &mut io::stderr())?;
+    }
+
+    if let Some(assertions) = &assertions {
+        assertions::check(assertions, &checked, &root, args, log)?;
+    }
+    if failed > 0 {
+        anyhow::bail!("unable to check {failed} package(s)");
+    }
+    if args.ci && !args.force && summary.files_changed > 0 {
+        anyhow::bail!("{} file(s) need patching", summary.files_changed);
+    }
+
+    Ok(summary)
+}
+
+/// diffs packages against their patches up front, on several threads with --jobs
+///
+/// The diffs come back in the order of the packages. With a single job, there are none unless
+/// --max-total-changes needs them all before anything is written; otherwise each package is
+/// diffed as the run comes to it.
+fn diff_in_parallel(
+    packages: &[(PathBuf, &Patch)],
+    args: &Args,
+    stats: &mut Stats,
+) -> Vec<anyhow::Result<Diff>> {
+    if args.jobs <= 1 && args.max_total_changes.is_none() {
+        return Vec::new();
+    }
+
+    let next = AtomicUsize::new(0);
+    let workers = thread::scope(|scope| {
+        let workers: Vec<_> = (0..args.jobs.min(packages.len()))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut stats = Stats::default();
+                    let mut diffs = Vec::new();
+                    loop {
+                        let idx = next.fetch_add(1, Ordering::Relaxed);
+                        let Some((package, patch)) = packages.get(idx) else {
+                            break;
+                        };
+                        diffs.push((idx, patch.diff(package, args, &mut stats)));
+                    }
+                    (diffs, stats)
+                })
+            })
+            .collect();
+
+        workers
+            .into_iter()
+            .map(|worker| worker.join().expect("diffing doesn't panic"))
+            .collect::<Vec<_>>()
+    });
+
+    let mut diffs = Vec::with_capacity(packages.len());
+    for (worker_diffs, worker_stats) in workers {
+        diffs.extend(worker_diffs);
+        stats.add(&worker_stats);
+    }
+    diffs.sort_by_key(|&(idx, _)| idx);
+    diffs.into_iter().map(|(_, diff)| diff).collect()
+}
+
+/// time spent in each phase of a run, for --stats
+#[derive(Debug, Default)]
+struct Stats {
+    scanning: Duration,
+    reading: Duration,
+    diffing: Duration,
+    writing: Duration,
+}
+
+impl Stats {
+    fn add(&mut self, other: &Stats) {
+        self.scanning += other.scanning;
+        self.reading += other.reading;
+        self.diffing += other.diffing;
+        self.writing += other.writing;
+    }
+
+    /// reports the time spent; with --jobs, that's the total over every thread
+    fn print(&self, total: Duration, out: &mut impl Write) -> io::Result<()> {
+        writeln!(out, "stats:")?;
+        writeln!(out, "    scanning  {:?}", self.scanning)?;
+        writeln!(out, "    reading   {:?}", self.reading)?;
+        writeln!(out, "    diffing   {:?}", self.diffing)?;
+        writeln!(out, "    writing   {:?}", self.writing)?;
+        writeln!(out, "    total     {total:?}")
+    }
+}
+
+fn timed<T>(total: &mut Duration, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let result = f();
+    *total += start.elapsed();
+    result
+}
+
+fn normalize(path: &Path) -> anyhow::Result<()> {
+    let (text, encoding) = encoding::read(path)?;
+    let normalized = normalized(&text);
+
+    // Leave files that are already tidy alone, so their timestamps don't change for nothing.
+
+    if normalized != text {
+        fs::write(path, encoding::encode(normalized.as_bytes(), encoding))?;
+        println!("normalized {}", path.display());
+    }
+
+    Ok(())
+}
+
+/// puts every line of a file in canonical form, keeping its line endings, and its last line
+/// unterminated if it was
+fn normalized(text: &str) -> String {
+    let newline = if text.contains("\r\n") { "\r\n" } else { "\n" };
+
+    let mut normalized = String::with_capacity(text.len());
+    for (idx, line) in text.lines().enumerate() {
+        if idx > 0 {
+            normalized += newline;
+        }
+        normalized += &line::parse(line).canonical();
+    }
+    if text.ends_with('\n') {
+        normalized += newline;
+    }
+    normalized
+}
+
+fn dump_parsed(path: &Path, out: &mut impl Write) -> anyhow::Result<()> {
+    let text = encoding::read_to_string(path)
+        .with_context(|| format!("unable to read {}", path.display()))?;
+    let comment = |comment: Option<Comment>| {
+        comment.map(
+            |comment| serde_json::json!({ "delimiter": comment.delimiter, "text": comment.text }),
+        )
+    };
+
+    let mut section = None;
+    let mut lines = Vec::new();
+    for (idx, line) in text.lines().enumerate() {
+        let mut parsed = match line::parse(line) {
+            Line::Blank => serde_json::json!({ "kind": "blank" }),
+            Line::Comment(text) => serde_json::json!({ "kind": "comment", "text": text }),
+            Line::Section { name, comment: c } => {
+                section = Some(name);
+                serde_json::json!({ "kind": "section", "name": name, "comment": comment(c) })
+            }
+            Line::Entry {
+                key,
+                value,
+                comment: c,
+            } => serde_json::json!({
+                "kind": "entry",
+                "address": format!("{}.{key}", section.unwrap_or_default()),
+                "key": key,
+                "value": value,
+                "comment": comment(c),
+            }),
+            Line::Other(text) => serde_json::json!({ "kind": "other", "text": text }),
+        };
+        parsed["line"] = (idx + 1).into();
+        lines.push(parsed);
+    }
+
+    writeln!(out, "{}", serde_json::to_string_pretty(&lines)?)?;
+    Ok(())
+}
+
+fn list_keys(path: &Path, values: bool, json: bool, out: &mut impl Write) -> anyhow::Result<()> {
+    let text = encoding::read_to_string(path)
+        .with_context(|| format!("unable to read {}", path.display()))?;
+
+    let mut section = None;
+    let mut keys = BTreeMap::new();
+    for line in text.lines() {
+        match line::parse(line) {
+            Line::Section { name, .. } => section = Some(name),
+            Line::Entry { key, value, .. } => {
+                keys.entry(format!("{}.{key}", section.unwrap_or_default()))
+                    .or_insert(value.trim());
+            }
+            _ => {}
+        }
+    }
+
+    match (json, values) {
+        (true, true) => writeln!(out, "{}", serde_json::to_string_pretty(&keys)?)?,
+        (true, false) => writeln!(
+            out,
+            "{}",
+            serde_json::to_string_pretty(&keys.keys().collect::<Vec<_>>())?
+        )?,
+        (false, true) => {
+            for (key, value) in &keys {
+                writeln!(out, "{key} = {value}")?;
+            }
+        }
+        (false, false) => {
+            for key in keys.keys() {
+                writeln!(out, "{key}")?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// reads the entries of a config file in order, by address (e.g. `ENGINE.0.max_rpm`)
+fn read_entries(text: &str) -> Vec<(String, String)> {
+    let mut entries = Vec::new();
+    let mut section = None;
+
+    for line in text.lines() {
+        match line::parse(line) {
+            Line::Section { name, .. } => section = Some(name),
+            Line::Entry { key, value, .. } => {
+                let address = match section {
+                    Some(section) => format!("{section}.{key}"),
+                    None => key.to_owned(),
+                };
+                entries.push((address, value.trim().to_owned()));
+            }
+            _ => {}
+        }
+    }
+
+    entries
+}
+
+/// finds the packages directory by walking up from `current` (the current directory), the way
+/// git finds its repository root
+fn find_root(marker: &str, current: &Path) -> anyhow::Result<PathBuf> {
+    for dir in current.ancestors() {
+        if dir.file_name() == Some(OsStr::new(marker)) {
+            return Ok(dir.into());
+        }
+
+        let candidate = dir.join(marker);
+        if candidate.is_dir() {
+            return Ok(candidate);
+        }
+        if candidate.is_file() {
+            return Ok(dir.into());
+        }
+    }
+
+    anyhow::bail!(
+        "no {marker} found in {} or any of its parents",
+        current.display()
+    )
+}
+
+/// patches built in with the `defaults` feature, from defaults.json
+///
+/// These are applied first, so patch files given on the command line take precedence.
+#[cfg(feature = "defaults")]
+const DEFAULTS: Option<&str> = Some(include_str!("../defaults.json"));
+#[cfg(not(feature = "defaults"))]
+const DEFAULTS: Option<&str> = None;
+
+/// reads the patches for a run from each of their sources, with the command line's overrides
+fn resolve_patches(
+    patches: Option<&String>,
+    args: &Args,
+    log: &Log,
+) -> anyhow::Result<HashMap<String, Patch>> {
+    let mut sources = Vec::new();
+    if let Some(text) = DEFAULTS.filter(|_| !args.no_defaults) {
+        sources.push((Path::new("(defaults)"), text.to_owned()));
+    }
+    match patches {
+        Some(url) if fetch::is_url(url) => {
+            sources.push((Path::new(url), fetch_patches(url, args, log)?))
+        }
+        Some(path) => {
+            let path = Path::new(path);
+            sources.push((path, fs::read_to_string(path)?));
+        }
+        None => {}
+    }
+    if let Some(spec) = &args.patch_from_git {
+        sources.push((Path::new(spec), git_show(spec, Path::new("."))?));
+    }
+    if let Some(path) = &args.csv {
+        let text = fs::read_to_string(path)?;
+        let patches = spreadsheet::read(&text)
+            .with_context(|| format!("unable to read {}", path.display()))?;
+        sources.push((path, patches.to_string()));
+    }
+    for path in &args.merge {
+        sources.push((path, fs::read_to_string(path)?));
+    }
+    let mut patches = merge_patches(&sources, args, log)?;
+    for setting in &args.set {
+        let target = Target::find(&setting.file)
+            .ok_or_else(|| anyhow::anyhow!("unknown file {:?} for --set", setting.file))?;
+        patches
+            .entry(setting.package.clone())
+            .or_default()
+            .file_mut(target)
+            .insert(
+                setting.key.clone(),
+                PatchValue {
+                    value: Some(setting.value.clone()),
+                    ..Default::default()
+                },
+            );
+    }
+    let mut patches = match &args.map {
+        Some(map) => remap(patches, &read_map(map)?)?,
+        None => patches,
+    };
+    if !args.tag.is_empty() {
+        for patch in patches.values_mut() {
+            for file in patch.files.values_mut() {
+                file.retain(|_, value| value.has_tag(&args.tag));
+            }
+        }
+    }
+    if args.ignore_case {
+        patches = fold_case(patches, log);
+    }
+    Ok(patches)
+}
+
+/// reads and merges patch files in order, with later files taking precedence
+fn merge_patches(
+    sources: &[(&Path, String)],
+    args: &Args,
+    log: &Log,
+) -> anyhow::Result<HashMap<String, Patch>> {
+    let mut merged: HashMap<String, Patch> = HashMap::new();
+    let mut origins: HashMap<(String, Target, String), &Path> = HashMap::new();
+
+    for &(source, ref text) in sources {
+        for (package, patch) in read_patches(source, text, args)? {
+            for (target, values) in patch.into_files() {
+                let into = merged.entry(package.clone()).or_default().file_mut(target);
+
+                for (key, value) in values {
+                    let origin = (package.clone(), target, key.clone());
+
+                    if let Some(previous) = into.get(&key).filter(|&previous| *previous != value) {
+                        let message = format!(
+                            "conflicting patches for {package} {} {key}: {previous} ({}) and {value} ({})",
+                            target.filename(),
+                            origins[&origin].display(),
+                            source.display()
+                        );
+
+                        if args.strict_merge {
+                            anyhow::bail!(message);
+                        }
+                        log.warn(message);
+                    }
+
+                    origins.insert(origin, source);
+                    into.insert(key, value);
+                }
+            }
+        }
+    }
+
+    Ok(merged)
+}
+
+/// fetches a patch file, or with --offline, or when the server can't be reached, takes the copy
+/// cached last time
+fn fetch_patches(url: &str, args: &Args, log: &Log) -> anyhow::Result<String> {
+    let dir = args
+        .cache_dir
+        .clone()
+        .or_else(fetch::default_cache_dir)
+        .context("no cache directory for patch URLs; use --cache-dir")?;
+    let mode = if args.offline {
+        fetch::Mode::Offline
+    } else if args.refresh {
+        fetch::Mode::Refresh
+    } else {
+        fetch::Mode::Revalidate
+    };
+
+    let fetched = fetch::fetch(url, &dir, mode)?;
+    if let Some(reason) = fetched.fallback {
+        log.warn(format_args!("{reason}; using the copy cached last time"));
+    }
+    Ok(fetched.text)
+}
+
+/// reads a file as it was at a revision of the repository in `dir`, for --patch-from-git
+fn git_show(spec: &str, dir: &Path) -> anyhow::Result<String> {
+    if !spec.contains(':') {
+        anyhow::bail!("expected <rev>:<path> for --patch-from-git, got {spec}");
+    }
+
+    let output = process::Command::new("git")
+        .args(["show", spec])
+        .current_dir(dir)
+        .output()
+        .context("unable to run git")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "unable to read {spec} from git: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    String::from_utf8(output.stdout).with_context(|| format!("{spec} is not UTF-8"))
+}
+
+/// the folders a package name maps to, in a --map file
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Folders {
+    One(String),
+    Many(Vec<String>),
+}
+
+fn read_map(path: &Path) -> anyhow::Result<HashMap<String, Folders>> {
+    let text = fs::read_to_string(path)?;
+    serde_json::from_str(&text).with_context(|| format!("unable to read map {}", path.display()))
+}
+
+/// re-keys patches by the folders they're mapped to
+///
+/// Patches for names that aren't mapped keep their names.
+fn remap(
+    patches: HashMap<String, Patch>,
+    map: &HashMap<String, Folders>,
+) -> anyhow::Result<HashMap<String, Patch>> {
+    let mut remapped: HashMap<String, Patch> = HashMap::new();
+    let mut origins: HashMap<(String, Target, String), String> = HashMap::new();
+
+    for (name, patch) in patches {
+        let folders = match map.get(&name) {
+            Some(Folders::One(folder)) => vec![folder.clone()],
+            Some(Folders::Many(folders)) => folders.clone(),
+            None => vec![name.clone()],
+        };
+
+        for folder in folders {
+            for (target, values) in patch.clone().into_files() {
+                let into = remapped.entry(folder.clone()).or_default().file_mut(target);
+
+                for (key, value) in values {
+                    let origin = (folder.clone(), target, key.clone());
+                    if let Some(previous) = into.get(&key).filter(|&previous| *previous != value) {
+                        anyhow::bail!(
+                            "conflicting patches for {folder} {} {key}: {previous} ({}) and {value} ({name})",
+                            target.filename(),
+                            origins[&origin],
+                        );
+                    }
+
+                    origins.insert(origin, name.clone());
+                    into.insert(key, value);
+                }
+            }
+        }
+    }
+
+    Ok(remapped)
+}
+
+/// named sets of patches, of which one is applied
+///
+/// The default profile is the one named by `default`, or failing that, the one named "default".
+#[derive(Debug, Deserialize)]
+struct Profiles {
+    profiles: HashMap<String, serde_json::Value>,
+    #[serde(default)]
+    default: Option<String>,
+}
+
+fn read_patches(path: &Path, text: &str, args: &Args) -> anyhow::Result<HashMap<String, Patch>> {
+    let mut value: serde_json::Value = serde_json::from_str(text)?;
+    let profile = args.profile.as_deref();
+    let package = args.package.as_deref();
+
+    check_version(path, &mut value, args.sim_version.as_ref())?;
+
+    // A file with a top-level "profiles" key holds profiles rather than patches.
+
+    let value = if value.get("profiles").is_none() {
+        if let Some(profile) = profile {
+            anyhow::bail!("{} has no profiles (requested {profile})", path.display());
+        }
+        value
+    } else {
+        let Profiles {
+            mut profiles,
+            default,
+        } = serde_json::from_value(value)?;
+        let name = profile.or(default.as_deref()).unwrap_or("default");
+
+        profiles.remove(name).ok_or_else(|| {
+            let mut names: Vec<_> = profiles.keys().map(String::as_str).collect();
+            names.sort();
+            anyhow::anyhow!(
+                "{} has no profile named {name} (profiles: {})",
+                path.display(),
+                names.join(", ")
+            )
+        })?
+    };
+
+    if !is_bare_patch(&value) {
+        return Ok(serde_json::from_value(value)?);
+    }
+
+    let Some(package) = package else {
+        anyhow::bail!(
+            "{} holds a single patch; name the package to apply it to with --package",
+            path.display()
+        );
+    };
+    Ok(HashMap::from([(
+        package.to_owned(),
+        serde_json::from_value(value)?,
+    )]))
+}
+
+/// matches a name against a pattern in which * stands for any run of characters, ignoring case
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    let name = name.to_lowercase();
+
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = name.strip_prefix(first) else {
+        return false;
+    };
+
+    // Without a *, the pattern has to match the whole name. Otherwise, each part after the first
+    // must appear in order, and the last must end the name.
+
+    let parts: Vec<_> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        return rest.is_empty();
+    };
+
+    for part in middle {
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+/// checks the sim versions a patch file declares it's for, removing the declarations
+///
+/// Versions are only checked when we're told which version we're patching for.
+fn check_version(
+    path: &Path,
+    value: &mut serde_json::Value,
+    sim_version: Option<&Version>,
+) -> anyhow::Result<()> {
+    let Some(object) = value.as_object_mut() else {
+        return Ok(());
+    };
+
+    let mut bound = |name: &str| -> anyhow::Result<Option<Version>> {
+        match object.remove(name) {
+            Some(serde_json::Value::String(version)) => version
+                .parse()
+                .map(Some)
+                .map_err(|e| anyhow::anyhow!("{}: bad {name} {version:?}: {e}", path.display())),
+            Some(other) => {
+                anyhow::bail!("{}: {name} must be a string, not {other}", path.display())
+            }
+            None => Ok(None),
+        }
+    };
+    let min = bound("min_version")?;
+    let max = bound("max_version")?;
+
+    let Some(version) = sim_version else {
+        return Ok(());
+    };
+
+    if let Some(min) = min.filter(|min| version < min) {
+        anyhow::bail!(
+            "{} requires sim version {min} or later (patching for {version})",
+            path.display()
+        );
+    }
+    if let Some(max) = max.filter(|max| version > max) {
+        anyhow::bail!(
+            "{} requires sim version {max} or earlier (patching for {version})",
+            path.display()
+        );
+    }
+
+    Ok(())
+}
+
+/// a sim version, e.g. 1.36.2
+///
+/// Missing components count as zero, so 1.36 and 1.36.0 are the same version.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct Version(Vec<u32>);
+
+impl FromStr for Version {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s
+            .trim()
+            .split('.')
+            .map(|part| {
+                part.parse()
+                    .map_err(|_| format!("expected a version like 1.36.2, found {s:?}"))
+            })
+            .collect::<Result<Vec<u32>, _>>()?;
+
+        while parts.last() == Some(&0) {
+            parts.pop();
+        }
+        Ok(Version(parts))
+    }
+}
+
+impl Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0.split_first() {
+            Some((first, rest)) => {
+                write!(f, "{first}")?;
+                rest.iter().try_for_each(|part| write!(f, ".{part}"))
+            }
+            None => f.write_str("0"),
+        }
+    }
+}
+
+/// whether a patch file holds a single patch, rather than patches keyed by package name
+fn is_bare_patch(value: &serde_json::Value) -> bool {
+    // These are the fields of Patch. Package names are never this plain.
+
+    value.as_object().is_some_and(|object| {
+        !object.is_empty()
+            && object
+                .keys()
+                .all(|key| Target::all().any(|target| target.key() == key))
+    })
+}
+
+/// lists the packages in a directory, which are the directories `depth` levels down
+fn read_packages(path: &Path, depth: usize) -> io::Result<Vec<PathBuf>> {
+    let dirs = fs::read_dir(path)?.filter_map(|entry| {
+        let entry = entry.ok()?;
+        let path = entry.path();
+        path.is_dir().then_some(path)
+    });
+    if depth <= 1 {
+        return Ok(dirs.collect());
+    }
+
+    let mut packages = Vec::new();
+    for dir in dirs {
+        packages.extend(read_packages(&dir, depth - 1)?);
+    }
+    Ok(packages)
+}
+
+/// reads a list of package paths, one per line, from a file or (given -) stdin
+fn read_package_list(list: &str, stdin: impl io::Read, log: &Log) -> anyhow::Result<Vec<PathBuf>> {
+    let text = if list == "-" {
+        io::read_to_string(stdin).context("unable to read packages from stdin")?
+    } else {
+        fs::read_to_string(list).with_context(|| format!("unable to read packages from {list}"))?
+    };
+
+    let mut packages = Vec::new();
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        let path = PathBuf::from(line);
+        if path.is_dir() {
+            packages.push(path);
+        } else {
+            log.warn(format_args!("{line}: not a package directory"));
+        }
+    }
+    Ok(packages)
+}
+
+/// pairs packages with their patches, leaving out packages without one
+fn match_packages<'a>(
+    candidates: Vec<PathBuf>,
+    patches: &'a HashMap<String, Patch>,
+    args: &'a Args,
+) -> impl Iterator<Item = (PathBuf, &'a Patch)> + 'a {
+    candidates.into_iter().filter_map(move |path| {
+        let field = if args.match_title {
+            Some("title")
+        } else {
+            args.package_name_from.as_deref()
+        };
+        let names = field
+            .map(|field| read_field(&path, field, args))
+            .unwrap_or_default();
+
+        // Only --package-name-from falls back to the folder name, for packages without the field.
+        let patch = if args.match_title || !names.is_empty() {
+            names
+                .iter()
+                .find_map(|name| patches.get(&*args.patch_name(name)))?
+        } else {
+            patches.get(&*args.patch_name(path.file_name()?.to_str()?))?
+        };
+        Some((path, patch))
+    })
+}
+
+/// rekeys patches by their lowercase names, for --ignore-case
+///
+/// Of several patches whose names differ only in case, the first in sort order is kept.
+fn fold_case(patches: HashMap<String, Patch>, log: &Log) -> HashMap<String, Patch> {
+    let mut patches: Vec<_> = patches.into_iter().collect();
+    patches.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let mut folded: HashMap<String, (String, Patch)> = HashMap::new();
+    for (name, patch) in patches {
+        match folded.get(&name.to_lowercase()) {
+            Some((kept, _)) => log.warn(format_args!(
+                "patches '{kept}' and '{name}' differ only in case; ignoring '{name}'"
+            )),
+            None => {
+                folded.insert(name.to_lowercase(), (name, patch));
+            }
+        }
+    }
+
+    folded
+        .into_iter()
+        .map(|(key, (_, patch))| (key, patch))
+        .collect()
+}
+
+/// warns about patches that matched no package but look like they were meant to
+///
+/// Most patch files cover more aircraft than any one person owns, so a patch that matches nothing
+/// is only worth mentioning when some unpatched folder has a similar name.
+fn warn_near_misses(patches: &HashMap<String, Patch>, names: &[String], log: &Log) {
+    let unpatched: Vec<_> = names
+        .iter()
+        .map(String::as_str)
+        .filter(|name| !patches.contains_key(*name))
+        .collect();
+
+    let mut missed: Vec<_> = patches.keys().filter(|key| !names.contains(key)).collect();
+    missed.sort();
+
+    for key in missed {
+        let suggestions = suggest(key, &unpatched);
+        if !suggestions.is_empty() {
+            log.warn(format_args!(
+                "no match for '{key}'; did you mean {}?",
+                suggestions
+                    .iter()
+                    .map(|name| format!("'{name}'"))
+                    .collect::<Vec<_>>()
+                    .join(" or ")
+            ));
+        }
+    }
+}
+
+/// the names closest to `key`, up to three, ignoring anything not close at all
+///
+/// A name containing the key (c172 for asobo-aircraft-c172sp) counts as close, as does a name
+/// within a few edits of it. Containing one or two letters doesn't count for much, though.
+fn suggest<'a>(key: &str, names: &[&'a str]) -> Vec<&'a str> {
+    let key = key.to_lowercase();
+    let limit = (key.chars().count() / 3).max(2);
+
+    let mut close: Vec<_> = names
+        .iter()
+        .filter_map(|&name| {
+            let lower = name.to_lowercase();
+            let distance = strsim::levenshtein(&key, &lower);
+            let contains = (key.len() >= 4 && lower.contains(&key))
+                || (lower.len() >= 4 && key.contains(&lower));
+            (contains || distance <= limit).then_some((!contains, distance, name))
+        })
+        .collect();
+    close.sort();
+    close.into_iter().take(3).map(|(_, _, name)| name).collect()
+}
+
+/// reads the values of a field of a package's aircraft.cfg, e.g. the titles of its aircraft, one
+/// per [FLTSIM.n] section
+fn read_field(package: &Path, field: &str, args: &Args) -> Vec<String> {
+    let Some(text) = find_path(package, "aircraft.cfg", args)
+        .and_then(|path| encoding::read_to_string(path).ok())
+    else {
+        return Vec::new();
+    };
+
+    // Titles are only ever in [FLTSIM.n] sections, but other fields, like atc_model, are
+    // usually in [GENERAL], so those are looked for everywhere.
+
+    let mut values = Vec::new();
+    let mut in_fltsim = false;
+
+    for line in text.lines() {
+        match line::parse(line) {
+            Line::Section { name, .. } => in_fltsim = name.starts_with("FLTSIM."),
+            Line::Entry { key, value, .. }
+                if key.eq_ignore_ascii_case(field) && (in_fltsim || field != "title") =>
+            {
+                let value = value.trim().trim_matches('"');
+                if !value.is_empty() {
+                    values.push(value.to_owned());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    values
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change(key: &str, old: &str, new: &str) -> Change {
+        Change {
+            section: Some(String::from("GENERALENGINEDATA")),
+            key: key.to_owned(),
+            old: old.to_owned(),
+            new: new.to_owned(),
+            comment: None,
+        }
+    }
+
+    fn comment_of(line: &str) -> Option<Comment<'_>> {
+        match line::parse(line) {
+            Line::Entry { comment, .. } => comment,
+            other => panic!("expected an entry, found {other:?}"),
+        }
+    }
+
+    #[test]
+    fn recorded_original_keeps_slashes() {
+        let line = "path = d/e ; patchcfg-orig: a/b/c ; note";
+        let change = Change {
+            comment: Some(String::from("new")),
+            ..change("path", " d/e ", "f/g")
+        };
+        assert_eq!(
+            change.line(comment_of(line), &Original::Once),
+            "path = f/g ; patchcfg-orig: a/b/c ; new"
+        );
+    }
+
+    fn rerun(args: &[&str]) -> (String, PathChanges) {
+        let mut patch = FilePatch::new();
+        patch.insert(
+            String::from("GENERALENGINEDATA.static_thrust"),
+            PatchValue::try_from(serde_json::json!("+10%")).unwrap(),
+        );
+        let args = Args::parse_from(
+            ["patchcfg"]
+                .iter()
+                .chain(args)
+                .chain(&["packages", "patches.json"]),
+        );
+        let text = "[GENERALENGINEDATA]\nstatic_thrust = 100 ; thrust\n";
+        let first =
+            build_diff(&patch, PathBuf::from("engines.cfg"), text, "package", &args).unwrap();
+        let patched = String::from_utf8(render(&first, &args).unwrap().0).unwrap();
+        let second = build_diff(
+            &patch,
+            PathBuf::from("engines.cfg"),
+            &patched,
+            "package",
+            &args,
+        )
+        .unwrap();
+        (patched, second)
+    }
+
+    #[test]
+    fn normalize_keeps_the_end_of_the_file() {
+        assert_eq!(normalized("[GENERAL]\na=1"), "[GENERAL]\na = 1");
+        assert_eq!(normalized("[GENERAL]\na=1\n"), "[GENERAL]\na = 1\n");
+        assert_eq!(normalized("[GENERAL]\r\na=1\r\n"), "[GENERAL]\r\na = 1\r\n");
+        assert_eq!(normalized(""), "");
+    }
+
+    #[test]
+    fn post_hook_sees_failure_on_warning() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-warn-{}", process::id()));
+        let package = dir.join("packages/asobo-aircraft-tbm930/SimObjects/Airplanes/tbm");
+        fs::create_dir_all(&package).unwrap();
+        fs::write(
+            package.join("engines.cfg"),
+            "[GENERALENGINEDATA]\nstatic_thrust = 100\n",
+        )
+        .unwrap();
+        let patches = dir.join("patches.json");
+        let patch = r#"{ "asobo-aircraft-tbm930": { "engines": { "GENERALENGINEDATA.no_such_key": "1" } } }"#;
+        fs::write(&patches, patch).unwrap();
+        let success = dir.join("success");
+
+        let hook = format!("echo $PATCHCFG_SUCCESS > '{}'", success.display());
+        let packages = dir.join("packages");
+        let args = Args::parse_from([
+            OsStr::new("patchcfg"),
+            OsStr::new("--fail-on-warning"),
+            OsStr::new("--post-hook-always"),
+            OsStr::new("--post-hook"),
+            OsStr::new(&hook),
+            packages.as_os_str(),
+            patches.as_os_str(),
+        ]);
+        let log = Log::new(&args).unwrap();
+        let result = run(&args, &log);
+        let success = fs::read_to_string(&success);
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(result.unwrap_err().to_string(), "failing on 1 warning(s)");
+        if cfg!(unix) {
+            assert_eq!(success.unwrap().trim(), "0");
+        }
+    }
+
+    #[test]
+    fn inverse_patch_undoes_the_run() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-inverse-{}", process::id()));
+        let package = dir.join("packages/asobo-aircraft-tbm930/SimObjects/Airplanes/tbm");
+        fs::create_dir_all(&package).unwrap();
+        let path = package.join("engines.cfg");
+        let original = "[GENERALENGINEDATA]\nstatic_thrust = 100 ; thrust\nmin_throttle = 0\n\n[TURBINEENGINEDATA]\nfuel_flow_gain = 0.002\n";
+        fs::write(&path, original).unwrap();
+        let patches = dir.join("patches.json");
+        let patch = r#"{ "asobo-aircraft-tbm930": { "engines": {
+            "GENERALENGINEDATA.static_thrust": "+10%",
+            "GENERALENGINEDATA.min_throttle": "-0.1"
+        } } }"#;
+        fs::write(&patches, patch).unwrap();
+        let inverse = dir.join("inverse.json");
+
+        let packages = dir.join("packages");
+        let args = Args::parse_from([
+            OsStr::new("patchcfg"),
+            OsStr::new("--force"),
+            OsStr::new("--no-backup"),
+            OsStr::new("--inverse-out"),
+            inverse.as_os_str(),
+            packages.as_os_str(),
+            patches.as_os_str(),
+        ]);
+        let log = Log::new(&args).unwrap();
+        let patched = run(&args, &log).map(|_| fs::read_to_string(&path).unwrap());
+
+        let args = Args::parse_from([
+            OsStr::new("patchcfg"),
+            OsStr::new("--force"),
+            OsStr::new("--no-backup"),
+            OsStr::new("--no-preserve-original"),
+            packages.as_os_str(),
+            inverse.as_os_str(),
+        ]);
+        let log = Log::new(&args).unwrap();
+        let restored = run(&args, &log).map(|_| fs::read_to_string(&path).unwrap());
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_ne!(patched.unwrap(), original);
+        assert_eq!(restored.unwrap(), original);
+    }
+
+    #[test]
+    fn exit_code_tells_a_change_from_nothing_to_do() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-exit-{}", process::id()));
+        let package = dir.join("packages/asobo-aircraft-tbm930/SimObjects/Airplanes/tbm");
+        fs::create_dir_all(&package).unwrap();
+        fs::write(
+            package.join("engines.cfg"),
+            "[GENERALENGINEDATA]\nstatic_thrust = 100\n",
+        )
+        .unwrap();
+        let packages = dir.join("packages");
+        let status = |key: &str, value: &str, flags: &[&str]| {
+            let patches = dir.join("patches.json");
+            let patch = format!(
+                r#"{{ "asobo-aircraft-tbm930": {{ "engines": {{ "{key}": "{value}" }} }} }}"#
+            );
+            fs::write(&patches, patch).unwrap();
+            let mut argv = vec![OsStr::new("patchcfg")];
+            argv.extend(flags.iter().map(OsStr::new));
+            argv.extend([packages.as_os_str(), patches.as_os_str()]);
+            let args = Args::parse_from(argv);
+            let result = run(&args, &Log::new(&args).unwrap());
+            exit_code(&result.map(|summary| summary.files_changed > 0), &args)
+        };
+
+        let thrust = "GENERALENGINEDATA.static_thrust";
+        let silent = status(thrust, "100", &["--summary-only-on-change"]);
+        let summarized = status(thrust, "200", &["--summary-only-on-change"]);
+        let plain = status(thrust, "200", &[]);
+        let failed = status("NO_SUCH_SECTION.key", "1", &["--summary-only-on-change"]);
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(silent, 0);
+        assert_eq!(summarized, 2);
+        assert_eq!(plain, 0);
+        assert_eq!(failed, 1);
+    }
+
+    #[test]
+    fn ci_only_sets_defaults() {
+        let parse = |flags: &[&str]| {
+            Args::try_parse_from(
+                ["patchcfg"]
+                    .iter()
+                    .chain(flags)
+                    .chain(&["packages", "patches.json"]),
+            )
+        };
+
+        let ci = parse(&["--ci"]).unwrap();
+        let composed = parse(&["--summary-json"]).unwrap();
+        assert_eq!(ci.summary_only(), composed.summary_only());
+        assert!(ci.summary_only() && !ci.fail_fast);
+        assert!(parse(&["--ci", "--hash", "--with-changes", "--report-format", "csv"]).is_ok());
+
+        let overridden = parse(&["--ci", "--no-summary-json", "--fail-fast"]).unwrap();
+        assert!(!overridden.summary_only() && overridden.fail_fast);
+        assert!(parse(&["--hash"]).is_err());
+        assert!(parse(&["--summary-json", "--no-summary-json"]).is_err());
+    }
+
+    #[test]
+    fn near_misses_are_suggested() {
+        let names = [
+            "asobo-aircraft-c172sp-classic",
+            "asobo-aircraft-tbm930",
+            "asobo-aircraft-a320-neo",
+        ];
+        assert_eq!(suggest("c172sp", &names), ["asobo-aircraft-c172sp-classic"]);
+        assert_eq!(
+            suggest("asobo-aircraft-tbm903", &names),
+            ["asobo-aircraft-tbm930"]
+        );
+        assert!(suggest("kodiak", &names).is_empty());
+    }
+
+    #[test]
+    fn hashes_tell_changed_files_from_unchanged() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-hash-{}", process::id()));
+        let package = dir.join("packages/asobo-aircraft-tbm930/SimObjects/Airplanes/tbm");
+        fs::create_dir_all(&package).unwrap();
+        let engines = "[GENERALENGINEDATA]\nstatic_thrust = 100\n";
+        fs::write(package.join("engines.cfg"), engines).unwrap();
+        fs::write(
+            package.join("flight_model.cfg"),
+            "[FLIGHT_TUNING]\npitch = 1\n",
+        )
+        .unwrap();
+        let patches = dir.join("patches.json");
+        let patch = r#"{ "asobo-aircraft-tbm930": {
+            "engines": { "GENERALENGINEDATA.static_thrust": "200" },
+            "flight_model": { "FLIGHT_TUNING.pitch": "1" }
+        } }"#;
+        fs::write(&patches, patch).unwrap();
+
+        let packages = dir.join("packages");
+        let args = Args::parse_from([
+            OsStr::new("patchcfg"),
+            OsStr::new("--force"),
+            OsStr::new("--summary-json"),
+            OsStr::new("--hash"),
+            packages.as_os_str(),
+            patches.as_os_str(),
+        ]);
+        let log = Log::new(&args).unwrap();
+        let summary = run(&args, &log);
+        let patched = fs::read(package.join("engines.cfg"));
+        let _ = fs::remove_dir_all(&dir);
+
+        let summary = summary.unwrap();
+        let hashes = |name: &str| {
+            let file = summary
+                .files
+                .iter()
+                .find(|file| file.path.ends_with(name))
+                .unwrap_or_else(|| panic!("no hashes for {name}"));
+            (file.before.as_str(), file.after.as_str())
+        };
+        let (before, after) = hashes("engines.cfg");
+        assert_eq!(before, sha256::hex_digest(engines.as_bytes()));
+        assert_eq!(after, sha256::hex_digest(&patched.unwrap()));
+        assert_ne!(before, after);
+        let (before, after) = hashes("flight_model.cfg");
+        assert_eq!(before, after);
+    }
+
+    #[test]
+    fn utf16_files_are_hashed_as_written() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-utf16-{}", process::id()));
+        let package = dir.join("packages/asobo-aircraft-tbm930/SimObjects/Airplanes/tbm");
+        fs::create_dir_all(&package).unwrap();
+        let path = package.join("engines.cfg");
+        let engines = encoding::encode(
+            "[GENERALENGINEDATA]\r\nstatic_thrust = 100 ; poussée\r\n".as_bytes(),
+            Encoding::Utf16Le,
+        );
+        fs::write(&path, &engines).unwrap();
+        let patches = dir.join("patches.json");
+        let patch = r#"{ "asobo-aircraft-tbm930": { "engines": { "GENERALENGINEDATA.static_thrust": "200" } } }"#;
+        fs::write(&patches, patch).unwrap();
+        let plan = dir.join("plan.json");
+
+        let packages = dir.join("packages");
+        let parse = |flags: &[&OsStr]| {
+            let mut argv = vec![OsStr::new("patchcfg")];
+            argv.extend(flags);
+            argv.extend([packages.as_os_str(), patches.as_os_str()]);
+            Args::parse_from(argv)
+        };
+        let preview = parse(&[OsStr::new("--plan-out"), plan.as_os_str()]);
+        let planned = run(&preview, &Log::new(&preview).unwrap())
+            .map(|_| serde_json::from_str::<Plan>(&fs::read_to_string(&plan).unwrap()).unwrap());
+        let args = parse(&[
+            OsStr::new("--force"),
+            OsStr::new("--summary-json"),
+            OsStr::new("--hash"),
+        ]);
+        let summary = run(&args, &Log::new(&args).unwrap());
+        let patched = fs::read(&path);
+        let _ = fs::remove_dir_all(&dir);
+
+        let (planned, summary, patched) = (planned.unwrap(), summary.unwrap(), patched.unwrap());
+        let (text, encoding) = encoding::decode(&patched).unwrap();
+        assert_eq!(encoding, Encoding::Utf16Le);
+        assert!(patched.starts_with(&[0xff, 0xfe]));
+        assert_eq!(
+            text,
+            "[GENERALENGINEDATA]\nstatic_thrust = 200 ;  100  ;  poussée\n"
+        );
+        assert_eq!(encoding::encode(text.as_bytes(), encoding), patched);
+
+        assert_eq!(planned.files[0].sha256, sha256::hex_digest(&engines));
+        let hashes = &summary.files[0];
+        assert_eq!(hashes.before, sha256::hex_digest(&engines));
+        assert_eq!(hashes.after, sha256::hex_digest(&patched));
+    }
+
+    #[test]
+    fn patch_from_git_reads_the_committed_copy() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-git-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let git = |args: &[&str]| {
+            process::Command::new("git")
+                .args([
+                    "-c",
+                    "user.name=patchcfg",
+                    "-c",
+                    "user.email=patchcfg@example.com",
+                ])
+                .args(args)
+                .current_dir(&dir)
+                .output()
+                .is_ok_and(|output| output.status.success())
+        };
+        if !git(&["init", "-q"]) {
+            // no git to test with
+            let _ = fs::remove_dir_all(&dir);
+            return;
+        }
+
+        let committed = r#"{ "asobo-aircraft-tbm930": { "engines": { "static_thrust": "229" } } }"#;
+        fs::write(dir.join("patches.json"), committed).unwrap();
+        assert!(git(&["add", "patches.json"]));
+        assert!(git(&["commit", "-q", "-m", "patches"]));
+        // A dirty working tree doesn't matter; the revision is what's read.
+        fs::write(dir.join("patches.json"), "{}").unwrap();
+
+        let shown = git_show("HEAD:patches.json", &dir);
+        let missing = git_show("HEAD:nothing.json", &dir);
+        let malformed = git_show("HEAD", &dir);
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(shown.unwrap(), committed);
+        assert!(missing.is_err());
+        assert!(malformed.is_err());
+    }
+
+    #[test]
+    fn project_file_reads_like_its_flags() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-project-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let project = dir.join("project.toml");
+        fs::write(
+            &project,
+            "packages = \"Community\"\npatches = 'patches.json'\n\
+             merge = [\"local.json\", \"more.json\"]\n\
+             backup_suffix = \"orig\" # comment\n\
+             packages-depth = 2\nexplain = true\nforce = false\n",
+        )
+        .unwrap();
+        let argv = |args: &[&str]| -> Vec<OsString> {
+            iter::once(OsStr::new("patchcfg"))
+                .chain([OsStr::new("--project"), project.as_os_str()])
+                .chain(args.iter().map(OsStr::new))
+                .map(OsStr::to_owned)
+                .collect()
+        };
+
+        let from_project = args_from(argv(&[]));
+        let explicit = args_from(argv(&[
+            "Community",
+            "patches.json",
+            "--merge=local.json",
+            "--merge=more.json",
+            "--backup-suffix=orig",
+            "--packages-depth=2",
+            "--explain",
+        ]));
+        let overridden = args_from(argv(&["--backup-suffix", "bak.cfg"]));
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(format!("{from_project:?}"), format!("{explicit:?}"));
+        assert!(!from_project.force);
+        assert_eq!(overridden.backup_suffix, "bak.cfg");
+        assert_eq!(overridden.packages.as_deref(), Some("Community"));
+    }
+
+    #[test]
+    fn bundle_holds_just_the_changed_files() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-bundle-{}", process::id()));
+        let package = dir.join("packages/asobo-aircraft-tbm930/SimObjects/Airplanes/tbm");
+        fs::create_dir_all(&package).unwrap();
+        let engines = "[GENERALENGINEDATA]\nstatic_thrust = 100\n";
+        fs::write(package.join("engines.cfg"), engines).unwrap();
+        fs::write(
+            package.join("flight_model.cfg"),
+            "[FLIGHT_TUNING]\npitch = 1\n",
+        )
+        .unwrap();
+        let patches = dir.join("patches.json");
+        let patch = r#"{ "asobo-aircraft-tbm930": {
+            "engines": { "GENERALENGINEDATA.static_thrust": "200" },
+            "flight_model": { "FLIGHT_TUNING.pitch": "1" }
+        } }"#;
+        fs::write(&patches, patch).unwrap();
+
+        let (packages, bundle) = (dir.join("packages"), dir.join("bundle.zip"));
+        let args = Args::parse_from([
+            OsStr::new("patchcfg"),
+            OsStr::new("--zip-out"),
+            bundle.as_os_str(),
+            packages.as_os_str(),
+            patches.as_os_str(),
+        ]);
+        let log = Log::new(&args).unwrap();
+        let summary = run(&args, &log);
+        let archive = fs::read(&bundle);
+        let untouched = fs::read_to_string(package.join("engines.cfg"));
+        let _ = fs::remove_dir_all(&dir);
+
+        summary.unwrap();
+        let entries = zip::tests::entries(&archive.unwrap());
+        let names: Vec<_> = entries.iter().map(|(name, _)| name.as_str()).collect();
+        let path = "asobo-aircraft-tbm930/SimObjects/Airplanes/tbm/engines.cfg";
+        assert_eq!(names, [path, "manifest.json"]);
+        assert!(String::from_utf8_lossy(&entries[0].1).contains("static_thrust = 200"));
+        let manifest: serde_json::Value = serde_json::from_slice(&entries[1].1).unwrap();
+        assert_eq!(manifest["files"][0]["path"], path);
+        assert_eq!(
+            untouched.unwrap(),
+            engines,
+            "a preview doesn't write in place"
+        );
+    }
+
+    #[test]
+    fn run_id_ties_the_log_to_the_backups() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-run-id-{}", process::id()));
+        let package = dir.join("packages/asobo-aircraft-tbm930/SimObjects/Airplanes/tbm");
+        fs::create_dir_all(&package).unwrap();
+        fs::write(
+            package.join("engines.cfg"),
+            "[GENERALENGINEDATA]\nstatic_thrust = 100\n",
+        )
+        .unwrap();
+        let patches = dir.join("patches.json");
+        let patch = r#"{ "asobo-aircraft-tbm930": { "engines": { "GENERALENGINEDATA.static_thrust": "200" } } }"#;
+        fs::write(&patches, patch).unwrap();
+
+        let (packages, log_file) = (dir.join("packages"), dir.join("patchcfg.log"));
+        let args = Args::parse_from([
+            OsStr::new("patchcfg"),
+            OsStr::new("--force"),
+            OsStr::new("--summary-json"),
+            OsStr::new("--log-file"),
+            log_file.as_os_str(),
+            packages.as_os_str(),
+            patches.as_os_str(),
+        ]);
+        let log = Log::new(&args).unwrap();
+        let summary = run(&args, &log);
+        drop(log);
+        let logged = fs::read_to_string(&log_file);
+        let _ = fs::remove_dir_all(&dir);
+
+        let run_id = summary.unwrap().run_id;
+        let logged = logged.unwrap();
+        assert!(
+            logged.contains(&format!("info: run {run_id}\n")),
+            "{logged}"
+        );
+        assert!(
+            logged.contains(&format!("info: run {run_id}: backed up ")),
+            "{logged}"
+        );
+    }
+
+    #[test]
+    fn percent_patch_does_not_compound() {
+        let (patched, second) = rerun(&[]);
+        assert!(
+            patched.contains("static_thrust = 110 ;  100  ;  thrust"),
+            "{patched}"
+        );
+        assert!(second.is_empty());
+
+        let (patched, second) = rerun(&["--preserve-original-once"]);
+        assert!(patched.contains("patchcfg-orig: 100"), "{patched}");
+        assert!(second.is_empty());
+    }
+
+    #[test]
+    fn indexed_sections_are_matched_exactly() {
+        let text = "[ENGINE.0]\nmax_rpm = 2000\n\n[ENGINE.1]\nmax_rpm = 2000\n\n[ENGINE.10]\nmax_rpm = 2000\n";
+        let args = Args::parse_from([
+            "patchcfg",
+            "--no-preserve-original",
+            "packages",
+            "patches.json",
+        ]);
+        let diff = |key: &str| {
+            let mut patch = FilePatch::new();
+            patch.insert(
+                String::from(key),
+                PatchValue::try_from(serde_json::json!("2100")).unwrap(),
+            );
+            build_diff(&patch, PathBuf::from("engines.cfg"), text, "package", &args)
+        };
+
+        let patched = diff("ENGINE.1.max_rpm").unwrap();
+        let patched = String::from_utf8(render(&patched, &args).unwrap().0).unwrap();
+        assert_eq!(
+            patched,
+            "[ENGINE.0]\nmax_rpm = 2000\n\n[ENGINE.1]\nmax_rpm = 2100\n\n[ENGINE.10]\nmax_rpm = 2000\n"
+        );
+
+        let missing = diff("ENGINE.2.max_rpm").unwrap_err().to_string();
+        assert!(
+            missing.contains("section [ENGINE.2] not found"),
+            "{missing}"
+        );
+    }
+
+    #[test]
+    fn each_target_is_backed_up_beside_itself() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-backup-{}", process::id()));
+        let patches = dir.join("patches.json");
+        let patch = r#"{ "asobo-aircraft-tbm930": {
+            "engines": { "GENERALENGINEDATA.static_thrust": "200" },
+            "flight_model": { "FLIGHT_TUNING.pitch": "2" }
+        } }"#;
+        let backups = |name: &str, flags: &[&str]| {
+            let packages = dir.join(name);
+            let tbm = packages.join("asobo-aircraft-tbm930/SimObjects/Airplanes/tbm");
+            fs::create_dir_all(tbm.join("tuning")).unwrap();
+            let engines = tbm.join("engines.cfg");
+            let flight_model = tbm.join("tuning/flight_model.cfg");
+            fs::write(&engines, "[GENERALENGINEDATA]\nstatic_thrust = 100\n").unwrap();
+            fs::write(&flight_model, "[FLIGHT_TUNING]\npitch = 1\n").unwrap();
+            fs::write(&patches, patch).unwrap();
+
+            let mut argv = vec![OsStr::new("patchcfg"), OsStr::new("--force")];
+            argv.extend(flags.iter().map(OsStr::new));
+            argv.extend([packages.as_os_str(), patches.as_os_str()]);
+            let args = Args::parse_from(argv);
+            run(&args, &Log::new(&args).unwrap()).unwrap();
+            [engines, flight_model].map(|path| {
+                let backup = args.backup_path(&path);
+                fs::read_to_string(backup).ok()
+            })
+        };
+
+        let all = backups("all", &[]);
+        let engines_only = backups("engines", &["--backup", "engines"]);
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(
+            all,
+            [
+                Some(String::from("[GENERALENGINEDATA]\nstatic_thrust = 100\n")),
+                Some(String::from("[FLIGHT_TUNING]\npitch = 1\n")),
+            ]
+        );
+        assert_eq!(
+            engines_only,
+            [
+                Some(String::from("[GENERALENGINEDATA]\nstatic_thrust = 100\n")),
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn against_backup_diffs_from_stock() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-against-{}", process::id()));
+        let package = dir.join("asobo-aircraft-tbm930");
+        fs::create_dir_all(&package).unwrap();
+        let engines = package.join("engines.cfg");
+        fs::write(&engines, "[GENERALENGINEDATA]\nstatic_thrust = 150 ; 100\n").unwrap();
+        let patch: Patch =
+            serde_json::from_str(r#"{ "engines": { "GENERALENGINEDATA.static_thrust": "200" } }"#)
+                .unwrap();
+
+        let old = |flags: &[&str]| {
+            let args = Args::parse_from(
+                ["patchcfg"]
+                    .iter()
+                    .chain(flags)
+                    .chain(&["packages", "patches.json"]),
+            );
+            let diff = patch.diff(&package, &args, &mut Stats::default()).unwrap();
+            let (_, file) = diff.files()[0];
+            file.changes.values().next().unwrap().old.trim().to_owned()
+        };
+        let no_backup = old(&["--against-backup"]);
+        fs::write(
+            package.join("engines.bak.cfg"),
+            "[GENERALENGINEDATA]\nstatic_thrust = 100\n",
+        )
+        .unwrap();
+        let current = old(&[]);
+        let stock = old(&["--against-backup"]);
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(current, "150");
+        assert_eq!(stock, "100");
+        assert_eq!(no_backup, "150");
+    }
+
+    #[test]
+    fn root_is_found_above_the_current_directory() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-root-{}", process::id()));
+        let community = dir.join("Community");
+        let nested = community.join("asobo-aircraft-tbm930/SimObjects/Airplanes");
+        fs::create_dir_all(&nested).unwrap();
+        fs::write(dir.join(".patchcfg-root"), "").unwrap();
+
+        let by_name = find_root("Community", &nested);
+        let by_file = find_root(".patchcfg-root", &nested);
+        let by_child = find_root("Community", &dir);
+        let missing = find_root("Official", &nested);
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(by_name.unwrap(), community);
+        assert_eq!(by_file.unwrap(), dir);
+        assert_eq!(by_child.unwrap(), community);
+        assert!(missing
+            .unwrap_err()
+            .to_string()
+            .starts_with("no Official found in"));
+    }
+
+    #[test]
+    fn conflicting_sources_are_reported() {
+        let sources = [
+            (
+                Path::new("base.json"),
+                String::from(
+                    r#"{ "tbm": { "engines": { "ENGINE.max_rpm": "2000", "ENGINE.min_rpm": "0" } } }"#,
+                ),
+            ),
+            (
+                Path::new("override.json"),
+                String::from(
+                    r#"{ "tbm": { "engines": { "ENGINE.max_rpm": "2100", "ENGINE.min_rpm": "0" } } }"#,
+                ),
+            ),
+        ];
+        let parse = |flags: &[&str]| {
+            Args::parse_from(
+                ["patchcfg"]
+                    .iter()
+                    .chain(flags)
+                    .chain(&["packages", "patches.json"]),
+            )
+        };
+
+        let args = parse(&[]);
+        let log = Log::new(&args).unwrap();
+        let merged = merge_patches(&sources, &args, &log).unwrap();
+        let engines = merged["tbm"]
+            .file(Target::find("engines").unwrap())
+            .unwrap();
+        assert_eq!(log.warnings(), 1, "only the key that differs conflicts");
+        assert_eq!(engines["ENGINE.max_rpm"].to_string(), "2100");
+
+        let args = parse(&["--strict-merge"]);
+        let error = merge_patches(&sources, &args, &Log::new(&args).unwrap()).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "conflicting patches for tbm engines.cfg ENGINE.max_rpm: 2000 (base.json) and 2100 (override.json)"
+        );
+    }
+
+    #[test]
+    fn epsilon_is_inclusive() {
+        assert!(values_equal("1.25", "1.5", 0.25));
+        assert!(!values_equal("1.25", "1.50390625", 0.25));
+        assert!(values_equal("1.0000001", "1.0", 1e-6));
+        assert!(!values_equal("1.00001", "1.0", 1e-6));
+        assert!(values_equal("1.0", "1", 0.0));
+        assert!(
+            !values_equal("abc", "ABC", 1.0),
+            "non-numbers compare as strings"
+        );
+        assert!(values_equal("TRUE", "TRUE", 0.0));
+
+        let text = "[GENERAL]\nscalar = 1.25\n";
+        let args = Args::parse_from(["patchcfg", "--epsilon", "0.25", "packages", "patches.json"]);
+        let changed = |value: &str| {
+            let mut patch = FilePatch::new();
+            patch.insert(
+                String::from("GENERAL.scalar"),
+                PatchValue::try_from(serde_json::json!(value)).unwrap(),
+            );
+            let diff = build_diff(
+                &patch,
+                PathBuf::from("flight_model.cfg"),
+                text,
+                "package",
+                &args,
+            );
+            !diff.unwrap().changes.is_empty()
+        };
+        assert!(!changed("1.5"));
+        assert!(!changed("1"));
+        assert!(changed("1.50390625"));
+        assert!(changed("0.99609375"));
+    }
+
+    #[test]
+    fn profiles_are_selected_by_name() {
+        let text = r#"{
+            "default": "realistic",
+            "profiles": {
+                "realistic": { "tbm": { "engines": { "GENERALENGINEDATA.static_thrust": "229" } } },
+                "arcade": { "tbm": { "engines": { "GENERALENGINEDATA.static_thrust": "400" } } }
+            }
+        }"#;
+        let thrust = |flags: &[&str]| {
+            let args = Args::parse_from(
+                ["patchcfg"]
+                    .iter()
+                    .chain(flags)
+                    .chain(&["packages", "patches.json"]),
+            );
+            read_patches(Path::new("patches.json"), text, &args).map(|patches| {
+                let engines = patches["tbm"].file(Target::find("engines").unwrap());
+                engines.unwrap()["GENERALENGINEDATA.static_thrust"].to_string()
+            })
+        };
+
+        assert_eq!(thrust(&[]).unwrap(), "229");
+        assert_eq!(thrust(&["--profile", "arcade"]).unwrap(), "400");
+        assert_eq!(
+            thrust(&["--profile", "sport"]).unwrap_err().to_string(),
+            "patches.json has no profile named sport (profiles: arcade, realistic)"
+        );
+
+        let plain = r#"{ "tbm": { "engines": { "GENERALENGINEDATA.static_thrust": "229" } } }"#;
+        let args = Args::parse_from([
+            "patchcfg",
+            "--profile",
+            "arcade",
+            "packages",
+            "patches.json",
+        ]);
+        let error = read_patches(Path::new("patches.json"), plain, &args).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "patches.json has no profiles (requested arcade)"
+        );
+    }
+
+    #[test]
+    fn commented_section_header_still_matches() {
+        let text = "[ENGINE.0] ; first engine\nmax_rpm = 2000\n\n[ENGINE.1]\nmax_rpm = 2000\n";
+        let args = Args::parse_from([
+            "patchcfg",
+            "--no-preserve-original",
+            "packages",
+            "patches.json",
+        ]);
+        let mut patch = FilePatch::new();
+        patch.insert(
+            String::from("ENGINE.0.max_rpm"),
+            PatchValue::try_from(serde_json::json!("2100")).unwrap(),
+        );
+
+        let diff =
+            build_diff(&patch, PathBuf::from("engines.cfg"), text, "package", &args).unwrap();
+        let patched = String::from_utf8(render(&diff, &args).unwrap().0).unwrap();
+        assert_eq!(
+            patched,
+            "[ENGINE.0] ; first engine\nmax_rpm = 2100\n\n[ENGINE.1]\nmax_rpm = 2000\n"
+        );
+    }
+
+    #[test]
+    fn missing_target_fails_only_when_strict() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-missing-{}", process::id()));
+        let package = dir.join("asobo-aircraft-tbm930");
+        fs::create_dir_all(&package).unwrap();
+        fs::write(
+            package.join("flight_model.cfg"),
+            "[FLIGHT_TUNING]\npitch = 1\n",
+        )
+        .unwrap();
+        let patch: Patch =
+            serde_json::from_str(r#"{ "engines": { "GENERALENGINEDATA.static_thrust": "200" } }"#)
+                .unwrap();
+
+        let diff = |flags: &[&str]| {
+            let args = Args::parse_from(
+                ["patchcfg"]
+                    .iter()
+                    .chain(flags)
+                    .chain(&["packages", "patches.json"]),
+            );
+            patch.diff(&package, &args, &mut Stats::default())
+        };
+        let lenient = diff(&[]);
+        let strict = diff(&["--fail-if-missing-target"]);
+        let _ = fs::remove_dir_all(&dir);
+
+        let lenient = lenient.unwrap();
+        assert!(lenient.is_empty());
+        assert_eq!(
+            lenient.skipped,
+            [(Target::find("engines").unwrap(), Diff::NOT_FOUND)]
+        );
+        assert_eq!(
+            strict.unwrap_err().to_string(),
+            "asobo-aircraft-tbm930: no engines.cfg found for patch"
+        );
+    }
+
+    #[test]
+    fn repeated_runs_report_deltas_from_the_last() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-track-{}", process::id()));
+        let packages = dir.join("packages");
+        let package = packages.join("asobo-aircraft-tbm930");
+        let tbm = package.join("SimObjects/Airplanes/tbm");
+        fs::create_dir_all(&tbm).unwrap();
+        let engines = tbm.join("engines.cfg");
+        let patches = dir.join("patches.json");
+        fs::write(&engines, "[GENERALENGINEDATA]\nstatic_thrust = 100\n").unwrap();
+        fs::write(
+            &patches,
+            r#"{ "asobo-aircraft-tbm930": { "engines": { "GENERALENGINEDATA.static_thrust": "200" } } }"#,
+        )
+        .unwrap();
+
+        let args = Args::parse_from([
+            OsStr::new("patchcfg"),
+            OsStr::new("--force"),
+            OsStr::new("--track-changes"),
+            packages.as_os_str(),
+            patches.as_os_str(),
+        ]);
+        let log = Log::new(&args).unwrap();
+        let first = run(&args, &log).unwrap();
+        let backup = args.backup_path(&engines);
+        let backed_up = fs::metadata(&backup).unwrap().modified().unwrap();
+        let state = fs::read_to_string(state_path(&engines)).unwrap();
+        let second = run(&args, &log).unwrap();
+        let still_backed_up = fs::metadata(&backup).unwrap().modified().unwrap();
+        let stock = fs::read_to_string(&backup).unwrap();
+
+        let shown = |patch: &str, flags: &[&str]| {
+            let patch: Patch = serde_json::from_str(patch).unwrap();
+            let args = Args::parse_from(
+                ["patchcfg", "--color", "never"]
+                    .iter()
+                    .chain(flags)
+                    .chain(&["packages", "patches.json"]),
+            );
+            let diff = patch.diff(&package, &args, &mut Stats::default()).unwrap();
+            let mut out = Vec::new();
+            diff.show_changes(&args, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let retuned = r#"{ "engines": { "GENERALENGINEDATA.static_thrust": "250" } }"#;
+        let tracked = shown(retuned, &["--track-changes"]);
+        let untracked = shown(retuned, &[]);
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(first.files_changed, 1);
+        assert_eq!(
+            serde_json::from_str::<BTreeMap<String, String>>(&state).unwrap(),
+            BTreeMap::from([(
+                String::from("GENERALENGINEDATA.static_thrust"),
+                String::from("200")
+            )])
+        );
+        assert_eq!(second.files_changed, 0);
+        assert_eq!(second.keys_changed, 0);
+        assert_eq!(still_backed_up, backed_up);
+        assert_eq!(stock, "[GENERALENGINEDATA]\nstatic_thrust = 100\n");
+        assert_eq!(
+            tracked,
+            "Engine changes:\nGENERALENGINEDATA.static_thrust:\n\t 200 \n\t250\n\t(was 200 last run)\n"
+        );
+        assert!(!untracked.contains("last run"), "{untracked}");
+    }
+
+    #[test]
+    fn packages_match_any_of_their_titles() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-title-{}", process::id()));
+        let tbm = dir.join("asobo-aircraft-tbm930");
+        let plain = dir.join("asobo-aircraft-c152");
+        fs::create_dir_all(tbm.join("SimObjects/Airplanes/tbm")).unwrap();
+        fs::create_dir_all(&plain).unwrap();
+        fs::write(
+            tbm.join("SimObjects/Airplanes/tbm/aircraft.cfg"),
+            "[GENERAL]\ntitle = \"Not A Livery\"\n\n[FLTSIM.0]\ntitle = \"TBM 930 Asobo\"\n\n[FLTSIM.1]\ntitle = \"TBM 930 Blue\"\n",
+        )
+        .unwrap();
+        let patch = |json: &str| serde_json::from_str::<Patch>(json).unwrap();
+        let args = Args::parse_from(["patchcfg", "--match-title", "packages", "patches.json"]);
+
+        let matched = |names: &[&str]| {
+            let patches: HashMap<String, Patch> = names
+                .iter()
+                .map(|&name| (name.to_owned(), patch("{}")))
+                .collect();
+            let candidates = vec![tbm.clone(), plain.clone()];
+            match_packages(candidates, &patches, &args)
+                .map(|(path, patch)| {
+                    let name = patches
+                        .iter()
+                        .find(|(_, candidate)| std::ptr::eq(*candidate, patch))
+                        .map(|(name, _)| name.clone())
+                        .unwrap();
+                    (path, name)
+                })
+                .collect::<Vec<_>>()
+        };
+        let second = matched(&["TBM 930 Blue"]);
+        let both = matched(&["TBM 930 Blue", "TBM 930 Asobo"]);
+        let outside = matched(&["Not A Livery", "asobo-aircraft-c152"]);
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(second, [(tbm.clone(), String::from("TBM 930 Blue"))]);
+        assert_eq!(both, [(tbm, String::from("TBM 930 Asobo"))]);
+        assert!(outside.is_empty(), "{outside:?}");
+    }
+
+    #[test]
+    fn tampered_backup_fails_verification() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-verify-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let engines = dir.join("engines.cfg");
+        let stock = "[GENERALENGINEDATA]\nstatic_thrust = 100\n";
+        let args = Args::parse_from([
+            "patchcfg",
+            "--force",
+            "--verify-backup",
+            "packages",
+            "patches.json",
+        ]);
+        let mut patch = FilePatch::new();
+        patch.insert(
+            String::from("GENERALENGINEDATA.static_thrust"),
+            PatchValue::try_from(serde_json::json!("200")).unwrap(),
+        );
+
+        let verified = |tamper: bool| {
+            fs::write(&engines, stock).unwrap();
+            let diff = build_diff(&patch, engines.clone(), stock, "package", &args).unwrap();
+            write_modified_file(&diff, true, &args).unwrap();
+            if tamper {
+                fs::write(args.backup_path(&engines), "[GENERALENGINEDATA]\n").unwrap();
+            }
+            let verified = verify_backup(&diff, &args);
+            (verified, fs::read_to_string(&engines).unwrap())
+        };
+        let (intact, patched) = verified(false);
+        let (tampered, restored) = verified(true);
+        let _ = fs::remove_dir_all(&dir);
+
+        intact.unwrap();
+        assert_eq!(patched, "[GENERALENGINEDATA]\nstatic_thrust = 200 ;  100\n");
+        let tampered = tampered.unwrap_err().to_string();
+        assert!(
+            tampered.contains("failed verification: it does not match the original"),
+            "{tampered}"
+        );
+        assert_eq!(restored, stock);
+    }
+
+    #[test]
+    fn foreign_backup_is_refused() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-safe-{}", process::id()));
+        let packages = dir.join("packages");
+        let tbm = packages.join("asobo-aircraft-tbm930/SimObjects/Airplanes/tbm");
+        fs::create_dir_all(&tbm).unwrap();
+        let engines = tbm.join("engines.cfg");
+        let patches = dir.join("patches.json");
+        fs::write(&engines, "[GENERALENGINEDATA]\nstatic_thrust = 100\n").unwrap();
+        let run_with = |thrust: &str| {
+            fs::write(
+                &patches,
+                format!(
+                    r#"{{ "asobo-aircraft-tbm930": {{ "engines": {{ "GENERALENGINEDATA.static_thrust": "{thrust}" }} }} }}"#
+                ),
+            )
+            .unwrap();
+            let args = Args::parse_from([
+                OsStr::new("patchcfg"),
+                OsStr::new("--force"),
+                OsStr::new("--safe-backups"),
+                OsStr::new("--verify-backup"),
+                packages.as_os_str(),
+                patches.as_os_str(),
+            ]);
+            run(&args, &Log::new(&args).unwrap())
+        };
+
+        run_with("200").unwrap();
+        let backup = tbm.join("engines.bak.cfg");
+        fs::write(&backup, "[SOMETHING_ELSE]\nkey = 1\n").unwrap();
+        let patched = fs::read_to_string(&engines).unwrap();
+        let refused = run_with("250");
+        let after = fs::read_to_string(&engines).unwrap();
+        let kept = fs::read_to_string(&backup).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+
+        let refused = format!("{:#}", refused.unwrap_err());
+        assert!(refused.contains("isn't one (--safe-backups)"), "{refused}");
+        assert_eq!(after, patched);
+        assert_eq!(kept, "[SOMETHING_ELSE]\nkey = 1\n");
+    }
+
+    #[test]
+    fn both_comment_styles_survive_a_rewrite() {
+        let text = "[GENERAL]\na = 1 ; semicolon\nb = 2 // slashes\nc = 3 // slashes ; then semicolon\nd = 4 ; semicolon // then slashes\n";
+        let args = Args::parse_from([
+            "patchcfg",
+            "--no-preserve-original",
+            "packages",
+            "patches.json",
+        ]);
+        let mut patch = FilePatch::new();
+        for key in ["a", "b", "c", "d"] {
+            patch.insert(
+                format!("GENERAL.{key}"),
+                PatchValue::try_from(serde_json::json!("9")).unwrap(),
+            );
+        }
+
+        let delimiters: Vec<_> = text
+            .lines()
+            .skip(1)
+            .map(|line| comment_of(line).map(|comment| (comment.delimiter, comment.text)))
+            .collect();
+        let diff = build_diff(
+            &patch,
+            PathBuf::from("aircraft.cfg"),
+            text,
+            "package",
+            &args,
+        )
+        .unwrap();
+        let patched = String::from_utf8(render(&diff, &args).unwrap().0).unwrap();
+
+        assert_eq!(
+            delimiters,
+            [
+                Some((";", " semicolon")),
+                Some(("//", " slashes")),
+                Some(("//", " slashes ; then semicolon")),
+                Some((";", " semicolon // then slashes")),
+            ]
+        );
+        assert_eq!(
+            patched,
+            "[GENERAL]\na = 9 ;  semicolon\nb = 9 //  slashes\nc = 9 //  slashes ; then semicolon\nd = 9 ;  semicolon // then slashes\n"
+        );
+    }
+
+    #[test]
+    fn stats_block_lists_every_phase() {
+        let stats = Stats {
+            scanning: Duration::from_millis(1),
+            reading: Duration::from_millis(2),
+            diffing: Duration::from_millis(3),
+            writing: Duration::from_millis(4),
+        };
+        let mut out = Vec::new();
+        stats.print(Duration::from_millis(12), &mut out).unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "stats:\n    scanning  1ms\n    reading   2ms\n    diffing   3ms\n    writing   4ms\n    total     12ms\n"
+        );
+    }
+
+    #[test]
+    fn comment_only_patch_replaces_the_comment() {
+        let text = "[GENERALENGINEDATA]\nstatic_thrust = 100 ; stock\nmax_rpm = 2000\n";
+        let args = Args::parse_from(["patchcfg", "packages", "patches.json"]);
+        let mut patch = FilePatch::new();
+        for key in ["static_thrust", "max_rpm"] {
+            patch.insert(
+                format!("GENERALENGINEDATA.{key}"),
+                PatchValue::try_from(serde_json::json!({ "comment": "tuned by squadron X" }))
+                    .unwrap(),
+            );
+        }
+
+        let diff =
+            build_diff(&patch, PathBuf::from("engines.cfg"), text, "package", &args).unwrap();
+        let patched = String::from_utf8(render(&diff, &args).unwrap().0).unwrap();
+        let again = build_diff(
+            &patch,
+            PathBuf::from("engines.cfg"),
+            &patched,
+            "package",
+            &args,
+        )
+        .unwrap();
+
+        assert_eq!(
+            patched,
+            "[GENERALENGINEDATA]\nstatic_thrust = 100 ; tuned by squadron X\nmax_rpm = 2000 ; tuned by squadron X\n"
+        );
+        assert!(again.is_empty(), "{:?}", again.changes);
+    }
+
+    #[test]
+    fn preserve_format_controls_the_recorded_original() {
+        let text = "[GENERALENGINEDATA]\nstatic_thrust = 100 ; thrust\nmax_rpm = 2000\n";
+        let mut patch = FilePatch::new();
+        for (key, value) in [("static_thrust", "200"), ("max_rpm", "2100")] {
+            patch.insert(
+                format!("GENERALENGINEDATA.{key}"),
+                PatchValue::try_from(serde_json::json!(value)).unwrap(),
+            );
+        }
+        let patched = |flags: &[&str]| {
+            let args = Args::parse_from(
+                ["patchcfg"]
+                    .iter()
+                    .chain(flags)
+                    .chain(&["packages", "patches.json"]),
+            );
+            let diff =
+                build_diff(&patch, PathBuf::from("engines.cfg"), text, "package", &args).unwrap();
+            String::from_utf8(render(&diff, &args).unwrap().0).unwrap()
+        };
+
+        assert_eq!(
+            patched(&["--preserve-format", "was: {original}"]),
+            "[GENERALENGINEDATA]\nstatic_thrust = 200 ; was: 100 ;  thrust\nmax_rpm = 2100 ; was: 2000\n"
+        );
+        assert_eq!(
+            patched(&["--preserve-format", "[{original}]"]),
+            "[GENERALENGINEDATA]\nstatic_thrust = 200 ; [100] ;  thrust\nmax_rpm = 2100 ; [2000]\n"
+        );
+        assert_eq!(
+            patched(&["--no-preserve-original"]),
+            "[GENERALENGINEDATA]\nstatic_thrust = 200 ;  thrust\nmax_rpm = 2100\n"
+        );
+
+        let invalid = |template: &str| {
+            Args::try_parse_from([
+                "patchcfg",
+                "--preserve-format",
+                template,
+                "packages",
+                "patches.json",
+            ])
+            .unwrap_err()
+            .to_string()
+        };
+        assert!(invalid("was: {value}").contains("template must contain {original}"));
+        assert!(invalid("{original} {value}").contains("{original} is the only placeholder"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinked_directories_are_followed() {
+        use std::os::unix::fs::symlink;
+
+        let dir = std::env::temp_dir().join(format!("patchcfg-symlink-{}", process::id()));
+        let packages = dir.join("packages");
+        let package = packages.join("asobo-aircraft-tbm930");
+        let stored = dir.join("stored/tbm");
+        fs::create_dir_all(&stored).unwrap();
+        fs::create_dir_all(package.join("SimObjects/Airplanes")).unwrap();
+        fs::write(
+            stored.join("engines.cfg"),
+            "[GENERALENGINEDATA]\nstatic_thrust = 100\n",
+        )
+        .unwrap();
+        let link = package.join("SimObjects/Airplanes/tbm");
+        symlink(&stored, &link).unwrap();
+        // A link back up the tree would be a loop, if it were followed blindly.
+        symlink(&package, stored.join("loop")).unwrap();
+        let patches = dir.join("patches.json");
+        fs::write(
+            &patches,
+            r#"{ "asobo-aircraft-tbm930": { "engines": { "GENERALENGINEDATA.static_thrust": "200" } } }"#,
+        )
+        .unwrap();
+
+        let found = |flags: &[&str]| {
+            let args = Args::parse_from(
+                ["patchcfg"]
+                    .iter()
+                    .chain(flags)
+                    .chain(&["packages", "patches.json"]),
+            );
+            find_path(&package, "engines.cfg", &args)
+        };
+        let unfollowed = found(&[]);
+        let followed = found(&["--follow-symlinks"]);
+        let args = Args::parse_from([
+            OsStr::new("patchcfg"),
+            OsStr::new("--force"),
+            OsStr::new("--follow-symlinks"),
+            packages.as_os_str(),
+            patches.as_os_str(),
+        ]);
+        let summary = run(&args, &Log::new(&args).unwrap()).unwrap();
+        let patched = fs::read_to_string(stored.join("engines.cfg")).unwrap();
+        let still_linked = fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink();
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(unfollowed, None);
+        assert_eq!(followed, Some(link.join("engines.cfg")));
+        assert_eq!(summary.files_changed, 1);
+        assert_eq!(patched, "[GENERALENGINEDATA]\nstatic_thrust = 200 ;  100\n");
+        assert!(still_linked);
+    }
+
+    #[test]
+    fn bare_patch_applies_to_the_named_package() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-bare-{}", process::id()));
+        let packages = dir.join("packages");
+        let engines = |package: &str| {
+            packages
+                .join(package)
+                .join("SimObjects/Airplanes/plane/engines.cfg")
+        };
+        for package in ["asobo-aircraft-tbm930", "asobo-aircraft-c152"] {
+            fs::create_dir_all(engines(package).parent().unwrap()).unwrap();
+            fs::write(
+                engines(package),
+                "[GENERALENGINEDATA]\nstatic_thrust = 100\n",
+            )
+            .unwrap();
+        }
+        let patches = dir.join("patches.json");
+        let bare = r#"{ "engines": { "GENERALENGINEDATA.static_thrust": "200" } }"#;
+        fs::write(&patches, bare).unwrap();
+
+        let unnamed = read_patches(
+            &patches,
+            bare,
+            &Args::parse_from(["patchcfg", "packages", "patches.json"]),
+        );
+        let args = Args::parse_from([
+            OsStr::new("patchcfg"),
+            OsStr::new("--force"),
+            OsStr::new("--package"),
+            OsStr::new("asobo-aircraft-tbm930"),
+            packages.as_os_str(),
+            patches.as_os_str(),
+        ]);
+        let parsed = read_patches(&patches, bare, &args).unwrap();
+        let summary = run(&args, &Log::new(&args).unwrap()).unwrap();
+        let tbm = fs::read_to_string(engines("asobo-aircraft-tbm930")).unwrap();
+        let c152 = fs::read_to_string(engines("asobo-aircraft-c152")).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+
+        assert!(unnamed
+            .unwrap_err()
+            .to_string()
+            .ends_with("holds a single patch; name the package to apply it to with --package"));
+        assert_eq!(parsed.keys().collect::<Vec<_>>(), ["asobo-aircraft-tbm930"]);
+        assert_eq!(summary.files_changed, 1);
+        assert_eq!(tbm, "[GENERALENGINEDATA]\nstatic_thrust = 200 ;  100\n");
+        assert_eq!(c152, "[GENERALENGINEDATA]\nstatic_thrust = 100\n");
+    }
+
+    #[test]
+    fn mapped_patches_apply_to_renamed_folders() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-map-{}", process::id()));
+        let packages = dir.join("packages");
+        let engines = |package: &str| {
+            packages
+                .join(package)
+                .join("SimObjects/Airplanes/plane/engines.cfg")
+        };
+        for package in ["tbm-renamed", "tbm-copy"] {
+            fs::create_dir_all(engines(package).parent().unwrap()).unwrap();
+            fs::write(
+                engines(package),
+                "[GENERALENGINEDATA]\nstatic_thrust = 100\nmax_rpm = 2000\n",
+            )
+            .unwrap();
+        }
+        let patches = dir.join("patches.json");
+        fs::write(
+            &patches,
+            r#"{
+                "asobo-aircraft-tbm930": { "engines": { "GENERALENGINEDATA.static_thrust": "200" } },
+                "tbm-rpm": { "engines": { "GENERALENGINEDATA.max_rpm": "2100" } }
+            }"#,
+        )
+        .unwrap();
+        let map = dir.join("map.json");
+        fs::write(
+            &map,
+            r#"{ "asobo-aircraft-tbm930": ["tbm-renamed", "tbm-copy"], "tbm-rpm": "tbm-renamed" }"#,
+        )
+        .unwrap();
+
+        let args = Args::parse_from([
+            OsStr::new("patchcfg"),
+            OsStr::new("--force"),
+            OsStr::new("--no-preserve-original"),
+            OsStr::new("--map"),
+            map.as_os_str(),
+            packages.as_os_str(),
+            patches.as_os_str(),
+        ]);
+        let summary = run(&args, &Log::new(&args).unwrap()).unwrap();
+        let renamed = fs::read_to_string(engines("tbm-renamed")).unwrap();
+        let copy = fs::read_to_string(engines("tbm-copy")).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(summary.files_changed, 2);
+        assert_eq!(
+            renamed,
+            "[GENERALENGINEDATA]\nstatic_thrust = 200\nmax_rpm = 2100\n"
+        );
+        assert_eq!(
+            copy,
+            "[GENERALENGINEDATA]\nstatic_thrust = 200\nmax_rpm = 2000\n"
+        );
+
+        let patch = |thrust: &str| {
+            serde_json::from_str::<Patch>(&format!(
+                r#"{{ "engines": {{ "GENERALENGINEDATA.static_thrust": "{thrust}" }} }}"#
+            ))
+            .unwrap()
+        };
+        let conflicting = HashMap::from([
+            (String::from("a"), patch("200")),
+            (String::from("b"), patch("300")),
+        ]);
+        let map = HashMap::from([
+            (String::from("a"), Folders::One(String::from("tbm"))),
+            (String::from("b"), Folders::One(String::from("tbm"))),
+        ]);
+        let conflict = remap(conflicting, &map).unwrap_err().to_string();
+        assert!(
+            conflict.starts_with(
+                "conflicting patches for tbm engines.cfg GENERALENGINEDATA.static_thrust"
+            ),
+            "{conflict}"
+        );
+    }
+
+    #[test]
+    fn sim_version_gates_the_patch_file() {
+        let path = Path::new("patches.json");
+        let checked = |version: Option<&str>| {
+            let mut value = serde_json::json!({
+                "min_version": "1.30",
+                "max_version": "1.36.2",
+                "asobo-aircraft-tbm930": {}
+            });
+            let version = version.map(|version| version.parse::<Version>().unwrap());
+            check_version(path, &mut value, version.as_ref()).map(|()| value)
+        };
+
+        let unchecked = checked(None).unwrap();
+        assert_eq!(
+            unchecked,
+            serde_json::json!({ "asobo-aircraft-tbm930": {} })
+        );
+        checked(Some("1.30")).unwrap();
+        checked(Some("1.33.7")).unwrap();
+        checked(Some("1.36.2.0")).unwrap();
+        assert_eq!(
+            checked(Some("1.29.9")).unwrap_err().to_string(),
+            "patches.json requires sim version 1.30 or later (patching for 1.29.9)"
+        );
+        assert_eq!(
+            checked(Some("1.36.3")).unwrap_err().to_string(),
+            "patches.json requires sim version 1.36.2 or earlier (patching for 1.36.3)"
+        );
+
+        assert_eq!("1.36.0".parse::<Version>(), "1.36".parse());
+        assert!("1.36".parse::<Version>().unwrap() < "1.36.1".parse().unwrap());
+        assert!("1.4".parse::<Version>().unwrap() < "1.36".parse().unwrap());
+        assert_eq!(
+            "1.x".parse::<Version>(),
+            Err(String::from(
+                "expected a version like 1.36.2, found \"1.x\""
+            ))
+        );
+        let mut bad = serde_json::json!({ "min_version": "one" });
+        let bad = check_version(path, &mut bad, None).unwrap_err().to_string();
+        assert!(
+            bad.starts_with("patches.json: bad min_version \"one\""),
+            "{bad}"
+        );
+        let mut number = serde_json::json!({ "max_version": 1.36 });
+        assert_eq!(
+            check_version(path, &mut number, None)
+                .unwrap_err()
+                .to_string(),
+            "patches.json: max_version must be a string, not 1.36"
+        );
+    }
+
+    #[test]
+    fn unchanged_keys_are_told_from_missing_ones() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-unchanged-{}", process::id()));
+        let packages = dir.join("packages");
+        let tbm = packages.join("asobo-aircraft-tbm930/SimObjects/Airplanes/tbm");
+        fs::create_dir_all(&tbm).unwrap();
+        let engines = tbm.join("engines.cfg");
+        fs::write(
+            &engines,
+            "[GENERALENGINEDATA]\nstatic_thrust = 200\nmax_rpm = 2000\n",
+        )
+        .unwrap();
+        let patches = dir.join("patches.json");
+        fs::write(
+            &patches,
+            r#"{ "asobo-aircraft-tbm930": { "engines": {
+                "GENERALENGINEDATA.static_thrust": "200",
+                "GENERALENGINEDATA.max_rpm": "2100",
+                "GENERALENGINEDATA.no_such_key": "1"
+            } } }"#,
+        )
+        .unwrap();
+        let log_file = dir.join("patchcfg.log");
+
+        let logged = |flags: &[&str]| {
+            let mut argv = vec![OsStr::new("patchcfg"), OsStr::new("--log-file")];
+            argv.push(log_file.as_os_str());
+            argv.extend(flags.iter().map(OsStr::new));
+            argv.extend([packages.as_os_str(), patches.as_os_str()]);
+            let args = Args::parse_from(argv);
+            run(&args, &Log::new(&args).unwrap()).unwrap();
+            fs::read_to_string(&log_file).unwrap()
+        };
+        let printed = logged(&["--print-unchanged"]);
+        let quiet = logged(&[]);
+        let _ = fs::remove_dir_all(&dir);
+
+        let prefix = format!("asobo-aircraft-tbm930: {}: patch key", engines.display());
+        let unchanged = format!("info: {prefix} GENERALENGINEDATA.static_thrust already 200");
+        let missing = format!("warning: {prefix} GENERALENGINEDATA.no_such_key not found");
+        assert!(printed.lines().any(|line| line == unchanged), "{printed}");
+        assert!(printed.lines().any(|line| line == missing), "{printed}");
+        assert!(!printed.contains("max_rpm already"), "{printed}");
+        assert!(!quiet.contains("already"), "{quiet}");
+        assert!(quiet.lines().any(|line| line == missing), "{quiet}");
+    }
+
+    #[test]
+    fn tags_select_the_keys_applied() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-tags-{}", process::id()));
+        let packages = dir.join("packages");
+        let tbm = packages.join("asobo-aircraft-tbm930/SimObjects/Airplanes/tbm");
+        fs::create_dir_all(&tbm).unwrap();
+        let engines = tbm.join("engines.cfg");
+        let stock = "[GENERALENGINEDATA]\nstatic_thrust = 100\nmax_rpm = 2000\nmin_throttle = 0\n";
+        let patches = dir.join("patches.json");
+        fs::write(
+            &patches,
+            r#"{ "asobo-aircraft-tbm930": { "engines": {
+                "GENERALENGINEDATA.static_thrust": { "value": "200", "tags": ["performance"] },
+                "GENERALENGINEDATA.max_rpm": { "value": "2100", "tags": ["realism", "performance"] },
+                "GENERALENGINEDATA.min_throttle": "-0.1"
+            } } }"#,
+        )
+        .unwrap();
+
+        let patched = |flags: &[&str]| {
+            fs::write(&engines, stock).unwrap();
+            let mut argv: Vec<_> = ["patchcfg", "--force", "--no-preserve-original"]
+                .iter()
+                .chain(flags)
+                .map(OsStr::new)
+                .collect();
+            argv.extend([packages.as_os_str(), patches.as_os_str()]);
+            let args = Args::parse_from(argv);
+            run(&args, &Log::new(&args).unwrap()).unwrap();
+            fs::read_to_string(&engines).unwrap()
+        };
+        let realism = patched(&["--tag", "realism"]);
+        let performance = patched(&["--tag", "@performance"]);
+        let either = patched(&["--tag", "realism", "--tag", "nothing"]);
+        let all = patched(&[]);
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(
+            realism,
+            "[GENERALENGINEDATA]\nstatic_thrust = 100\nmax_rpm = 2100\nmin_throttle = 0\n"
+        );
+        assert_eq!(
+            performance,
+            "[GENERALENGINEDATA]\nstatic_thrust = 200\nmax_rpm = 2100\nmin_throttle = 0\n"
+        );
+        assert_eq!(either, realism);
+        assert_eq!(
+            all,
+            "[GENERALENGINEDATA]\nstatic_thrust = 200\nmax_rpm = 2100\nmin_throttle = -0.1\n"
+        );
+    }
+
+    #[test]
+    fn pruned_directories_are_not_searched() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-prune-{}", process::id()));
+        let tbm = dir.join("SimObjects/Airplanes/tbm");
+        for sub in ["Model.AS", "TEXTURE.blue", "extras"] {
+            fs::create_dir_all(tbm.join(sub)).unwrap();
+            fs::write(tbm.join(sub).join(format!("{sub}.cfg")), "").unwrap();
+        }
+        let found = |name: &str, flags: &[&str]| {
+            let args = Args::parse_from(
+                ["patchcfg"]
+                    .iter()
+                    .chain(flags)
+                    .chain(&["packages", "patches.json"]),
+            );
+            find_path(&dir, name, &args)
+        };
+
+        let defaults = [
+            found("Model.AS.cfg", &[]),
+            found("TEXTURE.blue.cfg", &[]),
+            found("extras.cfg", &[]),
+        ];
+        let unpruned = found("Model.AS.cfg", &["--no-prune"]);
+        let custom = [
+            found("Model.AS.cfg", &["--prune", "ext*"]),
+            found("extras.cfg", &["--prune", "ext*"]),
+        ];
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(defaults, [None, None, Some(tbm.join("extras/extras.cfg"))]);
+        assert_eq!(unpruned, Some(tbm.join("Model.AS/Model.AS.cfg")));
+        assert_eq!(custom, [Some(tbm.join("Model.AS/Model.AS.cfg")), None]);
+    }
+
+    #[test]
+    fn overlay_holds_only_the_changes() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-overlay-{}", process::id()));
+        let packages = dir.join("packages");
+        let tbm = packages.join("asobo-aircraft-tbm930/SimObjects/Airplanes/tbm");
+        fs::create_dir_all(&tbm).unwrap();
+        let stock = "[GENERALENGINEDATA]\nstatic_thrust = 100 ; thrust\nmax_rpm = 2000\nmin_throttle = 0\n\n[TURBINEENGINEDATA]\nfuel_flow = 1\nitt_peak = 800\n";
+        fs::write(tbm.join("engines.cfg"), stock).unwrap();
+        let patches = dir.join("patches.json");
+        fs::write(
+            &patches,
+            r#"{ "asobo-aircraft-tbm930": { "engines": {
+                "GENERALENGINEDATA.static_thrust": "200",
+                "GENERALENGINEDATA.max_rpm": "2000",
+                "TURBINEENGINEDATA.itt_peak": "850"
+            } } }"#,
+        )
+        .unwrap();
+        let overlays = dir.join("overlays");
+
+        let args = Args::parse_from([
+            OsStr::new("patchcfg"),
+            OsStr::new("--overlay-out"),
+            overlays.as_os_str(),
+            packages.as_os_str(),
+            patches.as_os_str(),
+        ]);
+        run(&args, &Log::new(&args).unwrap()).unwrap();
+        let overlay = fs::read_to_string(
+            overlays.join("asobo-aircraft-tbm930/SimObjects/Airplanes/tbm/engines.cfg"),
+        );
+        let base = fs::read_to_string(tbm.join("engines.cfg")).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(
+            overlay.unwrap(),
+            "[GENERALENGINEDATA]\nstatic_thrust = 200\n\n[TURBINEENGINEDATA]\nitt_peak = 850\n"
+        );
+        assert_eq!(base, stock);
+    }
+
+    #[test]
+    fn no_escapes_leak_without_color() {
+        let mut patch = FilePatch::new();
+        patch.insert(
+            String::from("GENERALENGINEDATA.static_thrust"),
+            PatchValue::try_from(serde_json::json!("200")).unwrap(),
+        );
+        let shown = |color: &str| {
+            let args = Args::parse_from(["patchcfg", "--color", color, "packages", "patches.json"]);
+            let text = "[GENERALENGINEDATA]\nstatic_thrust = 100\n";
+            let mut diff = Diff::default();
+            *diff.file_mut(Target::find("engines").unwrap()) =
+                build_diff(&patch, PathBuf::from("engines.cfg"), text, "package", &args).unwrap();
+            let mut out = Vec::new();
+            diff.show_changes(&args, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        let never = shown("never");
+        assert_eq!(
+            never,
+            "Engine changes:\nGENERALENGINEDATA.static_thrust:\n\t 100\n\t200\n"
+        );
+        assert!(shown("always").contains("\x1b[32m200\x1b[0m"));
+    }
+
+    #[test]
+    fn section_body_is_replaced_wholesale() {
+        let text = "[GENERAL]\nname = tbm\n\n[FLIGHT_TUNING]\npitch = 1 ; stock\nroll = 1\n\n[FUEL]\nsize = 3\n";
+        let args = Args::parse_from(["patchcfg", "packages", "patches.json"]);
+        let diff = |json: serde_json::Value| {
+            let patch: FilePatch = serde_json::from_value(json).unwrap();
+            build_diff(
+                &patch,
+                PathBuf::from("flight_model.cfg"),
+                text,
+                "package",
+                &args,
+            )
+        };
+
+        let replaced = diff(serde_json::json!({
+            "[FLIGHT_TUNING]": ["pitch = 2", "yaw = 3"],
+            "FUEL.size": "4"
+        }))
+        .unwrap();
+        let patched = String::from_utf8(render(&replaced, &args).unwrap().0).unwrap();
+        assert_eq!(
+            patched,
+            "[GENERAL]\nname = tbm\n\n[FLIGHT_TUNING]\npitch = 2\nyaw = 3\n\n[FUEL]\nsize = 4 ;  3\n"
+        );
+        let block = replaced.blocks.values().next().unwrap();
+        assert_eq!(block.old, ["pitch = 1 ; stock", "roll = 1"]);
+
+        let both = diff(serde_json::json!({
+            "[FLIGHT_TUNING]": ["pitch = 2"],
+            "FLIGHT_TUNING.roll": "2"
+        }))
+        .unwrap_err()
+        .to_string();
+        assert_eq!(
+            both,
+            "patch key FLIGHT_TUNING.roll is in section [FLIGHT_TUNING], which the patch replaces"
+        );
+        let not_lines = diff(serde_json::json!({ "[FLIGHT_TUNING]": "2" }))
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            not_lines,
+            "patch key [FLIGHT_TUNING] replaces a section, so it needs a list of lines"
+        );
+    }
+
+    #[test]
+    fn package_list_is_read_from_stdin() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-stdin-{}", process::id()));
+        let (tbm, c152) = (
+            dir.join("asobo-aircraft-tbm930"),
+            dir.join("asobo-aircraft-c152"),
+        );
+        fs::create_dir_all(&tbm).unwrap();
+        fs::create_dir_all(&c152).unwrap();
+        let list = dir.join("packages.txt");
+        fs::write(&list, format!("{}\n", c152.display())).unwrap();
+        let stdin = format!(
+            "{}\n\n  {}  \n{}\n",
+            tbm.display(),
+            c152.display(),
+            dir.join("not-there").display()
+        );
+        let args = Args::parse_from(["patchcfg", "--packages-from", "-", "patches.json"]);
+        let log = Log::new(&args).unwrap();
+
+        let from_stdin = read_package_list("-", stdin.as_bytes(), &log);
+        let from_file = read_package_list(&list.to_string_lossy(), &b"ignored"[..], &log);
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(from_stdin.unwrap(), [tbm, c152.clone()]);
+        assert_eq!(log.warnings(), 1);
+        assert_eq!(from_file.unwrap(), [c152]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn post_hook_sees_the_run() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-hook-{}", process::id()));
+        let packages = dir.join("packages");
+        let tbm = packages.join("asobo-aircraft-tbm930/SimObjects/Airplanes/tbm");
+        fs::create_dir_all(&tbm).unwrap();
+        fs::write(
+            tbm.join("engines.cfg"),
+            "[GENERALENGINEDATA]\nstatic_thrust = 100\n",
+        )
+        .unwrap();
+        let patches = dir.join("patches.json");
+        fs::write(
+            &patches,
+            r#"{ "asobo-aircraft-tbm930": { "engines": { "GENERALENGINEDATA.static_thrust": "200" } } }"#,
+        )
+        .unwrap();
+        let env = dir.join("env");
+
+        let hooked = |hook: &str| {
+            let args = Args::parse_from([
+                OsStr::new("patchcfg"),
+                OsStr::new("--force"),
+                OsStr::new("--post-hook"),
+                OsStr::new(hook),
+                packages.as_os_str(),
+                patches.as_os_str(),
+            ]);
+            run(&args, &Log::new(&args).unwrap())
+        };
+        let summary = hooked(&format!(
+            "env | grep ^PATCHCFG_ | sort > '{}'",
+            env.display()
+        ));
+        let env = fs::read_to_string(&env);
+        let failed = hooked("exit 3");
+        let _ = fs::remove_dir_all(&dir);
+
+        let summary = summary.unwrap();
+        assert_eq!(
+            env.unwrap(),
+            format!(
+                "PATCHCFG_DRY_RUN=0\nPATCHCFG_FILES_CHANGED=1\nPATCHCFG_KEYS_CHANGED=1\n\
+                 PATCHCFG_PACKAGES_MATCHED=1\nPATCHCFG_RUN_ID={}\nPATCHCFG_SUCCESS=1\n",
+                summary.run_id
+            )
+        );
+        let failed = failed.unwrap_err().to_string();
+        assert!(
+            failed.starts_with("post-hook \"exit 3\" failed"),
+            "{failed}"
+        );
+    }
+
+    #[test]
+    fn one_livery_is_patched_by_index() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-fltsim-{}", process::id()));
+        let package = dir.join("asobo-aircraft-tbm930");
+        fs::create_dir_all(&package).unwrap();
+        let mut stock = String::from("[VERSION]\nmajor = 1\n");
+        for n in 0..12 {
+            stock += &format!("\n[FLTSIM.{n}]\ntitle = \"TBM 930 {n}\"\natc_id = \"N{n}\"\n");
+        }
+        fs::write(package.join("aircraft.cfg"), &stock).unwrap();
+        let patch: Patch = serde_json::from_str(
+            r#"{ "aircraft": { "FLTSIM.1.atc_id": "\"N930TB\"", "FLTSIM.10.title": "\"TBM 930 Ten\"" } }"#,
+        )
+        .unwrap();
+        let args = Args::parse_from([
+            "patchcfg",
+            "--no-preserve-original",
+            "packages",
+            "patches.json",
+        ]);
+
+        let diff = patch.diff(&package, &args, &mut Stats::default()).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        let (target, file) = diff.files()[0];
+        let patched = String::from_utf8(render(file, &args).unwrap().0).unwrap();
+
+        assert_eq!(target, Target::find("aircraft").unwrap());
+        let expected = stock
+            .replace("atc_id = \"N1\"", "atc_id = \"N930TB\"")
+            .replace("title = \"TBM 930 10\"", "title = \"TBM 930 Ten\"");
+        assert_eq!(patched, expected);
+        assert_eq!(file.changes.len(), 2);
+    }
+
+    #[test]
+    fn assume_yes_answers_the_prompt() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-yes-{}", process::id()));
+        let packages = dir.join("packages");
+        let tbm = packages.join("asobo-aircraft-tbm930/SimObjects/Airplanes/tbm");
+        fs::create_dir_all(&tbm).unwrap();
+        let engines = tbm.join("engines.cfg");
+        let stock = "[GENERALENGINEDATA]\nstatic_thrust = 100\n";
+        let patches = dir.join("patches.json");
+        fs::write(
+            &patches,
+            r#"{ "asobo-aircraft-tbm930": { "engines": { "GENERALENGINEDATA.static_thrust": "200" } } }"#,
+        )
+        .unwrap();
+
+        // Were the prompt not bypassed, this would wait on stdin.
+        let patched = |flags: &[&str]| {
+            fs::write(&engines, stock).unwrap();
+            let mut argv: Vec<_> = ["patchcfg"].iter().chain(flags).map(OsStr::new).collect();
+            argv.extend([packages.as_os_str(), patches.as_os_str()]);
+            let args = Args::parse_from(argv);
+            let summary = run(&args, &Log::new(&args).unwrap()).unwrap();
+            (summary.files_changed, fs::read_to_string(&engines).unwrap())
+        };
+        let confirmed = patched(&["--force", "--confirm", "-y"]);
+        let long = patched(&["--force", "--confirm", "--assume-yes"]);
+        let preview = patched(&["-y"]);
+        let _ = fs::remove_dir_all(&dir);
+
+        let written = (
+            1,
+            String::from("[GENERALENGINEDATA]\nstatic_thrust = 200 ;  100\n"),
+        );
+        assert_eq!(confirmed, written);
+        assert_eq!(long, written);
+        assert_eq!(preview.1, stock);
+        assert!(
+            Args::try_parse_from(["patchcfg", "--confirm", "packages", "patches.json"]).is_err()
+        );
+    }
+
+    #[test]
+    fn default_is_used_only_for_absent_keys() {
+        let text =
+            "[GENERALENGINEDATA]\nstatic_thrust = 200\n\n[TURBINEENGINEDATA]\nfuel_flow = 1\n";
+        let args = Args::parse_from([
+            "patchcfg",
+            "--no-preserve-original",
+            "packages",
+            "patches.json",
+        ]);
+        let diff = |json: serde_json::Value| {
+            let patch: FilePatch = serde_json::from_value(json).unwrap();
+            build_diff(&patch, PathBuf::from("engines.cfg"), text, "package", &args)
+        };
+
+        let scaled = diff(serde_json::json!({
+            "GENERALENGINEDATA.static_thrust": { "value": "+10%", "default": "100" },
+            "GENERALENGINEDATA.max_rpm": { "value": "+10%", "default": "2000" },
+            "GENERALENGINEDATA.min_throttle": { "default": "-0.1" }
+        }))
+        .unwrap();
+        let patched = String::from_utf8(render(&scaled, &args).unwrap().0).unwrap();
+        assert_eq!(
+            patched,
+            "[GENERALENGINEDATA]\nstatic_thrust = 220\nmax_rpm = 2200\nmin_throttle = -0.1\n\n[TURBINEENGINEDATA]\nfuel_flow = 1\n"
+        );
+        assert!(scaled.missing.is_empty());
+
+        let unsectioned = diff(serde_json::json!({ "max_rpm": { "default": "2000" } }))
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            unsectioned,
+            "patch key max_rpm has a default, so it needs a section to be added to"
+        );
+    }
+
+    #[test]
+    fn context_shows_the_lines_around_a_change() {
+        let text = "[GENERALENGINEDATA]\nengine_type = 5\nmin_throttle = 0\nstatic_thrust = 100 ; thrust\nmax_rpm = 2000\nfuel_flow = 1\nitt_peak = 800\n";
+        let mut patch = FilePatch::new();
+        for (key, value) in [("engine_type", "1"), ("static_thrust", "200")] {
+            patch.insert(
+                format!("GENERALENGINEDATA.{key}"),
+                PatchValue::try_from(serde_json::json!(value)).unwrap(),
+            );
+        }
+        let shown = |context: &str| {
+            let args = Args::parse_from([
+                "patchcfg",
+                "--color",
+                "never",
+                "--context",
+                context,
+                "packages",
+                "patches.json",
+            ]);
+            let mut diff = Diff::default();
+            *diff.file_mut(Target::find("engines").unwrap()) =
+                build_diff(&patch, PathBuf::from("engines.cfg"), text, "package", &args).unwrap();
+            let mut out = Vec::new();
+            diff.show_changes(&args, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(
+            shown("2"),
+            "Engine changes:\n\
+             GENERALENGINEDATA.engine_type:\n\
+             \t   1  [GENERALENGINEDATA]\n\
+             \t   2- engine_type = 5\n\
+             \t   2+ engine_type = 1 ;  5\n\
+             \t   3  min_throttle = 0\n\
+             \t   4  static_thrust = 100 ; thrust\n\
+             GENERALENGINEDATA.static_thrust:\n\
+             \t   2  engine_type = 5\n\
+             \t   3  min_throttle = 0\n\
+             \t   4- static_thrust = 100 ; thrust\n\
+             \t   4+ static_thrust = 200 ;  100  ;  thrust\n\
+             \t   5  max_rpm = 2000\n\
+             \t   6  fuel_flow = 1\n"
+        );
+        assert_eq!(
+            shown("0"),
+            "Engine changes:\nGENERALENGINEDATA.engine_type:\n\t 5\n\t1\nGENERALENGINEDATA.static_thrust:\n\t 100 \n\t200\n"
+        );
+    }
+
+    #[test]
+    fn locked_packages_are_left_alone() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-locked-{}", process::id()));
+        let packages = dir.join("packages");
+        let tbm = packages.join("asobo-aircraft-tbm930/SimObjects/Airplanes/tbm");
+        fs::create_dir_all(&tbm).unwrap();
+        let engines = tbm.join("engines.cfg");
+        let stock = "[GENERALENGINEDATA]\nstatic_thrust = 100\n";
+        fs::write(&engines, stock).unwrap();
+        let patches = dir.join("patches.json");
+        fs::write(
+            &patches,
+            r#"{ "asobo-aircraft-tbm930": { "engines": { "GENERALENGINEDATA.static_thrust": "200" } } }"#,
+        )
+        .unwrap();
+        let run_with = |flags: &[&str]| {
+            let mut argv: Vec<_> = ["patchcfg"].iter().chain(flags).map(OsStr::new).collect();
+            argv.extend([packages.as_os_str(), patches.as_os_str()]);
+            let args = Args::parse_from(argv);
+            run(&args, &Log::new(&args).unwrap())
+        };
+
+        let args = Args::parse_from(["patchcfg", "packages", "patches.json"]);
+        let lock = Lock::acquire(
+            packages.join(".patchcfg.lock"),
+            Ulid::new(),
+            &Log::new(&args).unwrap(),
+        )
+        .unwrap();
+        let locked = run_with(&["--force"]);
+        let preview = run_with(&[]);
+        let after = fs::read_to_string(&engines).unwrap();
+        let backed_up = args.backup_path(&engines).exists();
+        drop(lock);
+        let unlocked = run_with(&["--force"]);
+        let _ = fs::remove_dir_all(&dir);
+
+        let locked = locked.unwrap_err().to_string();
+        assert!(locked.starts_with("another run holds the lock"), "{locked}");
+        preview.unwrap();
+        assert_eq!(after, stock);
+        assert!(!backed_up);
+        assert_eq!(unlocked.unwrap().files_changed, 1);
+    }
+
+    #[test]
+    fn patch_files_take_precedence_over_defaults() {
+        let defaults = r#"{ "asobo-aircraft-tbm930": { "engines": {
+            "GENERALENGINEDATA.static_thrust": "200",
+            "GENERALENGINEDATA.max_rpm": "2100"
+        } } }"#;
+        let given = r#"{ "asobo-aircraft-tbm930": { "engines": {
+            "GENERALENGINEDATA.static_thrust": "250"
+        } }, "asobo-aircraft-c152": { "engines": { "GENERALENGINEDATA.max_rpm": "2700" } } }"#;
+        let args = Args::parse_from(["patchcfg", "packages", "patches.json"]);
+        let log = Log::new(&args).unwrap();
+
+        let value = |patches: &HashMap<String, Patch>, package: &str, key: &str| {
+            patches[package]
+                .file(Target::find("engines").unwrap())?
+                .get(key)?
+                .value
+                .clone()
+        };
+        let sources = [
+            (Path::new("(defaults)"), defaults.to_owned()),
+            (Path::new("patches.json"), given.to_owned()),
+        ];
+        let merged = merge_patches(&sources, &args, &log).unwrap();
+        let alone = merge_patches(&sources[..1], &args, &log).unwrap();
+
+        let thrust = "GENERALENGINEDATA.static_thrust";
+        let rpm = "GENERALENGINEDATA.max_rpm";
+        assert_eq!(
+            value(&alone, "asobo-aircraft-tbm930", thrust).as_deref(),
+            Some("200")
+        );
+        assert_eq!(
+            value(&merged, "asobo-aircraft-tbm930", thrust).as_deref(),
+            Some("250")
+        );
+        assert_eq!(
+            value(&merged, "asobo-aircraft-tbm930", rpm).as_deref(),
+            Some("2100")
+        );
+        assert_eq!(
+            value(&merged, "asobo-aircraft-c152", rpm).as_deref(),
+            Some("2700")
+        );
+    }
+
+    #[cfg(feature = "defaults")]
+    #[test]
+    fn embedded_defaults_are_patches() {
+        let args = Args::parse_from(["patchcfg", "packages"]);
+        read_patches(Path::new("(defaults)"), DEFAULTS.unwrap(), &args).unwrap();
+    }
+
+    #[test]
+    fn empty_runs_say_why() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-empty-{}", process::id()));
+        let packages = dir.join("packages");
+        for package in ["asobo-aircraft-tbm930", "asobo-aircraft-c152"] {
+            fs::create_dir_all(packages.join(package)).unwrap();
+        }
+        let patches = dir.join("patches.json");
+        let log_file = dir.join("patchcfg.log");
+        let logged = |patch: &str| {
+            fs::write(&patches, patch).unwrap();
+            let args = Args::parse_from([
+                OsStr::new("patchcfg"),
+                OsStr::new("--log-file"),
+                log_file.as_os_str(),
+                packages.as_os_str(),
+                patches.as_os_str(),
+            ]);
+            let log = Log::new(&args).unwrap();
+            run(&args, &log).unwrap();
+            (log.warnings(), fs::read_to_string(&log_file).unwrap())
+        };
+        let (empty_warnings, empty) = logged("{}");
+        let (unmatched_warnings, unmatched) = logged(
+            r#"{ "some-other-package": { "engines": { "GENERALENGINEDATA.static_thrust": "200" } } }"#,
+        );
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(empty_warnings, 0);
+        assert!(
+            empty
+                .lines()
+                .any(|line| line == "info: patch file contains no packages"),
+            "{empty}"
+        );
+        assert_eq!(unmatched_warnings, 0);
+        assert!(
+            unmatched
+                .lines()
+                .any(|line| line == "info: no packages matched any patch (scanned 2 directories)"),
+            "{unmatched}"
+        );
+        assert!(!unmatched.contains("contains no packages"), "{unmatched}");
+    }
+
+    #[test]
+    fn placeholders_are_filled_in() {
+        let text = "[FLTSIM.0]\nui_variation = \"Stock\"\n\n[FLTSIM.1]\nui_variation = \"Blue\"\n";
+        let args = Args::parse_from([
+            "patchcfg",
+            "--no-preserve-original",
+            "packages",
+            "patches.json",
+        ]);
+        let diff = |value: &str| {
+            let mut patch = FilePatch::new();
+            patch.insert(
+                String::from("ui_variation"),
+                PatchValue::try_from(serde_json::json!(value)).unwrap(),
+            );
+            build_diff(
+                &patch,
+                PathBuf::from("aircraft.cfg"),
+                text,
+                "asobo-aircraft-tbm930",
+                &args,
+            )
+        };
+
+        let expanded = diff("\"{package} {index} {{custom}}\"").unwrap();
+        let patched = String::from_utf8(render(&expanded, &args).unwrap().0).unwrap();
+        assert_eq!(
+            patched,
+            "[FLTSIM.0]\nui_variation = \"asobo-aircraft-tbm930 0 {custom}\"\n\n[FLTSIM.1]\nui_variation = \"asobo-aircraft-tbm930 1 {custom}\"\n"
+        );
+
+        assert_eq!(expand("{package}", "tbm", None).unwrap(), "tbm");
+        assert_eq!(expand("plain", "tbm", None).unwrap(), "plain");
+        let error = |value: &str, section| expand(value, "tbm", section).unwrap_err().to_string();
+        assert_eq!(
+            error("{livery}", None),
+            "unknown placeholder {livery} in {livery} (expected {package} or {index})"
+        );
+        assert_eq!(
+            error("{index}", Some("GENERAL")),
+            "{index} uses {index}, but section [GENERAL] has no index"
+        );
+        assert_eq!(
+            error("{package", None),
+            "unmatched brace in {package (use {{ or }} for a literal brace)"
+        );
+        assert!(diff("{livery}")
+            .unwrap_err()
+            .to_string()
+            .contains("ui_variation"));
+    }
+
+    #[test]
+    fn inline_settings_patch_without_a_file() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-set-{}", process::id()));
+        let packages = dir.join("packages");
+        let tbm = packages.join("asobo-aircraft-tbm930/SimObjects/Airplanes/tbm");
+        fs::create_dir_all(&tbm).unwrap();
+        let flight_model = tbm.join("flight_model.cfg");
+        let stock = "[FLIGHT_TUNING]\nlift_scalar = 1\ndrag_scalar = 1\n";
+        let patches = dir.join("patches.json");
+        fs::write(
+            &patches,
+            r#"{ "asobo-aircraft-tbm930": { "flight_model": {
+                "FLIGHT_TUNING.lift_scalar": "1.2",
+                "FLIGHT_TUNING.drag_scalar": "0.9"
+            } } }"#,
+        )
+        .unwrap();
+
+        let patched = |with_file: bool| {
+            fs::write(&flight_model, stock).unwrap();
+            let mut argv = vec![
+                OsStr::new("patchcfg"),
+                OsStr::new("--force"),
+                OsStr::new("--no-preserve-original"),
+                OsStr::new("--set"),
+                OsStr::new("asobo-aircraft-tbm930:flight_model:FLIGHT_TUNING.lift_scalar=1.1"),
+                packages.as_os_str(),
+            ];
+            if with_file {
+                argv.push(patches.as_os_str());
+            }
+            let args = Args::parse_from(argv);
+            run(&args, &Log::new(&args).unwrap()).unwrap();
+            fs::read_to_string(&flight_model).unwrap()
+        };
+        let alone = patched(false);
+        let over_file = patched(true);
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(
+            alone,
+            "[FLIGHT_TUNING]\nlift_scalar = 1.1\ndrag_scalar = 1\n"
+        );
+        assert_eq!(
+            over_file,
+            "[FLIGHT_TUNING]\nlift_scalar = 1.1\ndrag_scalar = 0.9\n"
+        );
+
+        let error = |set: &str| {
+            Args::try_parse_from(["patchcfg", "--set", set, "packages"])
+                .unwrap_err()
+                .to_string()
+        };
+        assert!(error("tbm:flight_model:lift_scalar")
+            .contains("expected <package>:<file>:<key>=<value>"));
+        assert!(error("tbm=1").contains("expected <package>:<file>:<key>=<value>"));
+        assert!(error(" :flight_model:lift_scalar=1").contains("package must not be empty"));
+        assert!(error("tbm:flight_model: =1").contains("key must not be empty"));
+    }
+
+    #[test]
+    fn explain_gives_a_reason_for_each_key() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-explain-{}", process::id()));
+        let packages = dir.join("packages");
+        let tbm = packages.join("asobo-aircraft-tbm930/SimObjects/Airplanes/tbm");
+        fs::create_dir_all(&tbm).unwrap();
+        let engines = tbm.join("engines.cfg");
+        fs::write(
+            &engines,
+            "[GENERALENGINEDATA]\nstatic_thrust = 100\nmax_rpm = 2000\n",
+        )
+        .unwrap();
+        let patches = dir.join("patches.json");
+        fs::write(
+            &patches,
+            r#"{ "asobo-aircraft-tbm930": {
+                "engines": {
+                    "GENERALENGINEDATA.static_thrust": "200",
+                    "GENERALENGINEDATA.max_rpm": "2000",
+                    "GENERALENGINEDATA.no_such_key": "1"
+                },
+                "flight_model": { "FLIGHT_TUNING.lift_scalar": "1.1" }
+            } }"#,
+        )
+        .unwrap();
+        let log_file = dir.join("patchcfg.log");
+
+        let args = Args::parse_from([
+            OsStr::new("patchcfg"),
+            OsStr::new("--explain"),
+            OsStr::new("--log-file"),
+            log_file.as_os_str(),
+            packages.as_os_str(),
+            patches.as_os_str(),
+        ]);
+        run(&args, &Log::new(&args).unwrap()).unwrap();
+        let logged = fs::read_to_string(&log_file).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+
+        let prefix = format!("info: asobo-aircraft-tbm930: {}:", engines.display());
+        let explained: Vec<_> = logged
+            .lines()
+            .filter(|line| line.starts_with("info: asobo-aircraft-tbm930: "))
+            .collect();
+        assert_eq!(
+            explained,
+            [
+                format!("{prefix} GENERALENGINEDATA.static_thrust: matched [GENERALENGINEDATA] line 2, value 100 != 200, will change"),
+                format!("{prefix} GENERALENGINEDATA.max_rpm: matched [GENERALENGINEDATA] line 3, already 2000, skipped"),
+                format!("{prefix} GENERALENGINEDATA.no_such_key: not found in file"),
+                String::from("info: asobo-aircraft-tbm930: flight_model.cfg: FLIGHT_TUNING.lift_scalar: file not found"),
+            ]
+        );
+    }
+
+    #[test]
+    fn root_scope_keys_are_patched_alone() {
+        let text = "version = 1\nname = root\n\n[GENERAL]\nversion = 1\n";
+        let args = Args::parse_from([
+            "patchcfg",
+            "--no-preserve-original",
+            "packages",
+            "patches.json",
+        ]);
+        let mut patch = FilePatch::new();
+        patch.insert(
+            String::from(".version"),
+            PatchValue::try_from(serde_json::json!("2")).unwrap(),
+        );
+
+        let diff = build_diff(
+            &patch,
+            PathBuf::from("aircraft.cfg"),
+            text,
+            "package",
+            &args,
+        )
+        .unwrap();
+        let patched = String::from_utf8(render(&diff, &args).unwrap().0).unwrap();
+        assert_eq!(
+            patched,
+            "version = 2\nname = root\n\n[GENERAL]\nversion = 1\n"
+        );
+
+        let unrooted = build_diff(
+            &patch,
+            PathBuf::from("aircraft.cfg"),
+            "[GENERAL]\nversion = 1\n",
+            "package",
+            &args,
+        )
+        .unwrap();
+        assert!(unrooted.is_empty());
+        assert_eq!(unrooted.missing, [".version"]);
+    }
+
+    #[test]
+    fn backup_space_counts_the_files_to_back_up() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-space-{}", process::id()));
+        let packages = dir.join("packages");
+        let tbm = packages.join("asobo-aircraft-tbm930");
+        let c152 = packages.join("asobo-aircraft-c152");
+        for package in [&tbm, &c152] {
+            fs::create_dir_all(package).unwrap();
+            fs::write(package.join("engines.cfg"), [b'x'; 40]).unwrap();
+            fs::write(package.join("flight_model.cfg"), [b'x'; 25]).unwrap();
+        }
+        let patch: Patch = serde_json::from_str(
+            r#"{
+                "engines": { "GENERALENGINEDATA.static_thrust": "200" },
+                "flight_model": { "FLIGHT_TUNING.pitch": "2" },
+                "aircraft": { "GENERAL.title": "tbm" }
+            }"#,
+        )
+        .unwrap();
+        let unpatched = Patch::default();
+        let matched = [(tbm, &patch), (c152, &unpatched)];
+        let space = |flags: &[&str]| {
+            let args = Args::parse_from(
+                ["patchcfg"]
+                    .iter()
+                    .chain(flags)
+                    .chain(&["packages", "patches.json"]),
+            );
+            backup_space(&matched, &args).unwrap()
+        };
+        let all = space(&[]);
+        let engines = space(&["--backup", "engines"]);
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(all, HashMap::from([(packages.clone(), 65)]));
+        assert_eq!(engines, HashMap::from([(packages, 40)]));
+    }
+
+    /// the values of thrust_scalar at 1, 1.5 and 2 left after setting them to 0 where `when` holds,
+    /// along with the keys ignored for not being numbers
+    fn conditioned(when: &str) -> (Vec<String>, Vec<String>) {
+        let text = "[ENGINE.0]\nthrust_scalar = 1\n\n[ENGINE.1]\nthrust_scalar = 1.5\n\n[ENGINE.2]\nthrust_scalar = 2\n\n[ENGINE.3]\nthrust_scalar = high\n";
+        let args = Args::parse_from([
+            "patchcfg",
+            "--no-preserve-original",
+            "packages",
+            "patches.json",
+        ]);
+        let mut patch = FilePatch::new();
+        patch.insert(
+            String::from("thrust_scalar"),
+            PatchValue::try_from(serde_json::json!({ "value": "0", "when": when })).unwrap(),
+        );
+
+        let diff =
+            build_diff(&patch, PathBuf::from("engines.cfg"), text, "package", &args).unwrap();
+        let patched = String::from_utf8(render(&diff, &args).unwrap().0).unwrap();
+        let values = patched
+            .lines()
+            .filter_map(|line| line.strip_prefix("thrust_scalar = "))
+            .map(ToOwned::to_owned)
+            .collect();
+        let ignored = diff
+            .ignored
+            .iter()
+            .map(|(address, _)| address.clone())
+            .collect();
+        (values, ignored)
+    }
+
+    #[test]
+    fn when_less() {
+        assert_eq!(conditioned("< 1.5").0, ["0", "1.5", "2", "high"]);
+    }
+
+    #[test]
+    fn when_less_or_equal() {
+        assert_eq!(conditioned("<= 1.5").0, ["0", "0", "2", "high"]);
+    }
+
+    #[test]
+    fn when_equal() {
+        assert_eq!(conditioned("== 1.5").0, ["1", "0", "2", "high"]);
+    }
+
+    #[test]
+    fn when_not_equal() {
+        assert_eq!(conditioned("!= 1.5").0, ["0", "1.5", "0", "high"]);
+    }
+
+    #[test]
+    fn when_greater_or_equal() {
+        assert_eq!(conditioned(">=1.5").0, ["1", "0", "0", "high"]);
+    }
+
+    #[test]
+    fn when_greater() {
+        assert_eq!(conditioned("> 1.5").0, ["1", "1.5", "0", "high"]);
+    }
+
+    #[test]
+    fn when_between() {
+        assert_eq!(conditioned("1..1.5").0, ["0", "0", "2", "high"]);
+    }
+
+    #[test]
+    fn when_skips_values_that_arent_numbers() {
+        assert_eq!(conditioned("> 0").1, ["thrust_scalar"]);
+    }
+
+    #[test]
+    fn when_must_be_a_condition() {
+        let error = |when: &str| {
+            PatchValue::try_from(serde_json::json!({ "value": "0", "when": when })).unwrap_err()
+        };
+        assert!(error("about 1").starts_with("unknown condition \"about 1\""));
+        assert!(error("> high").contains("expected a number in condition"));
+        assert!(error("2..1").contains("is empty"));
+    }
+
+    #[test]
+    fn disabled_sections_are_left_out() {
+        let patch: Patch = serde_json::from_str(
+            r#"{ "flight_model": {
+                "FLIGHT_TUNING": { "_enabled": false, "lift_scalar": "1.1" },
+                "AERODYNAMICS": { "_enabled": true, "drag_scalar": "0.9" },
+                "CONTACT_POINTS": { "wheel_friction": "1" },
+                "GENERAL.weight": "1000"
+            } }"#,
+        )
+        .unwrap();
+        let mut keys: Vec<_> = patch
+            .file(Target::find("flight_model").unwrap())
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect();
+        keys.sort();
+        assert_eq!(
+            keys,
+            [
+                "AERODYNAMICS.drag_scalar",
+                "CONTACT_POINTS.wheel_friction",
+                "GENERAL.weight"
+            ]
+        );
+
+        let bad = serde_json::from_str::<Patch>(
+            r#"{ "flight_model": { "FLIGHT_TUNING": { "_enabled": "no", "lift_scalar": "1.1" } } }"#,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(
+            bad.contains("_enabled in section FLIGHT_TUNING must be true or false, found \"no\""),
+            "{bad}"
+        );
+    }
+
+    #[test]
+    fn unchanged_files_are_left_out_of_the_output() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-focus-{}", process::id()));
+        let packages = dir.join("packages");
+        let engines = |package: &str| {
+            packages
+                .join(package)
+                .join("SimObjects/Airplanes/plane/engines.cfg")
+        };
+        for (package, thrust) in [
+            ("asobo-aircraft-tbm930", "200"),
+            ("asobo-aircraft-c152", "100"),
+        ] {
+            fs::create_dir_all(engines(package).parent().unwrap()).unwrap();
+            fs::write(
+                engines(package),
+                format!("[GENERALENGINEDATA]\nstatic_thrust = {thrust}\n"),
+            )
+            .unwrap();
+        }
+        let patch = r#"{ "engines": {
+            "GENERALENGINEDATA.static_thrust": "200",
+            "GENERALENGINEDATA.no_such_key": "1"
+        } }"#;
+        let patches = dir.join("patches.json");
+        fs::write(
+            &patches,
+            format!(r#"{{ "asobo-aircraft-tbm930": {patch}, "asobo-aircraft-c152": {patch} }}"#),
+        )
+        .unwrap();
+        let log_file = dir.join("patchcfg.log");
+
+        let logged = |flags: &[&str]| {
+            let mut argv: Vec<_> = [
+                "patchcfg",
+                "--explain",
+                "--hash",
+                "--summary-json",
+                "--log-file",
+            ]
+            .iter()
+            .map(OsStr::new)
+            .collect();
+            argv.push(log_file.as_os_str());
+            argv.extend(flags.iter().map(OsStr::new));
+            argv.extend([packages.as_os_str(), patches.as_os_str()]);
+            let args = Args::parse_from(argv);
+            let summary = run(&args, &Log::new(&args).unwrap()).unwrap();
+            let hashed: Vec<_> = summary.files.into_iter().map(|file| file.path).collect();
+            (fs::read_to_string(&log_file).unwrap(), hashed)
+        };
+        let (focused, focused_hashes) = logged(&["--diff-only-changed-files"]);
+        let (everything, all_hashes) = logged(&[]);
+        let _ = fs::remove_dir_all(&dir);
+
+        assert!(!focused.contains("asobo-aircraft-tbm930"), "{focused}");
+        assert!(focused.contains("asobo-aircraft-c152"), "{focused}");
+        assert_eq!(focused_hashes, [engines("asobo-aircraft-c152")]);
+        assert!(everything.contains("asobo-aircraft-tbm930"), "{everything}");
+        assert_eq!(all_hashes.len(), 2);
+    }
+
+    #[test]
+    fn targets_file_adds_and_renames_targets() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-targets-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let targets = |json: &str| {
+            let path = dir.join("targets.json");
+            fs::write(&path, json).unwrap();
+            load_targets(Some(&path))
+        };
+        let specs =
+            targets(r#"{ "systems": "systems.cfg", "engines": ["engines.cfg", "engine.cfg"] }"#);
+        let empty = targets(r#"{ "systems": [] }"#);
+        let malformed = targets(r#"{ "systems": 1 }"#);
+        let _ = fs::remove_dir_all(&dir);
+
+        let specs = specs.unwrap();
+        let builtin = load_targets(None).unwrap();
+        let summary = |specs: &[TargetSpec]| -> Vec<(String, String, Vec<String>)> {
+            specs
+                .iter()
+                .map(|spec| (spec.key.clone(), spec.label.clone(), spec.filenames.clone()))
+                .collect()
+        };
+        let names = |names: &[&str]| names.iter().map(|&name| name.to_owned()).collect();
+        assert_eq!(
+            summary(&builtin),
+            [
+                (
+                    String::from("engines"),
+                    String::from("Engine"),
+                    names(&["engines.cfg"])
+                ),
+                (
+                    String::from("flight_model"),
+                    String::from("Flight model"),
+                    names(&["flight_model.cfg"])
+                ),
+                (
+                    String::from("aircraft"),
+                    String::from("Aircraft"),
+                    names(&["aircraft.cfg"])
+                ),
+            ]
+        );
+        assert_eq!(
+            summary(&specs),
+            [
+                (
+                    String::from("engines"),
+                    String::from("Engine"),
+                    names(&["engines.cfg", "engine.cfg"])
+                ),
+                (
+                    String::from("flight_model"),
+                    String::from("Flight model"),
+                    names(&["flight_model.cfg"])
+                ),
+                (
+                    String::from("aircraft"),
+                    String::from("Aircraft"),
+                    names(&["aircraft.cfg"])
+                ),
+                (
+                    String::from("systems"),
+                    String::from("Systems"),
+                    names(&["systems.cfg"])
+                ),
+            ]
+        );
+        assert!(specs[3].sections.is_empty());
+        assert!(empty
+            .unwrap_err()
+            .to_string()
+            .starts_with("target systems in"));
+        assert!(malformed
+            .unwrap_err()
+            .to_string()
+            .starts_with("unable to read targets"));
+    }
+
+    #[test]
+    fn package_names_match_across_case() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-case-{}", process::id()));
+        let packages = dir.join("packages");
+        let tbm = packages.join("asobo-aircraft-tbm930/SimObjects/Airplanes/tbm");
+        fs::create_dir_all(&tbm).unwrap();
+        let engines = tbm.join("engines.cfg");
+        let stock = "[GENERALENGINEDATA]\nstatic_thrust = 100\n";
+        let patches = dir.join("patches.json");
+        fs::write(
+            &patches,
+            r#"{
+                "Asobo-Aircraft-TBM930": { "engines": { "GENERALENGINEDATA.static_thrust": "200" } },
+                "ASOBO-AIRCRAFT-TBM930": { "engines": { "GENERALENGINEDATA.static_thrust": "300" } }
+            }"#,
+        )
+        .unwrap();
+
+        let patched = |flags: &[&str]| {
+            fs::write(&engines, stock).unwrap();
+            let mut argv: Vec<_> = ["patchcfg", "--force", "--no-preserve-original"]
+                .iter()
+                .chain(flags)
+                .map(OsStr::new)
+                .collect();
+            argv.extend([packages.as_os_str(), patches.as_os_str()]);
+            let args = Args::parse_from(argv);
+            let log = Log::new(&args).unwrap();
+            run(&args, &log).unwrap();
+            (log.warnings(), fs::read_to_string(&engines).unwrap())
+        };
+        let folded = patched(&["--ignore-case"]);
+        let exact = patched(&[]);
+        let _ = fs::remove_dir_all(&dir);
+
+        // Of the two that collide, the first in sort order wins.
+        assert_eq!(
+            folded,
+            (
+                1,
+                String::from("[GENERALENGINEDATA]\nstatic_thrust = 300\n")
+            )
+        );
+        assert_eq!(exact.1, stock);
+    }
+
+    #[test]
+    fn all_or_nothing_skips_packages_missing_a_file() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-atomic-{}", process::id()));
+        let packages = dir.join("packages");
+        let tbm = packages.join("asobo-aircraft-tbm930/SimObjects/Airplanes/tbm");
+        fs::create_dir_all(&tbm).unwrap();
+        let engines = tbm.join("engines.cfg");
+        let stock = "[GENERALENGINEDATA]\nstatic_thrust = 100\n";
+        let patches = dir.join("patches.json");
+        fs::write(
+            &patches,
+            r#"{ "asobo-aircraft-tbm930": {
+                "engines": { "GENERALENGINEDATA.static_thrust": "200" },
+                "flight_model": { "FLIGHT_TUNING.parasite_drag_scalar": "1.5" }
+            } }"#,
+        )
+        .unwrap();
+
+        let patched = |flags: &[&str]| {
+            fs::write(&engines, stock).unwrap();
+            let mut argv: Vec<_> = ["patchcfg", "--force", "--no-preserve-original"]
+                .iter()
+                .chain(flags)
+                .map(OsStr::new)
+                .collect();
+            argv.extend([packages.as_os_str(), patches.as_os_str()]);
+            let args = Args::parse_from(argv);
+            let log = Log::new(&args).unwrap();
+            run(&args, &log).unwrap();
+            (log.warnings(), fs::read_to_string(&engines).unwrap())
+        };
+        let atomic = patched(&["--all-or-nothing"]);
+        let partial = patched(&[]);
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(atomic, (1, String::from(stock)));
+        assert_eq!(partial.1, "[GENERALENGINEDATA]\nstatic_thrust = 200\n");
+    }
+
+    /// applies `aggregate` to thrust_scalar over three engines of differing thrust
+    fn aggregated(aggregate: &str) -> String {
+        let text = "[ENGINE.0]\nthrust_scalar = 1\n\n[ENGINE.1]\nthrust_scalar = 2\n\n[ENGINE.2]\nthrust_scalar = 4.5\n";
+        let args = Args::parse_from([
+            "patchcfg",
+            "--no-preserve-original",
+            "packages",
+            "patches.json",
+        ]);
+        let patch: FilePatch =
+            serde_json::from_value(serde_json::json!({ "thrust_scalar": aggregate })).unwrap();
+        let diff =
+            build_diff(&patch, PathBuf::from("engines.cfg"), text, "package", &args).unwrap();
+        String::from_utf8(render(&diff, &args).unwrap().0).unwrap()
+    }
+
+    fn evened(value: &str) -> String {
+        format!(
+            "[ENGINE.0]\nthrust_scalar = {value}\n\n[ENGINE.1]\nthrust_scalar = {value}\n\n[ENGINE.2]\nthrust_scalar = {value}\n"
+        )
+    }
+
+    #[test]
+    fn min_sets_every_engine_to_the_least() {
+        assert_eq!(aggregated("@min"), evened("1"));
+    }
+
+    #[test]
+    fn max_sets_every_engine_to_the_greatest() {
+        assert_eq!(aggregated("@max"), evened("4.5"));
+    }
+
+    #[test]
+    fn avg_sets_every_engine_to_the_mean() {
+        assert_eq!(aggregated("@avg"), evened("2.5"));
+    }
+
+    #[test]
+    fn packages_are_found_below_vendor_folders() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-depth-{}", process::id()));
+        let packages = dir.join("packages");
+        let tbm = packages.join("asobo/asobo-aircraft-tbm930/SimObjects/Airplanes/tbm");
+        fs::create_dir_all(&tbm).unwrap();
+        fs::create_dir_all(packages.join("fenix/fnx-aircraft-a320")).unwrap();
+
+        let mut found = read_packages(&packages, 2).unwrap();
+        found.sort();
+        let shallow = read_packages(&packages, 1).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(
+            found,
+            [
+                packages.join("asobo/asobo-aircraft-tbm930"),
+                packages.join("fenix/fnx-aircraft-a320"),
+            ]
+        );
+        assert_eq!(shallow.len(), 2);
+        assert!(!shallow.contains(&packages.join("asobo/asobo-aircraft-tbm930")));
+
+        let zero = Args::try_parse_from([
+            "patchcfg",
+            "--packages-depth",
+            "0",
+            "packages",
+            "patches.json",
+        ])
+        .unwrap_err()
+        .to_string();
+        assert!(zero.contains("depth must be at least 1"), "{zero}");
+    }
+
+    #[test]
+    fn jobs_give_the_same_summary() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-jobs-{}", process::id()));
+        let packages = dir.join("packages");
+        let mut patches = serde_json::Map::new();
+        for n in 0..8 {
+            let name = format!("asobo-aircraft-tbm93{n}");
+            let tbm = packages.join(&name).join("SimObjects/Airplanes/tbm");
+            fs::create_dir_all(&tbm).unwrap();
+            // Every third package already has the patched thrust, and every other lacks a key.
+            let thrust = if n % 3 == 0 { 200 } else { 100 };
+            fs::write(
+                tbm.join("engines.cfg"),
+                format!("[GENERALENGINEDATA]\nstatic_thrust = {thrust}\n"),
+            )
+            .unwrap();
+            let mut engines = serde_json::json!({ "GENERALENGINEDATA.static_thrust": "200" });
+            if n % 2 == 0 {
+                engines["GENERALENGINEDATA.max_rpm"] = "2000".into();
+            }
+            patches.insert(name, serde_json::json!({ "engines": engines }));
+        }
+        let patches_path = dir.join("patches.json");
+        fs::write(&patches_path, serde_json::Value::from(patches).to_string()).unwrap();
+
+        let summary = |jobs: &str| {
+            let args = Args::parse_from([
+                OsStr::new("patchcfg"),
+                OsStr::new("--summary-json"),
+                OsStr::new("--with-changes"),
+                OsStr::new("--jobs"),
+                OsStr::new(jobs),
+                packages.as_os_str(),
+                patches_path.as_os_str(),
+            ]);
+            let mut summary =
+                serde_json::to_value(run(&args, &Log::new(&args).unwrap()).unwrap()).unwrap();
+            summary["run_id"].take();
+            summary
+        };
+        let serial = summary("1");
+        let parallel = summary("4");
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(serial["packages_matched"], 8);
+        assert_eq!(serial["keys_changed"], 5);
+        assert_eq!(serial["keys_skipped"], 3);
+        assert_eq!(serial["keys_missing"], 4);
+        assert_eq!(parallel, serial);
+    }
+
+    #[test]
+    fn files_over_max_changes_are_not_written() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-max-changes-{}", process::id()));
+        let packages = dir.join("packages");
+        let tbm = packages.join("asobo-aircraft-tbm930/SimObjects/Airplanes/tbm");
+        fs::create_dir_all(&tbm).unwrap();
+        let engines = tbm.join("engines.cfg");
+        let flight_model = tbm.join("flight_model.cfg");
+        let stock_engines = "[ENGINE.0]\nthrust_scalar = 1\n\n[ENGINE.1]\nthrust_scalar = 1\n\n[ENGINE.2]\nthrust_scalar = 1\n";
+        let stock_flight_model = "[FLIGHT_TUNING]\nparasite_drag_scalar = 1\n";
+        let patches = dir.join("patches.json");
+        fs::write(
+            &patches,
+            r#"{ "asobo-aircraft-tbm930": {
+                "engines": { "thrust_scalar": "2" },
+                "flight_model": { "FLIGHT_TUNING.parasite_drag_scalar": "1.5" }
+            } }"#,
+        )
+        .unwrap();
+
+        let patched = |flags: &[&str]| {
+            fs::write(&engines, stock_engines).unwrap();
+            fs::write(&flight_model, stock_flight_model).unwrap();
+            let mut argv: Vec<_> = ["patchcfg", "--force", "--no-preserve-original"]
+                .iter()
+                .chain(flags)
+                .map(OsStr::new)
+                .collect();
+            argv.extend([packages.as_os_str(), patches.as_os_str()]);
+            let args = Args::parse_from(argv);
+            let log = Log::new(&args).unwrap();
+            let result = run(&args, &log).map(|_| log.warnings());
+            let files = (
+                fs::read_to_string(&engines).unwrap(),
+                fs::read_to_string(&flight_model).unwrap(),
+            );
+            (result, files)
+        };
+        let (per_file, per_file_files) = patched(&["--max-changes", "2"]);
+        let (total, total_files) = patched(&["--max-total-changes", "3"]);
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(per_file.unwrap(), 1);
+        assert_eq!(per_file_files.0, stock_engines);
+        assert_eq!(
+            per_file_files.1,
+            "[FLIGHT_TUNING]\nparasite_drag_scalar = 1.5\n"
+        );
+        let total = format!("{:#}", total.unwrap_err());
+        assert!(total.contains("would make 4 changes"), "{total}");
+        assert_eq!(
+            total_files,
+            (
+                String::from(stock_engines),
+                String::from(stock_flight_model)
+            )
+        );
+    }
+
+    #[test]
+    fn dump_parsed_classifies_each_line() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-dump-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("engines.cfg");
+        fs::write(
+            &path,
+            "; engines\n[GENERALENGINEDATA] ; turboprop\nstatic_thrust = 100 ; thrust\n\nstray\n",
+        )
+        .unwrap();
+        let mut out = Vec::new();
+        let result = dump_parsed(&path, &mut out);
+        let _ = fs::remove_dir_all(&dir);
+        result.unwrap();
+
+        let dumped: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(
+            dumped,
+            serde_json::json!([
+                { "line": 1, "kind": "comment", "text": "; engines" },
+                {
+                    "line": 2,
+                    "kind": "section",
+                    "name": "GENERALENGINEDATA",
+                    "comment": { "delimiter": ";", "text": " turboprop" },
+                },
+                {
+                    "line": 3,
+                    "kind": "entry",
+                    "address": "GENERALENGINEDATA.static_thrust",
+                    "key": "static_thrust",
+                    "value": " 100 ",
+                    "comment": { "delimiter": ";", "text": " thrust" },
+                },
+                { "line": 4, "kind": "blank" },
+                { "line": 5, "kind": "other", "text": "stray" },
+            ])
+        );
+    }
+
+    #[test]
+    fn misnamed_flight_model_is_found_by_its_section() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-content-{}", process::id()));
+        let package = dir.join("asobo-aircraft-tbm930");
+        let tbm = package.join("SimObjects/Airplanes/tbm");
+        fs::create_dir_all(&tbm).unwrap();
+        fs::write(tbm.join("notes.txt"), "[FLIGHT_TUNING]\n").unwrap();
+        fs::write(tbm.join("systems.cfg"), "[ELECTRICAL]\nbattery = 1\n").unwrap();
+        fs::write(
+            tbm.join("tbm_flight.cfg"),
+            "[FLIGHT_TUNING]\nparasite_drag_scalar = 1\n",
+        )
+        .unwrap();
+
+        let flight_model = Target::find("flight_model").unwrap();
+        let found = |flags: &[&str]| {
+            let argv = ["patchcfg"]
+                .iter()
+                .chain(flags)
+                .chain(&["packages", "patches.json"]);
+            find_target(&package, flight_model, &Args::parse_from(argv))
+        };
+        let by_content = found(&["--match-content"]);
+        let by_name = found(&[]);
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(by_content, Some(tbm.join("tbm_flight.cfg")));
+        assert_eq!(by_name, None);
+    }
+
+    #[test]
+    fn list_keys_sorts_by_address() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-list-keys-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("engines.cfg");
+        fs::write(
+            &path,
+            "[TURBINEENGINEDATA]\nrated_shaft_hp = 850\n\n[GENERALENGINEDATA]\nstatic_thrust = 100 ; thrust\nengine_type = 5\n",
+        )
+        .unwrap();
+        let listed = |values: bool, json: bool| {
+            let mut out = Vec::new();
+            list_keys(&path, values, json, &mut out).map(|()| String::from_utf8(out).unwrap())
+        };
+        let plain = listed(false, false);
+        let with_values = listed(true, false);
+        let json = listed(true, true);
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(
+            plain.unwrap(),
+            "GENERALENGINEDATA.engine_type\nGENERALENGINEDATA.static_thrust\nTURBINEENGINEDATA.rated_shaft_hp\n"
+        );
+        assert_eq!(
+            with_values.unwrap(),
+            "GENERALENGINEDATA.engine_type = 5\nGENERALENGINEDATA.static_thrust = 100\nTURBINEENGINEDATA.rated_shaft_hp = 850\n"
+        );
+        let json: serde_json::Value = serde_json::from_str(&json.unwrap()).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "GENERALENGINEDATA.engine_type": "5",
+                "GENERALENGINEDATA.static_thrust": "100",
+                "TURBINEENGINEDATA.rated_shaft_hp": "850",
+            })
+        );
+    }
+
+    #[test]
+    fn divergence_includes_keys_patched_earlier() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-baseline-{}", process::id()));
+        let package = dir.join("packages/asobo-aircraft-tbm930");
+        let tbm = package.join("SimObjects/Airplanes/tbm");
+        let stock = dir.join("stock/asobo-aircraft-tbm930/SimObjects/Airplanes/tbm");
+        fs::create_dir_all(&tbm).unwrap();
+        fs::create_dir_all(&stock).unwrap();
+        fs::write(
+            tbm.join("engines.cfg"),
+            "[GENERALENGINEDATA]\nstatic_thrust = 100\n\n[TURBINEENGINEDATA]\nrated_shaft_hp = 900\n",
+        )
+        .unwrap();
+        fs::write(
+            stock.join("engines.cfg"),
+            "[GENERALENGINEDATA]\nstatic_thrust = 100\n\n[TURBINEENGINEDATA]\nrated_shaft_hp = 850\n",
+        )
+        .unwrap();
+        fs::write(
+            tbm.join("flight_model.cfg"),
+            "[FLIGHT_TUNING]\nparasite_drag_scalar = 1\n",
+        )
+        .unwrap();
+        let log_file = dir.join("patchcfg.log");
+
+        let patch: Patch = serde_json::from_str(
+            r#"{
+                "engines": { "GENERALENGINEDATA.static_thrust": "200" },
+                "flight_model": { "FLIGHT_TUNING.parasite_drag_scalar": "1.5" }
+            }"#,
+        )
+        .unwrap();
+        let baseline = dir.join("stock");
+        let args = Args::parse_from([
+            OsStr::new("patchcfg"),
+            OsStr::new("--color=never"),
+            OsStr::new("--log-file"),
+            log_file.as_os_str(),
+            OsStr::new("--baseline"),
+            baseline.as_os_str(),
+            OsStr::new("packages"),
+            OsStr::new("patches.json"),
+        ]);
+        let log = Log::new(&args).unwrap();
+        let mut out = Vec::new();
+        let shown = patch
+            .diff(&package, &args, &mut Stats::default())
+            .and_then(|diff| diff.show_divergence(&package, &baseline, &args, &log, &mut out));
+        let logged = fs::read_to_string(&log_file).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        shown.unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("rated_shaft_hp"), "{out}");
+        assert!(out.contains("static_thrust"), "{out}");
+        assert!(!out.contains("parasite_drag_scalar"), "{out}");
+        assert!(
+            logged.contains("info: asobo-aircraft-tbm930: no baseline for "),
+            "{logged}"
+        );
+    }
+
+    #[test]
+    fn third_element_of_a_list_is_replaced() {
+        let text =
+            "[CONTACT_POINTS]\npoint = 1, -15.5,  0.0, -4.2 ; nose\n\n[GEAR]\nscale = 1 2 3\n";
+        let args = Args::parse_from([
+            "patchcfg",
+            "--no-preserve-original",
+            "packages",
+            "patches.json",
+        ]);
+        let patched = |json: serde_json::Value| {
+            let patch: FilePatch = serde_json::from_value(json).unwrap();
+            build_diff(
+                &patch,
+                PathBuf::from("flight_model.cfg"),
+                text,
+                "package",
+                &args,
+            )
+            .and_then(|diff| Ok(String::from_utf8(render(&diff, &args)?.0)?))
+        };
+
+        assert_eq!(
+            patched(serde_json::json!({
+                "CONTACT_POINTS.point[2]": "1.5",
+                "GEAR.scale[2]": "4",
+            }))
+            .unwrap(),
+            "[CONTACT_POINTS]\npoint = 1, -15.5,  1.5, -4.2 ;  nose\n\n[GEAR]\nscale = 1 2 4\n"
+        );
+        let past_the_end = patched(serde_json::json!({ "GEAR.scale[3]": "4" }))
+            .unwrap_err()
+            .to_string();
+        assert!(past_the_end.contains("has 3 elements"), "{past_the_end}");
+    }
+
+    /// patches static_thrust, stock 1900, with `value`, returning the error if a rule refuses it
+    fn validated(value: serde_json::Value) -> Result<(), String> {
+        let text = "[GENERALENGINEDATA]\nstatic_thrust = 1900\n";
+        let args = Args::parse_from(["patchcfg", "packages", "patches.json"]);
+        let mut patch = FilePatch::new();
+        patch.insert(
+            String::from("GENERALENGINEDATA.static_thrust"),
+            PatchValue::try_from(value).unwrap(),
+        );
+        build_diff(&patch, PathBuf::from("engines.cfg"), text, "package", &args)
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn values_outside_the_range_are_refused() {
+        assert_eq!(
+            validated(serde_json::json!({ "value": "+10%", "range": "0..2000" })),
+            Err(String::from(
+                "unable to patch engines.cfg (GENERALENGINEDATA.static_thrust): 2090 is outside the allowed range 0..2000"
+            ))
+        );
+        assert_eq!(
+            validated(serde_json::json!({ "value": "+5%", "range": "0..2000" })),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn non_numeric_values_are_refused_a_range() {
+        assert_eq!(
+            validated(serde_json::json!({ "value": "high", "range": "> 0" })),
+            Err(String::from(
+                "unable to patch engines.cfg (GENERALENGINEDATA.static_thrust): high isn't a number, so it can't be > 0"
+            ))
+        );
+    }
+
+    #[test]
+    fn values_not_one_of_the_allowed_are_refused() {
+        let one_of = |value: &str| {
+            validated(serde_json::json!({ "value": value, "one_of": ["1900", "2000"] }))
+        };
+        assert_eq!(
+            one_of("2100"),
+            Err(String::from(
+                "unable to patch engines.cfg (GENERALENGINEDATA.static_thrust): 2100 isn't one of the allowed values: 1900, 2000"
+            ))
+        );
+        assert_eq!(one_of("2000"), Ok(()));
+    }
+
+    #[test]
+    fn values_not_matching_the_pattern_are_refused() {
+        let matches =
+            |value: &str| validated(serde_json::json!({ "value": value, "matches": "2*" }));
+        assert_eq!(
+            matches("1950"),
+            Err(String::from(
+                "unable to patch engines.cfg (GENERALENGINEDATA.static_thrust): 1950 doesn't match \"2*\""
+            ))
+        );
+        assert_eq!(matches("2050"), Ok(()));
+    }
+
+    #[test]
+    fn headers_with_an_equals_sign_are_left_alone() {
+        let text = "[ENGINE=LEFT] ; a=b\nthrust_scalar = 1\n[GENERALENGINEDATA]=stray\nthrust_scalar = 1\n";
+        let args = Args::parse_from([
+            "patchcfg",
+            "--no-preserve-original",
+            "packages",
+            "patches.json",
+        ]);
+        let patch: FilePatch =
+            serde_json::from_value(serde_json::json!({ "ENGINE=LEFT.thrust_scalar": "2" }))
+                .unwrap();
+        let diff =
+            build_diff(&patch, PathBuf::from("engines.cfg"), text, "package", &args).unwrap();
+        let patched = String::from_utf8(render(&diff, &args).unwrap().0).unwrap();
+
+        // The stray line isn't a header, so the key after it is still in [ENGINE=LEFT].
+        assert_eq!(
+            patched,
+            "[ENGINE=LEFT] ; a=b\nthrust_scalar = 2\n[GENERALENGINEDATA]=stray\nthrust_scalar = 2\n"
+        );
+    }
+
+    #[test]
+    fn only_sections_keeps_unqualified_keys_in_bounds() {
+        let text = "scalar = 1\n[FLIGHT_TUNING]\nscalar = 1\n\n[ENGINE.0]\nscalar = 1\n\n[ENGINE.1]\nscalar = 1\n";
+        let patched = |sections: &str| {
+            let args = Args::parse_from([
+                "patchcfg",
+                "--no-preserve-original",
+                "--only-sections",
+                sections,
+                "packages",
+                "patches.json",
+            ]);
+            let patch: FilePatch =
+                serde_json::from_value(serde_json::json!({ "scalar": "2" })).unwrap();
+            let diff = build_diff(
+                &patch,
+                PathBuf::from("flight_model.cfg"),
+                text,
+                "package",
+                &args,
+            )
+            .unwrap();
+            String::from_utf8(render(&diff, &args).unwrap().0).unwrap()
+        };
+
+        assert_eq!(
+            patched("flight_tuning"),
+            "scalar = 1\n[FLIGHT_TUNING]\nscalar = 2\n\n[ENGINE.0]\nscalar = 1\n\n[ENGINE.1]\nscalar = 1\n"
+        );
+        assert_eq!(
+            patched("FLIGHT_TUNING,ENGINE.1"),
+            "scalar = 1\n[FLIGHT_TUNING]\nscalar = 2\n\n[ENGINE.0]\nscalar = 1\n\n[ENGINE.1]\nscalar = 2\n"
+        );
+        assert_eq!(
+            patched("ENGINE.*"),
+            "scalar = 1\n[FLIGHT_TUNING]\nscalar = 1\n\n[ENGINE.0]\nscalar = 2\n\n[ENGINE.1]\nscalar = 2\n"
+        );
+    }
+
+    #[test]
+    fn resolved_patch_takes_every_override() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-resolved-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (base, tuned) = (dir.join("base.json"), dir.join("tuned.json"));
+        fs::write(
+            &base,
+            r#"{ "asobo-aircraft-tbm930": {
+                "engines": {
+                    "GENERALENGINEDATA.static_thrust": "200",
+                    "GENERALENGINEDATA.max_rpm": "2000"
+                },
+                "flight_model": { "FLIGHT_TUNING.parasite_drag_scalar": "1.0" }
+            } }"#,
+        )
+        .unwrap();
+        fs::write(
+            &tuned,
+            r#"{ "asobo-aircraft-tbm930": { "engines": {
+                "GENERALENGINEDATA.max_rpm": { "value": "2100", "comment": "tuned" }
+            } } }"#,
+        )
+        .unwrap();
+
+        let args = Args::parse_from([
+            OsStr::new("patchcfg"),
+            OsStr::new("--no-defaults"),
+            OsStr::new("--merge"),
+            tuned.as_os_str(),
+            OsStr::new("--set"),
+            OsStr::new("asobo-aircraft-tbm930:flight_model:FLIGHT_TUNING.parasite_drag_scalar=1.2"),
+            OsStr::new("--print-resolved-patch"),
+            OsStr::new("asobo-aircraft-tbm930"),
+            OsStr::new("packages"),
+            base.as_os_str(),
+        ]);
+        let log = Log::new(&args).unwrap();
+        let patches = resolve_patches(args.patches.as_ref(), &args, &log);
+        let _ = fs::remove_dir_all(&dir);
+
+        let patches = patches.unwrap();
+        assert_eq!(
+            patches["asobo-aircraft-tbm930"].to_json(),
+            serde_json::json!({
+                "engines": {
+                    "GENERALENGINEDATA.max_rpm": { "value": "2100", "comment": "tuned" },
+                    "GENERALENGINEDATA.static_thrust": "200",
+                },
+                "flight_model": { "FLIGHT_TUNING.parasite_drag_scalar": "1.2" },
+            })
+        );
+    }
+
+    #[test]
+    fn padding_alone_is_no_change() {
+        let text = "[GENERAL]\ntitle =  TBM 930   ; name\nscalar= 1.0 \nscale = 1, 2,3\nsize =3\n";
+        let diff = |json: serde_json::Value, flags: &[&str]| {
+            let argv = ["patchcfg", "--no-preserve-original"]
+                .iter()
+                .chain(flags)
+                .chain(&["packages", "patches.json"]);
+            let args = Args::parse_from(argv);
+            let patch: FilePatch = serde_json::from_value(json).unwrap();
+            let diff = build_diff(
+                &patch,
+                PathBuf::from("aircraft.cfg"),
+                text,
+                "package",
+                &args,
+            )
+            .unwrap();
+            let patched = String::from_utf8(render(&diff, &args).unwrap().0).unwrap();
+            (diff.count(), patched)
+        };
+
+        let padded = serde_json::json!({
+            "GENERAL.title": " TBM 930 ",
+            "GENERAL.scalar": "1.0  ",
+            "GENERAL.scale": "1,2, 3",
+        });
+        assert_eq!(
+            diff(padded.clone(), &["--ignore-list-spacing"]),
+            (0, String::from(text))
+        );
+        assert_eq!(diff(padded, &[]).0, 1);
+
+        // A change writes the value trimmed, however it was padded in the patch.
+        assert_eq!(
+            diff(
+                serde_json::json!({ "GENERAL.scalar": " 1.5 ", "GENERAL.size": "4 " }),
+                &[]
+            ),
+            (
+                2,
+                String::from(
+                    "[GENERAL]\ntitle =  TBM 930   ; name\nscalar = 1.5\nscale = 1, 2,3\nsize = 4\n"
+                )
+            )
+        );
+    }
+
+    #[test]
+    fn packages_are_matched_by_atc_model() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-name-from-{}", process::id()));
+        let aircraft = |package: &str, text: &str| {
+            let path = dir.join(package).join("SimObjects/Airplanes/plane");
+            fs::create_dir_all(&path).unwrap();
+            fs::write(path.join("aircraft.cfg"), text).unwrap();
+        };
+        aircraft(
+            "asobo-aircraft-tbm930",
+            "[GENERAL]\natc_model = \"TBM 930\" ; model\n\n[FLTSIM.0]\ntitle = \"TBM 930 Asobo\"\n",
+        );
+        aircraft("asobo-aircraft-c152", "[GENERAL]\natc_model = \"C152\"\n");
+        fs::create_dir_all(dir.join("asobo-aircraft-dr400")).unwrap();
+
+        let patch = serde_json::json!({ "engines": { "GENERALENGINEDATA.static_thrust": "200" } });
+        let patches: HashMap<String, Patch> = serde_json::from_value(serde_json::json!({
+            "TBM 930": patch,
+            "asobo-aircraft-c152": patch,
+            "asobo-aircraft-dr400": patch,
+        }))
+        .unwrap();
+        let args = Args::parse_from([
+            "patchcfg",
+            "--package-name-from",
+            "atc_model",
+            "packages",
+            "patches.json",
+        ]);
+        let mut candidates = read_packages(&dir, 1).unwrap();
+        candidates.sort();
+        let matched: Vec<_> = match_packages(candidates, &patches, &args)
+            .map(|(path, _)| path)
+            .collect();
+        let _ = fs::remove_dir_all(&dir);
+
+        // The C152 has an atc_model, so its folder name isn't tried; the DR400 has none.
+        assert_eq!(
+            matched,
+            [
+                dir.join("asobo-aircraft-dr400"),
+                dir.join("asobo-aircraft-tbm930")
+            ]
+        );
+    }
 }
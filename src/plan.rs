@@ -0,0 +1,165 @@
+//! reviewed changes, written by --plan-out and applied by the apply-plan command
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    check_backup, delta::Delta, encoding, lock::Lock, render::write_planned_file, sha256,
+    ulid::Ulid, Args, ChangeRecord, Log, Target,
+};
+
+/// changes reviewed ahead of time, as written by --plan-out and applied by apply-plan
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Plan {
+    pub run_id: String,
+
+    /// the lock the run would have taken to write these files, which applying the plan takes
+    pub lock: Option<PathBuf>,
+    pub files: Vec<PlannedFile>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct PlannedFile {
+    pub path: PathBuf,
+
+    /// the file's target, which decides whether it's backed up (see --backup)
+    pub target: String,
+
+    /// the SHA-256 of the file as it was when the plan was made
+    pub sha256: String,
+
+    /// the changes, for review; applying the plan goes by the delta
+    pub changes: Vec<ChangeRecord>,
+
+    /// the lines to change, which take the file from what it was to what it's to be
+    pub delta: Delta,
+}
+
+/// applies a plan, if none of its files have changed since it was made
+pub fn apply(path: &Path, args: &Args, log: &Log) -> anyhow::Result<()> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("unable to read plan {}", path.display()))?;
+    let plan: Plan = serde_json::from_str(&text)
+        .with_context(|| format!("unable to read plan {}", path.display()))?;
+
+    let lock = args.lock_file.as_ref().or(plan.lock.as_ref());
+    let _lock = match lock {
+        Some(lock) if !args.no_lock => Some(Lock::acquire(lock.clone(), Ulid::new(), log)?),
+        _ => None,
+    };
+
+    // Every file is checked before any is written, so a plan is applied whole or not at all.
+
+    let mut patched = Vec::new();
+    let mut drifted = 0;
+    for file in &plan.files {
+        let read = fs::read(&file.path).and_then(|bytes| {
+            let (text, encoding) = encoding::decode(&bytes)?;
+            Ok((sha256::hex_digest(&bytes), text, encoding))
+        });
+        let problem = match read {
+            Ok((sha256, ..)) if sha256 != file.sha256 => {
+                String::from("it has changed since the plan was made")
+            }
+            Ok((_, text, encoding)) => match file.delta.apply(&text) {
+                Some(new) => {
+                    patched.push((file, text, new, encoding));
+                    continue;
+                }
+                None => String::from("the plan's changes don't fit it"),
+            },
+            Err(e) => e.to_string(),
+        };
+        log.error(format_args!("{}: {problem}", file.path.display()));
+        drifted += 1;
+    }
+    if drifted > 0 {
+        anyhow::bail!(
+            "{drifted} file(s) don't match plan {}; nothing was changed",
+            path.display()
+        );
+    }
+
+    let backup = |file: &PlannedFile| {
+        Target::find(&file.target).is_some_and(|target| args.should_backup(target))
+    };
+    for (file, original, ..) in &patched {
+        if backup(file) {
+            check_backup(&file.path, original, args, log)?;
+        }
+    }
+
+    for (file, _, text, encoding) in patched {
+        write_planned_file(&file.path, &text, encoding, backup(file), args)
+            .with_context(|| format!("unable to write {}", file.path.display()))?;
+        println!(
+            "patched {} ({} change(s))",
+            file.path.display(),
+            file.changes.len()
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use clap::Parser;
+
+    use super::*;
+    use crate::render::temp_path;
+
+    #[test]
+    fn apply_plan_writes_like_a_run() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-plan-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("engines.cfg");
+        let original = "[GENERALENGINEDATA]\nstatic_thrust = 100\n";
+        let patched = "[GENERALENGINEDATA]\nstatic_thrust = 200 ; 100\n";
+        fs::write(&path, original).unwrap();
+
+        let plan = Plan {
+            run_id: Ulid::new().to_string(),
+            lock: Some(dir.join(".patchcfg.lock")),
+            files: vec![PlannedFile {
+                path: path.clone(),
+                target: String::from("engines"),
+                sha256: sha256::hex_digest(original.as_bytes()),
+                changes: Vec::new(),
+                delta: Delta::new(patched, original),
+            }],
+        };
+        let plan_path = dir.join("plan.json");
+        fs::write(&plan_path, serde_json::to_string(&plan).unwrap()).unwrap();
+
+        let args = Args::parse_from([
+            "patchcfg",
+            "--backup-suffix",
+            "orig",
+            "apply-plan",
+            "plan.json",
+        ]);
+        let log = Log::new(&args).unwrap();
+        apply(&plan_path, &args, &log).unwrap();
+        let written = fs::read_to_string(&path).unwrap();
+        let backup = fs::read_to_string(args.backup_path(&path));
+        let stale = apply(&plan_path, &args, &log);
+        let (lock, temp) = (
+            dir.join(".patchcfg.lock").exists(),
+            temp_path(&path).exists(),
+        );
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(written, patched);
+        assert_eq!(backup.unwrap(), original);
+        assert!(stale.is_err(), "the file no longer matches the plan");
+        assert!(!lock && !temp);
+    }
+}
@@ -0,0 +1,407 @@
+//! working out the new contents of patched files, and writing them in place of the old
+
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use hashbrown::HashSet;
+
+use crate::{
+    delta::Delta,
+    encoding::{self, Encoding},
+    line::{self, Line},
+    lock::retry,
+    sha256, tidy, Args, Original, PathChanges,
+};
+
+/// what write_modified_file wrote
+#[derive(Debug)]
+pub struct Written {
+    pub path: PathBuf,
+
+    /// the lines that were changed, as written
+    pub lines: Vec<String>,
+
+    /// where the original file was moved, if it was backed up
+    pub backup: Option<PathBuf>,
+
+    /// the checksum of what was written, for --hash
+    pub sha256: Option<String>,
+}
+
+/// writes a file's new contents beside it, then puts them in its place, so that the file is
+/// never left half written
+pub fn write_modified_file(patch: &PathChanges, backup: bool, args: &Args) -> io::Result<Written> {
+    with_temp(&patch.path, |temp| replace_file(patch, temp, backup, args))
+}
+
+/// writes a file's new contents, as given by a plan, the same way
+pub fn write_planned_file(
+    path: &Path,
+    text: &str,
+    encoding: Encoding,
+    backup: bool,
+    args: &Args,
+) -> io::Result<Option<PathBuf>> {
+    with_temp(path, |temp| {
+        fs::write(temp, encoding::encode(text.as_bytes(), encoding))?;
+        install(path, temp, Some(text.as_bytes()), backup, args)
+    })
+}
+
+/// writes a file's new contents to the temp file beside it, removing that if anything fails
+fn with_temp<T>(path: &Path, write: impl FnOnce(&Path) -> io::Result<T>) -> io::Result<T> {
+    let temp = temp_path(path);
+    let written = write(&temp);
+    if written.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    written
+}
+
+fn replace_file(
+    patch: &PathChanges,
+    temp: &Path,
+    backup: bool,
+    args: &Args,
+) -> io::Result<Written> {
+    // Tidying, a differential backup and a checksum all need the whole of the new contents, so
+    // only then are they worked out in memory. Otherwise, they're written a line at a time.
+
+    let buf = if args.tidy || args.hash || backup && args.differential_backup {
+        let (buf, lines) = render(patch, args)?;
+        let encoded = encoding::encode(&buf, patch.encoding);
+        fs::write(temp, &encoded)?;
+        Some((buf, lines, sha256::hex_digest(&encoded)))
+    } else {
+        None
+    };
+    let lines = match &buf {
+        Some((_, lines, _)) => lines.clone(),
+        None => {
+            let file = BufWriter::new(File::create(temp)?);
+            let mut out = encoding::Writer::new(file, patch.encoding);
+            let lines = render_to(patch, args, &mut out)?;
+            out.finish()?
+                .into_inner()
+                .map_err(io::IntoInnerError::into_error)?;
+            lines
+        }
+    };
+    let patched = buf.as_ref().map(|(buf, ..)| buf.as_slice());
+    let backup = install(&patch.path, temp, patched, backup, args)?;
+    Ok(Written {
+        path: patch.path.clone(),
+        lines,
+        backup,
+        sha256: buf.map(|(.., sha256)| sha256),
+    })
+}
+
+/// backs up a file, if it's to be, and puts the new contents written to `temp` in its place
+///
+/// A differential backup is worked out from the new contents, which are then in `patched`.
+fn install(
+    path: &Path,
+    temp: &Path,
+    patched: Option<&[u8]>,
+    backup: bool,
+    args: &Args,
+) -> io::Result<Option<PathBuf>> {
+    fs::set_permissions(temp, fs::metadata(path)?.permissions())?;
+
+    let backup = if backup && args.differential_backup {
+        let patched = patched.expect("differential backups are rendered in memory");
+        let original = encoding::read_to_string(path)?;
+        let delta = Delta::new(&original, &String::from_utf8_lossy(patched));
+        let backup = args.delta_path(path);
+        retry(args, || fs::write(&backup, serde_json::to_vec(&delta)?))?;
+        Some(backup)
+    } else if backup {
+        let backup = args.backup_path(path);
+        retry(args, || fs::rename(path, &backup))?;
+        Some(backup)
+    } else {
+        None
+    };
+
+    retry(args, || fs::rename(temp, path))?;
+    Ok(backup)
+}
+
+/// where a file's new contents are written before they take its place
+pub fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_owned();
+    name.push(".patchcfg-new");
+    path.with_file_name(name)
+}
+
+/// works out the new contents of a file, along with the lines changed
+pub fn render(patch: &PathChanges, args: &Args) -> io::Result<(Vec<u8>, Vec<String>)> {
+    let mut buf = Vec::new();
+    let lines = render_to(patch, args, &mut buf)?;
+
+    if args.tidy {
+        let touched: HashSet<_> = patch
+            .changes
+            .values()
+            .chain(patch.added.values().flatten())
+            .map(|change| change.section.as_deref())
+            .collect();
+        let text = String::from_utf8_lossy(&buf);
+        let tidied = tidy::tidy(&text, |section| touched.contains(&section), args.sort_keys);
+        buf = tidied.into_bytes();
+    }
+
+    Ok((buf, lines))
+}
+
+/// writes the new contents of a file to `buf` a line at a time, as they're worked out, returning
+/// the lines changed
+///
+/// This leaves out --tidy, which needs the whole file.
+fn render_to(patch: &PathChanges, args: &Args, buf: &mut impl Write) -> io::Result<Vec<String>> {
+    let original = args.original();
+    let mut lines = Vec::new();
+
+    if let Some(hash) = patch.marker {
+        writeln!(buf, "{MARKER}{hash:016x}")?;
+    }
+
+    let mut skip_to = 0;
+
+    for (idx, line) in patch.text.lines().enumerate() {
+        if idx == 0 && patch.marker.is_some() && read_marker(line).is_some() || idx < skip_to {
+            continue;
+        }
+
+        // A replaced section keeps its header, and its new body takes the place of the old.
+
+        if let Some(block) = patch.blocks.get(&idx) {
+            writeln!(buf, "{line}")?;
+            for line in &block.new {
+                writeln!(buf, "{line}")?;
+                lines.push(line.clone());
+            }
+            skip_to = block.end;
+            continue;
+        }
+
+        let line = if args.trim_trailing {
+            line.trim_end()
+        } else {
+            line
+        };
+
+        // If this is a key/value line we want to modify, write the modified line. Otherwise,
+        // just write the line to our output buffer without modifications.
+
+        match (line::parse(line), patch.changes.get(&idx)) {
+            (Line::Entry { comment, .. }, Some(change)) => {
+                let f = change.line(comment, &original);
+                let f = if args.trim_trailing { f.trim_end() } else { &f };
+                writeln!(buf, "{f}")?;
+                lines.push(f.to_owned());
+            }
+
+            _ => writeln!(buf, "{line}")?,
+        }
+
+        for change in patch.added.get(&idx).into_iter().flatten() {
+            let f = change.line(None, &Original::Omit);
+            writeln!(buf, "{f}")?;
+            lines.push(f);
+        }
+    }
+
+    Ok(lines)
+}
+
+/// the comment recording which patch was applied to a file, for --marker
+const MARKER: &str = "; patchcfg patch ";
+
+/// reads the patch hash from the marker on the first line of a file, if it has one
+pub fn read_marker(text: &str) -> Option<u64> {
+    let hash = text
+        .lines()
+        .next()?
+        .trim()
+        .strip_prefix(MARKER.trim_start())?;
+    u64::from_str_radix(hash.trim(), 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, process};
+
+    use clap::Parser;
+
+    use super::*;
+    use crate::{build_diff, FilePatch, Patch, PatchValue, Stats, Target};
+
+    #[test]
+    fn written_describes_the_write() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-written-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("engines.cfg");
+        let text = "[GENERALENGINEDATA]\nstatic_thrust = 100\nmin_throttle = 0\n";
+        fs::write(&path, text).unwrap();
+
+        let mut patch = FilePatch::new();
+        patch.insert(
+            String::from("GENERALENGINEDATA.static_thrust"),
+            PatchValue::try_from(serde_json::json!("200")).unwrap(),
+        );
+        let args = Args::parse_from([
+            "patchcfg",
+            "--hash",
+            "--summary-json",
+            "packages",
+            "patches.json",
+        ]);
+        let diff = build_diff(&patch, path.clone(), text, "package", &args).unwrap();
+        let written = write_modified_file(&diff, true, &args);
+        let (patched, backup) = (fs::read(&path), fs::read_to_string(args.backup_path(&path)));
+        let _ = fs::remove_dir_all(&dir);
+
+        let written = written.unwrap();
+        let patched = patched.unwrap();
+        assert_eq!(written.path, path);
+        assert_eq!(written.lines, ["static_thrust = 200 ;  100"]);
+        assert_eq!(written.backup, Some(args.backup_path(&path)));
+        assert_eq!(written.sha256, Some(sha256::hex_digest(&patched)));
+        assert_eq!(backup.unwrap(), text);
+    }
+
+    #[test]
+    fn large_file_is_written_as_rendered() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-large-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("engines.cfg");
+
+        let mut text = String::new();
+        for n in 0..20_000 {
+            text += &format!("[ENGINE.{n}]\nmax_rpm = {n} ; rpm\nmin_rpm = 0\n\n");
+        }
+        text += "[GENERALENGINEDATA]\nstatic_thrust = 100\n";
+        fs::write(&path, &text).unwrap();
+
+        let mut patch = FilePatch::new();
+        patch.insert(
+            String::from("GENERALENGINEDATA.static_thrust"),
+            PatchValue::try_from(serde_json::json!("200")).unwrap(),
+        );
+        let args = Args::parse_from(["patchcfg", "packages", "patches.json"]);
+        let diff = build_diff(&patch, path.clone(), &text, "package", &args).unwrap();
+        let (rendered, _) = render(&diff, &args).unwrap();
+        let written = write_modified_file(&diff, false, &args).unwrap();
+
+        let patched = fs::read(&path).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        assert_eq!(written.lines, ["static_thrust = 200 ;  100"]);
+        assert_eq!(patched, rendered);
+        assert_eq!(patched.len(), text.len() + " ;  100".len());
+        assert!(!temp_path(&path).exists());
+    }
+
+    #[test]
+    fn trim_trailing_strips_only_the_ends_of_lines() {
+        let text =
+            "[GENERAL]   \ntitle = \"TBM  930\"  \nkey = 1\t\n; note   \n   \nother = 2 ; kept  \n";
+        let mut patch = FilePatch::new();
+        patch.insert(
+            String::from("GENERAL.key"),
+            PatchValue::try_from(serde_json::json!("2")).unwrap(),
+        );
+        let rendered = |flags: &[&str]| {
+            let args = Args::parse_from(
+                ["patchcfg", "--no-preserve-original"]
+                    .iter()
+                    .chain(flags)
+                    .chain(&["packages", "patches.json"]),
+            );
+            let diff = build_diff(
+                &patch,
+                PathBuf::from("aircraft.cfg"),
+                text,
+                "package",
+                &args,
+            )
+            .unwrap();
+            String::from_utf8(render(&diff, &args).unwrap().0).unwrap()
+        };
+
+        assert_eq!(
+            rendered(&["--trim-trailing"]),
+            "[GENERAL]\ntitle = \"TBM  930\"\nkey = 2\n; note\n\nother = 2 ; kept\n"
+        );
+        assert_eq!(
+            rendered(&[]),
+            "[GENERAL]   \ntitle = \"TBM  930\"  \nkey = 2\n; note   \n   \nother = 2 ; kept  \n"
+        );
+    }
+
+    #[test]
+    fn marker_is_read_back_and_skips_the_same_patch() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-marker-{}", process::id()));
+        let package = dir.join("asobo-aircraft-tbm930");
+        fs::create_dir_all(&package).unwrap();
+        let path = package.join("engines.cfg");
+        let stock = "[GENERALENGINEDATA]\nstatic_thrust = 100\n";
+        fs::write(&path, stock).unwrap();
+        let patch = |thrust: &str| {
+            serde_json::from_str::<Patch>(&format!(
+                r#"{{ "engines": {{ "GENERALENGINEDATA.static_thrust": "{thrust}" }} }}"#
+            ))
+            .unwrap()
+        };
+        let args = Args::parse_from([
+            "patchcfg",
+            "--marker",
+            "--no-preserve-original",
+            "packages",
+            "patches.json",
+        ]);
+        let diff = |patch: &Patch| patch.diff(&package, &args, &mut Stats::default()).unwrap();
+
+        let first = diff(&patch("200"));
+        let (_, file) = first.files()[0];
+        let hash = file.marker.unwrap();
+        write_modified_file(file, false, &args).unwrap();
+        let patched = fs::read_to_string(&path).unwrap();
+        let same = diff(&patch("200"));
+        let changed = diff(&patch("300"));
+        let (_, file) = changed.files()[0];
+        let repatched = String::from_utf8(render(file, &args).unwrap().0).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(
+            patched,
+            format!("; patchcfg patch {hash:016x}\n[GENERALENGINEDATA]\nstatic_thrust = 200\n")
+        );
+        assert_eq!(read_marker(&patched), Some(hash));
+        assert!(same.is_empty());
+        assert_eq!(
+            same.skipped,
+            [(
+                Target::find("engines").unwrap(),
+                "file already has this patch (marker)"
+            )]
+        );
+        let new_hash = file.marker.unwrap();
+        assert_ne!(new_hash, hash);
+        assert_eq!(
+            repatched,
+            format!("; patchcfg patch {new_hash:016x}\n[GENERALENGINEDATA]\nstatic_thrust = 300\n")
+        );
+
+        assert_eq!(read_marker(stock), None);
+        assert_eq!(
+            read_marker("[GENERAL]\n; patchcfg patch 00000000000000ff\n"),
+            None
+        );
+        assert_eq!(read_marker("; patchcfg patch not-a-hash\n"), None);
+    }
+}
@@ -0,0 +1,271 @@
+//! what a run did, for --summary-json and --report-format
+
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{render::Written, Args, Diff, PathChanges, Target};
+
+/// totals for a run, for --summary-json
+#[derive(Debug, Default, Serialize)]
+pub struct Summary {
+    pub run_id: String,
+    pub packages_matched: usize,
+    pub files_changed: usize,
+    pub keys_changed: usize,
+    pub keys_skipped: usize,
+    pub keys_missing: usize,
+    pub dry_run: bool,
+
+    /// checksums of each file, for --hash
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<FileHashes>,
+
+    /// each change, for --with-changes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub changes: Option<Vec<ChangeRecord>>,
+}
+
+/// a change as recorded in --summary-json
+///
+/// A replaced section is recorded under the key `[NAME]`, with its old and new lines.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct ChangeRecord {
+    pub package: String,
+    pub file: String,
+    pub section: Option<String>,
+    pub key: String,
+
+    /// the value before, or none for a key that was added
+    pub old: Option<String>,
+    pub new: String,
+}
+
+impl ChangeRecord {
+    pub fn status(&self) -> &'static str {
+        if self.old.is_none() {
+            "added"
+        } else if self.key.starts_with('[') {
+            "replaced"
+        } else {
+            "changed"
+        }
+    }
+
+    /// what a change is matched by between reports
+    pub fn address(&self) -> String {
+        match &self.section {
+            Some(section) => format!("{}: {}: {section}.{}", self.package, self.file, self.key),
+            None => format!("{}: {}: {}", self.package, self.file, self.key),
+        }
+    }
+}
+
+/// SHA-256 checksums of a file before and after the run
+#[derive(Debug, Serialize)]
+pub struct FileHashes {
+    pub path: PathBuf,
+    pub before: String,
+    pub after: String,
+}
+
+impl Summary {
+    pub fn add_hashes(&mut self, diff: &Diff, written: &[Written], args: &Args) {
+        for (_, file) in diff.reported(args) {
+            if file.path.as_os_str().is_empty() {
+                continue;
+            }
+
+            let before = file.sha256.clone();
+            let after = written
+                .iter()
+                .find(|written| written.path == file.path)
+                .and_then(|written| written.sha256.clone())
+                .unwrap_or_else(|| before.clone());
+            self.files.push(FileHashes {
+                path: file.path.clone(),
+                before,
+                after,
+            });
+        }
+    }
+
+    pub fn add_changes(&mut self, package: &Path, diff: &Diff, args: &Args) {
+        let Some(changes) = &mut self.changes else {
+            return;
+        };
+        for (target, file) in diff.reported(args) {
+            changes.extend(change_records(package, target, file));
+        }
+    }
+
+    /// the changes as rows for --report-format, each row written by `row`
+    pub fn table(&self, row: fn(&[&str]) -> String) -> String {
+        let mut table = row(&["package", "file", "section", "key", "old", "new", "status"]);
+        for change in self.changes.iter().flatten() {
+            table += &row(&[
+                &change.package,
+                &change.file,
+                change.section.as_deref().unwrap_or_default(),
+                &change.key,
+                change.old.as_deref().unwrap_or_default(),
+                &change.new,
+                change.status(),
+            ]);
+        }
+        table
+    }
+
+    pub fn add(&mut self, diff: &Diff) {
+        self.packages_matched += 1;
+        for (_, file) in diff.files() {
+            self.files_changed += usize::from(!file.is_empty());
+            self.keys_changed += file.count();
+            self.keys_skipped += file.unchanged.len();
+            self.keys_missing += file.missing.len();
+        }
+    }
+}
+
+/// the changes to a file, as recorded in --summary-json and plans
+pub fn change_records(package: &Path, target: Target, file: &PathChanges) -> Vec<ChangeRecord> {
+    let package = package.file_name().unwrap_or_default().to_string_lossy();
+    let record =
+        |section: Option<&str>, key: String, old: Option<String>, new: String| ChangeRecord {
+            package: package.to_string(),
+            file: target.key().to_owned(),
+            section: section.map(ToOwned::to_owned),
+            key,
+            old,
+            new,
+        };
+
+    let mut records = Vec::new();
+    for change in file.changes.values() {
+        records.push(record(
+            change.section.as_deref(),
+            change.key.clone(),
+            Some(change.old.trim().to_owned()),
+            change.new.clone(),
+        ));
+    }
+    for change in file.added.values().flatten() {
+        records.push(record(
+            change.section.as_deref(),
+            change.key.clone(),
+            None,
+            change.new.clone(),
+        ));
+    }
+    for block in file.blocks.values() {
+        records.push(record(
+            Some(&block.name),
+            format!("[{}]", block.name),
+            Some(block.old.join("\n")),
+            block.new.join("\n"),
+        ));
+    }
+    records
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsStr, fs, process};
+
+    use clap::Parser;
+
+    use super::*;
+    use crate::{csv, run, Log};
+
+    #[test]
+    fn summary_has_just_the_totals() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-summary-{}", process::id()));
+        let package = dir.join("packages/asobo-aircraft-tbm930/SimObjects/Airplanes/tbm");
+        fs::create_dir_all(&package).unwrap();
+        fs::write(
+            package.join("engines.cfg"),
+            "[GENERALENGINEDATA]\nstatic_thrust = 100\nmin_throttle = 0\n",
+        )
+        .unwrap();
+        let patches = dir.join("patches.json");
+        let patch = r#"{ "asobo-aircraft-tbm930": { "engines": {
+            "GENERALENGINEDATA.static_thrust": "200",
+            "GENERALENGINEDATA.min_throttle": "0",
+            "GENERALENGINEDATA.no_such_key": "1"
+        } } }"#;
+        fs::write(&patches, patch).unwrap();
+
+        let packages = dir.join("packages");
+        let args = Args::parse_from([
+            OsStr::new("patchcfg"),
+            OsStr::new("--summary-json"),
+            packages.as_os_str(),
+            patches.as_os_str(),
+        ]);
+        let summary = run(&args, &Log::new(&args).unwrap());
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut summary = serde_json::to_value(summary.unwrap()).unwrap();
+        let run_id = summary["run_id"].take();
+        assert_eq!(run_id.as_str().map(str::len), Some(26));
+        assert_eq!(
+            summary,
+            serde_json::json!({
+                "run_id": null,
+                "packages_matched": 1,
+                "files_changed": 1,
+                "keys_changed": 1,
+                "keys_skipped": 1,
+                "keys_missing": 1,
+                "dry_run": true,
+            })
+        );
+    }
+
+    #[test]
+    fn changes_are_tabled_as_csv_and_tsv() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-table-{}", process::id()));
+        let package = dir.join("packages/asobo-aircraft-tbm930/SimObjects/Airplanes/tbm");
+        fs::create_dir_all(&package).unwrap();
+        fs::write(
+            package.join("engines.cfg"),
+            "[GENERALENGINEDATA]\nstatic_thrust = 100\n\n[FUEL]\nsize = 3\ntanks = 2\n",
+        )
+        .unwrap();
+        let patches = dir.join("patches.json");
+        let patch = r#"{ "asobo-aircraft-tbm930": { "engines": {
+            "GENERALENGINEDATA.static_thrust": "200, \"max\"",
+            "[FUEL]": ["size = 4"]
+        } } }"#;
+        fs::write(&patches, patch).unwrap();
+
+        let packages = dir.join("packages");
+        let args = Args::parse_from([
+            OsStr::new("patchcfg"),
+            OsStr::new("--summary-json"),
+            OsStr::new("--report-format=csv"),
+            packages.as_os_str(),
+            patches.as_os_str(),
+        ]);
+        let summary = run(&args, &Log::new(&args).unwrap());
+        let _ = fs::remove_dir_all(&dir);
+
+        let summary = summary.unwrap();
+        let csv = summary.table(csv::row);
+        assert_eq!(
+            csv,
+            "package,file,section,key,old,new,status\r\n\
+             asobo-aircraft-tbm930,engines,GENERALENGINEDATA,static_thrust,100,\"200, \"\"max\"\"\",changed\r\n\
+             asobo-aircraft-tbm930,engines,FUEL,[FUEL],\"size = 3\ntanks = 2\",size = 4,replaced\r\n"
+        );
+        let rows = csv::parse(&csv).unwrap();
+        assert!(rows.iter().all(|row| row.len() == 7));
+        assert_eq!(rows[1][5], "200, \"max\"");
+        assert_eq!(
+            summary.table(csv::tsv_row),
+            "package\tfile\tsection\tkey\told\tnew\tstatus\n\
+             asobo-aircraft-tbm930\tengines\tGENERALENGINEDATA\tstatic_thrust\t100\t200, \"max\"\tchanged\n\
+             asobo-aircraft-tbm930\tengines\tFUEL\t[FUEL]\tsize = 3\\ntanks = 2\tsize = 4\treplaced\n"
+        );
+    }
+}
@@ -0,0 +1,206 @@
+//! putting back the files patched in a packages directory, for the restore command
+
+use std::{ffi::OsStr, fs, path::Path};
+
+use anyhow::Context;
+
+use crate::{
+    encoding,
+    lock::{retry, Lock},
+    read_delta,
+    sidecar::{self, Sidecar},
+    Args, Log, Target,
+};
+
+/// puts back the backups (and sidecars) of the files in a packages directory
+pub fn restore(packages: &Path, args: &Args, log: &Log) -> anyhow::Result<()> {
+    let _lock = Lock::for_packages(packages, args, log)?;
+    let suffix = format!(".{}", args.backup_suffix);
+    let delta_suffix = format!(".{}.json", args.backup_suffix);
+
+    // Only backups of the files we patch are restored, so an unrelated file that happens to share
+    // the suffix is left alone.
+
+    for entry in walkdir::WalkDir::new(packages) {
+        let entry = entry?;
+        let Some(name) = entry.file_name().to_str() else {
+            continue;
+        };
+        let (stem, differential) = match name.strip_suffix(&delta_suffix) {
+            Some(stem) => (stem, true),
+            None => match name.strip_suffix(&suffix) {
+                Some(stem) => (stem, false),
+                None => continue,
+            },
+        };
+
+        let original = format!("{stem}.cfg");
+        if !entry.file_type().is_file()
+            || !Target::all().any(|target| {
+                target
+                    .filenames()
+                    .iter()
+                    .any(|filename| filename.eq_ignore_ascii_case(&original))
+            })
+        {
+            continue;
+        }
+
+        let target = entry.path().with_file_name(original);
+        if differential {
+            restore_delta(entry.path(), &target, args)?;
+        } else {
+            retry(args, || fs::rename(entry.path(), &target))
+                .with_context(|| format!("unable to restore {}", target.display()))?;
+        }
+        println!("restored {}", target.display());
+    }
+
+    // Sidecars are kept apart from the packages, at the same paths within their own directory.
+
+    let Some(dir) = &args.sidecar_changes else {
+        return Ok(());
+    };
+    for entry in walkdir::WalkDir::new(dir) {
+        let entry = entry?;
+        let path = entry.path();
+        if !entry.file_type().is_file() || path.extension() != Some(OsStr::new("changes")) {
+            continue;
+        }
+
+        let relative = path.strip_prefix(dir)?.with_extension("");
+        let target = packages.join(relative);
+        let sidecar: Sidecar = serde_json::from_str(&fs::read_to_string(path)?)
+            .with_context(|| format!("unable to read {}", path.display()))?;
+        let (text, encoding) = encoding::read(&target)
+            .with_context(|| format!("unable to restore {}", target.display()))?;
+
+        let restored = encoding::encode(sidecar::restore(&text, &sidecar).as_bytes(), encoding);
+        retry(args, || fs::write(&target, &restored))
+            .with_context(|| format!("unable to restore {}", target.display()))?;
+        fs::remove_file(path)?;
+        println!("restored {}", target.display());
+    }
+
+    Ok(())
+}
+
+/// puts back the lines recorded in a differential backup, then removes it
+fn restore_delta(backup: &Path, target: &Path, args: &Args) -> anyhow::Result<()> {
+    let delta =
+        read_delta(backup).with_context(|| format!("unable to read {}", backup.display()))?;
+    let (patched, encoding) = encoding::read(target)
+        .with_context(|| format!("unable to restore {}", target.display()))?;
+    let Some(original) = delta.apply(&patched) else {
+        anyhow::bail!(
+            "unable to restore {}: it has changed since it was patched",
+            target.display()
+        );
+    };
+
+    let original = encoding::encode(original.as_bytes(), encoding);
+    retry(args, || fs::write(target, &original))
+        .with_context(|| format!("unable to restore {}", target.display()))?;
+    fs::remove_file(backup)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use clap::Parser;
+
+    use super::*;
+    use crate::run;
+
+    #[test]
+    fn differential_backup_restores_the_original() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-delta-{}", process::id()));
+        let package = dir.join("packages/asobo-aircraft-tbm930/SimObjects/Airplanes/tbm");
+        fs::create_dir_all(&package).unwrap();
+        let path = package.join("engines.cfg");
+        let mut original = String::from("[GENERALENGINEDATA]\nstatic_thrust = 100 ; thrust\n");
+        for n in 0..1000 {
+            original += &format!("\n[ENGINE.{n}]\nmax_rpm = {n}\n");
+        }
+        fs::write(&path, &original).unwrap();
+        let patches = dir.join("patches.json");
+        let patch = r#"{ "asobo-aircraft-tbm930": { "engines": {
+            "GENERALENGINEDATA.static_thrust": "200",
+            "ENGINE.500.max_rpm": "2000"
+        } } }"#;
+        fs::write(&patches, patch).unwrap();
+
+        let packages = dir.join("packages");
+        let args = Args::parse_from([
+            OsStr::new("patchcfg"),
+            OsStr::new("--force"),
+            OsStr::new("--differential-backup"),
+            packages.as_os_str(),
+            patches.as_os_str(),
+        ]);
+        let log = Log::new(&args).unwrap();
+        let delta = args.delta_path(&path);
+        let patched = run(&args, &log).map(|_| {
+            let patched = fs::read_to_string(&path).unwrap();
+            let record = fs::metadata(&delta).unwrap().len();
+            (patched, record)
+        });
+        let restored = restore(&packages, &args, &log).map(|()| fs::read_to_string(&path).unwrap());
+        let left = (delta.exists(), args.backup_path(&path).exists());
+        let _ = fs::remove_dir_all(&dir);
+
+        let (patched, record) = patched.unwrap();
+        assert_ne!(patched, original);
+        assert!(record < 1000, "the record is {record} bytes");
+        assert_eq!(restored.unwrap(), original);
+        assert_eq!(left, (false, false));
+    }
+
+    #[test]
+    fn custom_suffix_backup_is_restored() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-suffix-{}", process::id()));
+        let package = dir.join("packages/asobo-aircraft-tbm930/SimObjects/Airplanes/tbm");
+        fs::create_dir_all(&package).unwrap();
+        let path = package.join("engines.cfg");
+        let original = "[GENERALENGINEDATA]\nstatic_thrust = 100\n";
+        fs::write(&path, original).unwrap();
+        let patches = dir.join("patches.json");
+        let patch = r#"{ "asobo-aircraft-tbm930": { "engines": { "GENERALENGINEDATA.static_thrust": "200" } } }"#;
+        fs::write(&patches, patch).unwrap();
+
+        let packages = dir.join("packages");
+        let args = Args::parse_from([
+            OsStr::new("patchcfg"),
+            OsStr::new("--force"),
+            OsStr::new("--backup-suffix"),
+            OsStr::new(".orig"),
+            packages.as_os_str(),
+            patches.as_os_str(),
+        ]);
+        let log = Log::new(&args).unwrap();
+        run(&args, &log).unwrap();
+        let backups = (
+            package.join("engines.orig").exists(),
+            package.join("engines.bak.cfg").exists(),
+        );
+        let restored = restore(&packages, &args, &log).map(|()| fs::read_to_string(&path).unwrap());
+        let left = package.join("engines.orig").exists();
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(backups, (true, false));
+        assert_eq!(restored.unwrap(), original);
+        assert!(!left);
+        for suffix in ["cfg", ".CFG", "", "bak/cfg"] {
+            let parsed = Args::try_parse_from([
+                "patchcfg",
+                "--backup-suffix",
+                suffix,
+                "packages",
+                "patches.json",
+            ]);
+            assert!(parsed.is_err(), "{suffix:?}");
+        }
+    }
+}
@@ -0,0 +1,183 @@
+//! sidecars, which keep the lines a patch changed apart from the files, for --sidecar-changes
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    line::{self, Line},
+    PathChanges,
+};
+
+/// the lines a patch changed, as they were, for --sidecar-changes
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Sidecar {
+    /// the original lines of changed keys, by address
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    changed: BTreeMap<String, String>,
+
+    /// the addresses of keys that were added
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    added: BTreeSet<String>,
+
+    /// the original bodies of replaced sections, by name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    blocks: BTreeMap<String, Vec<String>>,
+}
+
+/// where the sidecar for a file goes: at its path within the packages directory, under `dir`
+pub fn path(package: &Path, path: &Path, dir: &Path) -> PathBuf {
+    let relative = path.strip_prefix(package).unwrap_or(path);
+    let mut name = relative.as_os_str().to_owned();
+    name.push(".changes");
+    dir.join(package.file_name().unwrap_or_default()).join(name)
+}
+
+/// records the original lines a patch changes, keeping any recorded by an earlier run
+pub fn write(patch: &PathChanges, path: &Path) -> anyhow::Result<()> {
+    let mut sidecar: Sidecar = match fs::read_to_string(path) {
+        Ok(text) => serde_json::from_str(&text)
+            .with_context(|| format!("unable to read {}", path.display()))?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Sidecar::default(),
+        Err(e) => return Err(e.into()),
+    };
+
+    let lines: Vec<_> = patch.text.lines().collect();
+    for (&idx, change) in &patch.changes {
+        let address = change.address();
+        // A key an earlier run added has no original to go back to.
+        if !sidecar.added.contains(&address) {
+            sidecar
+                .changed
+                .entry(address)
+                .or_insert_with(|| lines[idx].to_owned());
+        }
+    }
+    for change in patch.added.values().flatten() {
+        sidecar.added.insert(change.address());
+    }
+    for block in patch.blocks.values() {
+        sidecar
+            .blocks
+            .entry(block.name.clone())
+            .or_insert_with(|| block.old.clone());
+    }
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, serde_json::to_string_pretty(&sidecar)? + "\n")
+        .with_context(|| format!("unable to write {}", path.display()))?;
+    Ok(())
+}
+
+/// puts back the original lines recorded in a sidecar
+pub fn restore(text: &str, sidecar: &Sidecar) -> String {
+    let mut restored = String::with_capacity(text.len());
+    let mut section = None;
+    let mut replaced = false;
+
+    for line in text.lines() {
+        match line::parse(line) {
+            Line::Section { name, .. } => {
+                section = Some(name);
+                restored += line;
+                restored.push('\n');
+
+                replaced = false;
+                if let Some(body) = sidecar.blocks.get(name) {
+                    for line in body {
+                        restored += line;
+                        restored.push('\n');
+                    }
+                    replaced = true;
+                }
+                continue;
+            }
+            _ if replaced => continue,
+            Line::Entry { key, .. } => {
+                let address = match section {
+                    Some(section) => format!("{section}.{key}"),
+                    None => key.to_owned(),
+                };
+                if sidecar.added.contains(&address) {
+                    continue;
+                }
+                if let Some(original) = sidecar.changed.get(&address) {
+                    restored += original;
+                    restored.push('\n');
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        restored += line;
+        restored.push('\n');
+    }
+
+    restored
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ffi::OsStr, process};
+
+    use clap::Parser;
+
+    use super::*;
+    use crate::{restore::restore, run, Args, Log};
+
+    #[test]
+    fn originals_go_to_the_sidecar_and_back() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-sidecar-{}", process::id()));
+        let packages = dir.join("packages");
+        let package = packages.join("asobo-aircraft-tbm930");
+        let tbm = package.join("SimObjects/Airplanes/tbm");
+        fs::create_dir_all(&tbm).unwrap();
+        let engines = tbm.join("engines.cfg");
+        let original = "[GENERALENGINEDATA]\nstatic_thrust = 100 ; thrust\n";
+        fs::write(&engines, original).unwrap();
+        let patches = dir.join("patches.json");
+        fs::write(
+            &patches,
+            r#"{ "asobo-aircraft-tbm930": { "engines": { "GENERALENGINEDATA.static_thrust": "200" } } }"#,
+        )
+        .unwrap();
+        let changes = dir.join("changes");
+
+        let args = Args::parse_from([
+            OsStr::new("patchcfg"),
+            OsStr::new("--force"),
+            OsStr::new("--no-backup"),
+            OsStr::new("--sidecar-changes"),
+            changes.as_os_str(),
+            packages.as_os_str(),
+            patches.as_os_str(),
+        ]);
+        let log = Log::new(&args).unwrap();
+        run(&args, &log).unwrap();
+        let patched = fs::read_to_string(&engines).unwrap();
+        let recorded = fs::read_to_string(path(&package, &engines, &changes));
+        let restored = restore(&packages, &args, &log).map(|()| fs::read_to_string(&engines));
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(
+            patched,
+            "[GENERALENGINEDATA]\nstatic_thrust = 200 ;  thrust\n"
+        );
+        let sidecar: Sidecar = serde_json::from_str(&recorded.unwrap()).unwrap();
+        assert_eq!(
+            sidecar.changed,
+            BTreeMap::from([(
+                String::from("GENERALENGINEDATA.static_thrust"),
+                String::from("static_thrust = 100 ; thrust"),
+            )])
+        );
+        assert_eq!(restored.unwrap().unwrap(), original);
+    }
+}
@@ -0,0 +1,103 @@
+//! patches kept in a spreadsheet, for --csv
+
+use crate::{csv, Target};
+
+/// turns the rows of a --csv file into a patch file
+pub fn read(text: &str) -> anyhow::Result<serde_json::Value> {
+    let mut rows = csv::parse(text).map_err(anyhow::Error::msg)?.into_iter();
+    let header: Vec<_> = rows
+        .next()
+        .unwrap_or_default()
+        .iter()
+        .map(|name| name.trim().to_lowercase())
+        .collect();
+    let column = |name: &str| header.iter().position(|column| column == name);
+    let required = |name: &str| {
+        column(name)
+            .ok_or_else(|| anyhow::anyhow!("no {name} column (columns are named in the first row)"))
+    };
+    let (package, file, key, value) = (
+        required("package")?,
+        required("file")?,
+        required("key")?,
+        required("value")?,
+    );
+    let (section, comment) = (column("section"), column("comment"));
+
+    let mut patches = serde_json::Map::new();
+    for (idx, row) in rows.enumerate() {
+        // The header is row 1.
+        let row_number = idx + 2;
+        let field = |column: Option<usize>| {
+            column
+                .and_then(|column| row.get(column))
+                .map(|field| field.trim())
+                .filter(|field| !field.is_empty())
+        };
+
+        let Some(name) = field(Some(package)) else {
+            anyhow::bail!("row {row_number}: no package");
+        };
+        let target = field(Some(file)).and_then(Target::find).ok_or_else(|| {
+            anyhow::anyhow!(
+                "row {row_number}: unknown file {:?}",
+                field(Some(file)).unwrap_or_default()
+            )
+        })?;
+        let Some(key) = field(Some(key)) else {
+            anyhow::bail!("row {row_number}: no key");
+        };
+        let address = match field(section) {
+            Some(section) => format!("{section}.{key}"),
+            None => key.to_owned(),
+        };
+
+        let mut patch = serde_json::Map::new();
+        if let Some(value) = field(Some(value)) {
+            patch.insert("value".into(), value.into());
+        }
+        if let Some(comment) = field(comment) {
+            patch.insert("comment".into(), comment.into());
+        }
+        if patch.is_empty() {
+            anyhow::bail!("row {row_number}: no value for {address}");
+        }
+
+        let file = patches
+            .entry(name)
+            .or_insert_with(|| serde_json::Value::Object(Default::default()))
+            .as_object_mut()
+            .expect("packages are objects")
+            .entry(target.key())
+            .or_insert_with(|| serde_json::Value::Object(Default::default()))
+            .as_object_mut()
+            .expect("files are objects");
+        if file.insert(address.clone(), patch.into()).is_some() {
+            anyhow::bail!("row {row_number}: {address} is given more than once for {name}");
+        }
+    }
+
+    Ok(patches.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_reads_like_the_json_it_stands_for() {
+        let csv = "Package,File,Section,Key,Value,Comment\n\
+            asobo-aircraft-tbm930,engines,GENERALENGINEDATA,static_thrust,229,more thrust\n\
+            asobo-aircraft-tbm930,flight_model,,pitch,1.5,\n";
+        let json = serde_json::json!({
+            "asobo-aircraft-tbm930": {
+                "engines": {
+                    "GENERALENGINEDATA.static_thrust": { "value": "229", "comment": "more thrust" }
+                },
+                "flight_model": { "pitch": { "value": "1.5" } }
+            }
+        });
+        assert_eq!(read(csv).unwrap(), json);
+        assert!(read("package,file,key\nx,engines,k\n").is_err());
+    }
+}
@@ -0,0 +1,178 @@
+//! values looked up by package, for `@table:` references and --table
+
+use std::{fs, path::PathBuf, sync::OnceLock};
+
+use anyhow::Context;
+use hashbrown::HashMap;
+
+use crate::csv;
+
+/// the tables loaded with --table, by name, each of values by package and column
+static TABLES: OnceLock<Tables> = OnceLock::new();
+
+type Tables = HashMap<String, HashMap<String, HashMap<String, String>>>;
+
+/// looks up a package's value in a table, for a reference like `@table:weights.empty_weight`
+pub fn lookup(reference: &str, package: &str) -> anyhow::Result<String> {
+    find(TABLES.get().unwrap_or(&Tables::new()), reference, package)
+}
+
+fn find(tables: &Tables, reference: &str, package: &str) -> anyhow::Result<String> {
+    let Some((name, column)) = reference.split_once('.') else {
+        anyhow::bail!("expected @table:NAME.COLUMN, got @table:{reference}");
+    };
+    let table = tables
+        .get(name)
+        .ok_or_else(|| anyhow::anyhow!("no table {name} for @table:{reference} (see --table)"))?;
+    let row = table
+        .get(package)
+        .ok_or_else(|| anyhow::anyhow!("table {name} has no row for {package}"))?;
+    row.get(column)
+        .cloned()
+        .ok_or_else(|| anyhow::anyhow!("table {name} has no {column} for {package}"))
+}
+
+/// reads the tables given with --table, for lookups for the rest of the run
+pub fn load(tables: &[(String, PathBuf)]) -> anyhow::Result<()> {
+    // A process only runs once, so the tables can only have been set already by another test in
+    // the same process, and those don't give --table.
+    let _ = TABLES.set(read(tables)?);
+    Ok(())
+}
+
+fn read(tables: &[(String, PathBuf)]) -> anyhow::Result<Tables> {
+    let mut loaded = Tables::new();
+    for (name, path) in tables {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("unable to read table {}", path.display()))?;
+        let table = if path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("csv"))
+        {
+            read_csv_table(&text)
+        } else {
+            read_json_table(&text)
+        };
+        let table = table.with_context(|| format!("unable to read table {}", path.display()))?;
+        if loaded.insert(name.clone(), table).is_some() {
+            anyhow::bail!("table {name} is given more than once");
+        }
+    }
+    Ok(loaded)
+}
+
+/// reads a table from CSV with a package column, leaving out empty cells
+fn read_csv_table(text: &str) -> anyhow::Result<HashMap<String, HashMap<String, String>>> {
+    let mut rows = csv::parse(text).map_err(anyhow::Error::msg)?.into_iter();
+    let header: Vec<_> = rows
+        .next()
+        .unwrap_or_default()
+        .iter()
+        .map(|name| name.trim().to_owned())
+        .collect();
+    let package = header
+        .iter()
+        .position(|name| name.eq_ignore_ascii_case("package"))
+        .context("no package column (columns are named in the first row)")?;
+
+    let mut table = HashMap::new();
+    for (idx, row) in rows.enumerate() {
+        let Some(name) = row.get(package).map(|name| name.trim()) else {
+            anyhow::bail!("row {}: no package", idx + 2);
+        };
+        let values = header
+            .iter()
+            .zip(&row)
+            .enumerate()
+            .filter(|&(column, (_, value))| column != package && !value.trim().is_empty())
+            .map(|(_, (column, value))| (column.clone(), value.trim().to_owned()))
+            .collect();
+        if table.insert(name.to_owned(), values).is_some() {
+            anyhow::bail!("row {}: package {name} is already in the table", idx + 2);
+        }
+    }
+    Ok(table)
+}
+
+/// reads a table from a JSON object of packages, each an object of strings or numbers
+fn read_json_table(text: &str) -> anyhow::Result<HashMap<String, HashMap<String, String>>> {
+    let packages: HashMap<String, HashMap<String, serde_json::Value>> = serde_json::from_str(text)?;
+    packages
+        .into_iter()
+        .map(|(package, values)| {
+            let values = values
+                .into_iter()
+                .map(|(column, value)| match value {
+                    serde_json::Value::String(value) => Ok((column, value)),
+                    serde_json::Value::Number(value) => Ok((column, value.to_string())),
+                    other => Err(anyhow::anyhow!(
+                        "{package}.{column} should be a string or a number, found {other}"
+                    )),
+                })
+                .collect::<anyhow::Result<_>>()?;
+            Ok((package, values))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use super::*;
+
+    #[test]
+    fn values_are_looked_up_by_package() {
+        let dir = std::env::temp_dir().join(format!("patchcfg-tables-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (weights, fuel) = (dir.join("weights.csv"), dir.join("fuel.json"));
+        fs::write(
+            &weights,
+            "package,empty_weight,max_gross_weight\n\
+             asobo-aircraft-tbm930,4629,7394\n\
+             asobo-aircraft-c152,1129,\n",
+        )
+        .unwrap();
+        fs::write(&fuel, r#"{ "asobo-aircraft-c152": { "capacity": 26 } }"#).unwrap();
+        let tables = read(&[
+            (String::from("weights"), weights),
+            (String::from("fuel"), fuel),
+        ]);
+        let _ = fs::remove_dir_all(&dir);
+
+        let tables = tables.unwrap();
+        let found = |reference: &str, package: &str| {
+            find(&tables, reference, package).map_err(|e| e.to_string())
+        };
+        assert_eq!(
+            found("weights.empty_weight", "asobo-aircraft-tbm930"),
+            Ok(String::from("4629"))
+        );
+        assert_eq!(
+            found("weights.empty_weight", "asobo-aircraft-c152"),
+            Ok(String::from("1129"))
+        );
+        assert_eq!(
+            found("fuel.capacity", "asobo-aircraft-c152"),
+            Ok(String::from("26"))
+        );
+        assert_eq!(
+            found("weights.max_gross_weight", "asobo-aircraft-c152"),
+            Err(String::from(
+                "table weights has no max_gross_weight for asobo-aircraft-c152"
+            ))
+        );
+        assert_eq!(
+            found("fuel.capacity", "asobo-aircraft-tbm930"),
+            Err(String::from(
+                "table fuel has no row for asobo-aircraft-tbm930"
+            ))
+        );
+        assert_eq!(
+            found("ballast.weight", "asobo-aircraft-tbm930"),
+            Err(String::from(
+                "no table ballast for @table:ballast.weight (see --table)"
+            ))
+        );
+    }
+}
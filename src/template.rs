@@ -0,0 +1,106 @@
+//! scaffolding patch files, for the template command
+
+use std::{io::Write, path::Path};
+
+use anyhow::Context;
+
+use crate::{encoding, find_target, read_entries, split_address, Args, Target};
+
+/// prints a patch file to start from, for the template command, filled in from `package` if
+/// given
+pub fn print(package: Option<&Path>, args: &Args, out: &mut impl Write) -> anyhow::Result<()> {
+    // JSON has no comments, but keys of a patch that aren't files are ignored, so the notes go in
+    // one of those.
+
+    let about = "Patches are keyed by package folder name. Under each file, keys are given as \
+        SECTION.key, and their values replace the ones in the file.";
+
+    let template = match package {
+        None => serde_json::json!({
+            "asobo-aircraft-tbm930": {
+                "_about": about,
+                "engines": {
+                    "GENERALENGINEDATA.static_thrust": "229",
+                    "TURBINEENGINEDATA.rated_shaft_hp": "850",
+                },
+                "flight_model": {
+                    "FLIGHT_TUNING.parasite_drag_scalar": "1.0",
+                },
+            }
+        }),
+        Some(package) => {
+            let name = package.file_name().unwrap_or_default().to_string_lossy();
+            let mut patch = serde_json::Map::new();
+            patch.insert("_about".into(), about.into());
+
+            for target in Target::all() {
+                let Some(path) = find_target(package, target, args) else {
+                    continue;
+                };
+                let text = encoding::read_to_string(&path)
+                    .with_context(|| format!("unable to read {}", path.display()))?;
+
+                let mut file = serde_json::Map::new();
+                for (address, value) in read_entries(&text) {
+                    // Keys before the first section are patched as .key
+                    let address = match split_address(&address) {
+                        (None, key) => format!(".{key}"),
+                        _ => address,
+                    };
+                    file.insert(address, value.into());
+                }
+                patch.insert(target.key().into(), file.into());
+            }
+
+            if patch.len() == 1 {
+                anyhow::bail!("no files to patch in {}", package.display());
+            }
+            serde_json::json!({ name: patch })
+        }
+    };
+
+    writeln!(out, "{}", serde_json::to_string_pretty(&template)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path, process};
+
+    use clap::Parser;
+
+    use crate::{read_patches, Args, Target};
+
+    #[test]
+    fn template_parses_back_as_patches() {
+        let args = Args::parse_from(["patchcfg", "template"]);
+        let mut out = Vec::new();
+        super::print(None, &args, &mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let patches = read_patches(Path::new("template.json"), &text, &args).unwrap();
+        let patch = &patches["asobo-aircraft-tbm930"];
+        assert!(patch.file(Target::find("engines").unwrap()).is_some());
+        assert!(patch.file(Target::find("flight_model").unwrap()).is_some());
+
+        let dir = std::env::temp_dir().join(format!("patchcfg-template-{}", process::id()));
+        let package = dir.join("asobo-aircraft-tbm930");
+        let tbm = package.join("SimObjects/Airplanes/tbm");
+        fs::create_dir_all(&tbm).unwrap();
+        fs::write(
+            tbm.join("engines.cfg"),
+            "[GENERALENGINEDATA]\nstatic_thrust = 100 ; thrust\n",
+        )
+        .unwrap();
+        let mut out = Vec::new();
+        let result = super::print(Some(&package), &args, &mut out);
+        let _ = fs::remove_dir_all(&dir);
+        result.unwrap();
+
+        let text = String::from_utf8(out).unwrap();
+        let patches = read_patches(Path::new("template.json"), &text, &args).unwrap();
+        let engines = patches["asobo-aircraft-tbm930"]
+            .file(Target::find("engines").unwrap())
+            .unwrap();
+        assert_eq!(engines.len(), 1);
+    }
+}
@@ -0,0 +1,167 @@
+//! cleaning up sections we patch, for --tidy
+//!
+//! Files that several tools have been at tend to collect duplicate keys. Within each section we
+//! touch, we keep only the last of each key (which is the one the sim reads) and can sort what's
+//! left. Comments above a key travel with it; lines before a section's first key and after its
+//! last stay where they are.
+
+use hashbrown::HashMap;
+
+use crate::line::{self, Line};
+
+/// a key along with the comments and blank lines leading up to it
+struct Item<'a> {
+    key: String,
+    lines: Vec<&'a str>,
+}
+
+/// tidies the sections of `text` for which `touched` returns true, by name
+///
+/// Keys before the first section belong to the section named `None`.
+pub fn tidy(text: &str, touched: impl Fn(Option<&str>) -> bool, sort: bool) -> String {
+    let mut tidied = String::with_capacity(text.len());
+    let mut section = None;
+    let mut body = Vec::new();
+
+    for line in text.lines() {
+        if let Line::Section { name, .. } = line::parse(line) {
+            tidy_section(&mut tidied, &body, touched(section), sort);
+            body.clear();
+            tidied += line;
+            tidied.push('\n');
+            section = Some(name);
+        } else {
+            body.push(line);
+        }
+    }
+    tidy_section(&mut tidied, &body, touched(section), sort);
+
+    tidied
+}
+
+fn tidy_section(tidied: &mut String, body: &[&str], touched: bool, sort: bool) {
+    let mut head = Vec::new();
+    let mut items: Vec<Item> = Vec::new();
+    let mut pending = Vec::new();
+
+    for &line in body {
+        match line::parse(line) {
+            Line::Entry { key, .. } if touched => {
+                pending.push(line);
+                let lines = std::mem::take(&mut pending);
+                items.push(Item {
+                    key: key.to_lowercase(),
+                    lines,
+                });
+            }
+            _ if items.is_empty() && touched => head.push(line),
+            _ => pending.push(line),
+        }
+    }
+    if items.is_empty() {
+        head.append(&mut pending);
+    }
+
+    // Working backwards, the first of each key we see is the last in the file, and so the one we
+    // keep. The comments of any earlier duplicate are moved to it.
+
+    let mut kept: HashMap<String, usize> = HashMap::new();
+    let mut dropped = vec![false; items.len()];
+    for idx in (0..items.len()).rev() {
+        let Some(&keep) = kept.get(&items[idx].key) else {
+            kept.insert(items[idx].key.clone(), idx);
+            continue;
+        };
+
+        dropped[idx] = true;
+        let mut comments = std::mem::take(&mut items[idx].lines);
+        comments.pop();
+        comments.append(&mut items[keep].lines);
+        items[keep].lines = comments;
+    }
+
+    let mut items: Vec<_> = items
+        .into_iter()
+        .zip(dropped)
+        .filter_map(|(item, dropped)| (!dropped).then_some(item))
+        .collect();
+    if sort {
+        items.sort_by(|a, b| a.key.cmp(&b.key));
+    }
+
+    let lines = head
+        .into_iter()
+        .chain(items.into_iter().flat_map(|item| item.lines))
+        .chain(pending);
+    for line in lines {
+        *tidied += line;
+        tidied.push('\n');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn touched_sections_keep_the_last_of_each_key() {
+        let text = "\
+[FLIGHT_TUNING]
+; tuning
+pitch = 1
+; lift
+lift = 1
+drag = 2
+; later lift
+LIFT = 3
+
+[GENERAL]
+b = 1
+a = 2
+b = 3
+";
+        let touched = |section: Option<&str>| section == Some("FLIGHT_TUNING");
+
+        assert_eq!(
+            tidy(text, touched, false),
+            "\
+[FLIGHT_TUNING]
+; tuning
+pitch = 1
+drag = 2
+; lift
+; later lift
+LIFT = 3
+
+[GENERAL]
+b = 1
+a = 2
+b = 3
+"
+        );
+        assert_eq!(
+            tidy(text, |_| true, true),
+            "\
+[FLIGHT_TUNING]
+; tuning
+drag = 2
+; lift
+; later lift
+LIFT = 3
+pitch = 1
+
+[GENERAL]
+a = 2
+b = 3
+"
+        );
+        assert_eq!(
+            tidy(
+                "x = 1\nx = 2\n[A]\nx = 1\nx = 2\n",
+                |section| section.is_none(),
+                false
+            ),
+            "x = 2\n[A]\nx = 1\nx = 2\n"
+        );
+    }
+}
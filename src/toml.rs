@@ -0,0 +1,260 @@
+//! reading project files, for --project
+//!
+//! A project file is TOML, but only the part of it a list of options needs: `key = value` pairs
+//! at the top level, where a value is a string, a number, a boolean, or an array of strings and
+//! numbers. Arrays may span lines. Tables aren't supported, and numbers and dates are kept as
+//! text, since they only ever end up on a command line.
+
+use std::{iter::Peekable, str::Chars};
+
+#[derive(Debug, PartialEq)]
+pub enum Value {
+    Text(String),
+    Bool(bool),
+    Array(Vec<String>),
+}
+
+/// reads the keys and values of a project file, in order
+pub fn parse(text: &str) -> Result<Vec<(String, Value)>, String> {
+    let mut parser = Parser {
+        chars: text.chars().peekable(),
+        line: 1,
+    };
+    let mut entries: Vec<(String, Value)> = Vec::new();
+
+    loop {
+        parser.skip_blank_lines();
+        match parser.chars.peek() {
+            None => break,
+            Some('[') => return Err(parser.error("tables aren't supported")),
+            Some(_) => {}
+        }
+
+        let key = parser.key()?;
+        parser.skip_spaces();
+        if parser.chars.next() != Some('=') {
+            return Err(parser.error(&format!("expected = after {key}")));
+        }
+        parser.skip_spaces();
+        let value = parser.value()?;
+        parser.skip_spaces();
+        parser.skip_comment();
+        match parser.chars.next() {
+            None => {}
+            Some('\n') => parser.line += 1,
+            Some('\r') if parser.chars.next() == Some('\n') => parser.line += 1,
+            Some(_) => return Err(parser.error("expected the end of the line")),
+        }
+
+        if entries.iter().any(|(existing, _)| *existing == key) {
+            return Err(format!("{key} is given more than once"));
+        }
+        entries.push((key, value));
+    }
+
+    Ok(entries)
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+    line: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: &str) -> String {
+        format!("line {}: {message}", self.line)
+    }
+
+    fn skip_spaces(&mut self) {
+        while self.chars.next_if(|&c| c == ' ' || c == '\t').is_some() {}
+    }
+
+    fn skip_comment(&mut self) {
+        if self.chars.peek() == Some(&'#') {
+            while self.chars.next_if(|&c| c != '\n').is_some() {}
+        }
+    }
+
+    /// skips spaces, comments and line endings
+    fn skip_blank_lines(&mut self) {
+        loop {
+            self.skip_spaces();
+            self.skip_comment();
+            match self.chars.peek() {
+                Some('\n') => self.line += 1,
+                Some('\r') => {}
+                _ => return,
+            }
+            self.chars.next();
+        }
+    }
+
+    fn key(&mut self) -> Result<String, String> {
+        if let Some(quote @ ('"' | '\'')) = self.chars.peek().copied() {
+            self.chars.next();
+            return self.string(quote);
+        }
+
+        let mut key = String::new();
+        while let Some(c) = self
+            .chars
+            .next_if(|&c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            key.push(c);
+        }
+        if key.is_empty() {
+            return Err(self.error("expected a key"));
+        }
+        Ok(key)
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        if self.chars.next_if_eq(&'[').is_none() {
+            return self.scalar().map(|(text, bool)| match bool {
+                Some(bool) => Value::Bool(bool),
+                None => Value::Text(text),
+            });
+        }
+
+        let mut items = Vec::new();
+        loop {
+            self.skip_blank_lines();
+            if self.chars.next_if_eq(&']').is_some() {
+                return Ok(Value::Array(items));
+            }
+            match self.scalar()? {
+                (_, Some(_)) => return Err(self.error("arrays may only hold strings and numbers")),
+                (text, None) => items.push(text),
+            }
+            self.skip_blank_lines();
+            match self.chars.next() {
+                Some(',') => {}
+                Some(']') => return Ok(Value::Array(items)),
+                _ => return Err(self.error("expected , or ] in array")),
+            }
+        }
+    }
+
+    /// a string, number or date as text, or a boolean
+    fn scalar(&mut self) -> Result<(String, Option<bool>), String> {
+        match self.chars.peek().copied() {
+            Some(quote @ ('"' | '\'')) => {
+                self.chars.next();
+                return Ok((self.string(quote)?, None));
+            }
+            Some('[') => return Err(self.error("arrays may not be nested")),
+            _ => {}
+        }
+
+        let mut text = String::new();
+        while let Some(c) = self
+            .chars
+            .next_if(|&c| c.is_ascii_alphanumeric() || "+-._:".contains(c))
+        {
+            text.push(c);
+        }
+        match text.as_str() {
+            "" => Err(self.error("expected a value")),
+            "true" => Ok((text, Some(true))),
+            "false" => Ok((text, Some(false))),
+            _ => Ok((text, None)),
+        }
+    }
+
+    /// the rest of a quoted string; only double-quoted strings have escapes
+    fn string(&mut self, quote: char) -> Result<String, String> {
+        let mut text = String::new();
+        loop {
+            match self.chars.next() {
+                None | Some('\n') => return Err(self.error("unterminated string")),
+                Some(c) if c == quote => return Ok(text),
+                Some('\\') if quote == '"' => match self.chars.next() {
+                    Some('"') => text.push('"'),
+                    Some('\\') => text.push('\\'),
+                    Some('n') => text.push('\n'),
+                    Some('t') => text.push('\t'),
+                    _ => return Err(self.error("unknown escape in string")),
+                },
+                Some(c) => text.push(c),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(value: &str) -> Value {
+        Value::Text(value.to_owned())
+    }
+
+    #[test]
+    fn reads_the_toml_it_supports() {
+        // Examples from the TOML spec's sections on strings, integers, floats, booleans, dates
+        // and arrays, as far as they go for us.
+        let project = r#"
+# This is a full-line comment
+key = "value"  # This is a comment at the end of a line
+bare_key = 'C:\Users\nodejs\templates'
+"quoted key" = "I'm a string. \"You can quote me\". Name\tJos\\ Mine.\nLocation\tSF."
+int = +99
+neg = -17
+flt = 6.626e-34
+date = 1979-05-27
+bool = false
+arr = [ "red", "yellow", 'green' ]
+multi = [
+  1,
+  2, # a comment in an array
+  3,
+]
+"#;
+        assert_eq!(
+            parse(project).unwrap(),
+            [
+                ("key", text("value")),
+                ("bare_key", text(r"C:\Users\nodejs\templates")),
+                (
+                    "quoted key",
+                    text("I'm a string. \"You can quote me\". Name\tJos\\ Mine.\nLocation\tSF."),
+                ),
+                ("int", text("+99")),
+                ("neg", text("-17")),
+                ("flt", text("6.626e-34")),
+                ("date", text("1979-05-27")),
+                ("bool", Value::Bool(false)),
+                (
+                    "arr",
+                    Value::Array(vec!["red".into(), "yellow".into(), "green".into()])
+                ),
+                (
+                    "multi",
+                    Value::Array(vec!["1".into(), "2".into(), "3".into()])
+                ),
+            ]
+            .map(|(key, value)| (key.to_owned(), value))
+        );
+        assert_eq!(parse("a = true\r\nb = 1\r\n").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn rejects_what_it_doesnt() {
+        let errors = [
+            ("[table]\nkey = 1", "line 1: tables aren't supported"),
+            ("a = 1\na = 2", "a is given more than once"),
+            ("a = \"open\nb = 1", "line 1: unterminated string"),
+            ("a = 1 2", "line 1: expected the end of the line"),
+            ("\n\na 1", "line 3: expected = after a"),
+            ("a = [[1]]", "line 1: arrays may not be nested"),
+            (
+                "a = [true]",
+                "line 1: arrays may only hold strings and numbers",
+            ),
+            ("a = \"\\q\"", "line 1: unknown escape in string"),
+        ];
+        for (project, error) in errors {
+            assert_eq!(parse(project).unwrap_err(), error, "{project:?}");
+        }
+    }
+}
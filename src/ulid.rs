@@ -0,0 +1,77 @@
+//! run IDs
+//!
+//! Each run gets a ULID: 48 bits of milliseconds since the epoch followed by 80 random bits,
+//! written in Crockford's base32. They're short enough to quote in a bug report and sort by time.
+
+use std::{
+    collections::hash_map::RandomState,
+    fmt::{self, Display},
+    hash::{BuildHasher, Hasher},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+#[derive(Clone, Copy, Debug)]
+pub struct Ulid(u128);
+
+impl Ulid {
+    pub fn new() -> Self {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis());
+
+        // The standard library has no random number generator, but every RandomState is seeded
+        // with fresh random keys, which is plenty for an ID nobody needs to be unguessable.
+
+        let random = |salt: u128| {
+            let mut hasher = RandomState::new().build_hasher();
+            hasher.write_u128(millis ^ salt);
+            hasher.finish() as u128
+        };
+        Ulid::from_parts(millis, random(0) << 64 | random(1))
+    }
+
+    /// puts together the milliseconds and random bits, keeping the 48 and 80 bits that fit
+    fn from_parts(millis: u128, random: u128) -> Self {
+        Ulid((millis & ((1 << 48) - 1)) << 80 | random & ((1 << 80) - 1))
+    }
+}
+
+impl Display for Ulid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // 26 characters of 5 bits each covers 130 bits, so the first character only holds 3.
+        for idx in (0..26).rev() {
+            let digit = (self.0 >> (idx * 5)) & 0x1f;
+            write!(f, "{}", ALPHABET[digit as usize] as char)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_the_spec() {
+        // The example from the ULID spec, whose timestamp is 1469918176385.
+        let ulid = Ulid::from_parts(1_469_918_176_385, 0xd676_4c61_efb9_9302_bd5b);
+        assert_eq!(ulid.to_string(), "01ARYZ6S41TSV4RRFFQ69G5FAV");
+        assert_eq!(Ulid::from_parts(0, 0).to_string(), "0".repeat(26));
+        assert_eq!(
+            Ulid::from_parts(u128::MAX, u128::MAX).to_string(),
+            "7ZZZZZZZZZZZZZZZZZZZZZZZZZ"
+        );
+    }
+
+    #[test]
+    fn new_ids_differ_and_sort_by_time() {
+        let earlier = Ulid::from_parts(1_469_918_176_385, u128::MAX).to_string();
+        let (a, b) = (Ulid::new().to_string(), Ulid::new().to_string());
+        assert_ne!(a, b);
+        assert_eq!(a.len(), 26);
+        assert!(a.chars().all(|c| ALPHABET.contains(&(c as u8))));
+        assert!(earlier < a);
+    }
+}
@@ -0,0 +1,191 @@
+//! writing zip archives, for --zip-out
+//!
+//! Config files are small and we only ever write a handful, so entries are stored rather than
+//! compressed. That keeps this to the headers and a CRC-32, and any unzip tool can read the result.
+
+use std::sync::OnceLock;
+
+/// a zip archive being built in memory
+#[derive(Debug, Default)]
+pub struct ZipWriter {
+    data: Vec<u8>,
+    central: Vec<u8>,
+    entries: u16,
+}
+
+impl ZipWriter {
+    /// adds a file, named by its path within the archive (with / separators)
+    pub fn add(&mut self, name: &str, contents: &[u8]) {
+        let offset = self.data.len() as u32;
+        let crc = crc32(contents);
+        let size = contents.len() as u32;
+
+        // Local file header: version 1.0, no flags, stored, no timestamp.
+        self.data.extend_from_slice(&0x0403_4b50_u32.to_le_bytes());
+        self.data.extend_from_slice(&10_u16.to_le_bytes());
+        self.data.extend_from_slice(&[0; 8]);
+        self.data.extend_from_slice(&crc.to_le_bytes());
+        self.data.extend_from_slice(&size.to_le_bytes());
+        self.data.extend_from_slice(&size.to_le_bytes());
+        self.data
+            .extend_from_slice(&(name.len() as u16).to_le_bytes());
+        self.data.extend_from_slice(&0_u16.to_le_bytes());
+        self.data.extend_from_slice(name.as_bytes());
+        self.data.extend_from_slice(contents);
+
+        // Central directory header, which repeats most of the above.
+        self.central
+            .extend_from_slice(&0x0201_4b50_u32.to_le_bytes());
+        self.central.extend_from_slice(&10_u16.to_le_bytes());
+        self.central.extend_from_slice(&10_u16.to_le_bytes());
+        self.central.extend_from_slice(&[0; 8]);
+        self.central.extend_from_slice(&crc.to_le_bytes());
+        self.central.extend_from_slice(&size.to_le_bytes());
+        self.central.extend_from_slice(&size.to_le_bytes());
+        self.central
+            .extend_from_slice(&(name.len() as u16).to_le_bytes());
+        self.central.extend_from_slice(&[0; 12]);
+        self.central.extend_from_slice(&offset.to_le_bytes());
+        self.central.extend_from_slice(name.as_bytes());
+
+        self.entries += 1;
+    }
+
+    /// finishes the archive, returning its bytes
+    pub fn finish(mut self) -> Vec<u8> {
+        let offset = self.data.len() as u32;
+        let size = self.central.len() as u32;
+        self.data.append(&mut self.central);
+
+        // End of central directory record.
+        self.data.extend_from_slice(&0x0605_4b50_u32.to_le_bytes());
+        self.data.extend_from_slice(&[0; 4]);
+        self.data.extend_from_slice(&self.entries.to_le_bytes());
+        self.data.extend_from_slice(&self.entries.to_le_bytes());
+        self.data.extend_from_slice(&size.to_le_bytes());
+        self.data.extend_from_slice(&offset.to_le_bytes());
+        self.data.extend_from_slice(&0_u16.to_le_bytes());
+        self.data
+    }
+}
+
+/// the CRC-32 (as used by zip and gzip) of some bytes
+fn crc32(data: &[u8]) -> u32 {
+    static TABLE: OnceLock<[u32; 256]> = OnceLock::new();
+    let table = TABLE.get_or_init(|| {
+        let mut table = [0; 256];
+        for (n, entry) in table.iter_mut().enumerate() {
+            let mut c = n as u32;
+            for _ in 0..8 {
+                c = if c & 1 != 0 {
+                    0xedb8_8320 ^ (c >> 1)
+                } else {
+                    c >> 1
+                };
+            }
+            *entry = c;
+        }
+        table
+    });
+
+    !data.iter().fold(!0, |crc, &byte| {
+        table[((crc ^ u32::from(byte)) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{fs, process};
+
+    use super::*;
+
+    fn u16_at(data: &[u8], at: usize) -> usize {
+        usize::from(u16::from_le_bytes([data[at], data[at + 1]]))
+    }
+
+    fn u32_at(data: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+    }
+
+    /// the names and contents of the entries in an archive, read by way of its central directory
+    pub(crate) fn entries(archive: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let end = archive.len() - 22;
+        assert_eq!(
+            u32_at(archive, end),
+            0x0605_4b50,
+            "no end of central directory"
+        );
+        let count = u16_at(archive, end + 10);
+        let mut at = u32_at(archive, end + 16) as usize;
+
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            assert_eq!(
+                u32_at(archive, at),
+                0x0201_4b50,
+                "bad central directory header"
+            );
+            let crc = u32_at(archive, at + 16);
+            let size = u32_at(archive, at + 20) as usize;
+            let name_len = u16_at(archive, at + 28);
+            let local = u32_at(archive, at + 42) as usize;
+            let name = String::from_utf8(archive[at + 46..at + 46 + name_len].to_vec()).unwrap();
+
+            assert_eq!(u32_at(archive, local), 0x0403_4b50, "bad local header");
+            let start = local + 30 + u16_at(archive, local + 26) + u16_at(archive, local + 28);
+            let contents = archive[start..start + size].to_vec();
+            assert_eq!(crc32(&contents), crc, "{name} fails its CRC");
+            entries.push((name, contents));
+            at += 46 + name_len;
+        }
+        entries
+    }
+
+    #[test]
+    fn crc32_matches_check_values() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(
+            crc32(b"The quick brown fox jumps over the lazy dog"),
+            0x414f_a339
+        );
+    }
+
+    #[test]
+    fn archive_reads_back() {
+        let mut zip = ZipWriter::default();
+        zip.add(
+            "pkg/SimObjects/engines.cfg",
+            b"[GENERALENGINEDATA]\nstatic_thrust = 200\n",
+        );
+        zip.add("manifest.json", b"{}\n");
+        zip.add("empty.cfg", b"");
+        let archive = zip.finish();
+
+        assert_eq!(
+            entries(&archive),
+            [
+                (
+                    "pkg/SimObjects/engines.cfg",
+                    &b"[GENERALENGINEDATA]\nstatic_thrust = 200\n"[..]
+                ),
+                ("manifest.json", b"{}\n"),
+                ("empty.cfg", b""),
+            ]
+            .map(|(name, contents)| (name.to_owned(), contents.to_vec()))
+        );
+
+        // Where unzip is installed, it has to agree.
+        let path = std::env::temp_dir().join(format!("patchcfg-zip-{}.zip", process::id()));
+        fs::write(&path, &archive).unwrap();
+        let status = process::Command::new("unzip")
+            .arg("-tq")
+            .arg(&path)
+            .stdout(process::Stdio::null())
+            .status();
+        let _ = fs::remove_file(&path);
+        if let Ok(status) = status {
+            assert!(status.success(), "unzip rejects the archive");
+        }
+    }
+}